@@ -0,0 +1,182 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+
+use crate::{config, Shared};
+
+/// Placeholder in an ASR command's arguments for the path of the recording to transcribe.
+const FILE_PLACEHOLDER: &str = "{file}";
+
+/// An external speech recognition command, like a `whisper.cpp` invocation, that transcribes a
+/// recording to its stdout.
+pub struct Asr {
+    /// Program and arguments, in which [`FILE_PLACEHOLDER`] stands for the recording.
+    command: Vec<String>,
+    timeout: Duration,
+}
+
+impl Asr {
+    pub fn new(command: Vec<String>, timeout: Duration) -> anyhow::Result<Self> {
+        config::check_command("asr_command", &command)?;
+        Ok(Self { command, timeout })
+    }
+
+    /// Returns the arguments to run for the recording at `audio`: each [`FILE_PLACEHOLDER`] is
+    /// replaced with its path, which is appended instead if there aren't any.
+    fn args(&self, audio: &Path) -> Vec<String> {
+        let audio = audio.to_string_lossy();
+        let mut args: Vec<String> = self.command[1..]
+            .iter()
+            .map(|arg| arg.replace(FILE_PLACEHOLDER, &audio))
+            .collect();
+        if !self
+            .command
+            .iter()
+            .any(|arg| arg.contains(FILE_PLACEHOLDER))
+        {
+            args.push(audio.into_owned());
+        }
+        args
+    }
+
+    /// Runs the command on the recording at `audio`, returning what it printed. It's killed if it
+    /// takes longer than the timeout.
+    pub async fn transcribe(&self, audio: &Path) -> anyhow::Result<String> {
+        let mut command = tokio::process::Command::new(&self.command[0]);
+        command
+            .args(self.args(audio))
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let output = tokio::time::timeout(self.timeout, command.output())
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out after {:?}", self.timeout))?
+            .with_context(|| format!("Failed to spawn {}", self.command[0]))?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "{} exited with {}: {}",
+                self.command[0],
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+/// Returns the path of the transcript of the recording at `audio`, like `recording-<id>.txt`, or
+/// its object name, like `<id>.txt`.
+pub fn transcript_name(audio: &str) -> String {
+    Path::new(audio)
+        .with_extension("txt")
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Transcribes a segment's recording, which must be at its final path, saving the transcript next
+/// to it and uploading it as `object_name` (the recording's, with a `.txt` extension), if set.
+///
+/// Failing to transcribe is logged, and otherwise ignored.
+pub async fn transcribe_segment(
+    shared: Arc<Shared>,
+    id: String,
+    audio: PathBuf,
+    object_name: Option<String>,
+) {
+    let Some(asr) = &shared.asr else {
+        return;
+    };
+    let transcript = match asr.transcribe(&audio).await {
+        Ok(transcript) => transcript,
+        Err(e) => {
+            warn!("Failed to transcribe segment {}: {:#}", id, e);
+            return;
+        }
+    };
+    let local = PathBuf::from(transcript_name(&audio.to_string_lossy()));
+    let res = tokio::fs::write(&local, &transcript)
+        .await
+        .and_then(|()| shared.permissions.apply(&local));
+    match res {
+        Ok(()) => debug!("Saved transcript of segment {} to {}", id, local.display()),
+        Err(e) => {
+            shared.storage.check_error(&e);
+            error!("Failed to save transcript of segment {}: {}", id, e);
+        }
+    }
+    let (Some(object_name), Some(client)) = (
+        object_name,
+        shared.uploader.as_ref().and_then(|u| u.client()),
+    ) else {
+        return;
+    };
+    let name = transcript_name(&object_name);
+    let metadata = serde_json::Value::Object(serde_json::Map::new());
+    match client
+        .put_meta(
+            &name,
+            transcript.as_bytes(),
+            "text/plain; charset=utf-8",
+            None,
+            &metadata,
+        )
+        .await
+    {
+        Ok(()) => info!(
+            "Uploaded transcript to GCS: gs://{}/{}{}",
+            client.path.bucket, client.path.prefix, name
+        ),
+        Err(e) => error!("Failed to upload transcript of segment {}: {:#}", id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let asr = |command: &[&str]| {
+            Asr::new(
+                command.iter().map(|s| s.to_string()).collect(),
+                Duration::from_secs(1),
+            )
+            .unwrap()
+        };
+        let audio = Path::new("/rec/recording-20240101T120000.flac");
+        assert_eq!(
+            asr(&["whisper-cli", "-m", "base.en.bin", "-f", "{file}", "-nt"]).args(audio),
+            [
+                "-m",
+                "base.en.bin",
+                "-f",
+                "/rec/recording-20240101T120000.flac",
+                "-nt"
+            ]
+        );
+        assert_eq!(
+            asr(&["transcribe", "--input={file}"]).args(audio),
+            ["--input=/rec/recording-20240101T120000.flac"]
+        );
+        assert_eq!(
+            asr(&["transcribe"]).args(audio),
+            ["/rec/recording-20240101T120000.flac"]
+        );
+        assert!(Asr::new(vec![], Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn test_transcript_name() {
+        assert_eq!(
+            transcript_name("/rec/loud/recording-20240101T120000.flac"),
+            "/rec/loud/recording-20240101T120000.txt"
+        );
+        assert_eq!(
+            transcript_name("loud/20240101T120000.flac"),
+            "loud/20240101T120000.txt"
+        );
+    }
+}
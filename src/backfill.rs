@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use log::{error, info};
+
+use crate::config::{AudioFormat, ContentHash};
+use crate::{gcs, naming, soxi};
+
+/// How each recording found by a backfill fared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Uploaded,
+    /// There's already an object by that name, like from an earlier backfill that was cut short.
+    Exists,
+    Failed,
+}
+
+/// Everything that a backfill needs to upload recordings like `reccon` would have.
+pub struct Backfill {
+    pub client: gcs::Client,
+    pub object_key: Option<naming::ObjectKeyTemplate>,
+    pub soxi: soxi::Soxi,
+    pub common_metadata: serde_json::Map<String, serde_json::Value>,
+    pub require_metadata: bool,
+    pub content_hash: Option<ContentHash>,
+    /// Names to give metadata keys in GCS instead of `reccon`'s own, per `metadata_keys`.
+    pub metadata_keys: BTreeMap<String, String>,
+    /// Most uploads at once.
+    pub concurrency: usize,
+}
+
+/// A finished recording found under the directory being backfilled.
+#[derive(Debug, PartialEq, Eq)]
+struct Found {
+    id: String,
+    object_name: String,
+    path: PathBuf,
+    format: AudioFormat,
+}
+
+/// Uploads every finished recording under `dir` that isn't in GCS yet, with the object name and
+/// metadata that it would have gotten had it been uploaded as it was recorded. This is safe to run
+/// again after it's interrupted or some uploads fail, since recordings already uploaded are
+/// skipped.
+pub async fn run(backfill: Backfill, dir: &Path) -> anyhow::Result<()> {
+    let mut found = Vec::new();
+    crate::upload::scan_files(dir, &mut found, |name| {
+        naming::id_from_recording_filename(name).is_some()
+    })
+    .await?;
+    // Oldest first, by segment ID rather than modification time, since the files may have been
+    // copied around since they were recorded.
+    let mut found: Vec<Found> = found
+        .into_iter()
+        .filter_map(|(_, path)| recording(dir, path))
+        .collect();
+    found.sort_by(|a, b| (&a.id, &a.object_name).cmp(&(&b.id, &b.object_name)));
+    let total = found.len();
+    info!("Backfilling {} recording(s) from {}", total, dir.display());
+
+    let backfill = Arc::new(backfill);
+    let concurrency = backfill.concurrency.max(1);
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut tally = Tally::new(total);
+    // Tally each upload as soon as it's done, making room for the next one, so that progress is
+    // logged as it's made rather than all at the end.
+    for recording in found {
+        if tasks.len() >= concurrency {
+            if let Some(res) = tasks.join_next().await {
+                tally.add(res.context("Backfill task failed")?);
+            }
+        }
+        let backfill = backfill.clone();
+        tasks.spawn(async move { upload(&backfill, &recording).await });
+    }
+    while let Some(res) = tasks.join_next().await {
+        tally.add(res.context("Backfill task failed")?);
+    }
+    if tally.failed > 0 {
+        anyhow::bail!(
+            "Failed to backfill {} recording(s); run the backfill again to retry",
+            tally.failed
+        );
+    }
+    Ok(())
+}
+
+/// How many of a backfill's recordings have fared each way so far.
+struct Tally {
+    total: usize,
+    uploaded: usize,
+    existing: usize,
+    failed: usize,
+}
+
+impl Tally {
+    fn new(total: usize) -> Self {
+        Self {
+            total,
+            uploaded: 0,
+            existing: 0,
+            failed: 0,
+        }
+    }
+
+    /// Counts another recording, logging the totals every 100 recordings and after the last.
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Uploaded => self.uploaded += 1,
+            Outcome::Exists => self.existing += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+        let done = self.uploaded + self.existing + self.failed;
+        if done.is_multiple_of(100) || done == self.total {
+            info!(
+                "Backfilled {}/{} recording(s): {} uploaded, {} already in GCS, {} failed",
+                done, self.total, self.uploaded, self.existing, self.failed
+            );
+        }
+    }
+}
+
+/// Makes sense of a file found by the scan, if it's a recording.
+fn recording(dir: &Path, path: PathBuf) -> Option<Found> {
+    let (id, object_name) = naming::object_name_for_recording(dir, &path)?;
+    let format = AudioFormat::from_extension(path.extension()?.to_str()?)?;
+    Some(Found {
+        id,
+        object_name,
+        path,
+        format,
+    })
+}
+
+async fn upload(backfill: &Backfill, recording: &Found) -> Outcome {
+    let client = &backfill.client;
+    let object_name = match &backfill.object_key {
+        Some(template) => template.render(&recording.id, &recording.object_name),
+        None => recording.object_name.clone(),
+    };
+    match client.exists(&object_name).await {
+        Ok(false) => {}
+        Ok(true) => {
+            info!(
+                "Skipping {}, which is already in GCS",
+                recording.path.display()
+            );
+            return Outcome::Exists;
+        }
+        Err(e) => {
+            error!("Failed to check for {} in GCS: {:#}", object_name, e);
+            return Outcome::Failed;
+        }
+    }
+    let res = async {
+        let mut metadata = backfill.common_metadata.clone();
+        let measured =
+            crate::recording_metadata(&backfill.soxi, backfill.require_metadata, &recording.path)
+                .await?;
+        metadata.extend(measured);
+        let mut contents = tokio::fs::read(&recording.path)
+            .await
+            .with_context(|| format!("Failed to read recording {}", recording.path.display()))?;
+        if let Some(hash) = backfill.content_hash {
+            let digest;
+            (contents, digest) = hash.hex_digest_blocking(contents).await?;
+            metadata.insert(hash.metadata_key().to_string(), digest.into());
+        }
+        let metadata = crate::upload::rename_keys(metadata, &backfill.metadata_keys);
+        let content_disposition = client.options.content_disposition(&recording.id);
+        client
+            .put_meta(
+                &object_name,
+                &contents,
+                recording.format.content_type(),
+                content_disposition.as_deref(),
+                &metadata.into(),
+            )
+            .await?;
+        anyhow::Ok(())
+    }
+    .await;
+    match res {
+        Ok(()) => {
+            info!(
+                "Uploaded {} to gs://{}/{}{}",
+                recording.path.display(),
+                client.path.bucket,
+                client.path.prefix,
+                object_name
+            );
+            Outcome::Uploaded
+        }
+        Err(e) => {
+            error!("Failed to backfill {}: {:#}", recording.path.display(), e);
+            Outcome::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording() {
+        let dir = Path::new("/archive");
+        assert_eq!(
+            recording(
+                dir,
+                PathBuf::from("/archive/2023/06/01/recording-20230601T120000.flac")
+            ),
+            Some(Found {
+                id: "20230601T120000".to_string(),
+                object_name: "20230601T120000.flac".to_string(),
+                path: PathBuf::from("/archive/2023/06/01/recording-20230601T120000.flac"),
+                format: AudioFormat::Flac,
+            })
+        );
+        assert_eq!(
+            recording(dir, PathBuf::from("/archive/loud/recording-x.16k.ogg"))
+                .map(|r| (r.object_name, r.format)),
+            Some(("loud/x.16k.ogg".to_string(), AudioFormat::Ogg))
+        );
+        assert_eq!(
+            recording(dir, PathBuf::from("/archive/recording-x.flac.local")),
+            None
+        );
+    }
+}
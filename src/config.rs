@@ -1,12 +1,572 @@
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 
 use serde::Deserialize;
 
 pub const DEFAULT_FILENAME: &str = "reccon.toml";
 
-#[derive(Debug, Deserialize)]
+/// Where recordings go if no `storage_dir` is set.
+pub fn default_storage_dir() -> PathBuf {
+    std::env::temp_dir().join("recordings")
+}
+
+/// Checks that a command to run per the option `name`, like `encoder_command`, has at least a
+/// program to run.
+pub fn check_command(name: &str, command: &[String]) -> anyhow::Result<()> {
+    if command.first().is_none_or(|program| program.is_empty()) {
+        anyhow::bail!("Need {name} to have at least a program to run");
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct Config {
+    pub startup_delay_secs: Option<f64>,
+    pub startup_jitter_secs: Option<f64>,
     pub storage_dir: Option<PathBuf>,
+    pub storage_dirs: Option<Vec<PathBuf>>,
+    pub local_layout: Option<LocalLayout>,
+    pub fallback_dir: Option<PathBuf>,
+    pub file_mode: Option<u32>,
+    pub file_group: Option<String>,
+    pub encoder_pool_size: Option<usize>,
+    pub encoder_command: Option<Vec<String>>,
+    pub strip_comments: Option<bool>,
     pub threshold: Option<f64>,
+    /// Alternative to `threshold`, in raw sample units.
+    pub threshold_raw: Option<i64>,
+    pub remove_dc: Option<bool>,
+    pub detect_gain: Option<f64>,
+    pub input_device: Option<String>,
+    pub input_channels: Option<u32>,
+    pub input_channel_map: Option<Vec<u32>>,
+    pub sample_format: Option<SampleFormat>,
+    pub byte_order: Option<ByteOrder>,
+    pub detector: Option<DetectorKind>,
+    pub detector_percentile: Option<f64>,
+    pub rolling_threshold_window_secs: Option<f64>,
+    pub rolling_threshold_percentile: Option<f64>,
+    pub detect_filter: Option<Vec<String>>,
+    pub vad_command: Option<Vec<String>>,
+    pub vad_fallback: Option<bool>,
+    pub split_channels: Option<bool>,
+    pub monitor: Option<bool>,
+    pub adaptive_tail: Option<bool>,
+    pub tail_min_secs: Option<f64>,
+    pub tail_max_secs: Option<f64>,
+    pub flush_interval_secs: Option<f64>,
+    pub relative_dip_db: Option<f64>,
+    pub relative_dip_secs: Option<f64>,
+    pub segment_jitter_percent: Option<f64>,
+    pub segment_jitter_seed: Option<u64>,
+    pub post_segment_cooldown_secs: Option<f64>,
+    pub pending_quiet_tolerance: Option<u32>,
+    pub discard_incomplete_on_eof: Option<bool>,
+    pub min_segment_secs: Option<f64>,
+    pub min_segment_counts_preroll: Option<bool>,
+    pub edge_fade_ms: Option<u32>,
+    pub lead_pad_ms: Option<u32>,
+    pub ring_buffer_secs: Option<f64>,
+    pub max_segments: Option<u64>,
+    pub max_segments_per_minute: Option<u32>,
+    pub max_segment_bytes: Option<u64>,
+    pub max_runtime_secs: Option<f64>,
+    pub exit_after_idle_secs: Option<f64>,
+    pub max_silent_secs: Option<f64>,
+    pub silence_action: Option<SilenceAction>,
+    pub silence_command: Option<Vec<String>>,
+    pub resume_window_secs: Option<f64>,
     pub gcs_bucket: Option<String>,
+    pub gcs_scopes: Option<Vec<String>>,
+    pub gcs_required: Option<bool>,
+    pub gcs_check_bucket: Option<bool>,
+    pub cache_control: Option<String>,
+    pub content_disposition: Option<String>,
+    pub object_key_template: Option<String>,
+    pub content_hash: Option<ContentHash>,
+    pub obfuscate_names: Option<bool>,
+    pub id_state_file: Option<PathBuf>,
+    pub local_retention_secs: Option<f64>,
+    pub gcs_metadata: Option<BTreeMap<String, String>>,
+    pub metadata_keys: Option<BTreeMap<String, String>>,
+    pub latest_alias: Option<String>,
+    pub stream_to_gcs: Option<bool>,
+    pub http_timeout_secs: Option<f64>,
+    pub http_connect_timeout_secs: Option<f64>,
+    pub http_pool_size: Option<usize>,
+    pub upload_queue_len: Option<usize>,
+    pub upload_concurrency: Option<usize>,
+    pub upload_deadline_secs: Option<f64>,
+    pub upload_journal: Option<bool>,
+    pub ordered_uploads: Option<bool>,
+    pub max_upload_memory_bytes: Option<u64>,
+    pub finalize_concurrency: Option<usize>,
+    pub encoder_reap_timeout_secs: Option<f64>,
+    pub encoder_write_timeout_secs: Option<f64>,
+    pub provenance_metadata: Option<bool>,
+    pub envelope_metadata: Option<bool>,
+    pub require_metadata: Option<bool>,
+    pub min_samples: Option<u64>,
+    pub verify_before_upload: Option<bool>,
+    pub manifest_mode: Option<ManifestMode>,
+    pub sqlite_db: Option<PathBuf>,
+    pub output_fifo: Option<PathBuf>,
+    pub level_log: Option<PathBuf>,
+    pub health_addr: Option<String>,
+    pub health_stall_secs: Option<f64>,
+    pub asr_command: Option<Vec<String>>,
+    pub asr_timeout_secs: Option<f64>,
+    pub post_process_command: Option<Vec<String>>,
+    pub post_process_timeout_secs: Option<f64>,
+    pub post_process_before_upload: Option<bool>,
+    pub post_process_save_output: Option<bool>,
+    pub sensor_command: Option<Vec<String>>,
+    pub sensor_timeout_secs: Option<f64>,
+    pub loudness_classes: Option<Vec<LoudnessClass>>,
+    pub secondary_encoding: Option<SecondaryEncoding>,
+    /// Inputs to record independently, each per a `[[source]]` table.
+    pub source: Option<Vec<Source>>,
+}
+
+impl Config {
+    /// Layers `overlay` on top of this config: each field set in `overlay` replaces the one here,
+    /// whole (so tables like `gcs_metadata` and lists like `loudness_classes` aren't combined).
+    /// Setting either of a pair of alternatives, like `threshold` and `threshold_raw`, replaces
+    /// both.
+    pub fn merge(mut self, overlay: Config) -> Config {
+        if overlay.threshold.is_some() || overlay.threshold_raw.is_some() {
+            (self.threshold, self.threshold_raw) = (None, None);
+        }
+        if overlay.storage_dir.is_some() || overlay.storage_dirs.is_some() {
+            (self.storage_dir, self.storage_dirs) = (None, None);
+        }
+        macro_rules! merge {
+            ($($field:ident,)*) => {
+                Config {
+                    $($field: overlay.$field.or(self.$field),)*
+                }
+            };
+        }
+        merge!(
+            startup_delay_secs,
+            startup_jitter_secs,
+            storage_dir,
+            storage_dirs,
+            local_layout,
+            fallback_dir,
+            file_mode,
+            file_group,
+            encoder_pool_size,
+            encoder_command,
+            strip_comments,
+            threshold,
+            threshold_raw,
+            remove_dc,
+            detect_gain,
+            input_device,
+            input_channels,
+            input_channel_map,
+            sample_format,
+            byte_order,
+            detector,
+            detector_percentile,
+            rolling_threshold_window_secs,
+            rolling_threshold_percentile,
+            detect_filter,
+            vad_command,
+            vad_fallback,
+            split_channels,
+            monitor,
+            adaptive_tail,
+            tail_min_secs,
+            tail_max_secs,
+            flush_interval_secs,
+            relative_dip_db,
+            relative_dip_secs,
+            segment_jitter_percent,
+            segment_jitter_seed,
+            post_segment_cooldown_secs,
+            pending_quiet_tolerance,
+            discard_incomplete_on_eof,
+            min_segment_secs,
+            min_segment_counts_preroll,
+            edge_fade_ms,
+            lead_pad_ms,
+            ring_buffer_secs,
+            max_segments,
+            max_segments_per_minute,
+            max_segment_bytes,
+            max_runtime_secs,
+            exit_after_idle_secs,
+            max_silent_secs,
+            silence_action,
+            silence_command,
+            resume_window_secs,
+            gcs_bucket,
+            gcs_scopes,
+            gcs_required,
+            gcs_check_bucket,
+            cache_control,
+            content_disposition,
+            object_key_template,
+            content_hash,
+            obfuscate_names,
+            id_state_file,
+            local_retention_secs,
+            gcs_metadata,
+            metadata_keys,
+            latest_alias,
+            stream_to_gcs,
+            http_timeout_secs,
+            http_connect_timeout_secs,
+            http_pool_size,
+            upload_queue_len,
+            upload_concurrency,
+            upload_deadline_secs,
+            upload_journal,
+            ordered_uploads,
+            max_upload_memory_bytes,
+            finalize_concurrency,
+            encoder_reap_timeout_secs,
+            encoder_write_timeout_secs,
+            provenance_metadata,
+            envelope_metadata,
+            require_metadata,
+            min_samples,
+            verify_before_upload,
+            manifest_mode,
+            sqlite_db,
+            output_fifo,
+            level_log,
+            health_addr,
+            health_stall_secs,
+            asr_command,
+            asr_timeout_secs,
+            post_process_command,
+            post_process_timeout_secs,
+            post_process_before_upload,
+            post_process_save_output,
+            sensor_command,
+            sensor_timeout_secs,
+            loudness_classes,
+            secondary_encoding,
+            source,
+        )
+    }
+}
+
+/// One of several inputs to record at once, each as if by its own `reccon`, per a `[[source]]`
+/// table of settings that replace the top-level ones.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Source {
+    /// Names the source, in its segments' IDs and its storage subdirectory.
+    pub label: String,
+    #[serde(flatten)]
+    pub config: Config,
+}
+
+impl Source {
+    /// Returns the config to record this source with: its own settings on top of `base`'s. Unless
+    /// it sets its own, it records into a subdirectory named for its label of each of `base`'s
+    /// storage directories (and fallback directory), so that sources never share one.
+    pub fn config(self, base: &Config) -> Config {
+        let mut own = self.config;
+        if own.storage_dir.is_none() && own.storage_dirs.is_none() {
+            match &base.storage_dirs {
+                Some(dirs) => {
+                    own.storage_dirs = Some(dirs.iter().map(|dir| dir.join(&self.label)).collect())
+                }
+                None => {
+                    let dir = base.storage_dir.clone().unwrap_or_else(default_storage_dir);
+                    own.storage_dir = Some(dir.join(&self.label));
+                }
+            }
+        }
+        if own.fallback_dir.is_none() {
+            own.fallback_dir = base.fallback_dir.as_ref().map(|dir| dir.join(&self.label));
+        }
+        let mut base = base.clone();
+        (base.storage_dir, base.storage_dirs, base.source) = (None, None, None);
+        base.merge(own)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoudnessClass {
+    /// Name of the class, used as a subdirectory and object name prefix.
+    pub name: String,
+    /// Minimum segment peak for this class, as a fraction of full scale.
+    pub min_peak: f64,
+}
+
+/// A second copy of each recording, encoded differently, like at a lower sample rate.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecondaryEncoding {
+    /// Inserted before the extension of filenames and object names, like `16k`.
+    pub suffix: String,
+    /// Sample rate in Hz. Defaults to that of the recording.
+    pub rate: Option<u32>,
+    #[serde(default)]
+    pub format: AudioFormat,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioFormat {
+    #[default]
+    Flac,
+    Wav,
+    /// Ogg Vorbis.
+    Ogg,
+}
+
+impl AudioFormat {
+    const ALL: [AudioFormat; 3] = [AudioFormat::Flac, AudioFormat::Wav, AudioFormat::Ogg];
+
+    /// Filename extension for this format, which is also its `sox(1)` file type.
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "flac",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Ogg => "ogg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Ogg => "audio/ogg",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.extension() == ext)
+    }
+}
+
+/// Format of the samples that `rec(1)` sends `reccon`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    /// Signed 16-bit integers.
+    #[default]
+    S16,
+    /// 32-bit floats, nominally between -1.0 and 1.0. These are converted to 16-bit samples as
+    /// they're read, so the rest of `reccon` only ever sees those.
+    F32,
+}
+
+impl SampleFormat {
+    pub fn bytes_per_sample(self) -> usize {
+        match self {
+            SampleFormat::S16 => 2,
+            SampleFormat::F32 => 4,
+        }
+    }
+}
+
+/// Byte order of the raw samples that `rec(1)` sends `reccon` and that `reccon` sends its
+/// encoders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ByteOrder {
+    /// Little-endian.
+    #[default]
+    Le,
+    /// Big-endian.
+    Be,
+}
+
+impl ByteOrder {
+    /// The `sox(1)` option for raw audio in this byte order.
+    pub fn sox_arg(self) -> &'static str {
+        match self {
+            ByteOrder::Le => "-L",
+            ByteOrder::Be => "-B",
+        }
+    }
+
+    pub fn read_i16(self, bytes: [u8; 2]) -> i16 {
+        match self {
+            ByteOrder::Le => i16::from_le_bytes(bytes),
+            ByteOrder::Be => i16::from_be_bytes(bytes),
+        }
+    }
+
+    pub fn write_i16(self, sample: i16) -> [u8; 2] {
+        match self {
+            ByteOrder::Le => sample.to_le_bytes(),
+            ByteOrder::Be => sample.to_be_bytes(),
+        }
+    }
+
+    pub fn read_f32(self, bytes: [u8; 4]) -> f32 {
+        match self {
+            ByteOrder::Le => f32::from_le_bytes(bytes),
+            ByteOrder::Be => f32::from_be_bytes(bytes),
+        }
+    }
+}
+
+/// Algorithm for the hash of each recording's contents that goes in its metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentHash {
+    Md5,
+    Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectorKind {
+    /// Compare each chunk's peak absolute sample against the threshold.
+    Peak,
+    /// Compare a high percentile of each chunk's absolute samples against the threshold.
+    Percentile,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LocalLayout {
+    /// All recordings directly in the storage directory (or their loudness class's subdirectory).
+    #[default]
+    Flat,
+    /// Recordings in `YYYY/MM/DD` subdirectories by the UTC date that they started.
+    Date,
+}
+
+/// What to do when nothing has been recorded for `max_silent_secs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SilenceAction {
+    /// Log an error, and keep recording.
+    #[default]
+    Log,
+    /// Log an error, and exit unsuccessfully once pending uploads are done.
+    Exit,
+    /// Log an error, run `silence_command`, and keep recording.
+    Command,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ManifestMode {
+    /// One JSON file alongside each recording.
+    Sidecar,
+    /// One line per recording in a daily `manifest-YYYYMMDD.jsonl`.
+    Jsonl,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge() {
+        let base = Config {
+            threshold: Some(0.1),
+            storage_dir: Some(PathBuf::from("/srv/recordings")),
+            gcs_bucket: Some("base-bucket".to_string()),
+            gcs_metadata: Some(BTreeMap::from([
+                ("site".to_string(), "home".to_string()),
+                ("mic".to_string(), "usb".to_string()),
+            ])),
+            ..Config::default()
+        };
+        let host = Config {
+            threshold: Some(0.2),
+            gcs_metadata: Some(BTreeMap::from([("site".to_string(), "garage".to_string())])),
+            monitor: Some(false),
+            ..Config::default()
+        };
+        let merged = base.merge(host);
+        // Set in the later file: overridden.
+        assert_eq!(merged.threshold, Some(0.2));
+        assert_eq!(merged.monitor, Some(false));
+        // Only in the earlier file: kept.
+        assert_eq!(merged.storage_dir, Some(PathBuf::from("/srv/recordings")));
+        assert_eq!(merged.gcs_bucket.as_deref(), Some("base-bucket"));
+        // Tables are replaced, not combined.
+        assert_eq!(
+            merged.gcs_metadata,
+            Some(BTreeMap::from([("site".to_string(), "garage".to_string())]))
+        );
+        // In neither: still unset.
+        assert_eq!(merged.max_segments, None);
+
+        // Setting one of a pair of alternatives replaces the other.
+        let host = Config {
+            threshold_raw: Some(500),
+            storage_dirs: Some(vec![PathBuf::from("/mnt/a"), PathBuf::from("/mnt/b")]),
+            ..Config::default()
+        };
+        let merged = merged.merge(host);
+        assert_eq!(merged.threshold, None);
+        assert_eq!(merged.threshold_raw, Some(500));
+        assert_eq!(merged.storage_dir, None);
+        assert_eq!(merged.storage_dirs.map(|dirs| dirs.len()), Some(2));
+    }
+
+    #[test]
+    fn test_source_config() {
+        let base = Config {
+            threshold: Some(0.1),
+            storage_dir: Some(PathBuf::from("/srv/recordings")),
+            fallback_dir: Some(PathBuf::from("/tmp/fallback")),
+            gcs_bucket: Some("bucket/rooms".to_string()),
+            ..Config::default()
+        };
+        let source = |label: &str, config| Source {
+            label: label.to_string(),
+            config,
+        };
+        let kitchen = source(
+            "kitchen",
+            Config {
+                threshold: Some(0.3),
+                input_device: Some("hw:1".to_string()),
+                ..Config::default()
+            },
+        )
+        .config(&base);
+        assert_eq!(kitchen.threshold, Some(0.3));
+        assert_eq!(kitchen.input_device.as_deref(), Some("hw:1"));
+        assert_eq!(kitchen.gcs_bucket.as_deref(), Some("bucket/rooms"));
+        assert_eq!(
+            kitchen.storage_dir,
+            Some(PathBuf::from("/srv/recordings/kitchen"))
+        );
+        assert_eq!(kitchen.storage_dirs, None);
+        assert_eq!(
+            kitchen.fallback_dir,
+            Some(PathBuf::from("/tmp/fallback/kitchen"))
+        );
+
+        // A source's own storage replaces the base's altogether.
+        let garage = source(
+            "garage",
+            Config {
+                storage_dirs: Some(vec![PathBuf::from("/a"), PathBuf::from("/b")]),
+                ..Config::default()
+            },
+        )
+        .config(&base);
+        assert_eq!(garage.storage_dir, None);
+        assert_eq!(
+            garage.storage_dirs,
+            Some(vec![PathBuf::from("/a"), PathBuf::from("/b")])
+        );
+
+        let base = Config {
+            storage_dirs: Some(vec![PathBuf::from("/a"), PathBuf::from("/b")]),
+            ..Config::default()
+        };
+        let attic = source("attic", Config::default()).config(&base);
+        assert_eq!(
+            attic.storage_dirs,
+            Some(vec![PathBuf::from("/a/attic"), PathBuf::from("/b/attic")])
+        );
+        assert_eq!(attic.fallback_dir, None);
+    }
 }
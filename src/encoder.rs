@@ -0,0 +1,443 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, error, warn};
+
+use crate::config::{self, AudioFormat, SecondaryEncoding};
+use crate::naming;
+use crate::storage::FilePermissions;
+
+/// Format of every primary recording, as written by [`spawn`].
+pub const FORMAT: AudioFormat = AudioFormat::Flac;
+
+/// Placeholders in an [`EncoderCommand`]'s arguments.
+const OUTPUT_PLACEHOLDER: &str = "{output}";
+const RATE_PLACEHOLDER: &str = "{rate}";
+const CHANNELS_PLACEHOLDER: &str = "{channels}";
+
+/// A command to encode recordings with instead of `sox(1)`, like a shell pipeline through a
+/// plugin host.
+///
+/// Like `sox(1)` would, it reads raw audio (signed 16-bit little-endian samples) from its stdin
+/// and writes [`FORMAT`] to the file at [`OUTPUT_PLACEHOLDER`]. Closing its stdin is what tells it
+/// that the recording is over, after which it should finish writing and exit. Its stdout is
+/// discarded, and its stderr goes to ours.
+#[derive(Debug, Clone)]
+pub struct EncoderCommand {
+    /// Program and arguments, with placeholders.
+    command: Vec<String>,
+    rate: u64,
+    channels: u32,
+}
+
+impl EncoderCommand {
+    /// Uses `command` to encode audio with the given sample rate and number of channels.
+    pub fn new(command: Vec<String>, rate: u64, channels: u32) -> anyhow::Result<Self> {
+        config::check_command("encoder_command", &command)?;
+        if !command.iter().any(|arg| arg.contains(OUTPUT_PLACEHOLDER)) {
+            anyhow::bail!("Need {OUTPUT_PLACEHOLDER} in encoder_command, for where to write");
+        }
+        Ok(Self {
+            command,
+            rate,
+            channels,
+        })
+    }
+
+    /// Returns the program and arguments to run to encode to `output`.
+    fn args(&self, output: &Path) -> Vec<String> {
+        let output = output.to_string_lossy();
+        self.command
+            .iter()
+            .map(|arg| {
+                arg.replace(OUTPUT_PLACEHOLDER, &output)
+                    .replace(RATE_PLACEHOLDER, &self.rate.to_string())
+                    .replace(CHANNELS_PLACEHOLDER, &self.channels.to_string())
+            })
+            .collect()
+    }
+}
+
+/// Spawns a process that encodes raw audio from its stdin to [`FORMAT`] at `path`: `sox(1)`, or
+/// `custom` if set. Unless `strip_comments` is false, `sox(1)` is told to write an empty comment
+/// rather than its default one; `custom` writes whatever it does either way.
+pub fn spawn(
+    path: &Path,
+    raw_audio_args: &[&str],
+    custom: Option<&EncoderCommand>,
+    strip_comments: bool,
+    permissions: &FilePermissions,
+) -> anyhow::Result<Child> {
+    if permissions.mode.is_some() || permissions.gid.is_some() {
+        permissions
+            .create(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+    }
+    let Some(custom) = custom else {
+        return Command::new("sox")
+            .arg("-q")
+            .args(raw_audio_args)
+            .arg("-")
+            .args(["-t", FORMAT.extension()])
+            .args(comment_args(strip_comments))
+            .arg(path)
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to spawn sox(1)");
+    };
+    let args = custom.args(path);
+    Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Failed to spawn encoder_command {}", args[0]))
+}
+
+/// Spawns `sox(1)` to encode raw audio to its standard output, for `stream_to_gcs`.
+///
+/// It can't seek back to the start of its output once it knows how long the recording is, so the
+/// FLAC header that it writes leaves out the sample count.
+pub fn spawn_streaming(raw_audio_args: &[&str], strip_comments: bool) -> anyhow::Result<Child> {
+    Command::new("sox")
+        .arg("-q")
+        .args(raw_audio_args)
+        .arg("-")
+        .args(["-t", FORMAT.extension()])
+        .args(comment_args(strip_comments))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn sox(1)")
+}
+
+/// Arguments that make `sox(1)` write an empty comment instead of its default one, if asked.
+fn comment_args(strip_comments: bool) -> &'static [&'static str] {
+    if strip_comments {
+        &["--comment", ""]
+    } else {
+        &[]
+    }
+}
+
+/// Writes to encoders' input on a thread of its own, giving up on an encoder that stops reading
+/// it, like when one is stuck on a hung disk, so that writing to one can't stall recording
+/// indefinitely.
+///
+/// Each write through [`write`] hands the encoder's input and the data over a bounded channel to
+/// the writer thread, and waits up to the timeout for it to finish. If it doesn't, the write fails
+/// with the input left behind on the thread. Killing the encoder wouldn't reliably unblock the
+/// write: another process may hold the other end of the pipe, as in an `encoder_command` pipeline,
+/// and a process stuck in the kernel can't be killed anyway. So the stuck thread is abandoned, to
+/// close the input and exit if its write ever does finish, and the next write starts a new one.
+/// Only one write is in progress at a time, so this is only for the main loop.
+pub struct InputWriter {
+    timeout: Duration,
+    state: Mutex<WriterState>,
+}
+
+#[derive(Default)]
+struct WriterState {
+    /// The thread that writes, unless there isn't one yet or the last one got stuck.
+    thread: Option<WriterThread>,
+    /// What the last write copied its data into, to reuse.
+    buf: Vec<u8>,
+}
+
+struct WriterThread {
+    writes: mpsc::SyncSender<(ChildStdin, Vec<u8>)>,
+    written: mpsc::Receiver<(ChildStdin, Vec<u8>, io::Result<()>)>,
+}
+
+impl WriterThread {
+    fn spawn() -> io::Result<Self> {
+        let (writes, to_write) = mpsc::sync_channel::<(ChildStdin, Vec<u8>)>(1);
+        let (done, written) = mpsc::sync_channel(1);
+        std::thread::Builder::new()
+            .name("encoder-writer".to_string())
+            .spawn(move || {
+                for (mut stdin, data) in to_write {
+                    let res = stdin.write_all(&data);
+                    // If the write took too long, nobody's waiting for it anymore.
+                    if done.send((stdin, data, res)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+        Ok(Self { writes, written })
+    }
+}
+
+impl InputWriter {
+    /// Gives up on encoders that take longer than `timeout` to accept a write.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            state: Mutex::new(WriterState::default()),
+        }
+    }
+
+    /// Writes `data` to `stdin`, returning it along with the result if the write finished in time.
+    fn write(&self, stdin: ChildStdin, data: &[u8]) -> io::Result<(ChildStdin, io::Result<()>)> {
+        let mut state = self.state.lock().unwrap();
+        let thread = match state.thread.take() {
+            Some(thread) => thread,
+            None => WriterThread::spawn()?,
+        };
+        let mut buf = std::mem::take(&mut state.buf);
+        buf.clear();
+        buf.extend_from_slice(data);
+        let gone = || io::Error::other("encoder writer thread exited");
+        // There's room in the channel, since the thread has handed back everything it was given.
+        thread.writes.send((stdin, buf)).map_err(|_| gone())?;
+        match thread.written.recv_timeout(self.timeout) {
+            Ok((stdin, buf, res)) => {
+                *state = WriterState {
+                    thread: Some(thread),
+                    buf,
+                };
+                Ok((stdin, res))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "encoder stopped reading its input",
+            )),
+            Err(mpsc::RecvTimeoutError::Disconnected) => Err(gone()),
+        }
+    }
+}
+
+/// Writes `data` to the encoder's input, through `writer` if set. A write that takes longer than
+/// the writer's timeout fails with [`io::ErrorKind::TimedOut`], and leaves the encoder without
+/// its input.
+pub fn write(encoder: &mut Child, data: &[u8], writer: Option<&InputWriter>) -> io::Result<()> {
+    let Some(stdin) = encoder.stdin.take() else {
+        return Err(io::ErrorKind::BrokenPipe.into());
+    };
+    let Some(writer) = writer else {
+        let stdin = encoder.stdin.insert(stdin);
+        return stdin.write_all(data);
+    };
+    let res = writer.write(stdin, data);
+    match res {
+        Ok((stdin, res)) => {
+            encoder.stdin = Some(stdin);
+            res
+        }
+        Err(e) => {
+            if e.kind() == io::ErrorKind::TimedOut {
+                error!(
+                    "Encoder (pid {}) hasn't read its input in {:?}; giving up on it",
+                    encoder.id(),
+                    writer.timeout
+                );
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Re-encodes the finished recording at `src` into `dst`, per `encoding`.
+pub async fn transcode(
+    src: &Path,
+    dst: &Path,
+    encoding: &SecondaryEncoding,
+    permissions: &FilePermissions,
+) -> anyhow::Result<()> {
+    if permissions.mode.is_some() || permissions.gid.is_some() {
+        permissions
+            .create(dst)
+            .with_context(|| format!("Failed to create {}", dst.display()))?;
+    }
+    let mut command = tokio::process::Command::new("sox");
+    command
+        .arg("-q")
+        .arg(src)
+        .args(["-t", encoding.format.extension()]);
+    if let Some(rate) = encoding.rate {
+        command.arg("-r").arg(rate.to_string());
+    }
+    let output = command
+        .arg(dst)
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .context("Failed to spawn sox(1)")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "sox(1) exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// An encoder that was started before it was needed, writing to a placeholder file.
+pub struct Spare {
+    pub encoder: Child,
+    pub path: PathBuf,
+}
+
+/// A small pool of pre-started encoders, so that starting a segment doesn't have to wait for a
+/// fork and exec.
+///
+/// The `sox(1)` CLI can't be told where to write after it starts, so each spare encoder writes to
+/// a placeholder `spare-*.flac.part` file. When a segment starts, it takes a spare and renames the
+/// placeholder to the segment's `.part` filename; the encoder keeps writing to the same file under
+/// its new name. Each spare taken (or discarded because the storage directory has changed) is
+/// replaced by a background thread. If no spare is ready, or the rename fails, the segment just
+/// spawns its own encoder as usual.
+///
+/// Placeholders left over when `reccon` exits are removed by [`remove_stale_spares`] at startup.
+pub struct EncoderPool {
+    spares: Arc<Mutex<Vec<Spare>>>,
+    /// Asks the refill thread for another spare in the given directory.
+    refill: Mutex<mpsc::Sender<PathBuf>>,
+}
+
+impl EncoderPool {
+    /// Starts a pool of `size` spare encoders writing into `dir`.
+    pub fn new(
+        size: usize,
+        dir: &Path,
+        raw_audio_args: &'static [&'static str],
+        custom: Option<EncoderCommand>,
+        strip_comments: bool,
+        permissions: FilePermissions,
+    ) -> Self {
+        let spares = Arc::new(Mutex::new(Vec::with_capacity(size)));
+        let (tx, rx) = mpsc::channel::<PathBuf>();
+        let thread_spares = spares.clone();
+        std::thread::spawn(move || {
+            static NEXT_SPARE: AtomicU64 = AtomicU64::new(0);
+            for dir in rx {
+                let n = NEXT_SPARE.fetch_add(1, Ordering::Relaxed);
+                let path = dir.join(naming::spare_filename(n));
+                match spawn(
+                    &path,
+                    raw_audio_args,
+                    custom.as_ref(),
+                    strip_comments,
+                    &permissions,
+                ) {
+                    Ok(encoder) => thread_spares.lock().unwrap().push(Spare { encoder, path }),
+                    Err(e) => warn!("Failed to start spare encoder: {:#}", e),
+                }
+            }
+        });
+        for _ in 0..size {
+            let _ = tx.send(dir.to_path_buf());
+        }
+        Self {
+            spares,
+            refill: Mutex::new(tx),
+        }
+    }
+
+    /// Takes a spare encoder writing into `dir`, if one is ready.
+    pub fn take(&self, dir: &Path) -> Option<Spare> {
+        let mut spares = self.spares.lock().unwrap();
+        let refill = self.refill.lock().unwrap();
+        // Spares elsewhere (e.g., after falling back to another storage directory) are useless.
+        let (here, elsewhere): (Vec<Spare>, Vec<Spare>) = std::mem::take(&mut *spares)
+            .into_iter()
+            .partition(|s| s.path.parent() == Some(dir));
+        *spares = here;
+        for spare in elsewhere {
+            debug!("Discarding spare encoder {}", spare.path.display());
+            discard(spare);
+            let _ = refill.send(dir.to_path_buf());
+        }
+        let spare = spares.pop()?;
+        let _ = refill.send(dir.to_path_buf());
+        Some(spare)
+    }
+}
+
+/// Stops a spare encoder and removes its placeholder.
+pub fn discard(mut spare: Spare) {
+    drop(spare.encoder.stdin.take());
+    let _ = spare.encoder.wait();
+    let _ = std::fs::remove_file(&spare.path);
+}
+
+/// Removes placeholders for spare encoders left over from a previous run.
+pub fn remove_stale_spares(dir: &Path) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if naming::is_spare_filename(&entry.file_name().to_string_lossy()) {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoder_command_args() {
+        let command = EncoderCommand::new(
+            vec![
+                "sh".to_string(),
+                "-c".to_string(),
+                "plugin-host -r {rate} -c {channels} | flac -s -o {output} -".to_string(),
+            ],
+            48000,
+            1,
+        )
+        .unwrap();
+        assert_eq!(
+            command.args(Path::new("rec/recording-x.flac.part")),
+            [
+                "sh",
+                "-c",
+                "plugin-host -r 48000 -c 1 | flac -s -o rec/recording-x.flac.part -"
+            ]
+        );
+
+        let err =
+            EncoderCommand::new(vec!["flac".to_string(), "-".to_string()], 48000, 1).unwrap_err();
+        assert!(format!("{:#}", err).contains("{output}"), "{:#}", err);
+        EncoderCommand::new(Vec::new(), 48000, 1).unwrap_err();
+    }
+
+    #[test]
+    fn test_input_writer() {
+        let writer = InputWriter::new(Duration::from_millis(200));
+        // More than fits in a pipe's buffer.
+        let data = vec![0; 1 << 20];
+
+        // The pipe is held open by a grandchild too, so killing the child wouldn't help.
+        let mut stuck = Command::new("sh")
+            .args(["-c", "sleep 10 & wait"])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let err = write(&mut stuck, &data, Some(&writer)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(stuck.stdin.is_none());
+        stuck.kill().unwrap();
+        stuck.wait().unwrap();
+
+        // A write that finishes in time is left alone, even if it's as large, on a new thread.
+        let mut reader = Command::new("cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .unwrap();
+        write(&mut reader, &data, Some(&writer)).unwrap();
+        write(&mut reader, &data, Some(&writer)).unwrap();
+        drop(reader.stdin.take());
+        assert!(reader.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use log::{debug, warn};
+
+#[cfg(target_os = "linux")]
+const O_NONBLOCK: i32 = 0o4000;
+#[cfg(not(target_os = "linux"))]
+const O_NONBLOCK: i32 = 0x0004;
+/// What opening a FIFO for writing without blocking fails with if nothing has it open for reading.
+const ENXIO: i32 = 6;
+
+/// Announces each finished segment as a line on a named pipe, for a consumer like a live
+/// transcriber to pick up right away.
+///
+/// Recording never waits on the reader. The FIFO is opened without blocking, which fails if no
+/// reader has it open; then the line is dropped. Once open, the FIFO stays open until a write fails
+/// because the reader went away (`EPIPE`). Then it's reopened right away, so that a reader that has
+/// already reconnected gets the line, and otherwise the line is dropped and the FIFO is reopened
+/// for the next one. A line that would block because the reader isn't keeping up is dropped too.
+pub struct Fifo {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl Fifo {
+    /// Checks that `path` is a FIFO, which is written to only once there's a line to write.
+    pub fn new(path: PathBuf) -> anyhow::Result<Self> {
+        let meta = std::fs::metadata(&path).with_context(|| {
+            format!(
+                "Failed to find output_fifo {}; create it with mkfifo(1)",
+                path.display()
+            )
+        })?;
+        if !meta.file_type().is_fifo() {
+            anyhow::bail!("output_fifo {} isn't a FIFO", path.display());
+        }
+        Ok(Self {
+            path,
+            file: Mutex::new(None),
+        })
+    }
+
+    /// Writes `line` and a newline to the FIFO, or drops it if there's no reader ready for it.
+    pub fn send(&self, line: &str) {
+        let line = format!("{}\n", line);
+        let mut file = self.file.lock().unwrap();
+        // A second try, in case the reader has reconnected since the FIFO was last opened.
+        for _ in 0..2 {
+            let f = match &mut *file {
+                Some(f) => f,
+                None => match open_for_writing(&self.path) {
+                    Ok(f) => file.insert(f),
+                    Err(e) if e.raw_os_error() == Some(ENXIO) => {
+                        debug!("No reader on {}; dropping line", self.path.display());
+                        return;
+                    }
+                    Err(e) => {
+                        warn!("Failed to open {}: {}", self.path.display(), e);
+                        return;
+                    }
+                },
+            };
+            match f.write_all(line.as_bytes()) {
+                Ok(()) => return,
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                    debug!("Reader of {} went away; reopening", self.path.display());
+                    *file = None;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    warn!(
+                        "Reader of {} isn't keeping up; dropping line",
+                        self.path.display()
+                    );
+                    return;
+                }
+                Err(e) => {
+                    warn!("Failed to write to {}: {}", self.path.display(), e);
+                    *file = None;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn open_for_writing(path: &Path) -> io::Result<File> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(O_NONBLOCK)
+        .open(path)
+}
+
+/// Formats the line announcing a finished segment: a JSON object with its `id`, the `path` where
+/// it is now, the `final_path` where it will end up once uploaded, and its `metadata`.
+pub fn segment_line(
+    id: &str,
+    path: &Path,
+    final_path: &Path,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("id".to_string(), id.to_string().into());
+    let path_str = |p: &Path| p.to_string_lossy().into_owned();
+    line.insert("path".to_string(), path_str(path).into());
+    line.insert("final_path".to_string(), path_str(final_path).into());
+    line.insert("metadata".to_string(), metadata.clone().into());
+    serde_json::Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_segment_line() {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("samples".to_string(), "48000".to_string().into());
+        let line = segment_line(
+            "20240101T120000",
+            Path::new("/rec/recording-20240101T120000.flac.local"),
+            Path::new("/rec/recording-20240101T120000.flac"),
+            &metadata,
+        );
+        let expected = concat!(
+            r#"{"final_path":"/rec/recording-20240101T120000.flac","id":"20240101T120000","#,
+            r#""metadata":{"samples":"48000"},"path":"/rec/recording-20240101T120000.flac.local"}"#,
+        );
+        assert_eq!(line, expected);
+    }
+
+    #[test]
+    fn test_send_reopens() {
+        let dir = std::env::temp_dir().join(format!("reccon-fifo-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("segments");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let fifo = Fifo::new(path.clone()).unwrap();
+        let open_reader = || {
+            std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(O_NONBLOCK)
+                .open(&path)
+                .unwrap()
+        };
+        let read_all = |reader: &mut File| {
+            let mut buf = Vec::new();
+            let _ = reader.read_to_end(&mut buf);
+            String::from_utf8(buf).unwrap()
+        };
+
+        // With no reader, lines are dropped without blocking.
+        fifo.send("dropped");
+
+        let mut reader = open_reader();
+        fifo.send("one");
+        assert_eq!(read_all(&mut reader), "one\n");
+
+        // Once the reader goes away, lines are dropped until a new one shows up.
+        drop(reader);
+        fifo.send("dropped");
+        let mut reader = open_reader();
+        fifo.send("two");
+        assert_eq!(read_all(&mut reader), "two\n");
+
+        drop(reader);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,194 @@
 use core::str::FromStr;
+use std::collections::BTreeMap;
+use std::fmt;
 
-use anyhow::Context as _;
+use anyhow::Context;
 use gcp_auth::AuthenticationManager;
 
+/// OAuth scope that lets uploads create objects.
+pub const DEFAULT_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
 pub struct Client {
     pub http: reqwest::Client,
     pub path: Path,
     pub auth: AuthenticationManager,
+    pub options: Options,
+}
+
+/// Settings for how objects are uploaded.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// OAuth scopes to request tokens for, like [`DEFAULT_SCOPE`].
+    pub scopes: Vec<String>,
+    /// `Cache-Control` for uploaded objects, if not the GCS default.
+    pub cache_control: Option<String>,
+    /// `Content-Disposition` for uploaded recordings, if any, with `{id}` standing for the
+    /// segment ID. See [`Options::content_disposition`].
+    pub content_disposition: Option<String>,
 }
 
+impl Options {
+    /// Returns the `Content-Disposition` for a recording of the segment `id`, if configured.
+    pub fn content_disposition(&self, id: &str) -> Option<String> {
+        self.content_disposition
+            .as_ref()
+            .map(|template| template.replace("{id}", id))
+    }
+}
+
+/// Checks that `value` can be served as the value of the HTTP header `name`: no line breaks or
+/// other control characters (except tabs), and nothing outside of ASCII.
+pub fn check_header_value(name: &str, value: &str) -> anyhow::Result<()> {
+    if let Some(c) = value
+        .chars()
+        .find(|&c| c != '\t' && !(' '..='~').contains(&c))
+    {
+        anyhow::bail!("Invalid character {c:?} in {name} {value:?}");
+    }
+    Ok(())
+}
+
+/// Permissions on the bucket that uploading needs, per [`Client::check_bucket`].
+const UPLOAD_PERMISSIONS: &[&str] = &["storage.objects.create"];
+
+/// Metadata keys that `reccon` sets itself, which custom metadata may not override.
+pub const RESERVED_METADATA_KEYS: &[&str] = &[
+    "samples",
+    "sample-rate",
+    "duration-secs",
+    "started-at",
+    "clipped",
+    "clipped-samples",
+    "edge",
+    "gap-secs",
+    "loudness-class",
+    "sox-version",
+    "input-device",
+    "envelope",
+    "envelope-frame-ms",
+    "content-md5",
+    "content-sha256",
+];
+
+/// Checks that custom metadata keys are non-empty and don't clobber any of `reccon`'s own keys.
+/// Keys are compared case-insensitively, as they are in HTTP headers.
+pub fn check_custom_metadata<'a>(keys: impl IntoIterator<Item = &'a str>) -> anyhow::Result<()> {
+    for key in keys {
+        if key.is_empty() {
+            anyhow::bail!("Custom metadata keys must be non-empty");
+        }
+        if RESERVED_METADATA_KEYS
+            .iter()
+            .any(|reserved| reserved.eq_ignore_ascii_case(key))
+        {
+            anyhow::bail!("Custom metadata key {key:?} is reserved for reccon's own metadata");
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `renames` (per `metadata_keys`) only renames `reccon`'s own metadata keys, to
+/// non-empty names that don't collide with each other's, with those left alone, or with the
+/// `custom` keys. Keys are compared case-insensitively, as they are in HTTP headers.
+pub fn check_metadata_keys<'a>(
+    renames: &'a BTreeMap<String, String>,
+    custom: impl IntoIterator<Item = &'a str>,
+) -> anyhow::Result<()> {
+    if let Some(from) = renames
+        .keys()
+        .find(|from| !RESERVED_METADATA_KEYS.contains(&from.as_str()))
+    {
+        anyhow::bail!(
+            "Can't rename metadata key {from:?} in metadata_keys, since reccon never sets it"
+        );
+    }
+    let mut keys: Vec<String> = RESERVED_METADATA_KEYS
+        .iter()
+        .map(|&key| renames.get(key).map_or(key, String::as_str))
+        .chain(custom)
+        .map(str::to_ascii_lowercase)
+        .collect();
+    if keys.iter().any(String::is_empty) {
+        anyhow::bail!("Need non-empty names in metadata_keys");
+    }
+    keys.sort();
+    if let Some(pair) = keys.windows(2).find(|pair| pair[0] == pair[1]) {
+        anyhow::bail!(
+            "Metadata key {:?} would be used twice, per metadata_keys",
+            pair[0]
+        );
+    }
+    Ok(())
+}
+
+/// Why a request to GCS failed, so that callers can decide whether and how to retry.
+///
+/// This converts to an `anyhow::Error` like any other error, for callers that don't care.
 #[derive(Debug)]
+pub enum GcsError {
+    /// Couldn't get an auth token at all, like if the metadata server is unreachable.
+    Auth {
+        scopes: Vec<String>,
+        source: gcp_auth::Error,
+    },
+    /// GCS responded, but with an error status, like for a rejected token or a missing bucket.
+    Http {
+        status: reqwest::StatusCode,
+        /// Body of the response, which usually explains the error.
+        message: String,
+    },
+    /// The request didn't get a response, like for a timeout or a refused connection.
+    Network(reqwest::Error),
+    /// The object's metadata couldn't be serialized.
+    Serialization(serde_json::Error),
+}
+
+impl fmt::Display for GcsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GcsError::Auth { scopes, .. } => write!(
+                f,
+                "Failed to get GCS auth token for scopes {:?}; are credentials (or the metadata \
+                 server) reachable?",
+                scopes
+            ),
+            GcsError::Http { status, message } => write!(
+                f,
+                "GCS request failed: {}{}: {}",
+                status,
+                status_hint(*status),
+                message.trim()
+            ),
+            GcsError::Network(_) => write!(f, "Failed to send request to GCS"),
+            GcsError::Serialization(_) => write!(f, "Failed to serialize metadata to JSON"),
+        }
+    }
+}
+
+impl std::error::Error for GcsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            GcsError::Auth { source, .. } => Some(source),
+            GcsError::Http { .. } => None,
+            GcsError::Network(e) => Some(e),
+            GcsError::Serialization(e) => Some(e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for GcsError {
+    fn from(e: reqwest::Error) -> Self {
+        GcsError::Network(e)
+    }
+}
+
+impl From<serde_json::Error> for GcsError {
+    fn from(e: serde_json::Error) -> Self {
+        GcsError::Serialization(e)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Path {
     pub bucket: String,
     pub prefix: String,
@@ -31,12 +210,22 @@ impl FromStr for Path {
             }
             Some(bp) => bp,
         };
+        if bucket.is_empty() {
+            anyhow::bail!("GCS path must name a bucket, but got \"gs://{s}\"");
+        }
         if !prefix.is_empty() && !prefix.ends_with('/') {
             anyhow::bail!("Non-empty GCS prefix must end with slash, but got {prefix:?}");
         }
+        // Drop empty path segments (from a leading slash or repeated slashes), so that object
+        // names never contain them.
+        let prefix: String = prefix
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .flat_map(|segment| [segment, "/"])
+            .collect();
         Ok(Path {
             bucket: bucket.into(),
-            prefix: prefix.into(),
+            prefix,
         })
     }
 }
@@ -50,62 +239,27 @@ impl Client {
         name: &str,
         contents: &[u8],
         content_type: &str,
+        content_disposition: Option<&str>,
         metadata: &serde_json::Value,
-    ) -> Result<(), anyhow::Error> {
-        const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
-        let token = self
-            .auth
-            .get_token(&[SCOPE])
-            .await
-            .context("Failed to get GCS auth token")?;
+    ) -> Result<(), GcsError> {
+        let token = self.token().await?;
 
-        let object_name = format!("{}{}", &self.path.prefix, name);
+        let metadata = self.object_resource(name, content_disposition, metadata);
+        let metadata = serde_json::to_string(&metadata)?;
 
-        let metadata = serde_json::json!({
-            "name": object_name,
-            "metadata": metadata,
-        });
-        let metadata =
-            serde_json::to_string(&metadata).context("Failed to serialize metadata to JSON")?;
-
-        let boundary: String = loop {
-            let boundary = multipart_boundary();
-            use memchr::memmem::Finder;
-            let finder = Finder::new(boundary.as_bytes());
-            if finder.find(metadata.as_bytes()).is_some() {
-                continue;
-            }
-            if finder.find(content_type.as_bytes()).is_some() {
-                continue;
-            }
-            if finder.find(contents).is_some() {
-                continue;
-            }
-            break boundary;
-        };
-        let mut body: Vec<u8> = Vec::new();
-        body.extend_from_slice(b"--");
-        body.extend_from_slice(boundary.as_bytes());
-        body.extend_from_slice(b"\r\n");
-        body.extend_from_slice(b"Content-Type: application/json; charset=UTF-8\r\n\r\n");
-        body.extend_from_slice(metadata.as_bytes());
-        body.extend_from_slice(b"\r\n\r\n");
-        body.extend_from_slice(b"--");
-        body.extend_from_slice(boundary.as_bytes());
-        body.extend_from_slice(b"Content-Type: ");
-        body.extend_from_slice(content_type.as_bytes());
-        body.extend_from_slice(b"\r\n\r\n");
-        body.extend_from_slice(contents);
-        body.extend_from_slice(b"\r\n");
-        body.extend_from_slice(b"--");
-        body.extend_from_slice(boundary.as_bytes());
-        body.extend_from_slice(b"--\r\n");
+        let (boundary, body) = multipart_body(
+            metadata.as_bytes(),
+            content_type,
+            contents,
+            multipart_boundary,
+        );
 
         let url = format!(
             "https://storage.googleapis.com/upload/storage/v1/b/{}/o?uploadType=multipart",
             urlencoding::encode(&self.path.bucket)
         );
-        self.http
+        let res = self
+            .http
             .post(url)
             .header("Authorization", format!("Bearer {}", token.as_str()))
             .header(
@@ -114,11 +268,370 @@ impl Client {
             )
             .body(body)
             .send()
+            .await?;
+        check_status(res).await
+    }
+
+    /// Starts a resumable upload of an object whose contents aren't all known yet, like while
+    /// they're still being encoded, and returns the session URI to send them to with
+    /// [`Client::put_chunk`].
+    ///
+    /// The object doesn't exist until the last chunk is sent, so a session that's never finished
+    /// leaves nothing behind.
+    pub async fn start_resumable(
+        &self,
+        name: &str,
+        content_type: &str,
+        content_disposition: Option<&str>,
+        metadata: &serde_json::Value,
+    ) -> Result<String, GcsError> {
+        let token = self.token().await?;
+        let resource = self.object_resource(name, content_disposition, metadata);
+        let url = format!(
+            "https://storage.googleapis.com/upload/storage/v1/b/{}/o?uploadType=resumable",
+            urlencoding::encode(&self.path.bucket)
+        );
+        let res = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token.as_str()))
+            .header("Content-Type", "application/json; charset=UTF-8")
+            .header("X-Upload-Content-Type", content_type)
+            .body(serde_json::to_string(&resource)?)
+            .send()
+            .await?;
+        let status = res.status();
+        let session = res
+            .headers()
+            .get("Location")
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        check_status(res).await?;
+        session.ok_or_else(|| GcsError::Http {
+            status,
+            message: "No session URI in response to starting a resumable upload".to_string(),
+        })
+    }
+
+    /// Sends `chunk`, the part of a resumable upload's contents starting at `offset`. Giving the
+    /// `total` length of the contents finishes the upload, once GCS has all of them.
+    ///
+    /// All but the last chunk must be a multiple of [`RESUMABLE_CHUNK_ALIGN`] long. GCS may keep
+    /// less of a chunk than was sent, so the returned status says where to continue from.
+    pub async fn put_chunk(
+        &self,
+        session: &str,
+        offset: u64,
+        chunk: &[u8],
+        total: Option<u64>,
+    ) -> Result<ChunkStatus, GcsError> {
+        // The session URI authorizes the upload by itself.
+        let res = self
+            .http
+            .put(session)
+            .header(
+                "Content-Range",
+                content_range(offset, chunk.len() as u64, total),
+            )
+            .body(chunk.to_vec())
+            .send()
+            .await?;
+        if res.status().as_u16() != RESUME_INCOMPLETE {
+            check_status(res).await?;
+            return Ok(ChunkStatus::Done);
+        }
+        let range = res.headers().get("Range").and_then(|v| v.to_str().ok());
+        match persisted_bytes(range) {
+            Some(persisted) => Ok(ChunkStatus::Incomplete { persisted }),
+            None => Err(GcsError::Http {
+                status: res.status(),
+                message: format!("Unexpected Range in resumable upload response: {range:?}"),
+            }),
+        }
+    }
+
+    /// Abandons a resumable upload, so that GCS can forget what was sent so far.
+    pub async fn cancel_resumable(&self, session: &str) -> Result<(), GcsError> {
+        // GCS responds to a cancellation with this (nonstandard) status.
+        const CANCELLED: u16 = 499;
+        let res = self.http.delete(session).send().await?;
+        if res.status().as_u16() == CANCELLED {
+            return Ok(());
+        }
+        check_status(res).await
+    }
+
+    /// Adds `metadata` to the custom metadata of an existing object, replacing any keys that it
+    /// already has.
+    pub async fn patch_metadata(
+        &self,
+        name: &str,
+        metadata: &serde_json::Value,
+    ) -> Result<(), GcsError> {
+        let token = self.token().await?;
+        let url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{}/o/{}",
+            urlencoding::encode(&self.path.bucket),
+            urlencoding::encode(&format!("{}{}", self.path.prefix, name)),
+        );
+        let body = serde_json::to_string(&serde_json::json!({ "metadata": metadata }))?;
+        let res = self
+            .http
+            .patch(url)
+            .header("Authorization", format!("Bearer {}", token.as_str()))
+            .header("Content-Type", "application/json; charset=UTF-8")
+            .body(body)
+            .send()
+            .await?;
+        check_status(res).await
+    }
+
+    /// Copies the object `src` to `dst` (both relative to the prefix) within the bucket, along
+    /// with its metadata, replacing any object already at `dst`.
+    pub async fn copy(&self, src: &str, dst: &str) -> Result<(), GcsError> {
+        let token = self.token().await?;
+        let bucket = urlencoding::encode(&self.path.bucket);
+        let url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{bucket}/o/{}/copyTo/b/{bucket}/o/{}",
+            urlencoding::encode(&format!("{}{}", self.path.prefix, src)),
+            urlencoding::encode(&format!("{}{}", self.path.prefix, dst)),
+        );
+        let res = self
+            .http
+            .post(url)
+            .header("Authorization", format!("Bearer {}", token.as_str()))
+            .body(Vec::new())
+            .send()
+            .await?;
+        check_status(res).await
+    }
+
+    /// Returns whether there's an object named `name` (relative to the prefix).
+    pub async fn exists(&self, name: &str) -> Result<bool, GcsError> {
+        let token = self.token().await?;
+        let url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{}/o/{}",
+            urlencoding::encode(&self.path.bucket),
+            urlencoding::encode(&format!("{}{}", self.path.prefix, name)),
+        );
+        let res = self
+            .http
+            .get(url)
+            .query(&[("fields", "name")])
+            .header("Authorization", format!("Bearer {}", token.as_str()))
+            .send()
+            .await?;
+        if res.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        check_status(res).await?;
+        Ok(true)
+    }
+
+    /// Checks that the bucket exists and that we may upload to it, so that a wrong bucket name or a
+    /// missing permission shows up at startup rather than at the first upload.
+    ///
+    /// This asks GCS which of the [`UPLOAD_PERMISSIONS`] we have on the bucket, which doesn't take
+    /// any permission itself, and doesn't write anything.
+    pub async fn check_bucket(&self) -> anyhow::Result<()> {
+        let token = self.token().await?;
+        let url = format!(
+            "https://storage.googleapis.com/storage/v1/b/{}/iam/testPermissions",
+            urlencoding::encode(&self.path.bucket)
+        );
+        let query: Vec<(&str, &str)> = UPLOAD_PERMISSIONS
+            .iter()
+            .map(|&permission| ("permissions", permission))
+            .collect();
+        let res = self
+            .http
+            .get(url)
+            .query(&query)
+            .header("Authorization", format!("Bearer {}", token.as_str()))
+            .send()
             .await
-            .and_then(|res| res.error_for_status())
-            .context("Failed to upload to GCS")?;
+            .map_err(GcsError::from)?;
+        let status = res.status();
+        let body = res.text().await.map_err(GcsError::from)?;
+        if !status.is_success() {
+            return Err(GcsError::Http {
+                status,
+                message: body,
+            })
+            .with_context(|| format!("Failed to check GCS bucket {}", self.path.bucket));
+        }
+        let granted: serde_json::Value =
+            serde_json::from_str(&body).context("Invalid response checking GCS bucket")?;
+        let missing = missing_permissions(&granted);
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "Missing permission on GCS bucket {}: {}",
+                self.path.bucket,
+                missing.join(", ")
+            );
+        }
         Ok(())
     }
+
+    /// Describes a new object named `name` (relative to the prefix), with the configured headers
+    /// and `metadata` as its custom metadata.
+    fn object_resource(
+        &self,
+        name: &str,
+        content_disposition: Option<&str>,
+        metadata: &serde_json::Value,
+    ) -> serde_json::Value {
+        let mut resource = serde_json::json!({
+            "name": format!("{}{}", &self.path.prefix, name),
+            "metadata": metadata,
+        });
+        if let Some(cache_control) = &self.options.cache_control {
+            resource["cacheControl"] = cache_control.as_str().into();
+        }
+        if let Some(content_disposition) = content_disposition {
+            resource["contentDisposition"] = content_disposition.into();
+        }
+        resource
+    }
+
+    /// Checks that we can get an auth token, like for a readiness check. This is usually cached.
+    pub async fn check_auth(&self) -> Result<(), GcsError> {
+        self.token().await.map(drop)
+    }
+
+    /// Gets an auth token for the configured scopes.
+    async fn token(&self) -> Result<gcp_auth::Token, GcsError> {
+        // The `AuthenticationManager` caches tokens for each set of scopes until shortly before
+        // they expire, so this only goes to the network (like the metadata server) for the first
+        // request and then about once an hour.
+        let scopes: Vec<&str> = self.options.scopes.iter().map(String::as_str).collect();
+        self.auth
+            .get_token(&scopes)
+            .await
+            .map_err(|source| GcsError::Auth {
+                scopes: self.options.scopes.clone(),
+                source,
+            })
+    }
+}
+
+/// How far along a resumable upload is, after sending it a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkStatus {
+    /// GCS has the first `persisted` bytes of the contents, and is waiting for the rest.
+    Incomplete { persisted: u64 },
+    /// GCS has all of the contents, and the object exists.
+    Done,
+}
+
+/// Status with which GCS acknowledges a chunk of a resumable upload that isn't finished yet.
+const RESUME_INCOMPLETE: u16 = 308;
+
+/// Chunks of a resumable upload, but the last, must be a multiple of this many bytes long.
+pub const RESUMABLE_CHUNK_ALIGN: usize = 256 * 1024;
+
+/// Formats the `Content-Range` header for a chunk of a resumable upload, with the contents' total
+/// length if it's known (so that this is the last chunk).
+fn content_range(offset: u64, len: u64, total: Option<u64>) -> String {
+    let total = total.map_or_else(|| "*".to_string(), |total| total.to_string());
+    match len {
+        0 => format!("bytes */{total}"),
+        _ => format!("bytes {}-{}/{}", offset, offset + len - 1, total),
+    }
+}
+
+/// Parses the `Range` header of a resumable upload's response, like `bytes=0-262143`, into how many
+/// bytes GCS has. GCS leaves out the header until it has some.
+fn persisted_bytes(range: Option<&str>) -> Option<u64> {
+    let Some(range) = range else {
+        return Some(0);
+    };
+    let last: u64 = range.strip_prefix("bytes=0-")?.parse().ok()?;
+    last.checked_add(1)
+}
+
+/// Returns which of the [`UPLOAD_PERMISSIONS`] are missing from a `testIamPermissions` response,
+/// which lists those granted (and leaves the list out if none are).
+fn missing_permissions(granted: &serde_json::Value) -> Vec<&'static str> {
+    let granted = granted["permissions"].as_array();
+    UPLOAD_PERMISSIONS
+        .iter()
+        .copied()
+        .filter(|&wanted| {
+            !granted.is_some_and(|granted| granted.iter().any(|p| p.as_str() == Some(wanted)))
+        })
+        .collect()
+}
+
+/// Turns an error status in `res` into a [`GcsError::Http`] with the body of the response.
+async fn check_status(res: reqwest::Response) -> Result<(), GcsError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let message = res.text().await.unwrap_or_default();
+    Err(GcsError::Http { status, message })
+}
+
+/// Explains a failed upload's status code, if it's likely to be an auth problem.
+fn status_hint(status: reqwest::StatusCode) -> &'static str {
+    match status.as_u16() {
+        401 => " (the auth token was rejected; check the credentials)",
+        403 => {
+            " (permission denied; check that the credentials can create objects in this bucket, \
+             and that the configured scopes allow it)"
+        }
+        _ => "",
+    }
+}
+
+/// Assembles a `multipart/related` body with a JSON metadata part followed by a media part.
+///
+/// Boundaries are drawn from `gen_boundary` until one is found that doesn't appear in any of the
+/// parts. Returns the chosen boundary along with the body.
+fn multipart_body<F>(
+    metadata: &[u8],
+    content_type: &str,
+    contents: &[u8],
+    mut gen_boundary: F,
+) -> (String, Vec<u8>)
+where
+    F: FnMut() -> String,
+{
+    let boundary: String = loop {
+        let boundary = gen_boundary();
+        use memchr::memmem::Finder;
+        let finder = Finder::new(boundary.as_bytes());
+        if finder.find(metadata).is_some() {
+            continue;
+        }
+        if finder.find(content_type.as_bytes()).is_some() {
+            continue;
+        }
+        if finder.find(contents).is_some() {
+            continue;
+        }
+        break boundary;
+    };
+    let mut body: Vec<u8> = Vec::new();
+    body.extend_from_slice(b"--");
+    body.extend_from_slice(boundary.as_bytes());
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(b"Content-Type: application/json; charset=UTF-8\r\n\r\n");
+    body.extend_from_slice(metadata);
+    body.extend_from_slice(b"\r\n\r\n");
+    body.extend_from_slice(b"--");
+    body.extend_from_slice(boundary.as_bytes());
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(b"Content-Type: ");
+    body.extend_from_slice(content_type.as_bytes());
+    body.extend_from_slice(b"\r\n\r\n");
+    body.extend_from_slice(contents);
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(b"--");
+    body.extend_from_slice(boundary.as_bytes());
+    body.extend_from_slice(b"--\r\n");
+    (boundary, body)
 }
 
 /// Generates a random boundary for a `multipart/related` (or similar) form.
@@ -129,3 +642,220 @@ fn multipart_boundary() -> String {
     let [a, b, c, d] = rand::random::<[u32; 4]>();
     format!("{:08x}-{:08x}-{:08x}-{:08x}", a, b, c, d)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a boundary generator that yields each of `boundaries` in turn.
+    fn fixed_boundaries(boundaries: &[&str]) -> impl FnMut() -> String {
+        let mut iter = boundaries
+            .iter()
+            .map(|b| b.to_string())
+            .collect::<Vec<_>>()
+            .into_iter();
+        move || iter.next().expect("ran out of boundaries")
+    }
+
+    fn parse(s: &str) -> (String, String) {
+        let path: Path = s.parse().unwrap();
+        (path.bucket, path.prefix)
+    }
+
+    #[test]
+    fn test_missing_permissions() {
+        let response = |permissions: Option<Vec<&str>>| {
+            let mut response = serde_json::Map::new();
+            response.insert(
+                "kind".to_string(),
+                "storage#testIamPermissionsResponse".into(),
+            );
+            if let Some(permissions) = permissions {
+                response.insert("permissions".to_string(), permissions.into());
+            }
+            serde_json::Value::Object(response)
+        };
+        let granted = response(Some(vec!["storage.objects.create"]));
+        assert_eq!(missing_permissions(&granted), Vec::<&str>::new());
+        // With none granted, the list is left out.
+        assert_eq!(
+            missing_permissions(&response(None)),
+            ["storage.objects.create"]
+        );
+        let other = response(Some(vec!["storage.objects.get"]));
+        assert_eq!(missing_permissions(&other), ["storage.objects.create"]);
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        let options = Options {
+            scopes: Vec::new(),
+            cache_control: None,
+            content_disposition: Some("attachment; filename=\"{id}.flac\"".to_string()),
+        };
+        assert_eq!(
+            options.content_disposition("20240102T030405").as_deref(),
+            Some("attachment; filename=\"20240102T030405.flac\"")
+        );
+        let none = Options {
+            content_disposition: None,
+            ..options
+        };
+        assert_eq!(none.content_disposition("20240102T030405"), None);
+
+        let check = |value: &str| check_header_value("content_disposition", value);
+        check("attachment; filename=\"{id}.flac\"").unwrap();
+        check("inline;\tfilename=x").unwrap();
+        check("attachment\r\nX-Injected: 1").unwrap_err();
+        check("attachment; filename=\"caf\u{e9}.flac\"").unwrap_err();
+    }
+
+    #[test]
+    fn test_path_from_str() {
+        let bp = |b: &str, p: &str| (b.to_string(), p.to_string());
+        assert_eq!(parse("gs://bucket"), bp("bucket", ""));
+        assert_eq!(parse("gs://bucket/"), bp("bucket", ""));
+        assert_eq!(parse("gs://bucket/a/b/"), bp("bucket", "a/b/"));
+    }
+
+    #[test]
+    fn test_path_normalizes_prefix() {
+        let bp = |b: &str, p: &str| (b.to_string(), p.to_string());
+        assert_eq!(parse("gs://bucket//a//b/"), bp("bucket", "a/b/"));
+        assert_eq!(parse("gs://bucket///a/"), bp("bucket", "a/"));
+        assert_eq!(parse("gs://bucket/a///"), bp("bucket", "a/"));
+        assert_eq!(parse("gs://bucket//"), bp("bucket", ""));
+    }
+
+    #[test]
+    fn test_path_rejects_invalid() {
+        assert!("bucket/a/".parse::<Path>().is_err());
+        assert!("gs:///a/".parse::<Path>().is_err());
+        assert!("gs://bucket/a".parse::<Path>().is_err());
+        assert!("gs://bucket//a//b".parse::<Path>().is_err());
+    }
+
+    #[test]
+    fn test_check_custom_metadata() {
+        assert!(check_custom_metadata(["project", "take"]).is_ok());
+        assert!(check_custom_metadata([]).is_ok());
+        assert!(check_custom_metadata(["project", "samples"]).is_err());
+        assert!(check_custom_metadata(["Started-At"]).is_err());
+        assert!(check_custom_metadata([""]).is_err());
+    }
+
+    #[test]
+    fn test_check_metadata_keys() {
+        let renames = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+            pairs
+                .iter()
+                .map(|&(from, to)| (from.to_string(), to.to_string()))
+                .collect()
+        };
+        assert!(check_metadata_keys(&renames(&[]), ["project"]).is_ok());
+        assert!(check_metadata_keys(&renames(&[("samples", "audio_samples")]), []).is_ok());
+        // Swapping two names is fine.
+        let swap = renames(&[("samples", "sample-rate"), ("sample-rate", "samples")]);
+        assert!(check_metadata_keys(&swap, []).is_ok());
+        // Only `reccon`'s own keys can be renamed.
+        assert!(check_metadata_keys(&renames(&[("project", "proj")]), ["project"]).is_err());
+        assert!(check_metadata_keys(&renames(&[("samples", "")]), []).is_err());
+        assert!(check_metadata_keys(&renames(&[("samples", "Edge")]), []).is_err());
+        let both = renames(&[("samples", "n"), ("sample-rate", "n")]);
+        assert!(check_metadata_keys(&both, []).is_err());
+        let custom = renames(&[("samples", "project")]);
+        assert!(check_metadata_keys(&custom, ["project"]).is_err());
+    }
+
+    #[test]
+    fn test_status_hint() {
+        assert!(status_hint(reqwest::StatusCode::UNAUTHORIZED).contains("rejected"));
+        assert!(status_hint(reqwest::StatusCode::FORBIDDEN).contains("permission denied"));
+        assert_eq!(status_hint(reqwest::StatusCode::NOT_FOUND), "");
+    }
+
+    #[test]
+    fn test_gcs_error() {
+        let forbidden = GcsError::Http {
+            status: reqwest::StatusCode::FORBIDDEN,
+            message: " Forbidden\n".to_string(),
+        };
+        assert_eq!(
+            forbidden.to_string(),
+            format!(
+                "GCS request failed: 403 Forbidden{}: Forbidden",
+                status_hint(reqwest::StatusCode::FORBIDDEN)
+            )
+        );
+        assert!(std::error::Error::source(&forbidden).is_none());
+
+        let json = serde_json::from_str::<serde_json::Value>("{").unwrap_err();
+        let serialization = GcsError::from(json);
+        assert!(matches!(serialization, GcsError::Serialization(_)));
+        assert!(std::error::Error::source(&serialization).is_some());
+
+        let auth = GcsError::Auth {
+            scopes: vec![DEFAULT_SCOPE.to_string()],
+            source: gcp_auth::Error::Other("no credentials".to_string()),
+        };
+        assert!(auth.to_string().contains(DEFAULT_SCOPE));
+        assert!(std::error::Error::source(&auth).is_some());
+
+        // Callers that don't care which it is can treat it like any other error.
+        let any: anyhow::Error = auth.into();
+        assert!(matches!(any.downcast_ref(), Some(GcsError::Auth { .. })));
+    }
+
+    #[test]
+    fn test_content_range() {
+        assert_eq!(content_range(0, 262144, None), "bytes 0-262143/*");
+        assert_eq!(
+            content_range(262144, 10, Some(262154)),
+            "bytes 262144-262153/262154"
+        );
+        assert_eq!(content_range(262144, 0, Some(262144)), "bytes */262144");
+    }
+
+    #[test]
+    fn test_persisted_bytes() {
+        assert_eq!(persisted_bytes(None), Some(0));
+        assert_eq!(persisted_bytes(Some("bytes=0-262143")), Some(262144));
+        assert_eq!(persisted_bytes(Some("bytes=0-")), None);
+        assert_eq!(persisted_bytes(Some("bytes=10-20")), None);
+    }
+
+    #[test]
+    fn test_multipart_body() {
+        let (boundary, body) = multipart_body(
+            br#"{"name":"x.flac"}"#,
+            "audio/flac",
+            b"fLaC...",
+            fixed_boundaries(&["BOUNDARY"]),
+        );
+        assert_eq!(boundary, "BOUNDARY");
+        let expected: &[u8] = b"--BOUNDARY\r\n\
+            Content-Type: application/json; charset=UTF-8\r\n\r\n\
+            {\"name\":\"x.flac\"}\r\n\r\n\
+            --BOUNDARY\r\n\
+            Content-Type: audio/flac\r\n\r\n\
+            fLaC...\r\n\
+            --BOUNDARY--\r\n";
+        assert_eq!(
+            String::from_utf8_lossy(&body),
+            String::from_utf8_lossy(expected)
+        );
+    }
+
+    #[test]
+    fn test_multipart_body_avoids_collisions() {
+        let (boundary, body) = multipart_body(
+            br#"{"name":"in-meta"}"#,
+            "audio/in-type",
+            b"in-contents",
+            fixed_boundaries(&["in-meta", "in-type", "in-contents", "clean"]),
+        );
+        assert_eq!(boundary, "clean");
+        assert!(body.starts_with(b"--clean\r\n"));
+        assert!(body.ends_with(b"--clean--\r\n"));
+    }
+}
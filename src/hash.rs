@@ -0,0 +1,116 @@
+//! Content hashes of recordings, per `content_hash`.
+//!
+//! They're used for deduplication, not security, but both are the standard algorithms, so their
+//! digests match those of `sha256sum(1)` and `md5sum(1)`.
+
+use anyhow::Context;
+use sha2::Digest;
+
+use crate::config::ContentHash;
+
+impl ContentHash {
+    /// The metadata key that the hash goes in.
+    pub fn metadata_key(self) -> &'static str {
+        match self {
+            ContentHash::Md5 => "content-md5",
+            ContentHash::Sha256 => "content-sha256",
+        }
+    }
+
+    /// Hashes `data`, returning the digest in lowercase hex.
+    pub fn hex_digest(self, data: &[u8]) -> String {
+        let mut hasher = Hasher::new(self);
+        hasher.update(data);
+        hasher.hex_digest()
+    }
+
+    /// Hashes a whole recording, as [`Self::hex_digest`], on a blocking thread, since that takes a
+    /// while. Returns the recording along with the digest.
+    pub async fn hex_digest_blocking(self, data: Vec<u8>) -> anyhow::Result<(Vec<u8>, String)> {
+        let hashed = tokio::task::spawn_blocking(move || {
+            let digest = self.hex_digest(&data);
+            (data, digest)
+        });
+        hashed.await.context("Failed to hash recording")
+    }
+}
+
+/// A hash of a recording in progress, for hashing it as it's read rather than all at once.
+pub enum Hasher {
+    Md5(md5::Md5),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub fn new(hash: ContentHash) -> Self {
+        match hash {
+            ContentHash::Md5 => Hasher::Md5(md5::Md5::new()),
+            ContentHash::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+        }
+    }
+
+    /// Hashes the next part of the recording.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the digest of everything hashed so far, in lowercase hex.
+    pub fn hex_digest(self) -> String {
+        let digest = match self {
+            Hasher::Md5(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_digest() {
+        let sha256 = |data: &[u8]| ContentHash::Sha256.hex_digest(data);
+        let md5 = |data: &[u8]| ContentHash::Md5.hex_digest(data);
+        assert_eq!(
+            sha256(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks, since the padding doesn't fit in the first.
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(md5(b""), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(md5(b"abc"), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            md5(
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890"
+            ),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+        // More than a block.
+        let data: Vec<u8> = (0..100).collect();
+        assert_eq!(
+            sha256(&data),
+            "bce0aff19cf5aa6a7469a30d61d04e4376e4bbf6381052ee9e7f33925c954d52"
+        );
+        assert_eq!(md5(&data), "7acedd1a84a4cfcb6e7a16003242945e");
+        // The same, however the data is split up.
+        let mut hasher = Hasher::new(ContentHash::Sha256);
+        for part in data.chunks(30) {
+            hasher.update(part);
+        }
+        assert_eq!(
+            hasher.hex_digest(),
+            "bce0aff19cf5aa6a7469a30d61d04e4376e4bbf6381052ee9e7f33925c954d52"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::Shared;
+
+/// How long a client may take to send its request before it's dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `/readyz` waits for a GCS auth token before calling auth failing.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most of a request that's read, which only needs to cover the request line.
+const MAX_REQUEST_BYTES: usize = 1024;
+/// Stands for a time that hasn't happened yet.
+const NEVER: u64 = u64::MAX;
+
+/// What the main loop has been up to, for health checks on `health_addr`.
+///
+/// The main loop reports each time around, and each time it reads audio; the server only reads
+/// these, so checks never wait on the main loop. Times are kept as milliseconds since `started`,
+/// so that they fit in atomics.
+pub struct Health {
+    started: Instant,
+    /// How long the main loop may go without coming around before it's considered wedged.
+    live_within: Duration,
+    /// How long input may go without a chunk before it's considered stalled.
+    ready_within: Duration,
+    last_loop: AtomicU64,
+    last_chunk: AtomicU64,
+}
+
+impl Health {
+    pub fn new(started: Instant, live_within: Duration, ready_within: Duration) -> Self {
+        Self {
+            started,
+            live_within,
+            ready_within,
+            last_loop: AtomicU64::new(0),
+            last_chunk: AtomicU64::new(NEVER),
+        }
+    }
+
+    /// Records that the main loop came around at `now`.
+    pub fn looped(&self, now: Instant) {
+        self.last_loop.store(self.millis(now), Ordering::Relaxed);
+    }
+
+    /// Records that a chunk of audio was read at `now`.
+    pub fn read_chunk(&self, now: Instant) {
+        self.last_chunk.store(self.millis(now), Ordering::Relaxed);
+    }
+
+    /// Whether `reccon` is alive as of `now`: the main loop has come around recently.
+    pub fn liveness(&self, now: Instant) -> Result<(), String> {
+        match self.age(&self.last_loop, now) {
+            Some(age) if age <= self.live_within => Ok(()),
+            age => Err(format!(
+                "main loop hasn't run for {:?}",
+                age.unwrap_or_default()
+            )),
+        }
+    }
+
+    /// Whether input is flowing as of `now`: a chunk has been read recently.
+    pub fn readiness(&self, now: Instant) -> Result<(), String> {
+        match self.age(&self.last_chunk, now) {
+            Some(age) if age <= self.ready_within => Ok(()),
+            Some(age) => Err(format!("no input for {:?}", age)),
+            None => Err("no input yet".to_string()),
+        }
+    }
+
+    fn millis(&self, now: Instant) -> u64 {
+        let millis = now.saturating_duration_since(self.started).as_millis();
+        u64::try_from(millis).unwrap_or(NEVER - 1)
+    }
+
+    /// How long before `now` the time in `at` was, if it has happened.
+    fn age(&self, at: &AtomicU64, now: Instant) -> Option<Duration> {
+        match at.load(Ordering::Relaxed) {
+            NEVER => None,
+            millis => Some(Duration::from_millis(
+                self.millis(now).saturating_sub(millis),
+            )),
+        }
+    }
+}
+
+/// Answers health checks on `listener`, forever: `/healthz` per [`Health::liveness`], and
+/// `/readyz` per [`Health::readiness`] and, if uploading to GCS, whether we can get an auth token.
+/// Each is `200 OK` if so, or `503 Service Unavailable` with the reason if not.
+pub async fn serve(listener: TcpListener, health: Arc<Health>, shared: Arc<Shared>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Failed to accept health check connection: {}", e);
+                tokio::time::sleep(REQUEST_TIMEOUT).await;
+                continue;
+            }
+        };
+        let (health, shared) = (health.clone(), shared.clone());
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &health, &shared).await {
+                debug!("Failed to answer health check: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, health: &Health, shared: &Shared) -> io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; MAX_REQUEST_BYTES];
+    while !request.windows(2).any(|w| w == b"\r\n") && request.len() < MAX_REQUEST_BYTES {
+        let n = tokio::time::timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "request timed out"))??;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let (head, status) = match request_path(&request) {
+        Some((head, "/healthz")) => (head, health.liveness(Instant::now())),
+        Some((head, "/readyz")) => {
+            let status = match health.readiness(Instant::now()) {
+                Ok(()) => check_gcs(shared).await,
+                not_ready => not_ready,
+            };
+            (head, status)
+        }
+        Some((head, _)) => {
+            let response = response("404 Not Found", "not found\n", head);
+            return stream.write_all(response.as_bytes()).await;
+        }
+        None => {
+            let response = response("400 Bad Request", "bad request\n", false);
+            return stream.write_all(response.as_bytes()).await;
+        }
+    };
+    let response = match status {
+        Ok(()) => response("200 OK", "ok\n", head),
+        Err(reason) => response("503 Service Unavailable", &format!("{reason}\n"), head),
+    };
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Whether uploads to GCS could authenticate, if there are any.
+async fn check_gcs(shared: &Shared) -> Result<(), String> {
+    let Some(uploader) = &shared.uploader else {
+        return Ok(());
+    };
+    let Some(client) = uploader.client() else {
+        return Err("no GCS client".to_string());
+    };
+    match tokio::time::timeout(AUTH_TIMEOUT, client.check_auth()).await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err(format!("GCS auth didn't finish within {:?}", AUTH_TIMEOUT)),
+    }
+}
+
+/// Parses the path out of an HTTP request's first line, with whether it's a `HEAD` request.
+/// Only `GET` and `HEAD` are understood.
+fn request_path(request: &str) -> Option<(bool, &str)> {
+    let line = request.lines().next()?;
+    let mut parts = line.split_whitespace();
+    let head = match parts.next()? {
+        "GET" => false,
+        "HEAD" => true,
+        _ => return None,
+    };
+    let target = parts.next()?;
+    parts.next()?.strip_prefix("HTTP/")?;
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+    Some((head, path))
+}
+
+/// Formats a whole response, leaving out the body for a `HEAD` request.
+fn response(status: &str, body: &str, head: bool) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        if head { "" } else { body }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let health = Health::new(t0, Duration::from_secs(30), Duration::from_secs(10));
+        assert!(health.liveness(secs(5)).is_ok());
+        assert_eq!(health.readiness(secs(5)), Err("no input yet".to_string()));
+        health.looped(secs(5));
+        health.read_chunk(secs(5));
+        assert!(health.readiness(secs(15)).is_ok());
+        // Input stalls, while the main loop keeps going (e.g., restarting `rec(1)`).
+        health.looped(secs(30));
+        assert!(health.readiness(secs(16)).is_err());
+        assert!(health.liveness(secs(40)).is_ok());
+        // Then the main loop wedges too.
+        assert!(health.liveness(secs(61)).is_err());
+        health.looped(secs(62));
+        health.read_chunk(secs(62));
+        assert!(health.liveness(secs(62)).is_ok());
+        assert!(health.readiness(secs(62)).is_ok());
+    }
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(
+            request_path("GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some((false, "/healthz"))
+        );
+        assert_eq!(
+            request_path("HEAD /readyz?verbose=1 HTTP/1.0\r\n"),
+            Some((true, "/readyz"))
+        );
+        assert_eq!(request_path("POST /healthz HTTP/1.1\r\n"), None);
+        assert_eq!(request_path("GET /healthz\r\n"), None);
+        assert_eq!(request_path(""), None);
+    }
+
+    #[test]
+    fn test_response() {
+        assert_eq!(
+            response("200 OK", "ok\n", false),
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: 3\r\n\
+             Connection: close\r\n\r\nok\n"
+        );
+        assert!(response("200 OK", "ok\n", true)
+            .ends_with("Content-Length: 3\r\nConnection: close\r\n\r\n"));
+    }
+}
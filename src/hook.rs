@@ -0,0 +1,186 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, error, warn};
+
+use crate::{config, Shared};
+
+/// An external command, per `post_process_command`, run on each finished recording.
+pub struct Hook {
+    /// Program and arguments, with placeholders per [`Hook::args`].
+    command: Vec<String>,
+    timeout: Duration,
+    /// Whether to run before each recording is uploaded, rather than after.
+    pub before_upload: bool,
+    /// Whether to save what the command prints next to the recording, rather than discard it.
+    save_output: bool,
+}
+
+impl Hook {
+    pub fn new(
+        command: Vec<String>,
+        timeout: Duration,
+        before_upload: bool,
+        save_output: bool,
+    ) -> anyhow::Result<Self> {
+        config::check_command("post_process_command", &command)?;
+        Ok(Self {
+            command,
+            timeout,
+            before_upload,
+            save_output,
+        })
+    }
+
+    /// Returns the arguments to run for segment `id`, whose recording is at `file`: `{file}`,
+    /// `{id}`, and `{metadata}` (as JSON) are replaced wherever they appear.
+    fn args(&self, file: &Path, id: &str, metadata: &str) -> Vec<String> {
+        let file = file.to_string_lossy();
+        self.command[1..]
+            .iter()
+            .map(|arg| {
+                arg.replace("{file}", &file)
+                    .replace("{id}", id)
+                    .replace("{metadata}", metadata)
+            })
+            .collect()
+    }
+
+    /// Runs the command for segment `id`, whose recording is at `file`, returning what it printed.
+    /// It's killed if it takes longer than the timeout.
+    async fn run(
+        &self,
+        file: &Path,
+        id: &str,
+        metadata: &serde_json::Map<String, serde_json::Value>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let metadata = serde_json::to_string(metadata)?;
+        let mut command = tokio::process::Command::new(&self.command[0]);
+        command
+            .args(self.args(file, id, &metadata))
+            .env("RECCON_FILE", file)
+            .env("RECCON_ID", id)
+            .env("RECCON_METADATA", &metadata)
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .stdout(match self.save_output {
+                true => Stdio::piped(),
+                false => Stdio::null(),
+            })
+            .kill_on_drop(true);
+        let output = tokio::time::timeout(self.timeout, command.output())
+            .await
+            .map_err(|_| anyhow::anyhow!("Timed out after {:?}", self.timeout))?
+            .with_context(|| format!("Failed to spawn {}", self.command[0]))?;
+        if !output.status.success() {
+            anyhow::bail!("{} exited with {}", self.command[0], output.status);
+        }
+        Ok(output.stdout)
+    }
+}
+
+/// Returns the path to save the command's output for the recording at `audio`, like
+/// `recording-<id>.out`.
+pub fn output_name(audio: &Path) -> PathBuf {
+    audio.with_extension("out")
+}
+
+/// Runs the `post_process_command` for segment `id`, whose recording is at `file`, saving its
+/// output for the recording that will end up at `final_filename`, if so configured.
+///
+/// Failing is logged, and otherwise ignored.
+pub async fn post_process(
+    shared: &Shared,
+    id: &str,
+    file: &Path,
+    final_filename: &Path,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) {
+    let Some(hook) = &shared.hook else {
+        return;
+    };
+    let output = match hook.run(file, id, metadata).await {
+        Ok(output) => output,
+        Err(e) => {
+            warn!("post_process_command failed for segment {}: {:#}", id, e);
+            return;
+        }
+    };
+    debug!("Ran post_process_command for segment {}", id);
+    if !hook.save_output {
+        return;
+    }
+    let local = output_name(final_filename);
+    let res = tokio::fs::write(&local, &output)
+        .await
+        .and_then(|()| shared.permissions.apply(&local));
+    if let Err(e) = res {
+        shared.storage.check_error(&e);
+        error!(
+            "Failed to save post_process_command output for segment {}: {}",
+            id, e
+        );
+    }
+}
+
+/// Like [`post_process`], but in the background, for a recording at its final path.
+pub fn spawn_post_process(
+    shared: &Arc<Shared>,
+    id: &str,
+    file: &Path,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) {
+    let background = &shared.background;
+    let (shared, id, file, metadata) = (
+        shared.clone(),
+        id.to_string(),
+        file.to_path_buf(),
+        metadata.clone(),
+    );
+    background.spawn(async move { post_process(&shared, &id, &file, &file, &metadata).await });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_args() {
+        let hook = |command: &[&str]| {
+            Hook::new(
+                command.iter().map(|s| s.to_string()).collect(),
+                Duration::from_secs(1),
+                false,
+                false,
+            )
+            .unwrap()
+        };
+        let audio = Path::new("/rec/recording-20240101T120000.flac");
+        assert_eq!(
+            hook(&["notify", "--id={id}", "{file}", "{metadata}"]).args(
+                audio,
+                "20240101T120000",
+                "{\"peak\":\"0.5\"}"
+            ),
+            [
+                "--id=20240101T120000",
+                "/rec/recording-20240101T120000.flac",
+                "{\"peak\":\"0.5\"}"
+            ]
+        );
+        // Unlike `asr_command`, the path isn't added if it's not asked for.
+        assert!(hook(&["true"]).args(audio, "x", "{}").is_empty());
+        assert!(Hook::new(vec![], Duration::from_secs(1), false, false).is_err());
+    }
+
+    #[test]
+    fn test_output_name() {
+        assert_eq!(
+            output_name(Path::new("/rec/loud/recording-20240101T120000.flac")),
+            Path::new("/rec/loud/recording-20240101T120000.out")
+        );
+    }
+}
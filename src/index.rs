@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::Context;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS recordings (
+        id TEXT PRIMARY KEY,
+        path TEXT NOT NULL,
+        started_at TEXT NOT NULL,
+        duration REAL,
+        samples INTEGER,
+        sample_rate REAL,
+        peak INTEGER NOT NULL,
+        end_reason TEXT NOT NULL,
+        uploaded INTEGER NOT NULL DEFAULT 0,
+        gcs_uri TEXT
+    );
+";
+
+/// A queryable SQLite index of finished recordings, with one row per segment.
+pub struct Index {
+    conn: Mutex<rusqlite::Connection>,
+}
+
+/// Everything recorded about a segment when it finishes.
+pub struct Row<'a> {
+    pub id: &'a str,
+    pub path: &'a Path,
+    pub started_at: &'a str,
+    pub duration: Option<f64>,
+    pub samples: Option<i64>,
+    pub sample_rate: Option<f64>,
+    pub peak: i32,
+    pub end_reason: &'a str,
+    /// Where the segment will be uploaded, if anywhere.
+    pub gcs_uri: Option<&'a str>,
+}
+
+impl Index {
+    /// Opens the database at `path`, creating it and its schema if needed.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let conn = rusqlite::Connection::open(path)
+            .with_context(|| format!("Failed to open SQLite database {}", path.display()))?;
+        conn.execute_batch(SCHEMA)
+            .context("Failed to create recordings table")?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Adds a finished segment, replacing any previous row with the same ID.
+    pub fn insert(&self, row: &Row) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        tokio::task::block_in_place(|| {
+            conn.execute(
+                "INSERT OR REPLACE INTO recordings
+                    (id, path, started_at, duration, samples, sample_rate, peak, end_reason,
+                     uploaded, gcs_uri)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 0, ?9)",
+                rusqlite::params![
+                    row.id,
+                    row.path.to_string_lossy(),
+                    row.started_at,
+                    row.duration,
+                    row.samples,
+                    row.sample_rate,
+                    row.peak,
+                    row.end_reason,
+                    row.gcs_uri,
+                ],
+            )
+        })
+        .with_context(|| format!("Failed to index segment {}", row.id))?;
+        Ok(())
+    }
+
+    /// Marks a segment as uploaded, with its final local path.
+    pub fn mark_uploaded(&self, id: &str, path: &Path) -> anyhow::Result<()> {
+        let conn = self.conn.lock().unwrap();
+        tokio::task::block_in_place(|| {
+            conn.execute(
+                "UPDATE recordings SET uploaded = 1, path = ?2 WHERE id = ?1",
+                rusqlite::params![id, path.to_string_lossy()],
+            )
+        })
+        .with_context(|| format!("Failed to mark segment {} as uploaded", id))?;
+        Ok(())
+    }
+}
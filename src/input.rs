@@ -0,0 +1,189 @@
+use std::io::{self, Read, Write};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+
+use anyhow::Context;
+
+use crate::config::ByteOrder;
+
+/// Number of raw chunks that may be read ahead of the detection sidechain. This needs to cover
+/// the filter's internal buffering, or else reading filtered audio would stall.
+const SIDECHAIN_BACKLOG: usize = 16;
+
+/// Source of audio chunks from `rec(1)`, each paired with a copy to feed to the detector.
+///
+/// Without a detection filter, the detector sees the raw audio. With one, each raw chunk is read
+/// once from `rec(1)` by a feeder thread, which writes it to a `sox(1)` filter process and also
+/// hands it to the main loop over a bounded channel. The main loop then reads the same number of
+/// bytes of filtered audio back from the filter, so the raw chunk goes to the encoder while the
+/// filtered copy goes to the detector. This requires the filter's effects to preserve the length
+/// of the audio (as, e.g., `highpass`, `lowpass`, `sinc`, and `compand` do).
+pub enum Input {
+    Direct(ChildStdout),
+    Sidechain {
+        raw: mpsc::Receiver<io::Result<Vec<u8>>>,
+        filtered: ChildStdout,
+        _filter: Child,
+    },
+}
+
+impl Input {
+    pub fn direct(rec: ChildStdout) -> Self {
+        Input::Direct(rec)
+    }
+
+    /// Starts a `sox(1)` process applying `effects` to a copy of the audio from `rec`.
+    pub fn sidechain(
+        mut rec: ChildStdout,
+        raw_audio_args: &[&str],
+        effects: &[String],
+        chunk_size: usize,
+    ) -> anyhow::Result<Self> {
+        let mut filter = Command::new("sox")
+            .arg("-q")
+            .args(raw_audio_args)
+            .arg("-")
+            .args(raw_audio_args)
+            .arg("-")
+            .args(effects)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to spawn sox(1) for detect_filter")?;
+        let mut filter_in = filter.stdin.take().unwrap();
+        let filtered = filter.stdout.take().unwrap();
+        let (tx, raw) = mpsc::sync_channel(SIDECHAIN_BACKLOG);
+        std::thread::spawn(move || loop {
+            let mut chunk = Vec::with_capacity(chunk_size);
+            if let Err(e) = (&mut rec)
+                .take(u64::try_from(chunk_size).unwrap())
+                .read_to_end(&mut chunk)
+            {
+                let _ = tx.send(Err(e));
+                return;
+            }
+            let eof = chunk.is_empty();
+            // On EOF, returning drops `filter_in`, which lets the filter flush and exit.
+            if !eof {
+                if let Err(e) = filter_in.write_all(&chunk) {
+                    let _ = tx.send(Err(e));
+                    return;
+                }
+            }
+            if tx.send(Ok(chunk)).is_err() || eof {
+                return;
+            }
+        });
+        Ok(Input::Sidechain {
+            raw,
+            filtered,
+            _filter: filter,
+        })
+    }
+
+    /// Reads the next chunk of raw audio into `chunk` and the corresponding audio for detection
+    /// into `detect`. An empty `chunk` means EOF.
+    pub fn read(
+        &mut self,
+        chunk_size: usize,
+        chunk: &mut Vec<u8>,
+        detect: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        chunk.clear();
+        detect.clear();
+        match self {
+            Input::Direct(pipe) => {
+                pipe.take(u64::try_from(chunk_size).unwrap())
+                    .read_to_end(chunk)
+                    .context("Failed to read chunk from rec(1) pipe")?;
+                detect.extend_from_slice(chunk);
+            }
+            Input::Sidechain { raw, filtered, .. } => {
+                *chunk = raw
+                    .recv()
+                    .context("Sidechain feeder thread exited")?
+                    .context("Failed to feed chunk from rec(1) to detect_filter")?;
+                filtered
+                    .take(u64::try_from(chunk.len()).unwrap())
+                    .read_to_end(detect)
+                    .context("Failed to read chunk from detect_filter")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Copies the 16-bit samples of one `channel` out of `interleaved` audio with `channels` channels
+/// into `out`, replacing its contents.
+///
+/// Interleaved audio stores one sample from each channel in turn, so each frame of
+/// `2 * channels` bytes holds this channel's sample at byte offset `2 * channel`. A trailing
+/// partial frame is dropped.
+pub fn deinterleave(interleaved: &[u8], channels: usize, channel: usize, out: &mut Vec<u8>) {
+    out.clear();
+    if channels == 1 {
+        out.extend_from_slice(interleaved);
+        return;
+    }
+    for frame in interleaved.chunks_exact(2 * channels) {
+        out.extend_from_slice(&frame[2 * channel..2 * channel + 2]);
+    }
+}
+
+/// Converts 32-bit float samples in `audio` to signed 16-bit ones, in place, both in `order`. Full
+/// scale is ±1.0, and samples beyond it are clamped to the 16-bit extremes (so they count as
+/// clipped). A trailing partial sample is dropped.
+pub fn f32_to_s16(audio: &mut Vec<u8>, order: ByteOrder) {
+    let samples = audio.len() / 4;
+    for i in 0..samples {
+        let sample = order.read_f32(audio[4 * i..4 * i + 4].try_into().unwrap());
+        // Casting saturates, and takes NaN to 0.
+        let converted = (sample * 32768.0).round() as i16;
+        audio[2 * i..2 * i + 2].copy_from_slice(&order.write_i16(converted));
+    }
+    audio.truncate(2 * samples);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_f32_to_s16() {
+        let samples = [
+            0.0f32,
+            0.5,
+            -0.5,
+            1.0,
+            -1.0,
+            1.5,
+            -2.0,
+            f32::NAN,
+            1.0e-6,
+            -0.25,
+        ];
+        let mut audio: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        audio.extend_from_slice(&[0xff, 0xff]); // partial sample
+        f32_to_s16(&mut audio, ByteOrder::Le);
+        let converted: Vec<i16> = audio
+            .chunks_exact(2)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(
+            converted,
+            [0, 16384, -16384, 32767, -32768, 32767, -32768, 0, 0, -8192]
+        );
+    }
+
+    #[test]
+    fn test_deinterleave() {
+        let stereo = [1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut out = vec![0xff];
+        deinterleave(&stereo, 2, 0, &mut out);
+        assert_eq!(out, [1, 2, 5, 6]);
+        deinterleave(&stereo, 2, 1, &mut out);
+        assert_eq!(out, [3, 4, 7, 8]);
+        deinterleave(&stereo, 1, 0, &mut out);
+        assert_eq!(out, stereo);
+    }
+}
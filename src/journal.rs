@@ -0,0 +1,606 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+use serde_json::Value;
+use tokio::sync::{mpsc, Notify};
+
+use crate::config::AudioFormat;
+use crate::storage::{self, Storage};
+use crate::{naming, upload};
+
+/// Name of the journal file, in the (first) storage directory.
+pub const FILENAME: &str = "reccon.journal";
+/// Lines that the journal may grow to before it's compacted, if most of them are obsolete.
+const COMPACT_LINES: usize = 10_000;
+
+/// How far along a recording is, as of a line of the journal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    /// Being recorded, into a `.part` file.
+    Started,
+    /// Recorded, into a `.local` file waiting to be uploaded.
+    Finished,
+    /// Uploaded, and at its final path.
+    Uploaded,
+    /// Never to be uploaded, like for being discarded or set aside.
+    Dropped,
+}
+
+impl Stage {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stage::Started => "started",
+            Stage::Finished => "finished",
+            Stage::Uploaded => "uploaded",
+            Stage::Dropped => "dropped",
+        }
+    }
+
+    fn parse(s: &str) -> Option<Self> {
+        [
+            Stage::Started,
+            Stage::Finished,
+            Stage::Uploaded,
+            Stage::Dropped,
+        ]
+        .into_iter()
+        .find(|stage| stage.as_str() == s)
+    }
+}
+
+/// One line of the journal: a recording reaching a new [`Stage`]. A recording is a segment's
+/// primary recording or its secondary encoding, so it's identified by the segment ID and which.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Entry {
+    id: String,
+    primary: bool,
+    stage: Stage,
+    /// The recording's final path, without any `.part` or `.local` suffix, when started or
+    /// finished.
+    file: Option<PathBuf>,
+    /// Its object name, relative to the GCS prefix and untemplated, when finished.
+    object_name: Option<String>,
+}
+
+impl Entry {
+    fn to_value(&self) -> Value {
+        let mut line = serde_json::Map::new();
+        line.insert("id".to_string(), self.id.as_str().into());
+        line.insert("primary".to_string(), self.primary.into());
+        line.insert("stage".to_string(), self.stage.as_str().into());
+        if let Some(file) = &self.file {
+            line.insert(
+                "file".to_string(),
+                file.to_string_lossy().into_owned().into(),
+            );
+        }
+        if let Some(object_name) = &self.object_name {
+            line.insert("object".to_string(), object_name.as_str().into());
+        }
+        line.into()
+    }
+
+    fn from_value(value: &Value) -> Option<Self> {
+        let entry = Self {
+            id: value.get("id")?.as_str()?.to_string(),
+            primary: value.get("primary")?.as_bool()?,
+            stage: Stage::parse(value.get("stage")?.as_str()?)?,
+            file: value.get("file").and_then(Value::as_str).map(PathBuf::from),
+            object_name: value
+                .get("object")
+                .and_then(Value::as_str)
+                .map(String::from),
+        };
+        // Only what's needed to pick up where a recording left off is checked for.
+        match entry.stage {
+            Stage::Started if entry.file.is_none() => None,
+            Stage::Finished if entry.file.is_none() || entry.object_name.is_none() => None,
+            _ => Some(entry),
+        }
+    }
+
+    fn key(&self) -> (String, bool) {
+        (self.id.clone(), self.primary)
+    }
+
+    /// What's at the recording's path with `suffix`, like `.local`.
+    fn with_suffix(&self, suffix: &str) -> Option<PathBuf> {
+        let mut path = self.file.as_ref()?.as_os_str().to_owned();
+        path.push(suffix);
+        Some(path.into())
+    }
+
+    /// The upload of a finished recording, if its format is known.
+    fn job(&self) -> Option<upload::Job> {
+        let final_filename = self.file.clone()?;
+        let format = AudioFormat::from_extension(final_filename.extension()?.to_str()?)?;
+        Some(upload::Job {
+            id: self.id.clone(),
+            object_name: self.object_name.clone()?,
+            local_filename: self.with_suffix(naming::LOCAL_SUFFIX)?,
+            final_filename,
+            metadata: serde_json::Map::new(),
+            measured: false,
+            format,
+            primary: self.primary,
+            ended: None,
+        })
+    }
+}
+
+/// Replays the lines of a journal, returning the recordings still started or finished as of the
+/// last line about each, and how many lines couldn't be read (like one torn by a crash).
+fn replay(
+    lines: impl IntoIterator<Item = Option<Value>>,
+) -> (BTreeMap<(String, bool), Entry>, usize) {
+    let mut live = BTreeMap::new();
+    let mut unreadable = 0;
+    for value in lines {
+        let Some(entry) = value.as_ref().and_then(Entry::from_value) else {
+            unreadable += 1;
+            continue;
+        };
+        apply(&mut live, entry);
+    }
+    (live, unreadable)
+}
+
+/// Checks what the journal says of a recording against what's on disk, per `exists`, returning it
+/// if it's still waiting to be uploaded.
+fn reconcile(entry: Entry, exists: impl Fn(&Path) -> bool) -> Option<Entry> {
+    let local = entry.with_suffix(naming::LOCAL_SUFFIX)?;
+    match entry.stage {
+        // Only `.local` files are ever uploaded, so this one's left to whoever wants it.
+        Stage::Started => {
+            let part = entry.with_suffix(naming::PART_SUFFIX)?;
+            if exists(&part) {
+                warn!(
+                    "Segment {} was interrupted while recording; leaving {} as it is",
+                    entry.id,
+                    part.display()
+                );
+            } else if exists(&local) {
+                info!("Segment {} was finished after all", entry.id);
+            }
+            None
+        }
+        Stage::Finished if exists(&local) => Some(entry),
+        // Uploading it and renaming it both happened, but not journaling that.
+        Stage::Finished if exists(entry.file.as_ref()?) => {
+            debug!("Segment {} was already uploaded", entry.id);
+            None
+        }
+        Stage::Finished => {
+            warn!(
+                "Segment {} is gone from {}; forgetting it",
+                entry.id,
+                local.display()
+            );
+            None
+        }
+        Stage::Uploaded | Stage::Dropped => None,
+    }
+}
+
+/// An append-only log of each recording's progress, per `upload_journal`, so that what's left to
+/// upload after a restart is known exactly, rather than worked out from filenames.
+///
+/// Each line is a JSON object for one recording reaching a new [`Stage`]. What's true of a
+/// recording is what the last line about it says. On opening, the journal is checked against
+/// what's on disk, and rewritten with only what's still pending. So is it whenever it grows past
+/// [`COMPACT_LINES`] lines, if most are about recordings that are done.
+///
+/// Recording a stage never waits on the disk, since that happens on the main loop too: lines are
+/// written in the background by [`Journal::write`], a few at a time, like any other storage
+/// operation, so that a hung disk only holds up the journal.
+pub struct Journal {
+    path: PathBuf,
+    /// Recordings started or finished, as of the last line about each.
+    live: Mutex<BTreeMap<(String, bool), Entry>>,
+    entries: mpsc::UnboundedSender<Entry>,
+    /// What [`Journal::write`] writes with, until it's started.
+    writer: Mutex<Option<Writer>>,
+    /// Entries recorded but not yet written (or failed to be), for [`Journal::flushed`].
+    unwritten: AtomicUsize,
+    written: Notify,
+}
+
+/// The journal file, as [`Journal::write`] has it.
+struct Writer {
+    entries: mpsc::UnboundedReceiver<Entry>,
+    file: Arc<File>,
+    /// Recordings started or finished, as of the last line written about each.
+    live: BTreeMap<(String, bool), Entry>,
+    /// Lines in the file.
+    lines: usize,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if need be, and picks up where it left off.
+    ///
+    /// Each recording that the journal has as finished is pending if its `.local` file is still
+    /// there. One that it only has as started was interrupted by a crash, so its `.part` file is
+    /// reported and left alone. Any `.local` file under `roots` that the journal doesn't have
+    /// (like from before it was kept) is added to it, per its filename, as before.
+    pub async fn open(path: PathBuf, roots: &[PathBuf]) -> anyhow::Result<Self> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read journal {}", path.display()))
+            }
+        };
+        let lines = contents.lines().map(|line| serde_json::from_str(line).ok());
+        let (recorded, unreadable) = replay(lines);
+        if unreadable > 0 {
+            warn!(
+                "Skipped {} unreadable line(s) of journal {}",
+                unreadable,
+                path.display()
+            );
+        }
+        let mut live: BTreeMap<_, _> = recorded
+            .into_values()
+            .filter_map(|entry| reconcile(entry, Path::exists))
+            .map(|entry| (entry.key(), entry))
+            .collect();
+
+        let mut found = Vec::new();
+        for root in roots {
+            let is_local = |name: &str| naming::id_from_local_filename(name).is_some();
+            if let Err(e) = upload::scan_files(root, &mut found, is_local).await {
+                error!(
+                    "Failed to scan {} for pending uploads: {:#}",
+                    root.display(),
+                    e
+                );
+            }
+        }
+        let known: HashSet<PathBuf> = live.values().filter_map(|e| e.file.clone()).collect();
+        let mut adopted = 0;
+        for (_, local) in found {
+            let Some(job) = upload::recovered_job(roots, local) else {
+                continue;
+            };
+            if known.contains(&job.final_filename) {
+                continue;
+            }
+            let entry = Entry {
+                id: job.id,
+                primary: job.primary,
+                stage: Stage::Finished,
+                file: Some(job.final_filename),
+                object_name: Some(job.object_name),
+            };
+            live.insert(entry.key(), entry);
+            adopted += 1;
+        }
+        if adopted > 0 {
+            info!(
+                "Added {} pending upload(s) from disk to journal {}",
+                adopted,
+                path.display()
+            );
+        }
+
+        let file = compact(&path, &live)
+            .with_context(|| format!("Failed to write journal {}", path.display()))?;
+        let (entries, rx) = mpsc::unbounded_channel();
+        let writer = Writer {
+            entries: rx,
+            file: Arc::new(file),
+            lines: live.len(),
+            live: live.clone(),
+        };
+        Ok(Self {
+            path,
+            live: Mutex::new(live),
+            entries,
+            writer: Mutex::new(Some(writer)),
+            unwritten: AtomicUsize::new(0),
+            written: Notify::new(),
+        })
+    }
+
+    /// Records that segment `id` started recording, to end up at `file`.
+    pub fn started(&self, id: &str, file: &Path) {
+        self.record(Entry {
+            id: id.to_string(),
+            primary: true,
+            stage: Stage::Started,
+            file: Some(file.to_path_buf()),
+            object_name: None,
+        });
+    }
+
+    /// Records that a recording of segment `id` finished, to be uploaded as `object_name` and end
+    /// up at `file`.
+    pub fn finished(&self, id: &str, primary: bool, file: &Path, object_name: &str) {
+        self.record(Entry {
+            id: id.to_string(),
+            primary,
+            stage: Stage::Finished,
+            file: Some(file.to_path_buf()),
+            object_name: Some(object_name.to_string()),
+        });
+    }
+
+    /// Records that a recording of segment `id` was uploaded.
+    pub fn uploaded(&self, id: &str, primary: bool) {
+        self.done(id, primary, Stage::Uploaded);
+    }
+
+    /// Records that a recording of segment `id` won't be uploaded.
+    pub fn dropped(&self, id: &str, primary: bool) {
+        self.done(id, primary, Stage::Dropped);
+    }
+
+    fn done(&self, id: &str, primary: bool, stage: Stage) {
+        self.record(Entry {
+            id: id.to_string(),
+            primary,
+            stage,
+            file: None,
+            object_name: None,
+        });
+    }
+
+    /// Returns the uploads of the finished recordings, oldest first.
+    pub fn pending(&self) -> Vec<upload::Job> {
+        let live = self.live.lock().unwrap();
+        live.values()
+            .filter(|entry| entry.stage == Stage::Finished)
+            .filter_map(Entry::job)
+            .collect()
+    }
+
+    fn record(&self, entry: Entry) {
+        apply(&mut self.live.lock().unwrap(), entry.clone());
+        self.unwritten.fetch_add(1, Ordering::AcqRel);
+        // Only once the journal is dropped does nothing receive these.
+        let _ = self.entries.send(entry);
+    }
+
+    /// Writes what's recorded to the journal file as it comes, forever, counting any failure
+    /// against `storage`. Only the first call does anything.
+    pub async fn write(&self, storage: &Storage) {
+        let Some(mut writer) = self.writer.lock().unwrap().take() else {
+            return;
+        };
+        while let Some(entry) = writer.entries.recv().await {
+            let mut batch = vec![entry];
+            while let Ok(entry) = writer.entries.try_recv() {
+                batch.push(entry);
+            }
+            let n = batch.len();
+            writer.append(&self.path, storage, batch).await;
+            if self.unwritten.fetch_sub(n, Ordering::AcqRel) == n {
+                self.written.notify_waiters();
+            }
+        }
+    }
+
+    /// Waits until everything recorded so far has been written, or has failed to be.
+    pub async fn flushed(&self) {
+        loop {
+            // Created before checking, so that the last write finishing in between still wakes
+            // this.
+            let notified = self.written.notified();
+            if self.unwritten.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Writer {
+    /// Appends `batch` to the journal at `path`, compacting it if it's due.
+    async fn append(&mut self, path: &Path, storage: &Storage, batch: Vec<Entry>) {
+        let mut lines = String::new();
+        // A recording that's just started is lost along with its `.part` file if the machine goes
+        // down, so only later stages need to be on disk right away.
+        let sync = batch.iter().any(|entry| entry.stage != Stage::Started);
+        self.lines += batch.len();
+        for entry in batch {
+            lines.push_str(&entry.to_value().to_string());
+            lines.push('\n');
+            apply(&mut self.live, entry);
+        }
+        let res = storage::retry("write to journal", || {
+            let (file, lines) = (self.file.clone(), lines.clone());
+            blocking(move || {
+                (&*file).write_all(lines.as_bytes())?;
+                match sync {
+                    true => file.sync_data(),
+                    false => Ok(()),
+                }
+            })
+        })
+        .await;
+        if let Err(e) = res {
+            storage.check_error(&e);
+            error!("Failed to write to journal {}: {}", path.display(), e);
+        }
+        if self.lines >= COMPACT_LINES && self.lines > 2 * self.live.len() {
+            let res = storage::retry("compact journal", || {
+                let (path, live) = (path.to_path_buf(), self.live.clone());
+                blocking(move || compact(&path, &live))
+            })
+            .await;
+            match res {
+                Ok(file) => {
+                    self.file = Arc::new(file);
+                    self.lines = self.live.len();
+                }
+                Err(e) => {
+                    storage.check_error(&e);
+                    error!("Failed to compact journal {}: {}", path.display(), e);
+                }
+            }
+        }
+    }
+}
+
+/// Updates `live` per a line of the journal.
+fn apply(live: &mut BTreeMap<(String, bool), Entry>, entry: Entry) {
+    match entry.stage {
+        Stage::Started | Stage::Finished => drop(live.insert(entry.key(), entry)),
+        Stage::Uploaded | Stage::Dropped => drop(live.remove(&entry.key())),
+    }
+}
+
+/// Runs blocking file I/O off the async workers.
+async fn blocking<T: Send + 'static>(
+    op: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(op)
+        .await
+        .unwrap_or_else(|e| Err(io::Error::other(e)))
+}
+
+/// Rewrites the journal at `path` with just the `live` recordings, replacing it atomically so that
+/// a crash can't leave it half-written, and returns it opened for appending.
+fn compact(path: &Path, live: &BTreeMap<(String, bool), Entry>) -> io::Result<File> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(naming::PART_SUFFIX);
+    let mut contents = String::new();
+    for entry in live.values() {
+        contents.push_str(&entry.to_value().to_string());
+        contents.push('\n');
+    }
+    let mut file = File::create(&part)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_data()?;
+    std::fs::rename(&part, path)?;
+    std::fs::OpenOptions::new().append(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, stage: Stage) -> Entry {
+        Entry {
+            id: id.to_string(),
+            primary: true,
+            stage,
+            file: Some(PathBuf::from(format!("/rec/recording-{id}.flac"))),
+            object_name: Some(format!("{id}.flac")),
+        }
+    }
+
+    #[test]
+    fn test_entry_value() {
+        let finished = entry("20240101T120000", Stage::Finished);
+        let value = finished.to_value();
+        assert_eq!(value["stage"], "finished");
+        assert_eq!(value["file"], "/rec/recording-20240101T120000.flac");
+        assert_eq!(value["object"], "20240101T120000.flac");
+        assert_eq!(Entry::from_value(&value), Some(finished));
+        let uploaded = Entry {
+            file: None,
+            object_name: None,
+            ..entry("20240101T120000", Stage::Uploaded)
+        };
+        assert_eq!(Entry::from_value(&uploaded.to_value()), Some(uploaded));
+        // A finished recording without an object name can't be uploaded.
+        let mut value = value;
+        value.as_object_mut().unwrap().remove("object");
+        assert_eq!(Entry::from_value(&value), None);
+        assert_eq!(Entry::from_value(&Value::Null), None);
+    }
+
+    #[test]
+    fn test_replay() {
+        let (a, b, c) = ("20240101T120000", "20240101T120100", "20240101T120200");
+        let secondary = Entry {
+            primary: false,
+            ..entry(a, Stage::Finished)
+        };
+        let lines = [
+            entry(a, Stage::Started),
+            entry(b, Stage::Started),
+            entry(a, Stage::Finished),
+            secondary.clone(),
+            entry(b, Stage::Dropped),
+            entry(c, Stage::Started),
+            entry(c, Stage::Finished),
+            entry(a, Stage::Uploaded),
+        ];
+        let values = lines
+            .iter()
+            .map(|entry| Some(entry.to_value()))
+            .chain([None]);
+        let (live, unreadable) = replay(values);
+        assert_eq!(unreadable, 1);
+        assert_eq!(
+            live.into_values().collect::<Vec<_>>(),
+            [secondary, entry(c, Stage::Finished)]
+        );
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let id = "20240101T120000";
+        let on_disk = |names: &'static [&'static str]| {
+            move |path: &Path| names.iter().any(|name| path == Path::new(name))
+        };
+        let local = on_disk(&["/rec/recording-20240101T120000.flac.local"]);
+        let part = on_disk(&["/rec/recording-20240101T120000.flac.part"]);
+        let uploaded = on_disk(&["/rec/recording-20240101T120000.flac"]);
+        let finished = entry(id, Stage::Finished);
+        assert_eq!(reconcile(finished.clone(), local), Some(finished.clone()));
+        assert_eq!(reconcile(finished.clone(), uploaded), None);
+        assert_eq!(reconcile(finished, on_disk(&[])), None);
+        // An interrupted recording is never pending.
+        assert_eq!(reconcile(entry(id, Stage::Started), part), None);
+        assert_eq!(reconcile(entry(id, Stage::Started), local), None);
+    }
+
+    #[test]
+    fn test_job() {
+        let job = entry("20240101T120000", Stage::Finished).job().unwrap();
+        assert_eq!(
+            job.local_filename,
+            Path::new("/rec/recording-20240101T120000.flac.local")
+        );
+        assert_eq!(
+            job.final_filename,
+            Path::new("/rec/recording-20240101T120000.flac")
+        );
+        assert_eq!(job.object_name, "20240101T120000.flac");
+        assert_eq!(job.format, AudioFormat::Flac);
+        assert!(job.primary && !job.measured);
+    }
+
+    #[tokio::test]
+    async fn test_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(FILENAME);
+        let journal = Arc::new(Journal::open(path.clone(), &[]).await.unwrap());
+        let storage = Arc::new(Storage::new(vec![tmp.path().to_path_buf()], None));
+        tokio::spawn({
+            let (journal, storage) = (journal.clone(), storage.clone());
+            async move { journal.write(&storage).await }
+        });
+        let file = tmp.path().join("recording-20240101T120000.flac");
+        journal.started("20240101T120000", &file);
+        journal.finished("20240101T120000", true, &file, "20240101T120000.flac");
+        // What's pending is known right away, before it's written.
+        assert_eq!(journal.pending().len(), 1);
+        journal.flushed().await;
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let stages: Vec<Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["stage"].clone())
+            .collect();
+        assert_eq!(stages, ["started", "finished"]);
+    }
+}
@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+
+use crate::config::ByteOrder;
+use crate::seg;
+
+/// How much of the log to hold in memory between writes.
+const BUFFER_SIZE: usize = 64 * 1024;
+/// Longest that a row may sit in the buffer before it's written out.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const HEADER: &str = "time,channel,peak,rms,level,threshold,hot";
+
+/// A CSV log of the level of every chunk of input, for picking thresholds offline.
+///
+/// This writes a row about every chunk, so it's buffered: rows are written out whenever the buffer
+/// fills, at least every [`FLUSH_INTERVAL`], and on exit.
+pub struct LevelLog {
+    out: BufWriter<File>,
+    remove_dc: bool,
+    byte_order: ByteOrder,
+    last_flush: Instant,
+}
+
+impl LevelLog {
+    /// Opens the log at `path` to append to, writing a header first if it's empty. Levels are
+    /// measured after removing the DC offset if `remove_dc` is set, like for detection.
+    pub fn open(path: &Path, remove_dc: bool, byte_order: ByteOrder) -> anyhow::Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Failed to open level_log {}", path.display()))?;
+        let mut out = BufWriter::with_capacity(BUFFER_SIZE, file);
+        if out.get_ref().metadata()?.len() == 0 {
+            writeln!(out, "{}", HEADER)?;
+        }
+        Ok(Self {
+            out,
+            remove_dc,
+            byte_order,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Logs a chunk of `channel` as seen by the detector, with the `level` and `threshold` that it
+    /// was judged by.
+    pub fn record(
+        &mut self,
+        channel: usize,
+        detect: &[u8],
+        level: i32,
+        threshold: i32,
+    ) -> std::io::Result<()> {
+        let time = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let row = format_row(
+            &time,
+            channel,
+            seg::chunk_peak(detect, self.remove_dc, self.byte_order),
+            seg::chunk_rms(detect, self.remove_dc, self.byte_order),
+            level,
+            threshold,
+        );
+        writeln!(self.out, "{}", row)?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.out.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+}
+
+fn format_row(
+    time: &str,
+    channel: usize,
+    peak: i32,
+    rms: f64,
+    level: i32,
+    threshold: i32,
+) -> String {
+    let hot = u8::from(level > threshold);
+    format!("{time},{channel},{peak},{rms:.1},{level},{threshold},{hot}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_row() {
+        let time = "2024-01-01T12:00:00.000Z";
+        assert_eq!(
+            format_row(time, 0, 1200, 345.678, 1200, 8191),
+            "2024-01-01T12:00:00.000Z,0,1200,345.7,1200,8191,0"
+        );
+        assert_eq!(
+            format_row(time, 1, 9000, 2000.0, 8500, 8191),
+            "2024-01-01T12:00:00.000Z,1,9000,2000.0,8500,8191,1"
+        );
+        assert_eq!(HEADER.split(',').count(), 7);
+    }
+}
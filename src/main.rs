@@ -1,57 +1,364 @@
-use std::io::{self, Read, Write};
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
 use log::{debug, error, info, warn};
 
+mod asr;
+mod backfill;
 mod config;
+mod encoder;
+mod fifo;
 mod gcs;
+mod hash;
+mod health;
+mod hook;
+mod index;
+mod input;
+mod journal;
+mod level_log;
+mod manifest;
+mod monitor;
+mod naming;
+mod order;
+mod prune;
+mod ring;
 mod seg;
+mod selftest;
+mod sensor;
+mod soxi;
+mod storage;
+mod stream;
+mod summary;
+mod tasks;
+mod throttle;
+mod upload;
+mod vad;
+mod verify;
 
 struct ActiveSegment {
     /// Unique ID for this segment, for logging/etc. purposes.
     id: String,
+    /// When the segment started recording, per `Event::Start`.
+    started_at: chrono::DateTime<chrono::Utc>,
     /// Filename used while this segment is still being actively recorded.
     part_filename: PathBuf,
     /// Filename used once this segment has finished recording but not been uploaded to GCS.
     local_filename: PathBuf,
     /// Filename used once this segment in its terminal state.
     final_filename: PathBuf,
-    /// `sox(1)` subprocess writing to the file at `part_filename`.
+    /// `sox(1)` subprocess writing to the file at `part_filename`, or to `stream`.
     encoder: Child,
+    /// Upload of the encoder's output as it's written, with `stream_to_gcs`. If this fails, the
+    /// recording is saved to `part_filename` after all, and uploaded from there.
+    stream: Option<stream::Stream>,
+    /// Whether this segment started so soon after the input did that it may have missed the start
+    /// of the sound. See [`EDGE_CHUNKS`].
+    edge: bool,
+    /// This segment's turn to finish, with `ordered_uploads`.
+    ticket: Option<u64>,
+    /// Time from the end of the channel's previous segment to the start of this one, if there was
+    /// a previous one. This is zero when a segment rolls over into the next.
+    gap: Option<Duration>,
+    /// Readings being taken for this segment, with `sensor_command`.
+    sensor: Option<tokio::task::JoinHandle<sensor::Readings>>,
 }
+
+/// Segmentation of one channel of the input, with its own segment in progress (and encoder).
+struct Channel {
+    /// Appended to the IDs of this channel's segments, like `-left`, if the input is split.
+    suffix: &'static str,
+    seg: seg::Segmentation,
+    active: Option<ActiveSegment>,
+    /// This channel's audio from the current chunk of input, and its copy for detection.
+    chunk: Vec<u8>,
+    detect_chunk: Vec<u8>,
+    /// Chunks since the size of the active segment's file was last checked, per
+    /// `max_segment_bytes`.
+    chunks_since_size_check: u32,
+    /// When this channel's last segment ended, if any.
+    last_ended: Option<Instant>,
+    /// Whether the segmenter's current segment wasn't started, per `max_segments_per_minute`, so
+    /// its audio is dropped until it ends.
+    throttled: bool,
+    /// Judges this channel's chunks instead of the threshold, per `vad_command`, until it fails.
+    vad: Option<vad::Vad>,
+}
+
+/// State shared by all segment-finishing tasks.
+struct Shared {
+    storage: storage::Storage,
+    permissions: storage::FilePermissions,
+    encoder_pool: Option<encoder::EncoderPool>,
+    /// Encodes recordings instead of `sox(1)`, if set.
+    encoder_command: Option<encoder::EncoderCommand>,
+    /// Whether `sox(1)` writes an empty comment instead of its default one.
+    strip_comments: bool,
+    /// `RAW_AUDIO_ARGS`, in the configured `byte_order`.
+    raw_audio_args: &'static [&'static str],
+    uploader: Option<upload::Uploader>,
+    manifest: Option<manifest::Manifest>,
+    index: Option<index::Index>,
+    fifo: Option<fifo::Fifo>,
+    asr: Option<asr::Asr>,
+    hook: Option<hook::Hook>,
+    sensor: Option<sensor::Sensor>,
+    /// Transcripts and `post_process_command`s running in the background, which are let finish
+    /// before exiting.
+    background: tasks::Tasks,
+    counters: Arc<summary::Counters>,
+    /// Limits how many segments are in the finalize stage at once. See [`finish_segment`].
+    finalize_permits: tokio::sync::Semaphore,
+    /// With `ordered_uploads`, makes segments finalize one at a time, in the order they started.
+    finish_order: Option<order::FinishOrder>,
+    loudness_classes: Vec<config::LoudnessClass>,
+    /// Metadata attached to every uploaded object, alongside the per-segment measurements.
+    common_metadata: serde_json::Map<String, serde_json::Value>,
+    soxi: soxi::Soxi,
+    /// Whether to hold back uploads whose measurements failed, rather than upload them without.
+    require_metadata: bool,
+    /// Recordings measured to have fewer samples than this are set aside instead of uploaded.
+    min_samples: u64,
+    /// Whether to decode each recording before uploading it, and set it aside if that fails.
+    verify_before_upload: bool,
+    secondary_encoding: Option<config::SecondaryEncoding>,
+    local_layout: config::LocalLayout,
+    /// How long to wait for an encoder to exit before killing it. See [`reap_encoder`].
+    reap_timeout: Duration,
+    /// Where to run uploads that stream recordings to GCS as they're encoded, with
+    /// `stream_to_gcs`.
+    stream_runtime: Option<tokio::runtime::Handle>,
+}
+
+/// Details about the capture setup, recorded for reproducibility.
+#[derive(Debug)]
+struct Provenance {
+    /// Output of `sox --version`, if it could be determined.
+    sox_version: Option<String>,
+    /// Input device used by `rec(1)`, per `input_device` or the `AUDIODEV` environment variable.
+    input_device: String,
+}
+
+/// Which audio device `rec(1)` records from, and which of its channels.
+#[derive(Debug, Default)]
+struct InputSource {
+    /// Device to record from instead of `AUDIODEV`, per `input_device`.
+    device: Option<String>,
+    /// Channels to open the device with, per `input_channels`, if not as many as are recorded.
+    channels: Option<u32>,
+    /// Device channels to record, numbered from 1, per `input_channel_map`.
+    channel_map: Option<Vec<u32>>,
+}
+
+impl InputSource {
+    /// Takes the input settings out of `config`, checking them against the number of channels
+    /// recorded.
+    fn new(config: &mut config::Config, recorded: usize) -> anyhow::Result<Self> {
+        if config.input_device.as_deref() == Some("") {
+            anyhow::bail!("Need a non-empty input_device");
+        }
+        let source = InputSource {
+            device: config.input_device.take(),
+            channels: config.input_channels,
+            channel_map: config.input_channel_map.take(),
+        };
+        match (source.channels, &source.channel_map) {
+            (Some(0), _) => anyhow::bail!("Need input_channels > 0"),
+            (None, Some(_)) => anyhow::bail!("Can't set input_channel_map without input_channels"),
+            (Some(channels), Some(map)) => {
+                if map.len() != recorded {
+                    anyhow::bail!(
+                        "Need input_channel_map to list {} channel(s), one per channel recorded, \
+                         but got {}",
+                        recorded,
+                        map.len()
+                    );
+                }
+                if let Some(bad) = map.iter().find(|&&c| c == 0 || c > channels) {
+                    anyhow::bail!(
+                        "Need input_channel_map entries between 1 and input_channels={channels}, \
+                         but got {bad}"
+                    );
+                }
+                info!(
+                    "Recording channel(s) {:?} of {} from the input device",
+                    map, channels
+                );
+            }
+            _ => {}
+        }
+        Ok(source)
+    }
+
+    /// Returns the command that records from this source, writing audio per `input_args` to
+    /// stdout.
+    fn command(&self, input_args: &[&str]) -> Command {
+        let mut command = match self.channels {
+            // `rec(1)` doesn't take options for its input, only its output, so open the default
+            // device (`-d`) with `sox(1)` instead, which is all that `rec(1)` does anyway.
+            Some(channels) => {
+                let mut command = Command::new("sox");
+                command.args(["-q", "-c", &channels.to_string(), "-d"]);
+                command
+            }
+            None => {
+                let mut command = Command::new("rec");
+                command.arg("-q");
+                command
+            }
+        };
+        command.args(input_args).arg("-");
+        if let Some(map) = &self.channel_map {
+            command
+                .arg("remix")
+                .args(map.iter().map(|channel| channel.to_string()));
+        }
+        if let Some(device) = &self.device {
+            command.env("AUDIODEV", device);
+        }
+        command
+    }
+}
+
 const CHUNK_SIZE: usize = 16384;
+// Chunks must hold whole samples (16-bit, mono per channel), and enough of them for a level to mean
+// something: the duration math and the detector both assume as much.
+const _: () = assert!(CHUNK_SIZE >= 1024 && CHUNK_SIZE.is_multiple_of(2));
 const MAX_TOTAL_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(60 * 10));
 const MIN_HOT_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(1));
 const MAX_QUIET_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(5));
-
-const PART_SUFFIX: &str = ".part";
-const LOCAL_SUFFIX: &str = ".local";
+/// Segments that start within this many chunks of the input starting (or restarting) are flagged
+/// as `edge` in their metadata: with so little audio before them, they were likely hot from the
+/// first chunk, so the sound may have begun before `rec(1)` was listening.
+const EDGE_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(3));
+/// How often to check the size of each segment's file against `max_segment_bytes`.
+const SIZE_CHECK_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(1));
+const DEFAULT_TAIL_MIN_SECS: f64 = 5.0;
+const DEFAULT_TAIL_MAX_SECS: f64 = 10.0;
+const DEFAULT_RELATIVE_DIP_SECS: f64 = 0.5;
+/// How far back the running level goes, for `relative_dip_db`.
+const RELATIVE_DIP_RUNNING_CHUNKS: u32 = duration_to_chunks(Duration::from_secs(5));
+const DEFAULT_DETECTOR_PERCENTILE: f64 = 95.0;
+const DEFAULT_ROLLING_THRESHOLD_PERCENTILE: f64 = 90.0;
+const DEFAULT_UPLOAD_QUEUE_LEN: usize = 64;
+const DEFAULT_FINALIZE_CONCURRENCY: usize = 2;
+/// By default, only recordings with no audio at all are set aside instead of uploaded.
+const DEFAULT_MIN_SAMPLES: u64 = 1;
+const DEFAULT_ENCODER_REAP_TIMEOUT_SECS: u64 = 60;
+const DEFAULT_ASR_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_POST_PROCESS_TIMEOUT_SECS: u64 = 300;
+const DEFAULT_SENSOR_TIMEOUT_SECS: u64 = 5;
+/// Window over which `max_segments_per_minute` counts segments.
+const THROTTLE_WINDOW: Duration = Duration::from_secs(60);
+/// How long a `vad_command` gets to judge each chunk, many times the length of a chunk.
+const VAD_TIMEOUT: Duration = Duration::from_secs(2);
+/// Keeps `edge_fade_ms` small enough to write to an encoder without waiting on it.
+const MAX_EDGE_FADE_MS: u32 = 100;
+/// Keeps `lead_pad_ms` to a lead that buffers in memory without trouble.
+const MAX_LEAD_PAD_MS: u32 = 5000;
+const DEFAULT_UPLOAD_CONCURRENCY: usize = 1;
+/// Long enough to upload a 10-minute recording over a slow link, at about 50 KiB/s.
+const DEFAULT_HTTP_TIMEOUT_SECS: u64 = 600;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_SECS: u64 = 30;
+/// How long input may go without a chunk before `/readyz` fails, many times the length of a chunk.
+const DEFAULT_HEALTH_STALL_SECS: u64 = 10;
+/// Samples per frame of the envelope in each segment's metadata (100 ms), before any merging.
+const ENVELOPE_FRAME_SAMPLES: usize = SAMPLE_RATE as usize / 10;
+/// Keeps the envelope within about 512 bytes of metadata.
+const ENVELOPE_MAX_FRAMES: usize = 256;
 
 const RAW_AUDIO_ARGS: &[&str] = &[
     "-L", "-t", "raw", "-c", "1", "-e", "signed", "-b", "16", "-r", "48k",
 ];
+/// Like `RAW_AUDIO_ARGS`, but with two interleaved channels, for `split_channels`.
+const STEREO_AUDIO_ARGS: &[&str] = &[
+    "-L", "-t", "raw", "-c", "2", "-e", "signed", "-b", "16", "-r", "48k",
+];
+/// Like `RAW_AUDIO_ARGS` and `STEREO_AUDIO_ARGS`, but for `rec(1)` to send 32-bit floats, per
+/// `sample_format`.
+const FLOAT_AUDIO_ARGS: &[&str] = &[
+    "-L", "-t", "raw", "-c", "1", "-e", "float", "-b", "32", "-r", "48k",
+];
+const FLOAT_STEREO_AUDIO_ARGS: &[&str] = &[
+    "-L", "-t", "raw", "-c", "2", "-e", "float", "-b", "32", "-r", "48k",
+];
+/// ID suffixes for the channels of `STEREO_AUDIO_ARGS`, in order.
+const STEREO_SUFFIXES: &[&str] = &["-left", "-right"];
+
+/// Returns `args` for raw audio in `order` instead of little-endian.
+fn with_byte_order(
+    args: &'static [&'static str],
+    order: config::ByteOrder,
+) -> &'static [&'static str] {
+    match order {
+        config::ByteOrder::Le => args,
+        // This only happens a few times at startup, so the leak doesn't add up.
+        order => args
+            .iter()
+            .map(|&arg| if arg == "-L" { order.sox_arg() } else { arg })
+            .collect::<Vec<_>>()
+            .leak(),
+    }
+}
+
+/// Samples per second of audio in the `RAW_AUDIO_ARGS` format.
+const SAMPLE_RATE: u64 = 48000;
+/// Bytes per second of audio in the `RAW_AUDIO_ARGS` format.
+const BYTES_PER_SEC: u64 = SAMPLE_RATE * 2;
 
 const fn duration_to_chunks(d: Duration) -> u32 {
-    const SAMPLES_PER_MS: u64 = 48;
-    const BYTES_PER_MS: u64 = SAMPLES_PER_MS * 2;
+    const BYTES_PER_MS: u64 = BYTES_PER_SEC / 1000;
     (d.as_millis() * BYTES_PER_MS as u128 / CHUNK_SIZE as u128) as u32
 }
 
-fn read_config() -> anyhow::Result<config::Config> {
-    let arg = std::env::args_os().nth(1);
-    let config_file = arg
-        .as_deref()
-        .unwrap_or_else(|| std::ffi::OsStr::new(config::DEFAULT_FILENAME));
-    let config_file = Path::new(config_file);
+/// Converts a configured duration, `name` = `secs`, to whole chunks, rounding down. Warns if a
+/// nonzero duration rounds down to no chunks at all, which is rarely what was meant.
+fn configured_chunks(name: &str, secs: f64) -> u32 {
+    let chunks = duration_to_chunks(Duration::from_secs_f64(secs));
+    if secs > 0.0 && chunks == 0 {
+        warn!(
+            "{}={} → 0 chunks (chunk is {:.2}s); use at least one chunk's worth",
+            name,
+            secs,
+            CHUNK_SIZE as f64 / BYTES_PER_SEC as f64
+        );
+    }
+    chunks
+}
+
+/// Reads the config files at `args`, each overriding the ones before it, or the default config
+/// file if there are none.
+fn read_config(args: Vec<std::ffi::OsString>) -> anyhow::Result<config::Config> {
+    if args.is_empty() {
+        return read_config_file(Path::new(config::DEFAULT_FILENAME), false);
+    }
+    let mut config = config::Config::default();
+    for arg in args {
+        config = config.merge(read_config_file(Path::new(&arg), true)?);
+    }
+    Ok(config)
+}
+
+/// Reads one config file. If it's not `required`, a missing file reads as an empty config.
+fn read_config_file(config_file: &Path, required: bool) -> anyhow::Result<config::Config> {
     let contents = match std::fs::read(config_file) {
-        Ok(c) => c,
+        Ok(c) => {
+            info!("Using config file {}", absolute(config_file).display());
+            c
+        }
         // If no config file was explicitly given and the default wasn't found, behave as if the
         // config file were empty, producing a "default" config.
-        Err(e) if e.kind() == io::ErrorKind::NotFound && arg.is_none() => Vec::new(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+            warn!(
+                "No config file at {}; using default settings",
+                absolute(config_file).display()
+            );
+            Vec::new()
+        }
         Err(e) => {
             return Err(e).with_context(|| {
                 format!("Failed to read config file from {}", config_file.display())
@@ -63,75 +370,858 @@ fn read_config() -> anyhow::Result<config::Config> {
         .with_context(|| format!("Invalid config in {}", config_file.display()))
 }
 
-async fn finish_segment(mut seg: ActiveSegment, gcs: Option<Arc<gcs::Client>>) {
+/// Resolves `path` against the current directory (following symlinks if it exists), for logging.
+fn absolute(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    })
+}
+
+/// Runs `sox --version` and returns the reported version (e.g., `SoX v14.4.2`).
+fn sox_version() -> anyhow::Result<String> {
+    let output = Command::new("sox")
+        .arg("--version")
+        .output()
+        .context("Failed to run sox(1); is SoX installed?")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "sox --version failed ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = stdout.trim();
+    Ok(version
+        .strip_prefix("sox:")
+        .unwrap_or(version)
+        .trim()
+        .to_string())
+}
+
+impl Provenance {
+    fn detect(input_device: Option<&str>) -> Self {
+        let sox_version = match sox_version() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                warn!("Couldn't determine SoX version: {:#}", e);
+                None
+            }
+        };
+        // `rec(1)` uses `AUDIODEV` if set, or else the default device of its audio driver.
+        let input_device = match input_device {
+            Some(device) => device.to_string(),
+            None => std::env::var("AUDIODEV").unwrap_or_else(|_| "default".to_string()),
+        };
+        Provenance {
+            sox_version,
+            input_device,
+        }
+    }
+}
+
+/// Finishes a segment whose encoder's input has been closed, in three stages:
+///
+/// 1.  Reap: wait for the encoder to exit. Every segment does this as soon as it ends, with no
+///     concurrency limit, so that encoders never pile up as zombies behind slower stages.
+/// 2.  Finalize: classify the segment, rename it to `.local`, measure it, and record its metadata.
+///     At most `finalize_concurrency` segments do this at once, since it runs `soxi(1)` and
+///     touches storage.
+/// 3.  Upload: hand off the segment to the upload queue, which `upload_concurrency` workers drain
+///     independently. Nothing here waits on an upload.
+///
+/// With `ordered_uploads`, segments also take turns to finalize, in the order that they started,
+/// so that they're queued for upload in that order. Only reaping is still concurrent.
+///
+/// The time that each stage finishes is logged (at debug level) relative to `ended`, when the
+/// segment's audio stopped, so that it's clear where the latency until upload goes.
+///
+/// This is safe to run more than once for the same segment, as when two triggers both finish it.
+/// Reaping an encoder that was already reaped just sees the same exit status again. Renaming the
+/// `.part` file to `.local` claims the segment for finalizing, so whichever run finds it already
+/// renamed (or uploaded and renamed again) skips the rest, and the segment is only recorded and
+/// uploaded once.
+async fn finish_segment(
+    seg: ActiveSegment,
+    stats: seg::Stats,
+    ended: Instant,
+    shared: Arc<Shared>,
+) {
     info!("Finishing segment {}", seg.id);
-    match tokio::task::spawn_blocking(move || seg.encoder.wait())
-        .await
-        .unwrap()
-    {
-        Ok(st) if st.success() => {}
-        Ok(st) => error!("Encoder for segment {} exited unhealthy: {}", seg.id, st),
+    let mut seg = reap_encoder(seg, shared.reap_timeout).await;
+    debug!(
+        "Reaped encoder for segment {} {:.3} s after it ended",
+        seg.id,
+        ended.elapsed().as_secs_f64()
+    );
+    let readings = match seg.sensor.take() {
+        Some(sensor) => sensor.await.unwrap_or_else(|e| {
+            warn!(
+                "Failed to take sensor readings for segment {}: {}",
+                seg.id, e
+            );
+            sensor::Readings::new()
+        }),
+        None => sensor::Readings::new(),
+    };
+    let streamed = seg.stream.is_some();
+    if let Some(stream) = seg.stream.take() {
+        let mut metadata = streamed_metadata(&stats);
+        add_segment_metadata(&mut metadata, &seg, &stats, None);
+        let renames = shared.uploader.as_ref().map(|u| &u.metadata_keys);
+        sensor::merge(&mut metadata, &readings, renames, &seg.id);
+        match stream.finish(metadata).await {
+            Ok(bytes) => {
+                shared.counters.add_segment(bytes);
+                shared.counters.add_upload();
+                if let Some(journal) = journal(&shared) {
+                    journal.uploaded(&seg.id, true);
+                }
+                info!(
+                    "Streamed segment {} to GCS {:.3} s after it ended",
+                    seg.id,
+                    ended.elapsed().as_secs_f64()
+                );
+                return;
+            }
+            Err(fallback) => {
+                warn!(
+                    "Failed to stream segment {} to GCS; uploading it from a file instead: {:#}",
+                    seg.id, fallback.error
+                );
+                let res = storage::retry("save streamed segment", || {
+                    tokio::fs::write(&seg.part_filename, &fallback.contents)
+                })
+                .await
+                .and_then(|()| shared.permissions.apply(&seg.part_filename));
+                if let Err(e) = res {
+                    shared.storage.check_error(&e);
+                    error!("Failed to save segment {}: {}", seg.id, e);
+                    shared.counters.add_failure();
+                    return;
+                }
+            }
+        }
+    }
+    let ticket = seg.ticket;
+    if let (Some(order), Some(ticket)) = (&shared.finish_order, ticket) {
+        order.wait(ticket).await;
+    }
+    // The semaphore is never closed.
+    let permit = shared.finalize_permits.acquire().await.unwrap();
+    finalize_segment(seg, stats, readings, ended, &shared, streamed).await;
+    drop(permit);
+    if let (Some(order), Some(ticket)) = (&shared.finish_order, ticket) {
+        order.done(ticket);
+    }
+}
+
+/// Closes a segment's encoder's input, after writing `padding` (e.g., silence) to it, if it hasn't
+/// already failed.
+fn close_encoder(seg: &mut ActiveSegment, padding: &[u8], writer: Option<&encoder::InputWriter>) {
+    if seg.encoder.stdin.is_some() && !padding.is_empty() {
+        if let Err(e) = encoder::write(&mut seg.encoder, padding, writer) {
+            warn!(
+                "Failed to write trailing silence to encoder for segment {}: {}",
+                seg.id, e
+            );
+        }
+    }
+    drop(seg.encoder.stdin.take());
+}
+
+/// Throws away a segment whose encoder's input has been closed, once the encoder exits.
+async fn discard_segment(seg: ActiveSegment, shared: Arc<Shared>) {
+    let mut seg = reap_encoder(seg, shared.reap_timeout).await;
+    if let Some(stream) = seg.stream.take() {
+        stream.discard().await;
+    }
+    match tokio::fs::remove_file(&seg.part_filename).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => {
+            shared.storage.check_error(&e);
+            error!("Failed to remove discarded segment {}: {}", seg.id, e);
+        }
+    }
+    if let Some(journal) = journal(&shared) {
+        journal.dropped(&seg.id, true);
+    }
+    // Nothing to upload, but later segments may be waiting on this one's turn.
+    if let (Some(order), Some(ticket)) = (&shared.finish_order, seg.ticket) {
+        order.wait(ticket).await;
+        order.done(ticket);
+    }
+}
+
+/// Waits for a segment's encoder to exit, logging if it didn't exit cleanly.
+///
+/// If the encoder is still running after `timeout` (e.g., stuck on a hung disk), it's killed, and
+/// the segment is finalized anyway with whatever the encoder managed to write. This polls rather
+/// than blocking a thread on `wait`, so a stuck encoder can't leak a blocking thread.
+async fn reap_encoder(mut seg: ActiveSegment, timeout: Duration) -> ActiveSegment {
+    const KILL_GRACE: Duration = Duration::from_secs(5);
+    match try_wait_for(&mut seg.encoder, timeout).await {
+        Ok(Some(st)) if st.success() => {}
+        Ok(Some(st)) => error!("Encoder for segment {} exited unhealthy: {}", seg.id, st),
         Err(e) => error!("Failed to reap encoder for segment {}: {}", seg.id, e),
+        Ok(None) => {
+            error!(
+                "Encoder for segment {} still running after {:?}; killing it and finalizing \
+                 what it wrote",
+                seg.id, timeout
+            );
+            if let Err(e) = seg.encoder.kill() {
+                error!("Failed to kill encoder for segment {}: {}", seg.id, e);
+            }
+            match try_wait_for(&mut seg.encoder, KILL_GRACE).await {
+                Ok(Some(_)) => {}
+                Ok(None) => error!("Encoder for segment {} didn't exit when killed", seg.id),
+                Err(e) => error!("Failed to reap encoder for segment {}: {}", seg.id, e),
+            }
+        }
+    }
+    seg
+}
+
+/// Waits up to `limit` for `child` to exit, returning `None` if it's still running.
+async fn try_wait_for(
+    child: &mut Child,
+    limit: Duration,
+) -> std::io::Result<Option<std::process::ExitStatus>> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    let deadline = tokio::time::Instant::now() + limit;
+    loop {
+        match child.try_wait()? {
+            None if tokio::time::Instant::now() < deadline => {
+                tokio::time::sleep(POLL_INTERVAL).await
+            }
+            status => return Ok(status),
+        }
+    }
+}
+
+/// Renames a finished segment's file and records it, uploading it if uploads are enabled.
+///
+/// A `streamed` segment's file is what was left after its streaming upload failed, which `soxi(1)`
+/// can't measure, so it's measured by what the segmenter saw instead. Any sensor `readings` are
+/// added to its metadata.
+async fn finalize_segment(
+    mut seg: ActiveSegment,
+    stats: seg::Stats,
+    readings: sensor::Readings,
+    ended: Instant,
+    shared: &Arc<Shared>,
+    streamed: bool,
+) {
+    // Until its `.local` files are pushed below, with what's gathered here, a rescan mustn't take
+    // them as left over from before.
+    let _claim = shared
+        .uploader
+        .as_ref()
+        .map(|uploader| uploader.claim(&seg.id));
+    // Classified segments live in a subdirectory from here on, so that the class can be recovered
+    // from the path of a `.local` file after a restart.
+    let class = naming::loudness_class(&shared.loudness_classes, stats.peak);
+    if let Some(class) = class {
+        let dir = seg.final_filename.parent().unwrap().join(class);
+        let res =
+            storage::retry("create class directory", || tokio::fs::create_dir_all(&dir)).await;
+        if let Err(e) = res {
+            shared.storage.check_error(&e);
+            error!(
+                "Failed to create directory {} for segment {}: {:#}",
+                dir.display(),
+                seg.id,
+                e
+            );
+            shared.counters.add_failure();
+            return;
+        }
+        seg.local_filename = dir.join(seg.local_filename.file_name().unwrap());
+        seg.final_filename = dir.join(seg.final_filename.file_name().unwrap());
     }
-    if let Err(e) = tokio::fs::rename(&seg.part_filename, &seg.local_filename).await {
+    let res = storage::retry("mark segment as finished", || {
+        tokio::fs::rename(&seg.part_filename, &seg.local_filename)
+    })
+    .await;
+    if let Err(e) = res {
+        if storage::already_renamed(&e, &seg.local_filename)
+            || storage::already_renamed(&e, &seg.final_filename)
+        {
+            info!("Segment {} was already finished; skipping it", seg.id);
+            return;
+        }
+        shared.storage.check_error(&e);
         error!(
             "Failed to mark segment {} as locally finished: {:#}",
             seg.id, e
         );
+        shared.counters.add_failure();
         return;
     }
-    if let Some(gcs) = gcs {
-        let res = upload_segment(&seg.id, &seg.local_filename, &seg.final_filename, &gcs).await;
+    let bytes = tokio::fs::metadata(&seg.local_filename)
+        .await
+        .map_or(0, |m| m.len());
+    shared.counters.add_segment(bytes);
+    debug!(
+        "Renamed segment {} to .local {:.3} s after it ended",
+        seg.id,
+        ended.elapsed().as_secs_f64()
+    );
+    let measure = shared.uploader.is_some() || shared.manifest.is_some() || shared.index.is_some();
+    let (mut metadata, measured) = if streamed {
+        let mut metadata = shared.common_metadata.clone();
+        metadata.extend(streamed_metadata(&stats));
+        (metadata, true)
+    } else if measure {
+        match segment_metadata(shared, &seg.local_filename).await {
+            Ok(m) => (m, true),
+            Err(e) => {
+                warn!(
+                    "Couldn't measure segment {}; will retry before uploading: {:#}",
+                    seg.id, e
+                );
+                (shared.common_metadata.clone(), false)
+            }
+        }
+    } else {
+        (serde_json::Map::new(), true)
+    };
+    let started_at = add_segment_metadata(&mut metadata, &seg, &stats, class);
+    let renames = shared.uploader.as_ref().map(|u| &u.metadata_keys);
+    sensor::merge(&mut metadata, &readings, renames, &seg.id);
+    if let Some(manifest) = &shared.manifest {
+        if let Err(e) = manifest
+            .record(&seg.id, &seg.final_filename, &metadata)
+            .await
+        {
+            error!("Failed to record manifest for segment {}: {:#}", seg.id, e);
+        }
+    }
+    let object_name = naming::object_name(class, &seg.id, encoder::FORMAT);
+    if let Some(index) = &shared.index {
+        let field = |key: &str| metadata.get(key).and_then(|v| v.as_str());
+        let gcs_uri = shared.uploader.as_ref().map(|u| {
+            let path = &u.path;
+            let object_key = u.object_key(&seg.id, &object_name);
+            format!("gs://{}/{}{}", path.bucket, path.prefix, object_key)
+        });
+        let row = index::Row {
+            id: &seg.id,
+            path: &seg.final_filename,
+            started_at: &started_at,
+            duration: field("duration-secs").and_then(|v| v.parse().ok()),
+            samples: field("samples").and_then(|v| v.parse().ok()),
+            sample_rate: field("sample-rate").and_then(|v| v.parse().ok()),
+            peak: stats.peak,
+            end_reason: stats.end_reason.as_str(),
+            gcs_uri: gcs_uri.as_deref(),
+        };
+        if let Err(e) = index.insert(&row) {
+            error!("{:#}", e);
+        }
+    }
+    // This reads the primary recording, so do it before the primary is uploaded and renamed.
+    let secondary = match &shared.secondary_encoding {
+        Some(encoding) => encode_secondary(&seg, encoding, shared).await,
+        None => None,
+    };
+    if let Some(uploader) = &shared.uploader {
+        if let Some(fifo) = &shared.fifo {
+            let local = &seg.local_filename;
+            fifo.send(&fifo::segment_line(
+                &seg.id,
+                local,
+                &seg.final_filename,
+                &metadata,
+            ));
+        }
+        if let (Some(local_filename), Some(encoding)) = (secondary, &shared.secondary_encoding) {
+            let object_name = naming::secondary_object_name(class, &seg.id, encoding);
+            let final_filename = local_filename.with_extension("");
+            if let Some(journal) = &uploader.journal {
+                journal.finished(&seg.id, false, &final_filename, &object_name);
+            }
+            uploader.push(upload::Job {
+                id: seg.id.clone(),
+                object_name,
+                final_filename,
+                local_filename,
+                // Its sample rate and such differ from the primary's, so measure it separately.
+                metadata: metadata.clone(),
+                measured: false,
+                format: encoding.format,
+                primary: false,
+                ended: Some(ended),
+            });
+        }
+        if let Some(journal) = &uploader.journal {
+            journal.finished(&seg.id, true, &seg.final_filename, &object_name);
+        }
+        uploader.push(upload::Job {
+            object_name,
+            id: seg.id,
+            local_filename: seg.local_filename,
+            final_filename: seg.final_filename,
+            metadata,
+            measured,
+            format: encoder::FORMAT,
+            primary: true,
+            ended: Some(ended),
+        });
+    } else {
+        let res = storage::retry("finalize segment", || {
+            tokio::fs::rename(&seg.local_filename, &seg.final_filename)
+        })
+        .await;
         if let Err(e) = res {
-            error!("Failed to upload segment {} to GCS: {:#}", seg.id, e);
+            shared.storage.check_error(&e);
+            error!(
+                "Failed to finalize filename for segment {}: {:#}",
+                seg.id, e
+            );
+            return;
+        }
+        if let Some(fifo) = &shared.fifo {
+            let path = &seg.final_filename;
+            fifo.send(&fifo::segment_line(&seg.id, path, path, &metadata));
+        }
+        // Without uploads, there's no before or after, so it always runs in the background.
+        if shared.hook.is_some() {
+            hook::spawn_post_process(shared, &seg.id, &seg.final_filename, &metadata);
+        }
+        if shared.asr.is_some() {
+            let (shared, id) = (shared.clone(), seg.id);
+            let task = asr::transcribe_segment(shared.clone(), id, seg.final_filename, None);
+            shared.background.spawn(task);
         }
-    } else if let Err(e) = tokio::fs::rename(&seg.local_filename, &seg.final_filename).await {
+    }
+}
+
+/// Adds what the segmenter saw of a segment to its metadata, and returns when it started, as
+/// recorded there.
+fn add_segment_metadata(
+    metadata: &mut serde_json::Map<String, serde_json::Value>,
+    seg: &ActiveSegment,
+    stats: &seg::Stats,
+    class: Option<&str>,
+) -> String {
+    let started_at = seg
+        .started_at
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    metadata.insert("started-at".to_string(), started_at.clone().into());
+    if stats.bytes > 0 && !metadata.contains_key("duration-secs") {
+        // Fall back to what the segmenter saw, e.g., if `soxi(1)` failed.
+        let duration = format!("{:.3}", stats.bytes as f64 / BYTES_PER_SEC as f64);
+        metadata.insert("duration-secs".to_string(), duration.into());
+    }
+    metadata.insert(
+        "clipped".to_string(),
+        (stats.clipped_samples > 0).to_string().into(),
+    );
+    metadata.insert(
+        "clipped-samples".to_string(),
+        stats.clipped_samples.to_string().into(),
+    );
+    metadata.insert("edge".to_string(), seg.edge.to_string().into());
+    if let Some(gap) = seg.gap {
+        let gap = format!("{:.3}", gap.as_secs_f64());
+        metadata.insert("gap-secs".to_string(), gap.into());
+    }
+    if let Some(class) = class {
+        metadata.insert("loudness-class".to_string(), class.into());
+    }
+    if stats.envelope.frame_samples > 0 {
+        let frame_ms = stats.envelope.frame_samples as u64 * 1000 / SAMPLE_RATE;
+        metadata.insert("envelope".to_string(), stats.envelope.to_hex().into());
+        metadata.insert("envelope-frame-ms".to_string(), frame_ms.to_string().into());
+    }
+    started_at
+}
+
+/// Measures a streamed recording by what the segmenter wrote to its encoder, since its FLAC header
+/// doesn't say how long it is.
+fn streamed_metadata(stats: &seg::Stats) -> serde_json::Map<String, serde_json::Value> {
+    let samples = stats.bytes / (BYTES_PER_SEC / SAMPLE_RATE);
+    let duration = format!("{:.3}", stats.bytes as f64 / BYTES_PER_SEC as f64);
+    let mut metadata = serde_json::Map::new();
+    metadata.insert("duration-secs".to_string(), duration.into());
+    metadata.insert("samples".to_string(), samples.to_string().into());
+    metadata.insert("sample-rate".to_string(), SAMPLE_RATE.to_string().into());
+    metadata
+}
+
+/// Writes the secondary encoding of a segment next to its `.local` file, returning its path: a
+/// `.local` file to upload if uploads are enabled, or else its final path.
+///
+/// Failing to make the secondary encoding is logged, but doesn't affect the primary recording.
+async fn encode_secondary(
+    seg: &ActiveSegment,
+    encoding: &config::SecondaryEncoding,
+    shared: &Shared,
+) -> Option<PathBuf> {
+    let final_filename = seg
+        .local_filename
+        .with_file_name(naming::secondary_filename(&seg.id, encoding));
+    let with_suffix = |suffix: &str| {
+        let mut path = final_filename.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let part_filename = with_suffix(naming::PART_SUFFIX);
+    let filename = if shared.uploader.is_some() {
+        with_suffix(naming::LOCAL_SUFFIX)
+    } else {
+        final_filename.clone()
+    };
+    let res = encoder::transcode(
+        &seg.local_filename,
+        &part_filename,
+        encoding,
+        &shared.permissions,
+    )
+    .await;
+    if let Err(e) = res {
+        error!(
+            "Failed to make secondary encoding of segment {}: {:#}",
+            seg.id, e
+        );
+        let _ = tokio::fs::remove_file(&part_filename).await;
+        return None;
+    }
+    let res = storage::retry("finish secondary encoding", || {
+        tokio::fs::rename(&part_filename, &filename)
+    })
+    .await;
+    if let Err(e) = res {
+        shared.storage.check_error(&e);
         error!(
-            "Failed to finalize filename for segment {}: {:#}",
+            "Failed to finish secondary encoding of segment {}: {:#}",
             seg.id, e
         );
+        return None;
     }
+    Some(filename)
 }
 
-/// Runs `soxi $query $file` and returns the output (with trailing whitespace trimmed).
-async fn soxi(query: &str, file: &Path) -> anyhow::Result<String> {
-    let output = tokio::process::Command::new("soxi")
-        .arg(query)
-        .arg(file)
-        .output()
-        .await?;
-    if !output.status.success() {
-        anyhow::bail!(
-            "Failed to query soxi {:?} for {} ({}): {}",
-            query,
-            file.display(),
-            output.status,
-            String::from_utf8_lossy(&output.stderr)
-        );
+/// Builds the HTTP client for uploads, with timeouts so that a hung connection fails the upload
+/// (leaving its `.local` file to retry) instead of stalling an upload worker forever.
+fn http_client(config: &config::Config) -> anyhow::Result<reqwest::Client> {
+    let secs = |name: &str, value: Option<f64>, default: u64| match value {
+        None => Ok(Duration::from_secs(default)),
+        Some(secs) if secs > 0.0 && secs.is_finite() => Ok(Duration::from_secs_f64(secs)),
+        Some(secs) => Err(anyhow::anyhow!("Need {name} > 0, but got {secs}")),
+    };
+    let timeout = secs(
+        "http_timeout_secs",
+        config.http_timeout_secs,
+        DEFAULT_HTTP_TIMEOUT_SECS,
+    )?;
+    let connect_timeout = secs(
+        "http_connect_timeout_secs",
+        config.http_connect_timeout_secs,
+        DEFAULT_HTTP_CONNECT_TIMEOUT_SECS,
+    )?;
+    let mut builder = reqwest::Client::builder()
+        .timeout(timeout)
+        .connect_timeout(connect_timeout);
+    if let Some(size) = config.http_pool_size {
+        builder = builder.pool_max_idle_per_host(size);
+    }
+    builder.build().context("Failed to create HTTP client")
+}
+
+/// Authenticates to GCS, for uploads to `path`.
+async fn connect_gcs(
+    http: &reqwest::Client,
+    path: &gcs::Path,
+    options: &gcs::Options,
+) -> anyhow::Result<gcs::Client> {
+    log::debug!("Attempting to authenticate to GCS");
+    let auth = gcp_auth::AuthenticationManager::new()
+        .await
+        .with_context(|| {
+            format!(
+                "GCS bucket specified (gs://{}/{}) but no valid credentials found",
+                path.bucket, path.prefix
+            )
+        })?;
+    log::info!("Authenticated to GCS");
+    Ok(gcs::Client {
+        http: http.clone(),
+        path: path.clone(),
+        auth,
+        options: options.clone(),
+    })
+}
+
+/// Connects to GCS at startup, for uploads to `path`, and checks that the bucket is writable, per
+/// `gcs_check_bucket`. Without `gcs_required`, failing either is only logged, since uploads are
+/// retried until they succeed; failing to connect then gives no client.
+fn connect_gcs_checked(
+    rt: &tokio::runtime::Runtime,
+    http: &reqwest::Client,
+    path: &gcs::Path,
+    options: &gcs::Options,
+    config: &config::Config,
+) -> anyhow::Result<Option<gcs::Client>> {
+    match rt.block_on(connect_gcs(http, path, options)) {
+        Ok(client) if config.gcs_check_bucket.unwrap_or(true) => {
+            match rt.block_on(client.check_bucket()) {
+                Ok(()) => info!("Checked that GCS bucket {} is writable", path.bucket),
+                // Uploads will keep failing the same way, but they'll be retried later.
+                Err(e) if !config.gcs_required.unwrap_or(true) => {
+                    warn!("{:#}; recording locally until uploads succeed", e)
+                }
+                Err(e) => return Err(e),
+            }
+            Ok(Some(client))
+        }
+        Ok(client) => Ok(Some(client)),
+        Err(e) if !config.gcs_required.unwrap_or(true) => {
+            warn!("{:#}; recording locally until GCS is available", e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Starts `concurrency` upload workers, first retrying authentication in the background, with
+/// backoff, if it failed at startup. Until then, finished recordings pile up as `.local` files,
+/// which the workers pick up once they start.
+async fn start_uploads(
+    shared: Arc<Shared>,
+    http: reqwest::Client,
+    options: gcs::Options,
+    concurrency: usize,
+) {
+    const MAX_AUTH_RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+    let Some(uploader) = &shared.uploader else {
+        return;
+    };
+    let mut delay = Duration::from_secs(10);
+    while uploader.client().is_none() {
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_AUTH_RETRY_DELAY);
+        match connect_gcs(&http, &uploader.path, &options).await {
+            Ok(client) => {
+                info!("Uploading recordings made while GCS was unavailable");
+                uploader.set_client(client);
+            }
+            Err(e) => warn!("{:#}; will retry in {:?}", e, delay),
+        }
+    }
+    for _ in 0..concurrency {
+        tokio::spawn(upload::drain(shared.clone()));
+    }
+}
+
+/// Saves `last`, the latest timestamp issued for a segment ID, to `path`, replacing it atomically
+/// so that a crash can't leave it half-written.
+fn write_id_state(path: &Path, last: i64) -> io::Result<()> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(naming::PART_SUFFIX);
+    std::fs::write(&part, format!("{}\n", last))?;
+    std::fs::rename(&part, path)
+}
+
+/// The journal of each recording's progress, per `upload_journal`, if kept.
+fn journal(shared: &Shared) -> Option<&journal::Journal> {
+    shared.uploader.as_ref()?.journal.as_ref()
+}
+
+/// Spawns an encoder for a new segment, recording into `dir` (or its date subdirectory, per
+/// `local_layout`).
+///
+/// This uses a spare encoder from the pool if one is ready, and otherwise spawns a new one. With
+/// `stream_to_gcs`, the encoder's output is uploaded as it's written instead, once there's a GCS
+/// client to upload with.
+fn start_segment(id: String, dir: &Path, shared: &Arc<Shared>) -> anyhow::Result<ActiveSegment> {
+    let seg_dir = match (shared.local_layout, naming::date_dir(&id)) {
+        (config::LocalLayout::Date, Some(date_dir)) => {
+            let seg_dir = dir.join(date_dir);
+            std::fs::create_dir_all(&seg_dir)
+                .inspect_err(|e| shared.storage.check_error(e))
+                .with_context(|| format!("Failed to create directory {}", seg_dir.display()))?;
+            seg_dir
+        }
+        _ => dir.to_path_buf(),
+    };
+    let filename = naming::recording_filename(&id, encoder::FORMAT);
+    let part_filename = seg_dir.join(format!("{}{}", filename, naming::PART_SUFFIX));
+    let local_filename = seg_dir.join(format!("{}{}", filename, naming::LOCAL_SUFFIX));
+    let final_filename = seg_dir.join(filename);
+    info!("Starting segment {}", id);
+    if let Some(journal) = journal(shared) {
+        journal.started(&id, &final_filename);
+    }
+    let started_at = chrono::Utc::now();
+    let sensor = shared.sensor.as_ref().map(|sensor| sensor.start(&id));
+    let uploader = shared.uploader.as_ref().filter(|u| u.client().is_some());
+    if let (Some(runtime), Some(uploader)) = (&shared.stream_runtime, uploader) {
+        let mut encoder = encoder::spawn_streaming(shared.raw_audio_args, shared.strip_comments)?;
+        let output = encoder.stdout.take().unwrap();
+        let object_name = naming::object_name(None, &id, encoder::FORMAT);
+        let mut metadata = shared.common_metadata.clone();
+        let started = started_at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        metadata.insert("started-at".to_string(), started.into());
+        let dest = stream::Destination {
+            object_name: uploader.object_key(&id, &object_name),
+            id: id.clone(),
+            metadata,
+        };
+        let stream = stream::Stream::start(runtime, shared.clone(), dest, output);
+        return Ok(ActiveSegment {
+            id,
+            started_at,
+            part_filename,
+            local_filename,
+            final_filename,
+            encoder,
+            stream: Some(stream),
+            edge: false,
+            gap: None,
+            ticket: None,
+            sensor,
+        });
     }
-    let mut s: String = match String::from_utf8(output.stdout) {
-        Ok(s) => s,
-        Err(e) => String::from_utf8_lossy(&e.into_bytes()).into_owned(),
+    // Spares wait in `dir` itself, and move into the date subdirectory when they're taken.
+    let spare = shared.encoder_pool.as_ref().and_then(|pool| pool.take(dir));
+    let encoder = match spare {
+        Some(spare) => match std::fs::rename(&spare.path, &part_filename) {
+            Ok(()) => spare.encoder,
+            Err(e) => {
+                warn!(
+                    "Failed to use spare encoder {}: {}",
+                    spare.path.display(),
+                    e
+                );
+                encoder::discard(spare);
+                encoder::spawn(
+                    &part_filename,
+                    shared.raw_audio_args,
+                    shared.encoder_command.as_ref(),
+                    shared.strip_comments,
+                    &shared.permissions,
+                )?
+            }
+        },
+        None => encoder::spawn(
+            &part_filename,
+            shared.raw_audio_args,
+            shared.encoder_command.as_ref(),
+            shared.strip_comments,
+            &shared.permissions,
+        )?,
+    };
+    Ok(ActiveSegment {
+        id,
+        started_at,
+        part_filename,
+        local_filename,
+        final_filename,
+        encoder,
+        stream: None,
+        edge: false,
+        gap: None,
+        ticket: shared
+            .finish_order
+            .as_ref()
+            .map(|order| order.take_ticket()),
+        sensor,
+    })
+}
+
+/// Starts `rec(1)` and, with a `detect_filter`, the filter for its detection sidechain.
+fn start_input(
+    source: &InputSource,
+    input_args: &'static [&'static str],
+    detect_filter: Option<&[String]>,
+    chunk_size: usize,
+) -> anyhow::Result<(Child, input::Input)> {
+    let mut rec = source
+        .command(input_args)
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn rec(1); is SoX installed?")?;
+    let pipe = rec.stdout.take().unwrap();
+    let input = match detect_filter {
+        Some(effects) => input::Input::sidechain(pipe, input_args, effects, chunk_size)?,
+        None => input::Input::direct(pipe),
     };
-    s.truncate(s.trim_end().len());
-    Ok(s)
+    Ok((rec, input))
+}
+
+/// Tries to start `rec(1)` again after it exited, once a second until `deadline`.
+fn restart_input(
+    deadline: Instant,
+    source: &InputSource,
+    input_args: &'static [&'static str],
+    detect_filter: Option<&[String]>,
+    chunk_size: usize,
+) -> Option<(Child, input::Input)> {
+    // Also keeps from spinning if `rec(1)` keeps exiting right away, as while a device is gone.
+    const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+    loop {
+        std::thread::sleep(RETRY_INTERVAL);
+        if Instant::now() >= deadline {
+            return None;
+        }
+        match start_input(source, input_args, detect_filter, chunk_size) {
+            Ok(started) => return Some(started),
+            Err(e) => warn!("Failed to restart rec(1): {:#}", e),
+        }
+    }
 }
 
-async fn upload_segment(
-    id: &str,
+/// Measures the finished recording at `local_name` and returns its metadata, on top of
+/// `common_metadata`.
+///
+/// If a measurement fails, this fails with `require_metadata`, and otherwise just omits it.
+async fn segment_metadata(
+    shared: &Shared,
     local_name: &Path,
-    final_name: &Path,
-    gcs: &gcs::Client,
-) -> anyhow::Result<()> {
-    let contents = tokio::fs::read(local_name);
-    let samples = soxi("-s", local_name);
-    let sample_rate = soxi("-r", local_name);
-    let (contents, samples, sample_rate) = tokio::join!(contents, samples, sample_rate);
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let mut metadata = shared.common_metadata.clone();
+    metadata.extend(recording_metadata(&shared.soxi, shared.require_metadata, local_name).await?);
+    Ok(metadata)
+}
 
-    let contents = contents
-        .with_context(|| format!("Failed to read segment from {}", local_name.display()))?;
+/// Measures a recording with `soxi(1)`, for [`segment_metadata`], failing if a measurement fails
+/// and `require_metadata` is set.
+async fn recording_metadata(
+    soxi: &soxi::Soxi,
+    require_metadata: bool,
+    local_name: &Path,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    soxi::sync(local_name).await;
+    let samples = soxi.query("-s", local_name);
+    let sample_rate = soxi.query("-r", local_name);
+    let (samples, sample_rate) = tokio::join!(samples, sample_rate);
+    if require_metadata {
+        if let Err(e) = &samples {
+            anyhow::bail!("Couldn't measure sample count: {:#}", e);
+        }
+        if let Err(e) = &sample_rate {
+            anyhow::bail!("Couldn't measure sample rate: {:#}", e);
+        }
+    }
 
     let mut metadata = serde_json::Map::new();
+    if let (Ok(samples), Ok(sample_rate)) = (&samples, &sample_rate) {
+        match (samples.parse::<u64>(), sample_rate.parse::<f64>()) {
+            (Ok(n), Ok(rate)) if rate > 0.0 => {
+                let duration = format!("{:.3}", n as f64 / rate);
+                metadata.insert("duration-secs".to_string(), duration.into());
+            }
+            _ => warn!(
+                "Couldn't compute duration from {:?} samples at rate {:?}",
+                samples, sample_rate
+            ),
+        }
+    }
     match samples {
         Ok(v) => drop(metadata.insert("samples".to_string(), v.into())),
         Err(e) => warn!("Couldn't measure sample count: {}", e),
@@ -140,40 +1230,35 @@ async fn upload_segment(
         Ok(v) => drop(metadata.insert("sample-rate".to_string(), v.into())),
         Err(e) => warn!("Couldn't measure sample rate: {}", e),
     };
-    let metadata = metadata.into();
-
-    let object_name = &format!("{}.flac", id);
-    gcs.put_meta(object_name, &contents, "audio/flac", &metadata)
-        .await?;
-    debug!(
-        "Uploaded to GCS: gs://{}/{}{}",
-        gcs.path.bucket, gcs.path.prefix, object_name
-    );
-
-    tokio::fs::rename(local_name, final_name)
-        .await
-        .context("Failed to finalize filename")?;
-    Ok(())
+    Ok(metadata)
 }
 
 fn main() -> anyhow::Result<()> {
+    // No need to ignore `SIGPIPE` ourselves: the Rust runtime does so before `main`, so writes to
+    // pipes whose readers have exited fail with `EPIPE` instead. (Subprocesses get the default
+    // disposition back, so `rec(1)` and `sox(1)` still behave normally.)
     init_logging();
 
-    let mut config = read_config()?;
-    let threshold = (config.threshold.unwrap_or(0.25).clamp(0.0, 1.0) * f64::from(i16::MAX)) as i16;
-    let storage_dir = config
-        .storage_dir
-        .unwrap_or_else(|| std::env::temp_dir().join("recordings"));
-    match std::fs::create_dir(&storage_dir) {
-        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
-            anyhow::bail!(
-                "Failed to create storage directory {}: {}",
-                storage_dir.display(),
-                e
-            );
-        }
-        _ => {}
+    // Usage: `reccon [selftest | backfill DIR] [--summary | --stdout] [CONFIG_FILE...]`.
+    let mut args = std::env::args_os().skip(1).peekable();
+    let selftest = args.next_if(|arg| arg == "selftest").is_some();
+    let backfill_dir = match args.next_if(|arg| arg == "backfill") {
+        Some(_) => Some(PathBuf::from(
+            args.next().context("Need a directory to backfill")?,
+        )),
+        None => None,
     };
+    let print_summary = args.next_if(|arg| arg == "--summary").is_some();
+    let to_stdout = args.next_if(|arg| arg == "--stdout").is_some();
+    if to_stdout && (print_summary || selftest || backfill_dir.is_some()) {
+        anyhow::bail!("Can't pass --stdout with --summary, selftest, or backfill");
+    }
+    let mut config = read_config(args.collect())?;
+    if selftest {
+        let recorded_channels = recorded_channels(&config);
+        let source = InputSource::new(&mut config, recorded_channels)?;
+        return selftest::run(threshold(&config)?, source);
+    }
 
     let num_cpus = std::thread::available_parallelism()
         .map(|n| n.get())
@@ -185,113 +1270,1675 @@ fn main() -> anyhow::Result<()> {
         )
         .build()
         .context("Failed to start async runtime")?;
+    let counters = Arc::new(summary::Counters::default());
+    let Some(sources) = config.source.take() else {
+        let options = RunOptions {
+            label: None,
+            backfill_dir,
+            to_stdout,
+            print_summary,
+            counters,
+        };
+        return run(config, &rt, options);
+    };
+    if to_stdout || backfill_dir.is_some() {
+        anyhow::bail!("Can't pass --stdout or backfill with [[source]] tables");
+    }
+    run_sources(config, sources, &rt, print_summary, counters)
+}
 
-    let gcs = match config.gcs_bucket.take() {
-        None => None,
-        Some(bucket) => Some(Arc::new(rt.block_on(async {
-            let http = reqwest::Client::new();
-            let path: gcs::Path = bucket.parse()?;
-            log::debug!("Attempting to authenticate to GCS");
-            let auth = gcp_auth::AuthenticationManager::new()
-                .await
-                .with_context(|| {
-                    format!("GCS bucket specified ({bucket}) but no valid credentials found")
-                })?;
-            log::info!("Authenticated to GCS");
-            anyhow::Ok(gcs::Client { http, path, auth })
-        })?)),
-    };
-
-    let mut sp_rec = Command::new("rec")
-        .arg("-q")
-        .args(RAW_AUDIO_ARGS)
-        .arg("-")
-        .stdout(Stdio::piped())
-        .spawn()
-        .context("Failed to spawn rec(1); is SoX installed?")?;
-    let mut pipe = sp_rec.stdout.take().unwrap();
-    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
-    let mut seg = seg::Segmentation::new(seg::Config {
-        chunk_size: CHUNK_SIZE,
-        max_total_chunks: MAX_TOTAL_CHUNKS,
-        min_hot_chunks: MIN_HOT_CHUNKS,
-        max_quiet_chunks: MAX_QUIET_CHUNKS,
-        threshold,
-    });
-    let mut active: Option<ActiveSegment> = None;
-    fn gen_id() -> String {
-        chrono::Utc::now().format("%Y%m%dT%H%M%S").to_string()
+/// Returns the detection threshold, in raw sample units, per `threshold` or `threshold_raw`.
+/// How many channels are recorded, each into its own segments, per `split_channels`.
+fn recorded_channels(config: &config::Config) -> usize {
+    match config.split_channels.unwrap_or(false) {
+        true => STEREO_SUFFIXES.len(),
+        false => 1,
     }
+}
 
-    loop {
-        chunk.clear();
-        (&mut pipe)
-            .take(u64::try_from(CHUNK_SIZE).unwrap())
-            .read_to_end(&mut chunk)
-            .context("Failed to read chunk from rec(1) pipe")?;
-        for ev in seg.accept(&chunk, gen_id) {
-            match ev {
-                seg::Event::Start { id } => {
-                    let None = active else {
-                        panic!("Got Event::Start with active segment");
-                    };
-                    let part_filename =
-                        storage_dir.join(&format!("recording-{}.flac{}", id, PART_SUFFIX));
-                    let local_filename =
-                        storage_dir.join(&format!("recording-{}.flac{}", id, LOCAL_SUFFIX));
-                    let final_filename = storage_dir.join(&format!("recording-{}.flac", id));
-                    info!("Starting segment {}", id);
-                    let sp_sox = Command::new("sox")
-                        .arg("-q")
-                        .args(RAW_AUDIO_ARGS)
-                        .arg("-")
-                        .args(["-t", "flac", "--comment", ""])
-                        .arg(&part_filename)
-                        .stdin(Stdio::piped())
-                        .spawn()
-                        .context("Failed to spawn sox(1)")?;
-                    active = Some(ActiveSegment {
-                        id,
-                        encoder: sp_sox,
-                        part_filename,
-                        local_filename,
-                        final_filename,
-                    });
-                }
-                seg::Event::Data(data) => {
-                    let Some(ActiveSegment { encoder, .. }) = active.as_mut() else {
-                        panic!("Got Event::Data with no active segment");
-                    };
-                    if let Err(e) = encoder.stdin.as_mut().unwrap().write_all(data) {
-                        error!("Failed to write chunk to encoder: {}", e);
-                    }
-                }
-                seg::Event::End => {
-                    let Some(mut active) = active.take() else {
-                        panic!("Got Event::End with no active segment");
-                    };
-                    active.encoder.stdin.take();
-                    rt.spawn(finish_segment(active, gcs.clone()));
-                }
-            }
+fn threshold(config: &config::Config) -> anyhow::Result<i16> {
+    Ok(match (config.threshold, config.threshold_raw) {
+        (Some(_), Some(_)) => anyhow::bail!("Can't set both threshold and threshold_raw"),
+        (_, Some(raw)) => i16::try_from(raw)
+            .ok()
+            .filter(|&raw| raw >= 0)
+            .with_context(|| format!("Need 0 <= threshold_raw <= 32767, but got {raw}"))?,
+        (threshold, None) => {
+            (threshold.unwrap_or(0.25).clamp(0.0, 1.0) * f64::from(i16::MAX)) as i16
         }
-        if chunk.is_empty() {
-            break;
-        }
-    }
+    })
+}
 
+/// Creates each of `dirs` that doesn't exist yet, but not their parents: a missing parent is more
+/// likely a disk that isn't mounted than a place to start recording.
+fn create_storage_dirs(dirs: &[PathBuf]) -> anyhow::Result<()> {
+    for storage_dir in dirs {
+        match std::fs::create_dir(storage_dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                anyhow::bail!(
+                    "Failed to create storage directory {}: {}",
+                    storage_dir.display(),
+                    e
+                );
+            }
+            _ => {}
+        };
+    }
     Ok(())
 }
 
-fn init_logging() {
-    use env_logger::{Builder, Env};
-    use systemd_journal_logger::{connected_to_journal, JournalLog};
-
-    if connected_to_journal() {
-        JournalLog::default().install().unwrap();
-        log::set_max_level(log::LevelFilter::Debug);
-    } else {
-        Builder::from_env(Env::default().default_filter_or(log::LevelFilter::Info.to_string()))
-            .init();
+/// Records each of `sources` at once, on its own thread, with its settings on top of `base`'s,
+/// until all of them have stopped. A source that fails is logged while the others keep going, and
+/// then fails the whole run once they've stopped. All of them count toward the same `counters`,
+/// for one `--summary` of the whole run.
+fn run_sources(
+    base: config::Config,
+    sources: Vec<config::Source>,
+    rt: &tokio::runtime::Runtime,
+    print_summary: bool,
+    counters: Arc<summary::Counters>,
+) -> anyhow::Result<()> {
+    if sources.is_empty() {
+        anyhow::bail!("Need at least one [[source]] table");
+    }
+    for (setting, set) in [
+        ("health_addr", base.health_addr.is_some()),
+        ("id_state_file", base.id_state_file.is_some()),
+        ("sqlite_db", base.sqlite_db.is_some()),
+        ("output_fifo", base.output_fifo.is_some()),
+        ("level_log", base.level_log.is_some()),
+    ] {
+        if set {
+            anyhow::bail!(
+                "Can't set {setting} outside of [[source]] tables, since sources can't share it"
+            );
+        }
+    }
+    let mut labels: Vec<&str> = Vec::new();
+    for source in &sources {
+        let label = source.label.as_str();
+        if label.is_empty()
+            || !label
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "Need a [[source]] label of letters, digits, '-', and '_', but got {label:?}"
+            );
+        }
+        if labels.contains(&label) {
+            anyhow::bail!("Can't have two [[source]] tables labeled {label:?}");
+        }
+        if source.config.source.is_some() {
+            anyhow::bail!("Can't nest [[source]] tables, in the one labeled {label:?}");
+        }
+        labels.push(label);
+    }
+    // Sources that don't set their own storage record into subdirectories of the top-level one.
+    if sources
+        .iter()
+        .any(|s| s.config.storage_dir.is_none() && s.config.storage_dirs.is_none())
+    {
+        let dirs = match (&base.storage_dir, &base.storage_dirs) {
+            (Some(_), Some(_)) => anyhow::bail!("Can't set both storage_dir and storage_dirs"),
+            (_, Some(dirs)) => dirs.clone(),
+            (dir, None) => vec![dir.clone().unwrap_or_else(config::default_storage_dir)],
+        };
+        create_storage_dirs(&dirs)?;
+    }
+    let configs: Vec<(String, config::Config)> = sources
+        .into_iter()
+        .map(|source| (source.label.clone(), source.config(&base)))
+        .collect();
+    // Sources mustn't share directories, including one inside another's, since each scans its own
+    // for recordings to upload.
+    let mut dirs: Vec<(&str, PathBuf)> = Vec::new();
+    for (label, c) in &configs {
+        let own = c.storage_dir.iter().chain(c.storage_dirs.iter().flatten());
+        for dir in own.chain(&c.fallback_dir) {
+            dirs.push((label, storage::canonical_dir(dir)));
+        }
+    }
+    for (i, (label, dir)) in dirs.iter().enumerate() {
+        for (other_label, other) in dirs[i + 1..]
+            .iter()
+            .filter(|(other_label, _)| other_label != label)
+        {
+            if dir == other {
+                anyhow::bail!(
+                    "Can't record more than one [[source]] into {}",
+                    dir.display()
+                );
+            }
+            if dir.starts_with(other) || other.starts_with(dir) {
+                anyhow::bail!(
+                    "Can't record [[source]] {label:?} into {} and {other_label:?} into {}, \
+                    since one is inside the other",
+                    dir.display(),
+                    other.display()
+                );
+            }
+        }
+    }
+    // Each source only keeps its own latest_alias moving forward, so two sharing one object could
+    // move it backward.
+    let mut aliases: Vec<(&str, String)> = Vec::new();
+    for (label, c) in &configs {
+        let (Some(bucket), Some(alias)) = (&c.gcs_bucket, &c.latest_alias) else {
+            continue;
+        };
+        let path: gcs::Path = bucket.parse()?;
+        let object = format!("gs://{}/{}{}", path.bucket, path.prefix, alias);
+        if let Some((other_label, _)) = aliases.iter().find(|(_, other)| *other == object) {
+            anyhow::bail!(
+                "Can't set latest_alias to {object} for both [[source]] {other_label:?} and \
+                {label:?}"
+            );
+        }
+        aliases.push((label, object));
+    }
+
+    let started = Instant::now();
+    let total = configs.len();
+    let failed = std::thread::scope(|scope| -> anyhow::Result<usize> {
+        let mut threads = Vec::new();
+        for (label, config) in configs {
+            let options = RunOptions {
+                label: Some(label.clone()),
+                backfill_dir: None,
+                to_stdout: false,
+                print_summary: false,
+                counters: counters.clone(),
+            };
+            info!("Starting source {}", label);
+            let thread = std::thread::Builder::new()
+                .name(format!("source-{label}"))
+                .spawn_scoped(scope, move || {
+                    let res = run(config, rt, options);
+                    match &res {
+                        Ok(()) => info!("Source {} stopped", label),
+                        Err(e) => error!("Source {} failed: {:#}", label, e),
+                    }
+                    res
+                })
+                .context("Failed to start thread for source")?;
+            threads.push(thread);
+        }
+        // A panic was already reported, by the panic hook.
+        Ok(threads
+            .into_iter()
+            .map(|thread| thread.join())
+            .filter(|res| !matches!(res, Ok(Ok(()))))
+            .count())
+    })?;
+    if print_summary {
+        println!("{}", counters.summary(started.elapsed()));
+    }
+    if failed > 0 {
+        anyhow::bail!("{failed} of {total} source(s) failed");
+    }
+    Ok(())
+}
+
+/// How [`run`] was asked to run, beyond its config.
+struct RunOptions {
+    /// Names the source, with `[[source]]` tables, which goes in its segments' IDs.
+    label: Option<String>,
+    backfill_dir: Option<PathBuf>,
+    to_stdout: bool,
+    print_summary: bool,
+    /// Totals for `--summary`, maybe shared with other sources.
+    counters: Arc<summary::Counters>,
+}
+
+/// Records from one input, per `config`, until it ends or a limit (like `max_segments`) is reached;
+/// or backfills, if asked to.
+fn run(
+    mut config: config::Config,
+    rt: &tokio::runtime::Runtime,
+    options: RunOptions,
+) -> anyhow::Result<()> {
+    let RunOptions {
+        label,
+        backfill_dir,
+        to_stdout,
+        print_summary,
+        counters,
+    } = options;
+    let threshold = threshold(&config)?;
+    let http = http_client(&config)?;
+    let startup_delay = config.startup_delay_secs.unwrap_or(0.0);
+    let startup_jitter = config.startup_jitter_secs.unwrap_or(0.0);
+    if !(startup_delay >= 0.0 && startup_delay.is_finite()) {
+        anyhow::bail!("Need startup_delay_secs >= 0, but got {startup_delay}");
+    }
+    if !(startup_jitter >= 0.0 && startup_jitter.is_finite()) {
+        anyhow::bail!("Need startup_jitter_secs >= 0, but got {startup_jitter}");
+    }
+    let storage_dirs = match (config.storage_dir.take(), config.storage_dirs.take()) {
+        (Some(_), Some(_)) => anyhow::bail!("Can't set both storage_dir and storage_dirs"),
+        (None, Some(dirs)) if dirs.is_empty() => anyhow::bail!("Need a non-empty storage_dirs"),
+        (None, Some(dirs)) => dirs,
+        (dir, None) => vec![dir.unwrap_or_else(config::default_storage_dir)],
+    };
+    // Where files that aren't recordings go, like the daily manifest.
+    let storage_dir = storage_dirs[0].clone();
+    let fallback_dir = config.fallback_dir.take();
+
+    let recorded_channels = recorded_channels(&config);
+    let source = InputSource::new(&mut config, recorded_channels)?;
+    let metadata_keys = config.metadata_keys.take().unwrap_or_default();
+    let custom_keys = config.gcs_metadata.iter().flat_map(|custom| custom.keys());
+    gcs::check_metadata_keys(&metadata_keys, custom_keys.map(String::as_str))?;
+    let mut common_metadata = serde_json::Map::new();
+    if let Some(custom) = config.gcs_metadata.take() {
+        gcs::check_custom_metadata(custom.keys().map(String::as_str))?;
+        common_metadata.extend(custom.into_iter().map(|(k, v)| (k, v.into())));
+    }
+
+    let gcs_path: Option<gcs::Path> = config.gcs_bucket.as_deref().map(str::parse).transpose()?;
+    let gcs_options = gcs::Options {
+        scopes: match config.gcs_scopes.take() {
+            Some(scopes) if scopes.is_empty() => anyhow::bail!("Need at least one GCS scope"),
+            Some(scopes) => scopes,
+            None => vec![gcs::DEFAULT_SCOPE.to_string()],
+        },
+        cache_control: config.cache_control.take(),
+        content_disposition: match config.content_disposition.take() {
+            Some(template) => {
+                gcs::check_header_value("content_disposition", &template)?;
+                Some(template)
+            }
+            None => None,
+        },
+    };
+    let object_key = match config.object_key_template.take() {
+        None => None,
+        Some(template) => Some(naming::ObjectKeyTemplate::new(&template)?),
+    };
+    // A backfill's recordings weren't necessarily made with this machine's input device or SoX, so
+    // they don't get its provenance.
+    if let Some(dir) = backfill_dir {
+        let client = match &gcs_path {
+            None => None,
+            Some(path) => connect_gcs_checked(rt, &http, path, &gcs_options, &config)?,
+        };
+        let Some(client) = client else {
+            anyhow::bail!("Need a gcs_bucket, and GCS credentials, to backfill");
+        };
+        let backfill = backfill::Backfill {
+            client,
+            object_key,
+            soxi: soxi::Soxi::new(),
+            common_metadata,
+            require_metadata: config.require_metadata.unwrap_or(false),
+            content_hash: config.content_hash,
+            metadata_keys,
+            concurrency: config
+                .upload_concurrency
+                .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY),
+        };
+        return rt.block_on(backfill::run(backfill, &dir));
+    }
+    let loudness_classes = config.loudness_classes.take().unwrap_or_default();
+    for class in &loudness_classes {
+        if class.name.is_empty() || class.name.contains('/') || class.name.starts_with('.') {
+            anyhow::bail!(
+                "Loudness class name must be a non-empty path component, but got {:?}",
+                class.name
+            );
+        }
+    }
+    let permissions = storage::FilePermissions {
+        mode: config.file_mode,
+        gid: match &config.file_group {
+            Some(group) => Some(storage::resolve_group(group)?),
+            None => None,
+        },
+    };
+    if let Some(mode) = permissions.mode {
+        if mode & !0o7777 != 0 {
+            anyhow::bail!("Invalid file_mode: {:#o}", mode);
+        }
+    }
+    let manifest = config
+        .manifest_mode
+        .map(|mode| manifest::Manifest::new(mode, storage_dir.clone()));
+    let fifo = match config.output_fifo.take() {
+        Some(path) => Some(fifo::Fifo::new(path)?),
+        None => None,
+    };
+    let asr = match config.asr_command.take() {
+        None => None,
+        Some(command) => {
+            let timeout = match config.asr_timeout_secs {
+                None => Duration::from_secs(DEFAULT_ASR_TIMEOUT_SECS),
+                Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+                Some(secs) => anyhow::bail!("Need asr_timeout_secs > 0, but got {secs}"),
+            };
+            Some(asr::Asr::new(command, timeout)?)
+        }
+    };
+    let hook = match config.post_process_command.take() {
+        None => None,
+        Some(command) => {
+            let timeout = match config.post_process_timeout_secs {
+                None => Duration::from_secs(DEFAULT_POST_PROCESS_TIMEOUT_SECS),
+                Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+                Some(secs) => {
+                    anyhow::bail!("Need post_process_timeout_secs > 0, but got {secs}")
+                }
+            };
+            Some(hook::Hook::new(
+                command,
+                timeout,
+                config.post_process_before_upload.unwrap_or(false),
+                config.post_process_save_output.unwrap_or(false),
+            )?)
+        }
+    };
+    let sensor = match config.sensor_command.take() {
+        None => None,
+        Some(command) => {
+            let timeout = match config.sensor_timeout_secs {
+                None => Duration::from_secs(DEFAULT_SENSOR_TIMEOUT_SECS),
+                Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+                Some(secs) => anyhow::bail!("Need sensor_timeout_secs > 0, but got {secs}"),
+            };
+            Some(sensor::Sensor::new(command, timeout, rt.handle().clone())?)
+        }
+    };
+    let verify_before_upload = config.verify_before_upload.unwrap_or(false);
+    if verify_before_upload {
+        let secondary = config.secondary_encoding.as_ref().map(|e| e.format);
+        verify::check_installed(std::iter::once(encoder::FORMAT).chain(secondary))?;
+    }
+    let latest_alias = config.latest_alias.take();
+    if let Some(name) = &latest_alias {
+        if name.is_empty() || name.starts_with('/') || name.contains("//") {
+            anyhow::bail!("Need a latest_alias object name like \"latest.flac\", but got {name:?}");
+        }
+    }
+    let ordered_uploads = config.ordered_uploads.unwrap_or(false);
+    let max_upload_memory_bytes = match config.max_upload_memory_bytes {
+        Some(0) => anyhow::bail!("Need max_upload_memory_bytes > 0"),
+        bytes => bytes,
+    };
+    let stream_to_gcs = config.stream_to_gcs.unwrap_or(false);
+    if stream_to_gcs {
+        if gcs_path.is_none() {
+            anyhow::bail!("Can't set stream_to_gcs without gcs_bucket");
+        }
+        // These all need each recording as a file (or, for loudness classes, its peak before it's
+        // uploaded).
+        let conflicts = [
+            (
+                "encoder_pool_size",
+                config.encoder_pool_size.unwrap_or(0) > 0,
+            ),
+            ("encoder_command", config.encoder_command.is_some()),
+            ("max_segment_bytes", config.max_segment_bytes.is_some()),
+            ("ordered_uploads", ordered_uploads),
+            ("latest_alias", latest_alias.is_some()),
+            ("verify_before_upload", verify_before_upload),
+            ("manifest_mode", manifest.is_some()),
+            ("sqlite_db", config.sqlite_db.is_some()),
+            ("output_fifo", fifo.is_some()),
+            ("asr_command", asr.is_some()),
+            ("post_process_command", hook.is_some()),
+            ("loudness_classes", !loudness_classes.is_empty()),
+            ("secondary_encoding", config.secondary_encoding.is_some()),
+        ];
+        if let Some((name, _)) = conflicts.iter().find(|(_, set)| *set) {
+            anyhow::bail!("Can't set {name} with stream_to_gcs");
+        }
+    }
+    let local_retention = match config.local_retention_secs {
+        None => None,
+        Some(_) if gcs_path.is_none() => {
+            anyhow::bail!("Can't set local_retention_secs without gcs_bucket")
+        }
+        Some(secs) if secs > 0.0 && secs.is_finite() => {
+            let roots: Vec<_> = storage_dirs
+                .iter()
+                .cloned()
+                .chain(fallback_dir.clone())
+                .collect();
+            Some((Duration::from_secs_f64(secs), roots))
+        }
+        Some(secs) => anyhow::bail!("Need local_retention_secs > 0, but got {secs}"),
+    };
+    let upload_deadline = match config.upload_deadline_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("Need upload_deadline_secs > 0, but got {secs}"),
+    };
+    let upload_journal = config.upload_journal.unwrap_or(false);
+    if upload_journal && gcs_path.is_none() {
+        anyhow::bail!("Can't set upload_journal without gcs_bucket");
+    }
+    let encoder_command = match config.encoder_command.take() {
+        // Each channel gets its own mono recording, even with `split_channels`.
+        Some(command) => Some(encoder::EncoderCommand::new(command, SAMPLE_RATE, 1)?),
+        None => None,
+    };
+    let strip_comments = config.strip_comments.unwrap_or(true);
+    let byte_order = config.byte_order.unwrap_or_default();
+    let raw_audio_args = with_byte_order(RAW_AUDIO_ARGS, byte_order);
+    let encoder_pool_size = config.encoder_pool_size.unwrap_or(0);
+    // Spares only help in the directory they were started in, but segments take turns.
+    if encoder_pool_size > 0 && storage_dirs.len() > 1 {
+        anyhow::bail!("Can't set encoder_pool_size with more than one of storage_dirs");
+    }
+    let secondary_encoding = config.secondary_encoding.take();
+    if let Some(encoding) = &secondary_encoding {
+        let suffix = &encoding.suffix;
+        if suffix.is_empty() || suffix.contains(['/', '.']) {
+            anyhow::bail!(
+                "Need a secondary_encoding suffix without slashes or dots, but got {suffix:?}"
+            );
+        }
+        if encoding.rate == Some(0) {
+            anyhow::bail!("Need a secondary_encoding rate > 0");
+        }
+        info!(
+            "Also encoding each recording as {} ({}, {})",
+            naming::secondary_filename("<id>", encoding),
+            encoding.format.extension(),
+            match encoding.rate {
+                Some(rate) => format!("{rate} Hz"),
+                None => "original rate".to_string(),
+            }
+        );
+    }
+
+    let reap_timeout = match config.encoder_reap_timeout_secs {
+        None => Duration::from_secs(DEFAULT_ENCODER_REAP_TIMEOUT_SECS),
+        Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        Some(secs) => anyhow::bail!("Need encoder_reap_timeout_secs > 0, but got {}", secs),
+    };
+    let upload_concurrency = config
+        .upload_concurrency
+        .unwrap_or(DEFAULT_UPLOAD_CONCURRENCY)
+        .max(1);
+    if ordered_uploads && upload_concurrency > 1 {
+        anyhow::bail!("Can't set upload_concurrency above 1 with ordered_uploads");
+    }
+
+    let rolling_threshold = match config.rolling_threshold_window_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => {
+            let percentile = config
+                .rolling_threshold_percentile
+                .unwrap_or(DEFAULT_ROLLING_THRESHOLD_PERCENTILE);
+            if !(0.0..=100.0).contains(&percentile) {
+                anyhow::bail!(
+                    "Need 0 <= rolling_threshold_percentile <= 100, but got {percentile}"
+                );
+            }
+            Some(seg::RollingThreshold {
+                window_chunks: configured_chunks("rolling_threshold_window_secs", secs).max(1),
+                percentile,
+            })
+        }
+        Some(secs) => anyhow::bail!("Need rolling_threshold_window_secs > 0, but got {secs}"),
+    };
+
+    let adaptive_tail = if config.adaptive_tail.unwrap_or(false) {
+        let min = config.tail_min_secs.unwrap_or(DEFAULT_TAIL_MIN_SECS);
+        let max = config.tail_max_secs.unwrap_or(DEFAULT_TAIL_MAX_SECS);
+        if !(0.0 <= min && min <= max && max.is_finite()) {
+            anyhow::bail!("Need 0 <= tail_min_secs <= tail_max_secs, but got {min} and {max}");
+        }
+        let min_quiet_chunks = configured_chunks("tail_min_secs", min);
+        let max_quiet_chunks = configured_chunks("tail_max_secs", max);
+        if min < max && min_quiet_chunks == max_quiet_chunks {
+            warn!(
+                "tail_min_secs={} and tail_max_secs={} → both {} chunks (chunk is {:.2}s), so the \
+                 tail won't adapt",
+                min,
+                max,
+                min_quiet_chunks,
+                CHUNK_SIZE as f64 / BYTES_PER_SEC as f64
+            );
+        }
+        Some(seg::TailBounds {
+            min_quiet_chunks,
+            max_quiet_chunks,
+        })
+    } else {
+        None
+    };
+
+    let flush_chunks = match config.flush_interval_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => {
+            Some(configured_chunks("flush_interval_secs", secs).max(1))
+        }
+        Some(secs) => anyhow::bail!("Need flush_interval_secs > 0, but got {secs}"),
+    };
+
+    let relative_dip = match config.relative_dip_db {
+        None => None,
+        Some(db) if db > 0.0 && db.is_finite() => {
+            let secs = match config.relative_dip_secs {
+                None => DEFAULT_RELATIVE_DIP_SECS,
+                Some(secs) if secs > 0.0 && secs.is_finite() => secs,
+                Some(secs) => anyhow::bail!("Need relative_dip_secs > 0, but got {secs}"),
+            };
+            Some(seg::RelativeDip {
+                ratio: 10f64.powf(-db / 20.0),
+                min_chunks: configured_chunks("relative_dip_secs", secs).max(1),
+                running_chunks: RELATIVE_DIP_RUNNING_CHUNKS,
+            })
+        }
+        Some(db) => anyhow::bail!("Need relative_dip_db > 0, but got {db}"),
+    };
+
+    let jitter = match config.segment_jitter_percent {
+        None => None,
+        Some(p) if p > 0.0 && p < 100.0 => {
+            let seed = config.segment_jitter_seed.unwrap_or_else(rand::random);
+            info!("Jittering segment thresholds by up to {p}% (seed {seed})");
+            Some(seg::Jitter {
+                fraction: p / 100.0,
+                seed,
+            })
+        }
+        Some(p) => anyhow::bail!("Need 0 < segment_jitter_percent < 100, but got {p}"),
+    };
+
+    let cooldown_secs = config.post_segment_cooldown_secs.unwrap_or(0.0);
+    if !(0.0..=f64::from(u32::MAX)).contains(&cooldown_secs) {
+        anyhow::bail!("Need post_segment_cooldown_secs >= 0, but got {cooldown_secs}");
+    }
+    let cooldown_chunks = configured_chunks("post_segment_cooldown_secs", cooldown_secs);
+
+    let detector = match config.detector.unwrap_or(config::DetectorKind::Peak) {
+        config::DetectorKind::Peak => seg::Detector::Peak,
+        config::DetectorKind::Percentile => {
+            let p = config
+                .detector_percentile
+                .unwrap_or(DEFAULT_DETECTOR_PERCENTILE);
+            if !(0.0..=100.0).contains(&p) {
+                anyhow::bail!("Need 0 <= detector_percentile <= 100, but got {p}");
+            }
+            seg::Detector::Percentile(p)
+        }
+    };
+
+    let max_segments = match config.max_segments {
+        Some(0) => anyhow::bail!("Need max_segments > 0"),
+        n => n,
+    };
+    let mut throttle = match config.max_segments_per_minute {
+        Some(0) => anyhow::bail!("Need max_segments_per_minute > 0"),
+        Some(n) => Some(throttle::Throttle::new(n, THROTTLE_WINDOW)),
+        None => None,
+    };
+    let max_segment_bytes = match config.max_segment_bytes {
+        Some(0) => anyhow::bail!("Need max_segment_bytes > 0"),
+        n => n,
+    };
+    let max_runtime = match config.max_runtime_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("Need max_runtime_secs > 0, but got {secs}"),
+    };
+    let exit_after_idle = match config.exit_after_idle_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("Need exit_after_idle_secs > 0, but got {secs}"),
+    };
+    if exit_after_idle.is_some() && config.ring_buffer_secs.is_some() {
+        anyhow::bail!("Can't set both exit_after_idle_secs and ring_buffer_secs");
+    }
+    let max_silent = match config.max_silent_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("Need max_silent_secs > 0, but got {secs}"),
+    };
+    if max_silent.is_some() && config.ring_buffer_secs.is_some() {
+        anyhow::bail!("Can't set both max_silent_secs and ring_buffer_secs");
+    }
+    let silence_action = config.silence_action.unwrap_or_default();
+    let silence_command = config.silence_command.take();
+    match (silence_action, &silence_command) {
+        (config::SilenceAction::Command, None) => {
+            anyhow::bail!("Need a silence_command with silence_action = \"command\"")
+        }
+        (config::SilenceAction::Command, Some(command)) if command.is_empty() => {
+            anyhow::bail!("Need a non-empty silence_command")
+        }
+        (config::SilenceAction::Command, Some(_)) => {}
+        (_, Some(_)) => {
+            anyhow::bail!("Can't set silence_command without silence_action = \"command\"")
+        }
+        (_, None) => {}
+    }
+
+    // With `split_channels`, each read covers one chunk of each channel.
+    let sample_format = config.sample_format.unwrap_or_default();
+    let stereo = config.split_channels.unwrap_or(false);
+    if stereo && config.ring_buffer_secs.is_some() {
+        anyhow::bail!("Can't set both split_channels and ring_buffer_secs");
+    }
+    if to_stdout && (stereo || config.ring_buffer_secs.is_some()) {
+        anyhow::bail!("Can't pass --stdout with split_channels or ring_buffer_secs");
+    }
+    if stereo {
+        info!("Recording in stereo, segmenting each channel separately");
+    }
+    let (input_args, suffixes) = match (stereo, sample_format) {
+        (false, config::SampleFormat::S16) => (RAW_AUDIO_ARGS, &[""][..]),
+        (false, config::SampleFormat::F32) => (FLOAT_AUDIO_ARGS, &[""][..]),
+        (true, config::SampleFormat::S16) => (STEREO_AUDIO_ARGS, STEREO_SUFFIXES),
+        (true, config::SampleFormat::F32) => (FLOAT_STEREO_AUDIO_ARGS, STEREO_SUFFIXES),
+    };
+    let input_args = with_byte_order(input_args, byte_order);
+    let input_chunk_size = CHUNK_SIZE * suffixes.len();
+    // Float samples are twice as big until they're converted.
+    let input_read_size = input_chunk_size / 2 * sample_format.bytes_per_sample();
+    let detect_filter = config.detect_filter.take();
+    if let Some(effects) = &detect_filter {
+        info!("Detecting audio through filter: {}", effects.join(" "));
+    }
+    let resume_window = match config.resume_window_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some(Duration::from_secs_f64(secs)),
+        Some(secs) => anyhow::bail!("Need resume_window_secs > 0, but got {secs}"),
+    };
+    let health_addr = config.health_addr.take();
+    let ready_within = match config.health_stall_secs {
+        None => Duration::from_secs(DEFAULT_HEALTH_STALL_SECS),
+        Some(secs) if secs > 0.0 && secs.is_finite() => Duration::from_secs_f64(secs),
+        Some(secs) => anyhow::bail!("Need health_stall_secs > 0, but got {secs}"),
+    };
+    let detect_gain = match config.detect_gain {
+        None => None,
+        Some(gain) if gain > 0.0 && gain.is_finite() => {
+            info!("Boosting levels by {gain}x before comparing them to the threshold");
+            Some(gain)
+        }
+        Some(gain) => anyhow::bail!("Need detect_gain > 0, but got {gain}"),
+    };
+    let lead_bytes = match config.lead_pad_ms {
+        None => None,
+        Some(ms) if ms <= MAX_LEAD_PAD_MS => {
+            Some((u64::from(ms) * SAMPLE_RATE / 1000 * 2) as usize)
+        }
+        Some(ms) => anyhow::bail!("Need lead_pad_ms <= {MAX_LEAD_PAD_MS}, but got {ms}"),
+    };
+    if lead_bytes.is_some() && config.ring_buffer_secs.is_some() {
+        anyhow::bail!("Can't set both lead_pad_ms and ring_buffer_secs");
+    }
+    let envelope_frame_samples = if config.envelope_metadata.unwrap_or(false) {
+        ENVELOPE_FRAME_SAMPLES
+    } else {
+        0
+    };
+    let seg_config = seg::Config {
+        chunk_size: CHUNK_SIZE,
+        max_total_chunks: MAX_TOTAL_CHUNKS,
+        min_hot_chunks: MIN_HOT_CHUNKS,
+        max_quiet_chunks: MAX_QUIET_CHUNKS,
+        threshold,
+        byte_order,
+        remove_dc: config.remove_dc.unwrap_or(false),
+        detect_gain,
+        detector,
+        rolling_threshold,
+        adaptive_tail,
+        flush_chunks,
+        relative_dip,
+        jitter,
+        cooldown_chunks,
+        pending_quiet_tolerance: config.pending_quiet_tolerance.unwrap_or(0),
+        envelope_frame_samples,
+        envelope_max_frames: ENVELOPE_MAX_FRAMES,
+        lead_bytes,
+    };
+    let mut channels: Vec<Channel> = suffixes
+        .iter()
+        .map(|&suffix| Channel {
+            suffix,
+            seg: seg::Segmentation::new(seg_config.clone()),
+            active: None,
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            detect_chunk: Vec::with_capacity(CHUNK_SIZE),
+            chunks_since_size_check: 0,
+            last_ended: None,
+            throttled: false,
+            vad: None,
+        })
+        .collect();
+    let vad_fallback = config.vad_fallback.unwrap_or(true);
+    if let Some(command) = &config.vad_command {
+        if command.is_empty() {
+            anyhow::bail!("Need a non-empty vad_command");
+        }
+        if config.ring_buffer_secs.is_some() {
+            anyhow::bail!("Can't set both vad_command and ring_buffer_secs");
+        }
+    }
+    let obfuscate_names = config.obfuscate_names.unwrap_or(false);
+    let id_state_file = config.id_state_file.take();
+    let id_clock = match &id_state_file {
+        None => naming::IdClock::default(),
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(last) => naming::IdClock::after(
+                last.trim()
+                    .parse()
+                    .with_context(|| format!("Failed to parse id_state_file {}", path.display()))?,
+            ),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => naming::IdClock::default(),
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to read id_state_file {}", path.display()))
+            }
+        },
+    };
+    // So that `gen_id` needn't be borrowed mutably, since segmentation also calls it.
+    let id_clock = std::cell::RefCell::new(id_clock);
+    // With `[[source]]` tables, IDs (and so filenames and object names) say which source they're
+    // from, like `20240102T030405-kitchen-left`.
+    let source_suffix = label.map_or(String::new(), |label| format!("-{label}"));
+    let gen_id = move |suffix: &str| {
+        let now = chrono::Utc::now();
+        let mut id_clock = id_clock.borrow_mut();
+        let last = id_clock.last();
+        let secs = id_clock.next(now.timestamp(), suffix);
+        if let (Some(path), true) = (&id_state_file, last != Some(secs)) {
+            if let Err(e) = write_id_state(path, secs) {
+                error!("Failed to write id_state_file {}: {}", path.display(), e);
+            }
+        }
+        let timestamp = chrono::DateTime::from_timestamp(secs, 0)
+            .unwrap_or(now)
+            .format("%Y%m%dT%H%M%S");
+        let suffix = format!("{source_suffix}{suffix}");
+        naming::segment_id(timestamp, &suffix, obfuscate_names.then(rand::random))
+    };
+
+    // In ring buffer mode, audio isn't segmented; it's only saved when triggered by `SIGUSR2`.
+    let mut ring = match config.ring_buffer_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => {
+            info!("Keeping the last {secs} seconds of audio; send SIGUSR2 to save it");
+            Some(ring::RingBuffer::new(
+                (secs * BYTES_PER_SEC as f64) as usize,
+            ))
+        }
+        Some(secs) => anyhow::bail!("Need ring_buffer_secs > 0, but got {secs}"),
+    };
+    if config.monitor.unwrap_or(false) && (ring.is_some() || suffixes.len() > 1) {
+        anyhow::bail!("Can't set monitor with ring_buffer_secs or split_channels");
+    }
+
+    // Silence written to the end of each segment, so that its encoder doesn't end on an abrupt cut.
+    let edge_fade_ms = config.edge_fade_ms.unwrap_or(0);
+    if edge_fade_ms > MAX_EDGE_FADE_MS {
+        anyhow::bail!("Need edge_fade_ms <= {MAX_EDGE_FADE_MS}, but got {edge_fade_ms}");
+    }
+    let edge_padding = vec![0u8; (u64::from(edge_fade_ms) * SAMPLE_RATE / 1000 * 2) as usize];
+    let writer = match config.encoder_write_timeout_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => {
+            Some(encoder::InputWriter::new(Duration::from_secs_f64(secs)))
+        }
+        Some(secs) => anyhow::bail!("Need encoder_write_timeout_secs > 0, but got {secs}"),
+    };
+    let discard_incomplete_on_eof = config.discard_incomplete_on_eof.unwrap_or(false);
+    let min_segment_bytes = match config.min_segment_secs {
+        None => None,
+        Some(secs) if secs > 0.0 && secs.is_finite() => Some((secs * BYTES_PER_SEC as f64) as u64),
+        Some(secs) => anyhow::bail!("Need min_segment_secs > 0, but got {secs}"),
+    };
+    let min_segment_counts_preroll = config.min_segment_counts_preroll.unwrap_or(true);
+
+    // Only now that the whole configuration has been checked does this source start anything (or
+    // sleep), so that a mistake in it can't leave part of the source running.
+
+    // Spread out a fleet of instances that all start at boot, so that they don't all hit GCS at
+    // once.
+    let delay = startup_delay + rand::random::<f64>() * startup_jitter;
+    if delay > 0.0 {
+        info!("Waiting {:.1} seconds before starting", delay);
+        std::thread::sleep(Duration::from_secs_f64(delay));
+    }
+    create_storage_dirs(&storage_dirs)?;
+    let described_dirs: Vec<_> = storage_dirs
+        .iter()
+        .map(|dir| absolute(dir).display().to_string())
+        .collect();
+    info!(
+        "Recording to {} with threshold {:.4}; uploads to GCS {}",
+        described_dirs.join(", "),
+        f64::from(threshold) / f64::from(i16::MAX),
+        match &config.gcs_bucket {
+            Some(bucket) => format!("enabled ({bucket})"),
+            None => "disabled".to_string(),
+        }
+    );
+    if let Some(dir) = &fallback_dir {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Failed to create fallback directory {}", dir.display()))?;
+        info!(
+            "Will record to {} if the storage directory becomes unavailable",
+            absolute(dir).display()
+        );
+    }
+    let provenance = Provenance::detect(source.device.as_deref());
+    info!(
+        "Recording from input device {:?} with {}",
+        provenance.input_device,
+        provenance
+            .sox_version
+            .as_deref()
+            .unwrap_or("unknown SoX version")
+    );
+    if config.provenance_metadata.unwrap_or(false) {
+        if let Some(v) = &provenance.sox_version {
+            common_metadata.insert("sox-version".to_string(), v.clone().into());
+        }
+        common_metadata.insert("input-device".to_string(), provenance.input_device.into());
+    }
+    let gcs = match &gcs_path {
+        None => None,
+        Some(path) => connect_gcs_checked(rt, &http, path, &gcs_options, &config)?,
+    };
+    let index = match &config.sqlite_db {
+        Some(path) => Some(index::Index::open(path)?),
+        None => None,
+    };
+    let journal = match upload_journal {
+        false => None,
+        true => {
+            let roots: Vec<_> = storage_dirs
+                .iter()
+                .cloned()
+                .chain(fallback_dir.clone())
+                .collect();
+            let path = storage_dir.join(journal::FILENAME);
+            Some(rt.block_on(journal::Journal::open(path, &roots))?)
+        }
+    };
+    let uploader = gcs_path.map(|path| {
+        let capacity = config.upload_queue_len.unwrap_or(DEFAULT_UPLOAD_QUEUE_LEN);
+        let roots = storage_dirs
+            .iter()
+            .cloned()
+            .chain(fallback_dir.clone())
+            .collect();
+        let recovery_delay = Duration::from_secs_f64(rand::random::<f64>() * startup_jitter);
+        let mut uploader = upload::Uploader::new(
+            path,
+            roots,
+            capacity,
+            recovery_delay,
+            latest_alias,
+            max_upload_memory_bytes,
+            ordered_uploads,
+        );
+        uploader.object_key = object_key;
+        uploader.content_hash = config.content_hash;
+        uploader.metadata_keys = metadata_keys;
+        uploader.deadline = upload_deadline;
+        uploader.journal = journal;
+        if let Some(client) = gcs {
+            uploader.set_client(client);
+        }
+        uploader
+    });
+    for dir in storage_dirs.iter().chain(&fallback_dir) {
+        if let Err(e) = encoder::remove_stale_spares(dir) {
+            warn!(
+                "Failed to clean up spare encoders in {}: {}",
+                dir.display(),
+                e
+            );
+        }
+    }
+    let mut level_log = match &config.level_log {
+        Some(path) => {
+            info!("Logging the level of every chunk to {}", path.display());
+            Some(level_log::LevelLog::open(
+                path,
+                seg_config.remove_dc,
+                byte_order,
+            )?)
+        }
+        None => None,
+    };
+    let health_listener = match &health_addr {
+        None => None,
+        Some(addr) => {
+            let listener = rt
+                .block_on(tokio::net::TcpListener::bind(addr))
+                .with_context(|| format!("Failed to listen on health_addr {addr}"))?;
+            info!("Answering health checks on {addr}");
+            Some(listener)
+        }
+    };
+    let sigusr2 = if ring.is_some() {
+        use tokio::signal::unix::{signal, SignalKind};
+        let sigusr2 = rt
+            .block_on(async { signal(SignalKind::user_defined2()) })
+            .context("Failed to listen for SIGUSR2")?;
+        Some(sigusr2)
+    } else {
+        None
+    };
+    if let Some(command) = config.vad_command.take() {
+        info!("Detecting speech with: {}", command.join(" "));
+        for ch in &mut channels {
+            ch.vad = Some(vad::Vad::spawn(&command, VAD_TIMEOUT)?);
+        }
+    }
+    let mut monitor = if config.monitor.unwrap_or(false) {
+        info!("Playing recorded audio through play(1) to monitor it");
+        Some(monitor::Monitor::spawn(raw_audio_args)?)
+    } else {
+        None
+    };
+    let encoder_pool = match encoder_pool_size {
+        0 => None,
+        size => Some(encoder::EncoderPool::new(
+            size,
+            &storage_dir,
+            raw_audio_args,
+            encoder_command.clone(),
+            strip_comments,
+            permissions.clone(),
+        )),
+    };
+    let (mut sp_rec, mut input) = start_input(
+        &source,
+        input_args,
+        detect_filter.as_deref(),
+        input_read_size,
+    )?;
+    let shared = Arc::new(Shared {
+        storage: storage::Storage::new(storage_dirs, fallback_dir),
+        permissions,
+        encoder_pool,
+        encoder_command,
+        strip_comments,
+        raw_audio_args,
+        uploader,
+        manifest,
+        index,
+        fifo,
+        asr,
+        hook,
+        sensor,
+        background: tasks::Tasks::new(),
+        counters,
+        finalize_permits: tokio::sync::Semaphore::new(
+            config
+                .finalize_concurrency
+                .unwrap_or(DEFAULT_FINALIZE_CONCURRENCY)
+                .max(1),
+        ),
+        finish_order: ordered_uploads.then(order::FinishOrder::new),
+        loudness_classes,
+        common_metadata,
+        soxi: soxi::Soxi::new(),
+        require_metadata: config.require_metadata.unwrap_or(false),
+        min_samples: config.min_samples.unwrap_or(DEFAULT_MIN_SAMPLES),
+        verify_before_upload,
+        secondary_encoding,
+        local_layout: config.local_layout.unwrap_or_default(),
+        reap_timeout,
+        stream_runtime: stream_to_gcs.then(|| rt.handle().clone()),
+    });
+    rt.spawn(start_uploads(
+        shared.clone(),
+        http,
+        gcs_options,
+        upload_concurrency,
+    ));
+    rt.spawn({
+        let shared = shared.clone();
+        async move { shared.storage.watch().await }
+    });
+    rt.spawn({
+        let shared = shared.clone();
+        async move {
+            let uploader = shared.uploader.as_ref();
+            if let Some(journal) = uploader.and_then(|u| u.journal.as_ref()) {
+                journal.write(&shared.storage).await;
+            }
+        }
+    });
+    if let Some((retention, roots)) = local_retention {
+        info!(
+            "Deleting uploaded recordings after {:.0} seconds",
+            retention.as_secs_f64()
+        );
+        rt.spawn(prune::run(roots, retention));
+    }
+    let health = health_listener.map(|listener| {
+        // The main loop only waits that long for input, or longer while restarting `rec(1)`.
+        let live_within = ready_within + resume_window.unwrap_or_default();
+        let health = Arc::new(health::Health::new(
+            Instant::now(),
+            live_within,
+            ready_within,
+        ));
+        rt.spawn(health::serve(listener, health.clone(), shared.clone()));
+        health
+    });
+    let triggered = Arc::new(AtomicBool::new(false));
+    if let Some(mut sigusr2) = sigusr2 {
+        let triggered = triggered.clone();
+        rt.spawn(async move {
+            while sigusr2.recv().await.is_some() {
+                triggered.store(true, Ordering::SeqCst);
+            }
+        });
+    }
+    // When `rec(1)` first exited, if it's being restarted.
+    let mut rec_down_since: Option<Instant> = None;
+    // Chunks read since `rec(1)` last started, up to `EDGE_CHUNKS`.
+    let mut input_chunks: u32 = 0;
+    let mut chunk: Vec<u8> = Vec::with_capacity(input_read_size);
+    let mut detect_chunk: Vec<u8> = Vec::with_capacity(input_read_size);
+    let started = Instant::now();
+    // When the audio last wasn't quiet, or a segment was last in progress, per
+    // `exit_after_idle_secs`.
+    let mut active_at = started;
+    // When a segment last started or was in progress, per `max_silent_secs`.
+    let mut sound_at = started;
+    // Whether we're stopping for `max_silent_secs`, which is a failure.
+    let mut stopped_silent = false;
+    let mut segments_finished: u64 = 0;
+    // With `--stdout`, segments' audio goes there instead of to encoders, until it's closed.
+    let mut stdout = to_stdout.then(|| io::stdout().lock());
+    let mut stdout_closed = false;
+    if to_stdout {
+        info!("Writing segments to stdout instead of recording them");
+    }
+    // Tasks finishing segments, which must complete before exiting.
+    let mut finishing: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+
+    loop {
+        let silent = max_silent.filter(|&t| sound_at.elapsed() >= t);
+        if let Some(t) = silent {
+            error!(
+                "Nothing recorded for {:?}, per max_silent_secs; is the microphone working?",
+                t
+            );
+        }
+        if let (Some(_), Some(command)) = (silent, &silence_command) {
+            rt.spawn(run_silence_command(command.clone()));
+        }
+        if silent.is_some() {
+            // Check again after another `max_silent_secs`, if still going.
+            sound_at = Instant::now();
+        }
+        let limit_reached = if max_segments.is_some_and(|n| segments_finished >= n) {
+            info!("Recorded {} segment(s); stopping", segments_finished);
+            true
+        } else if max_runtime.is_some_and(|t| started.elapsed() >= t) {
+            info!("Ran for {:?}; stopping", started.elapsed());
+            true
+        } else if exit_after_idle.is_some_and(|t| active_at.elapsed() >= t) {
+            info!("Idle for {:?}; stopping", active_at.elapsed());
+            true
+        } else if stdout_closed {
+            info!("Stdout was closed; stopping");
+            true
+        } else if silent.is_some() && silence_action == config::SilenceAction::Exit {
+            stopped_silent = true;
+            true
+        } else {
+            false
+        };
+        if limit_reached {
+            for ch in &mut channels {
+                if let (Some(mut current), Some(stats)) =
+                    (ch.active.take(), ch.seg.finish(seg::EndReason::Stopped))
+                {
+                    close_encoder(&mut current, &edge_padding, writer.as_ref());
+                    finishing.push(rt.spawn(finish_segment(
+                        current,
+                        stats,
+                        Instant::now(),
+                        shared.clone(),
+                    )));
+                }
+            }
+            let _ = sp_rec.kill();
+            let _ = sp_rec.wait();
+            break;
+        }
+        finishing.retain(|task| !task.is_finished());
+        if let Some(health) = &health {
+            health.looped(Instant::now());
+        }
+
+        input.read(input_read_size, &mut chunk, &mut detect_chunk)?;
+        if let (Some(health), false) = (&health, chunk.is_empty()) {
+            health.read_chunk(Instant::now());
+        }
+        if sample_format == config::SampleFormat::F32 {
+            input::f32_to_s16(&mut chunk, byte_order);
+            input::f32_to_s16(&mut detect_chunk, byte_order);
+        }
+        input_chunks = input_chunks.saturating_add(1);
+        // If `rec(1)` exits (e.g., after a USB glitch), keep the segments going, with their
+        // encoders, while trying to start it again. Only if that takes too long is this EOF.
+        match (chunk.is_empty(), resume_window) {
+            (true, Some(window)) => {
+                let status = sp_rec.wait();
+                let down_since = *rec_down_since.get_or_insert_with(|| {
+                    warn!(
+                        "rec(1) exited ({}); restarting it for up to {:?}",
+                        status.map_or_else(|e| e.to_string(), |st| st.to_string()),
+                        window
+                    );
+                    Instant::now()
+                });
+                let deadline = down_since + window;
+                if let Some((rec, resumed)) = restart_input(
+                    deadline,
+                    &source,
+                    input_args,
+                    detect_filter.as_deref(),
+                    input_read_size,
+                ) {
+                    (sp_rec, input) = (rec, resumed);
+                    input_chunks = 0;
+                    continue;
+                }
+                warn!("rec(1) didn't come back within {:?}; stopping", window);
+            }
+            (false, _) if rec_down_since.take().is_some() => {
+                info!("rec(1) is back; resuming recording");
+            }
+            _ => {}
+        }
+        if let Some(ring) = &mut ring {
+            ring.push(&chunk);
+            if triggered.swap(false, Ordering::SeqCst) && !ring.is_empty() {
+                let buffered_ms = ring.len() as u64 * 1000 / BYTES_PER_SEC;
+                info!("Saving {} ms of buffered audio", buffered_ms);
+                let audio = ring.take();
+                let mut saved = start_segment(gen_id(""), shared.storage.next_dir(), &shared)?;
+                saved.started_at -= chrono::Duration::milliseconds(buffered_ms as i64);
+                let stats = seg::Stats::measure(
+                    &audio,
+                    byte_order,
+                    seg::EndReason::Triggered,
+                    seg::Envelope::new(envelope_frame_samples, ENVELOPE_MAX_FRAMES),
+                );
+                let shared = shared.clone();
+                let ended = Instant::now();
+                let edge_padding = edge_padding.clone();
+                // Encoding the whole buffer takes a while, so do it off the main loop, which
+                // needs to keep up with `rec(1)`.
+                segments_finished += 1;
+                finishing.push(rt.spawn(async move {
+                    let saved = tokio::task::spawn_blocking(move || {
+                        let mut stdin = saved.encoder.stdin.take().unwrap();
+                        if let Err(e) = stdin.write_all(&audio) {
+                            error!("Failed to write buffered audio to encoder: {}", e);
+                        }
+                        saved.encoder.stdin = Some(stdin);
+                        close_encoder(&mut saved, &edge_padding, None);
+                        saved
+                    })
+                    .await
+                    .unwrap();
+                    finish_segment(saved, stats, ended, shared).await;
+                }));
+            }
+            if chunk.is_empty() {
+                break;
+            }
+            continue;
+        }
+        // Each channel gets every `channels.len()`th sample. With only one, that's a plain copy.
+        for (i, ch) in channels.iter_mut().enumerate() {
+            input::deinterleave(&chunk, suffixes.len(), i, &mut ch.chunk);
+            input::deinterleave(&detect_chunk, suffixes.len(), i, &mut ch.detect_chunk);
+        }
+        for (i, ch) in channels.iter_mut().enumerate() {
+            let suffix = ch.suffix;
+            let hot = match &mut ch.vad {
+                Some(vad) if !ch.detect_chunk.is_empty() => match vad.judge(&ch.detect_chunk) {
+                    Ok(hot) => Some(hot),
+                    Err(e) if vad_fallback => {
+                        error!("{:#}; using the threshold from now on", e);
+                        ch.vad = None;
+                        None
+                    }
+                    Err(e) => return Err(e),
+                },
+                _ => None,
+            };
+            for ev in ch
+                .seg
+                .accept_judged(&ch.chunk, &ch.detect_chunk, hot, || gen_id(suffix))
+            {
+                // Any event, including data, means that a segment is starting or in progress.
+                sound_at = Instant::now();
+                if let Some(out) = &mut stdout {
+                    match ev {
+                        seg::Event::Start { .. }
+                            if stdout_closed
+                                || max_segments.is_some_and(|n| segments_finished >= n) =>
+                        {
+                            break;
+                        }
+                        seg::Event::Start { id } => info!("Writing segment {} to stdout", id),
+                        seg::Event::Data(data) => {
+                            if let Some(monitor) = &mut monitor {
+                                monitor.send(data);
+                            }
+                            match out.write_all(data).and_then(|()| out.flush()) {
+                                Ok(()) => {}
+                                // Like when the reading end of a pipeline has exited.
+                                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                                    stdout_closed = true;
+                                    break;
+                                }
+                                Err(e) => return Err(e).context("Failed to write to stdout"),
+                            }
+                        }
+                        seg::Event::End(stats) => {
+                            segments_finished += 1;
+                            info!(
+                                "Wrote {:.3} s to stdout ({})",
+                                stats.bytes as f64 / BYTES_PER_SEC as f64,
+                                stats.end_reason.as_str()
+                            );
+                        }
+                    }
+                    continue;
+                }
+                match ev {
+                    // Don't start a segment (e.g., rolling over from the last one) only to stop
+                    // it.
+                    seg::Event::Start { .. }
+                        if max_segments.is_some_and(|n| segments_finished >= n) =>
+                    {
+                        break;
+                    }
+                    seg::Event::Start { id }
+                        if throttle
+                            .as_mut()
+                            .is_some_and(|t| !t.try_start(Instant::now())) =>
+                    {
+                        if throttle.as_ref().is_some_and(|t| t.skipped == 1) {
+                            warn!(
+                                "Started too many segments in the last minute, per \
+                                 max_segments_per_minute; skipping segment {} and any more \
+                                 until the rate drops",
+                                id
+                            );
+                        } else {
+                            debug!("Skipping segment {} per max_segments_per_minute", id);
+                        }
+                        ch.throttled = true;
+                    }
+                    // The segmenter never emits events out of order, but if it ever does,
+                    // recover as best we can rather than losing everything.
+                    seg::Event::Start { id } => {
+                        if let Some(mut stray) = ch.active.take() {
+                            debug_assert!(false, "Got Event::Start with active segment");
+                            error!(
+                                "Got Event::Start with segment {} still active; finishing it",
+                                stray.id
+                            );
+                            close_encoder(&mut stray, &edge_padding, writer.as_ref());
+                            let stats = seg::Stats {
+                                end_reason: seg::EndReason::Interrupted,
+                                ..Default::default()
+                            };
+                            finishing.push(rt.spawn(finish_segment(
+                                stray,
+                                stats,
+                                Instant::now(),
+                                shared.clone(),
+                            )));
+                        }
+                        if let Some(t) = throttle.as_mut().filter(|t| t.skipped > 0) {
+                            info!(
+                                "Starting segments again, after skipping {} per \
+                                 max_segments_per_minute",
+                                std::mem::take(&mut t.skipped)
+                            );
+                        }
+                        ch.throttled = false;
+                        let mut segment = start_segment(id, shared.storage.next_dir(), &shared)?;
+                        segment.edge = input_chunks <= EDGE_CHUNKS;
+                        segment.gap = ch.last_ended.map(|ended| ended.elapsed());
+                        ch.active = Some(segment);
+                    }
+                    seg::Event::Data(_) if ch.throttled => {}
+                    seg::Event::Data(data) => {
+                        if ch.active.is_none() {
+                            debug_assert!(false, "Got Event::Data with no active segment");
+                            error!("Got Event::Data with no active segment; starting one");
+                            let dir = shared.storage.next_dir();
+                            ch.active = Some(start_segment(gen_id(suffix), dir, &shared)?);
+                        }
+                        if let Some(monitor) = &mut monitor {
+                            monitor.send(data);
+                        }
+                        let current = ch.active.as_mut().unwrap();
+                        // An encoder whose input has failed is skipped for the rest of its
+                        // segment.
+                        if current.encoder.stdin.is_none() {
+                            continue;
+                        }
+                        let Err(e) = encoder::write(&mut current.encoder, data, writer.as_ref())
+                        else {
+                            continue;
+                        };
+                        // The Rust runtime ignores `SIGPIPE`, so an encoder that has exited shows
+                        // up here as `EPIPE` rather than killing us. Stop feeding it, but keep
+                        // recording.
+                        error!(
+                            "Failed to write chunk to encoder for segment {}: {}",
+                            current.id, e
+                        );
+                        current.encoder.stdin.take();
+                        let dir = if e.kind() == io::ErrorKind::TimedOut {
+                            // The encoder stopped reading its input, so it's given up on. Finish
+                            // what it managed to write, killing it if it doesn't exit in time, and
+                            // continue the recording in a new segment with a new encoder.
+                            shared.storage.next_dir()
+                        } else {
+                            // If the encoder died because its storage went away, likewise, but on
+                            // the fallback.
+                            let Some(fallback) = shared.storage.fallback_dir() else {
+                                continue;
+                            };
+                            if current.part_filename.starts_with(fallback) {
+                                continue;
+                            }
+                            shared.storage.mark_failed();
+                            fallback
+                        };
+                        let broken = ch.active.take().unwrap();
+                        warn!("Continuing segment {} in {}", broken.id, dir.display());
+                        let stats = seg::Stats {
+                            end_reason: seg::EndReason::Interrupted,
+                            ..Default::default()
+                        };
+                        finishing.push(rt.spawn(finish_segment(
+                            broken,
+                            stats,
+                            Instant::now(),
+                            shared.clone(),
+                        )));
+                        let mut continuation = start_segment(gen_id(suffix), dir, &shared)?;
+                        continuation.gap = Some(Duration::ZERO);
+                        if let Err(e) =
+                            encoder::write(&mut continuation.encoder, data, writer.as_ref())
+                        {
+                            error!("Failed to write chunk to encoder: {}", e);
+                        }
+                        ch.active = Some(continuation);
+                    }
+                    seg::Event::End(_) if ch.throttled => ch.throttled = false,
+                    seg::Event::End(stats) => {
+                        let Some(mut active) = ch.active.take() else {
+                            debug_assert!(false, "Got Event::End with no active segment");
+                            error!("Got Event::End with no active segment; ignoring it");
+                            continue;
+                        };
+                        ch.last_ended = Some(Instant::now());
+                        close_encoder(&mut active, &edge_padding, writer.as_ref());
+                        if stats.incomplete && discard_incomplete_on_eof {
+                            info!(
+                                "Input ended before segment {} had enough loud audio; \
+                                 discarding it",
+                                active.id
+                            );
+                            finishing.push(rt.spawn(discard_segment(active, shared.clone())));
+                            continue;
+                        }
+                        let len = if min_segment_counts_preroll {
+                            stats.bytes
+                        } else {
+                            stats.active_bytes
+                        };
+                        if min_segment_bytes.is_some_and(|min| len < min) {
+                            info!(
+                                "Segment {} is only {:.3} s long; discarding it",
+                                active.id,
+                                len as f64 / BYTES_PER_SEC as f64
+                            );
+                            finishing.push(rt.spawn(discard_segment(active, shared.clone())));
+                            continue;
+                        }
+                        segments_finished += 1;
+                        finishing.push(rt.spawn(finish_segment(
+                            active,
+                            stats,
+                            Instant::now(),
+                            shared.clone(),
+                        )));
+                    }
+                }
+            }
+            // The encoder's output lags its input by what's buffered in the pipe and in `sox(1)`,
+            // so this is only checked so often, and a file can end up a bit over the limit.
+            if let (Some(max_bytes), Some(current)) = (max_segment_bytes, &ch.active) {
+                ch.chunks_since_size_check += 1;
+                if ch.chunks_since_size_check >= SIZE_CHECK_CHUNKS {
+                    ch.chunks_since_size_check = 0;
+                    let size = std::fs::metadata(&current.part_filename).map_or(0, |m| m.len());
+                    if size >= max_bytes {
+                        if let Some(stats) = ch.seg.roll_over(seg::EndReason::MaxSize) {
+                            debug!(
+                                "Segment {} reached {} bytes; rolling over to new segment",
+                                current.id, size
+                            );
+                            let mut full = ch.active.take().unwrap();
+                            ch.last_ended = Some(Instant::now());
+                            close_encoder(&mut full, &edge_padding, writer.as_ref());
+                            segments_finished += 1;
+                            finishing.push(rt.spawn(finish_segment(
+                                full,
+                                stats,
+                                Instant::now(),
+                                shared.clone(),
+                            )));
+                        }
+                    }
+                }
+            } else {
+                ch.chunks_since_size_check = 0;
+            }
+            let (level, threshold) = ch.seg.last_level();
+            if ch.active.is_some() || level > threshold {
+                active_at = Instant::now();
+            }
+            if let (Some(log), false) = (&mut level_log, ch.chunk.is_empty()) {
+                if let Err(e) = log.record(i, &ch.detect_chunk, level, threshold) {
+                    error!(
+                        "Failed to write to level_log; no longer logging levels: {}",
+                        e
+                    );
+                    level_log = None;
+                }
+            }
+        }
+        if chunk.is_empty() {
+            break;
+        }
+    }
+
+    // Let in-flight segments finish, and their uploads go out, before the runtime shuts down.
+    rt.block_on(async {
+        for task in finishing {
+            let _ = task.await;
+        }
+        if let Some(uploader) = &shared.uploader {
+            uploader.idle().await;
+        }
+        shared.background.idle().await;
+        if let Some(journal) = shared.uploader.as_ref().and_then(|u| u.journal.as_ref()) {
+            journal.flushed().await;
+        }
+    });
+
+    if print_summary {
+        println!("{}", shared.counters.summary(started.elapsed()));
+    }
+    if stopped_silent {
+        anyhow::bail!("Stopped after recording nothing for max_silent_secs");
+    }
+    Ok(())
+}
+
+/// Runs `silence_command`, for `max_silent_secs`, logging if it fails.
+async fn run_silence_command(command: Vec<String>) {
+    match tokio::process::Command::new(&command[0])
+        .args(&command[1..])
+        .status()
+        .await
+    {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("silence_command exited with {}", status),
+        Err(e) => error!("Failed to run silence_command {}: {}", command[0], e),
+    }
+}
+
+/// Environment variables from which to read a log filter, like `info` or `reccon=debug,warn`, in
+/// order of precedence.
+const LOG_ENV_VARS: &[&str] = &["RECCON_LOG", "RUST_LOG"];
+
+fn init_logging() {
+    use env_logger::Builder;
+    use systemd_journal_logger::{connected_to_journal, JournalLog};
+
+    let spec = LOG_ENV_VARS
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|v| !v.is_empty()));
+    if connected_to_journal() {
+        let filter = env_logger::filter::Builder::new()
+            .parse(spec.as_deref().unwrap_or("debug"))
+            .build();
+        log::set_max_level(filter.filter());
+        let logger = FilteredLog {
+            filter,
+            inner: JournalLog::default(),
+        };
+        log::set_boxed_logger(Box::new(logger)).unwrap();
+    } else {
+        let mut builder = Builder::new();
+        builder.parse_filters(spec.as_deref().unwrap_or("info"));
+        // As `env_logger` does by default, let `RUST_LOG_STYLE` choose whether to color output.
+        if let Ok(style) = std::env::var("RUST_LOG_STYLE") {
+            builder.parse_write_style(&style);
+        }
+        builder.init();
+    }
+}
+
+/// Forwards to another logger only the records that pass an `env_logger`-style filter.
+struct FilteredLog<L> {
+    filter: env_logger::filter::Filter,
+    inner: L,
+}
+
+impl<L: log::Log> log::Log for FilteredLog<L> {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.filter.enabled(metadata) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.filter.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_misconfigured_source_starts_nothing() {
+        let tmp = tempfile::tempdir().unwrap();
+        // A port to ask for health checks on, which is free again once this is dropped.
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let base = config::Config {
+            storage_dir: Some(tmp.path().to_path_buf()),
+            ..config::Config::default()
+        };
+        let source = config::Source {
+            label: "kitchen".to_string(),
+            config: config::Config {
+                health_addr: Some(addr.to_string()),
+                health_stall_secs: Some(-1.0),
+                // Waited out before starting anything, so this would hang if it got that far.
+                startup_delay_secs: Some(3600.0),
+                ..config::Config::default()
+            },
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let options = RunOptions {
+            label: Some(source.label.clone()),
+            backfill_dir: None,
+            to_stdout: false,
+            print_summary: false,
+            counters: Arc::default(),
+        };
+        let err = run(source.config(&base), &rt, options).unwrap_err();
+        assert!(err.to_string().contains("health_stall_secs"), "{err:#}");
+        // Neither its storage directory nor its health checks were set up.
+        assert!(!tmp.path().join("kitchen").exists());
+        std::net::TcpListener::bind(addr).unwrap();
+    }
+
+    #[test]
+    fn test_sources_share_latest_alias() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = config::Config {
+            storage_dir: Some(tmp.path().to_path_buf()),
+            gcs_bucket: Some("gs://my-bucket/rooms/".to_string()),
+            latest_alias: Some("latest.flac".to_string()),
+            ..config::Config::default()
+        };
+        let source = |label: &str, gcs_bucket: Option<&str>| config::Source {
+            label: label.to_string(),
+            config: config::Config {
+                gcs_bucket: gcs_bucket.map(str::to_string),
+                ..config::Config::default()
+            },
+        };
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let sources = vec![
+            source("kitchen", None),
+            source("porch", Some("gs://my-bucket/porch/")),
+            // The same prefix as the top level's, but spelled differently.
+            source("garage", Some("gs://my-bucket//rooms/")),
+        ];
+        let err = run_sources(base, sources, &rt, false, Arc::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Can't set latest_alias to gs://my-bucket/rooms/latest.flac for both [[source]] \
+             \"kitchen\" and \"garage\""
+        );
     }
 }
@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::ManifestMode;
+
+/// Writes a local record of each finished segment's metadata.
+pub struct Manifest {
+    mode: ManifestMode,
+    dir: PathBuf,
+    /// Serializes appends so that concurrent finishers don't interleave lines.
+    lock: tokio::sync::Mutex<()>,
+}
+
+impl Manifest {
+    pub fn new(mode: ManifestMode, dir: PathBuf) -> Self {
+        Self {
+            mode,
+            dir,
+            lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Records the metadata for the segment `id`, whose recording lives (or will live) at `file`.
+    pub async fn record(
+        &self,
+        id: &str,
+        file: &Path,
+        metadata: &serde_json::Map<String, serde_json::Value>,
+    ) -> anyhow::Result<()> {
+        let entry = serde_json::json!({
+            "id": id,
+            "file": file.file_name().map(|f| f.to_string_lossy()),
+            "metadata": metadata,
+        });
+        let mut line = serde_json::to_string(&entry).context("Failed to serialize manifest")?;
+        line.push('\n');
+        match self.mode {
+            ManifestMode::Sidecar => {
+                let path = file.with_extension("json");
+                tokio::fs::write(&path, line)
+                    .await
+                    .with_context(|| format!("Failed to write sidecar {}", path.display()))
+            }
+            ManifestMode::Jsonl => {
+                // Name the file by the current UTC date, so that the manifest rolls over daily.
+                let name = chrono::Utc::now()
+                    .format("manifest-%Y%m%d.jsonl")
+                    .to_string();
+                let path = self.dir.join(name);
+                let _guard = self.lock.lock().await;
+                append_line(&path, line.as_bytes())
+                    .await
+                    .with_context(|| format!("Failed to append to manifest {}", path.display()))
+            }
+        }
+    }
+}
+
+/// Appends a complete line to the file at `path` and flushes it to disk, so that a crash leaves
+/// at worst a missing line rather than a torn one.
+async fn append_line(path: &Path, line: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    file.write_all(line).await?;
+    file.sync_data().await
+}
@@ -0,0 +1,59 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, TrySendError};
+
+use anyhow::Context;
+use log::{debug, warn};
+
+/// Number of chunks that may wait to be played before more are dropped.
+const MONITOR_BACKLOG: usize = 8;
+
+/// Plays the audio being recorded through `play(1)`, to check by ear that the right source is
+/// being captured.
+///
+/// Only audio that goes into a segment is played, so it's silent between segments. Recording never
+/// waits on playback: each chunk is handed to a thread that writes it to `play(1)`, and a chunk
+/// that doesn't fit in that thread's backlog is dropped. If `play(1)` fails or exits, monitoring
+/// stops for the rest of the run, but recording goes on.
+pub struct Monitor {
+    tx: Option<mpsc::SyncSender<Vec<u8>>>,
+}
+
+impl Monitor {
+    /// Starts `play(1)` on the default output device (or `$AUDIODEV`), reading raw audio in the
+    /// format given by `raw_audio_args`.
+    pub fn spawn(raw_audio_args: &[&str]) -> anyhow::Result<Self> {
+        let mut play = Command::new("play")
+            .args(["-q", "-V1"])
+            .args(raw_audio_args)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Failed to spawn play(1) for monitor")?;
+        let mut stdin = play.stdin.take().unwrap();
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(MONITOR_BACKLOG);
+        std::thread::spawn(move || {
+            for chunk in rx {
+                if let Err(e) = stdin.write_all(&chunk) {
+                    warn!("Failed to write to monitor; no longer monitoring: {}", e);
+                    break;
+                }
+            }
+            drop(stdin);
+            let _ = play.wait();
+        });
+        Ok(Self { tx: Some(tx) })
+    }
+
+    /// Queues `data` to be played, or drops it if playback isn't keeping up.
+    pub fn send(&mut self, data: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        match tx.try_send(data.to_vec()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => debug!("Monitor isn't keeping up; dropping chunk"),
+            Err(TrySendError::Disconnected(_)) => self.tx = None,
+        }
+    }
+}
@@ -0,0 +1,547 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::{AudioFormat, LoudnessClass, SecondaryEncoding};
+
+/// Suffix for a recording that is still being written by its encoder.
+pub const PART_SUFFIX: &str = ".part";
+/// Suffix for a recording that has finished but not been uploaded to GCS.
+pub const LOCAL_SUFFIX: &str = ".local";
+/// Suffix for a recording that was set aside instead of uploaded, for having too few samples.
+pub const REJECTED_SUFFIX: &str = ".rejected";
+
+/// Returns the ID for a segment that started at `timestamp` (like `20240102T030405`), on the
+/// channel with `suffix`. A random `token`, with `obfuscate_names`, goes at the end, so that IDs
+/// still sort by when they started but can't be guessed from it.
+pub fn segment_id(timestamp: impl std::fmt::Display, suffix: &str, token: Option<u128>) -> String {
+    match token {
+        Some(token) => format!("{timestamp}{suffix}-{token:032x}"),
+        None => format!("{timestamp}{suffix}"),
+    }
+}
+
+/// Issues the timestamps that segment IDs start with, so that no two segments on a channel get
+/// the same ID, even if the clock is set back.
+///
+/// Timestamps are whole seconds (Unix time), and normally just the time the segment started. But
+/// a timestamp that a channel has already had (or one before it) is never issued again: if the
+/// clock has gone backward, or two segments start within a second, the next one is a second after
+/// the last issued instead. So each channel's IDs strictly increase, and so still sort by when the
+/// segments started, though timestamps can run ahead of the clock until it catches up.
+#[derive(Debug, Default)]
+pub struct IdClock {
+    /// The latest timestamp issued, if any.
+    last: Option<i64>,
+    /// The suffixes of the channels that have had `last` already.
+    suffixes: Vec<String>,
+}
+
+impl IdClock {
+    /// Resumes after `last`, like from `id_state_file`, so as not to reuse any timestamp up to it.
+    pub fn after(last: i64) -> Self {
+        IdClock {
+            last: Some(last.saturating_add(1)),
+            suffixes: Vec::new(),
+        }
+    }
+
+    /// The latest timestamp issued, if any.
+    pub fn last(&self) -> Option<i64> {
+        self.last
+    }
+
+    /// Returns the timestamp for a segment starting at `now` on the channel with `suffix`.
+    pub fn next(&mut self, now: i64, suffix: &str) -> i64 {
+        match self.last {
+            Some(last) if now <= last => {
+                if self.suffixes.iter().any(|s| s == suffix) {
+                    self.last = Some(last + 1);
+                    self.suffixes.clear();
+                }
+            }
+            _ => {
+                self.last = Some(now);
+                self.suffixes.clear();
+            }
+        }
+        self.suffixes.push(suffix.to_string());
+        self.last.unwrap()
+    }
+}
+
+/// Returns the filename of the finished recording for segment `id`, encoded in `format`.
+pub fn recording_filename(id: &str, format: AudioFormat) -> String {
+    format!("recording-{}.{}", id, format.extension())
+}
+
+/// Returns the filename of the secondary encoding of segment `id`.
+pub fn secondary_filename(id: &str, encoding: &SecondaryEncoding) -> String {
+    recording_filename(&format!("{}.{}", id, encoding.suffix), encoding.format)
+}
+
+/// Returns the filename of the placeholder written by the `n`th spare encoder.
+pub fn spare_filename(n: u64) -> String {
+    format!("spare-{}.flac{}", n, PART_SUFFIX)
+}
+
+/// Whether `name` is the filename of a spare encoder's placeholder.
+pub fn is_spare_filename(name: &str) -> bool {
+    name.strip_prefix("spare-")
+        .and_then(|rest| rest.strip_suffix(PART_SUFFIX))
+        .and_then(|rest| rest.strip_suffix(".flac"))
+        .is_some_and(|n| n.parse::<u64>().is_ok())
+}
+
+/// Returns the object name for segment `id`, encoded in `format`, relative to the GCS prefix.
+///
+/// This has the same extension as the segment's [`recording_filename`], so that the object name
+/// can be recovered from the filename after a restart.
+pub fn object_name(class: Option<&str>, id: &str, format: AudioFormat) -> String {
+    match class {
+        Some(class) => format!("{}/{}.{}", class, id, format.extension()),
+        None => format!("{}.{}", id, format.extension()),
+    }
+}
+
+/// Returns the object name for the secondary encoding of segment `id`, relative to the GCS prefix.
+pub fn secondary_object_name(
+    class: Option<&str>,
+    id: &str,
+    encoding: &SecondaryEncoding,
+) -> String {
+    object_name(
+        class,
+        &format!("{}.{}", id, encoding.suffix),
+        encoding.format,
+    )
+}
+
+/// A template for the names of uploaded objects, relative to the GCS prefix, for spreading them
+/// across the bucket's keyspace, like `{hash2}/{name}`.
+///
+/// Placeholders are `{name}` for the object name that would otherwise be used (per
+/// [`object_name`]), `{id}` for the segment ID, and `{hash1}` through `{hash16}` for that many
+/// leading hex digits of a hash of the segment ID. The template must include `{name}`, which keeps
+/// object names unique. Since the hash only depends on the segment ID, a recording's secondary
+/// encoding gets the same prefix as its primary, and a recording has the same object name if it's
+/// uploaded after a restart.
+#[derive(Debug, Clone)]
+pub struct ObjectKeyTemplate {
+    pieces: Vec<KeyPiece>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum KeyPiece {
+    Literal(String),
+    Name,
+    Id,
+    /// This many leading hex digits of [`id_hash`].
+    Hash(usize),
+}
+
+impl ObjectKeyTemplate {
+    pub fn new(template: &str) -> anyhow::Result<Self> {
+        let mut pieces = Vec::new();
+        let mut rest = template;
+        while !rest.is_empty() {
+            let Some(start) = rest.find(['{', '}']) else {
+                pieces.push(KeyPiece::Literal(rest.to_string()));
+                break;
+            };
+            if start > 0 {
+                pieces.push(KeyPiece::Literal(rest[..start].to_string()));
+            }
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) if rest.starts_with('{') => end,
+                _ => anyhow::bail!("Unmatched brace in object_key_template {template:?}"),
+            };
+            let placeholder = &rest[1..end];
+            pieces.push(match placeholder {
+                "name" => KeyPiece::Name,
+                "id" => KeyPiece::Id,
+                _ => match placeholder.strip_prefix("hash").map(str::parse) {
+                    Some(Ok(n @ 1..=16)) => KeyPiece::Hash(n),
+                    _ => anyhow::bail!(
+                        "Unknown placeholder {{{placeholder}}} in object_key_template {template:?}"
+                    ),
+                },
+            });
+            rest = &rest[end + 1..];
+        }
+        if !pieces.contains(&KeyPiece::Name) {
+            anyhow::bail!("Need {{name}} in object_key_template, but got {template:?}");
+        }
+        if let Some(KeyPiece::Literal(s)) = pieces.first() {
+            if s.starts_with('/') {
+                anyhow::bail!("Need a relative object_key_template, but got {template:?}");
+            }
+        }
+        Ok(Self { pieces })
+    }
+
+    /// Returns the object name for segment `id`, in place of `name`.
+    pub fn render(&self, id: &str, name: &str) -> String {
+        let hash = format!("{:016x}", id_hash(id));
+        let mut key = String::new();
+        for piece in &self.pieces {
+            match piece {
+                KeyPiece::Literal(s) => key.push_str(s),
+                KeyPiece::Name => key.push_str(name),
+                KeyPiece::Id => key.push_str(id),
+                KeyPiece::Hash(n) => key.push_str(&hash[..*n]),
+            }
+        }
+        key
+    }
+}
+
+/// Hashes a segment ID with 64-bit FNV-1a, which (unlike the standard library's hashers) is
+/// guaranteed to stay the same across builds, so that object names do too.
+fn id_hash(id: &str) -> u64 {
+    id.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Splits the filename of a finished recording, if it is one, into its segment ID and the rest of
+/// its object name: `.flac` for a primary recording, or like `.16k.flac` for a secondary encoding.
+fn split_recording_filename(name: &str) -> Option<(&str, &str)> {
+    let name = name.strip_prefix("recording-")?;
+    let (_, ext) = name.rsplit_once('.')?;
+    AudioFormat::from_extension(ext)?;
+    Some(name.split_at(name.find('.')?))
+}
+
+/// Like [`split_recording_filename`], but for a `.local` recording.
+fn split_local_filename(name: &str) -> Option<(&str, &str)> {
+    split_recording_filename(name.strip_suffix(LOCAL_SUFFIX)?)
+}
+
+/// Extracts the segment ID from the filename of a `.local` recording, if it is one.
+pub fn id_from_local_filename(name: &str) -> Option<&str> {
+    split_local_filename(name).map(|(id, _)| id)
+}
+
+/// Extracts the segment ID from the filename of a finished recording, if it is one.
+pub fn id_from_recording_filename(name: &str) -> Option<&str> {
+    split_recording_filename(name).map(|(id, _)| id)
+}
+
+/// Derives the segment ID and object name for a `.local` recording found under `storage_dir`.
+///
+/// The object name mirrors the file's subdirectory of `storage_dir`, which is how the loudness
+/// class of a segment survives a restart. A leading date subdirectory (per [`date_dir`]) is left
+/// out, though, so that the object name doesn't depend on the local layout.
+pub fn object_name_for_local(storage_dir: &Path, local: &Path) -> Option<(String, String)> {
+    split_local_filename(local.file_name()?.to_str()?)?;
+    object_name_for_recording(storage_dir, &local.with_extension(""))
+}
+
+/// Like [`object_name_for_local`], but for a finished recording found under `root`, like one
+/// already uploaded.
+pub fn object_name_for_recording(root: &Path, path: &Path) -> Option<(String, String)> {
+    let (id, rest) = split_recording_filename(path.file_name()?.to_str()?)?;
+    let mut rel_dir = path.parent()?.strip_prefix(root).ok()?;
+    if let Some(rest) = date_dir(id).and_then(|date_dir| rel_dir.strip_prefix(date_dir).ok()) {
+        rel_dir = rest;
+    }
+    let mut object_name = String::new();
+    for component in rel_dir.components() {
+        object_name.push_str(component.as_os_str().to_str()?);
+        object_name.push('/');
+    }
+    object_name.push_str(id);
+    object_name.push_str(rest);
+    Some((id.to_string(), object_name))
+}
+
+/// Returns the `YYYY/MM/DD` subdirectory for segment `id`, per the date that its ID starts with,
+/// or `None` if the ID doesn't start with a date.
+pub fn date_dir(id: &str) -> Option<PathBuf> {
+    let date = id
+        .get(..8)
+        .filter(|d| d.bytes().all(|b| b.is_ascii_digit()))?;
+    Some([&date[..4], &date[4..6], &date[6..]].iter().collect())
+}
+
+/// Picks the loudness class for a segment with the given peak absolute sample.
+///
+/// The segment belongs to the class with the highest `min_peak` that its peak (as a fraction of
+/// full scale) meets or exceeds. Returns `None` if there is no such class.
+pub fn loudness_class(classes: &[LoudnessClass], peak: i32) -> Option<&str> {
+    let peak = f64::from(peak) / f64::from(i16::MAX);
+    classes
+        .iter()
+        .filter(|c| peak >= c.min_peak)
+        .max_by(|a, b| a.min_peak.total_cmp(&b.min_peak))
+        .map(|c| c.name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(name: &str, min_peak: f64) -> LoudnessClass {
+        LoudnessClass {
+            name: name.to_string(),
+            min_peak,
+        }
+    }
+
+    #[test]
+    fn test_loudness_class() {
+        let classes = [class("normal", 0.0), class("loud", 0.5)];
+        let full = i32::from(i16::MAX);
+        assert_eq!(loudness_class(&classes, 0), Some("normal"));
+        assert_eq!(loudness_class(&classes, full / 4), Some("normal"));
+        assert_eq!(loudness_class(&classes, full / 2 + 1), Some("loud"));
+        assert_eq!(loudness_class(&classes, full), Some("loud"));
+        assert_eq!(loudness_class(&classes, 32768), Some("loud"));
+    }
+
+    #[test]
+    fn test_object_name_for_local() {
+        let dir = Path::new("/rec");
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/rec/recording-20230601T120000.flac.local")),
+            Some((
+                "20230601T120000".to_string(),
+                "20230601T120000.flac".to_string()
+            ))
+        );
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/rec/loud/recording-x.flac.local")),
+            Some(("x".to_string(), "loud/x.flac".to_string()))
+        );
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/rec/loud/recording-x.16k.ogg.local")),
+            Some(("x".to_string(), "loud/x.16k.ogg".to_string()))
+        );
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/rec/recording-x.flac.part")),
+            None
+        );
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/rec/recording-x.16k.mp4.local")),
+            None
+        );
+        assert_eq!(
+            object_name_for_local(dir, Path::new("/elsewhere/recording-x.flac.local")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_id_clock() {
+        let mut clock = IdClock::default();
+        assert_eq!(clock.next(100, ""), 100);
+        assert_eq!(clock.next(105, ""), 105);
+        // Two segments in the same second.
+        assert_eq!(clock.next(105, ""), 106);
+        // The clock steps back a minute, but timestamps keep increasing until it catches up.
+        assert_eq!(clock.next(50, ""), 107);
+        assert_eq!(clock.next(51, ""), 108);
+        assert_eq!(clock.next(108, ""), 109);
+        assert_eq!(clock.next(120, ""), 120);
+        // Other channels can share a second, but not with themselves.
+        assert_eq!(clock.next(130, "-left"), 130);
+        assert_eq!(clock.next(130, "-right"), 130);
+        assert_eq!(clock.next(130, "-left"), 131);
+        assert_eq!(clock.last(), Some(131));
+
+        // After a restart, nothing up to the last timestamp is reused.
+        let mut clock = IdClock::after(131);
+        assert_eq!(clock.next(60, "-left"), 132);
+        assert_eq!(clock.next(60, "-right"), 132);
+        assert_eq!(clock.next(200, "-left"), 200);
+    }
+
+    #[test]
+    fn test_segment_id() {
+        assert_eq!(segment_id("20240102T030405", "", None), "20240102T030405");
+        assert_eq!(
+            segment_id("20240102T030405", "-left", None),
+            "20240102T030405-left"
+        );
+        let id = segment_id("20240102T030405", "-left", Some(0xc0ffee));
+        assert_eq!(id, "20240102T030405-left-00000000000000000000000000c0ffee");
+        assert_eq!(date_dir(&id), Some(PathBuf::from("2024/01/02")));
+        assert_eq!(
+            id_from_local_filename(&format!(
+                "{}.local",
+                recording_filename(&id, AudioFormat::Flac)
+            )),
+            Some(id.as_str())
+        );
+    }
+
+    #[test]
+    fn test_date_dir() {
+        assert_eq!(
+            date_dir("20230601T120000"),
+            Some(PathBuf::from("2023/06/01"))
+        );
+        assert_eq!(
+            date_dir("20230601T120000-left"),
+            Some(PathBuf::from("2023/06/01"))
+        );
+        assert_eq!(date_dir("x"), None);
+        assert_eq!(date_dir("2023-06-01"), None);
+
+        let dir = Path::new("/rec");
+        let id = "20230601T120000".to_string();
+        assert_eq!(
+            object_name_for_local(
+                dir,
+                Path::new("/rec/2023/06/01/recording-20230601T120000.flac.local")
+            ),
+            Some((id.clone(), "20230601T120000.flac".to_string()))
+        );
+        assert_eq!(
+            object_name_for_local(
+                dir,
+                Path::new("/rec/2023/06/01/loud/recording-20230601T120000.flac.local")
+            ),
+            Some((id.clone(), "loud/20230601T120000.flac".to_string()))
+        );
+        // Only the segment's own date is left out.
+        assert_eq!(
+            object_name_for_local(
+                dir,
+                Path::new("/rec/2023/06/02/recording-20230601T120000.flac.local")
+            ),
+            Some((id, "2023/06/02/20230601T120000.flac".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_object_name_for_recording() {
+        let dir = Path::new("/archive");
+        assert_eq!(
+            object_name_for_recording(dir, Path::new("/archive/loud/recording-x.flac")),
+            Some(("x".to_string(), "loud/x.flac".to_string()))
+        );
+        assert_eq!(
+            object_name_for_recording(dir, Path::new("/archive/recording-x.16k.ogg")),
+            Some(("x".to_string(), "x.16k.ogg".to_string()))
+        );
+        // Not finished, or not a recording at all.
+        assert_eq!(
+            object_name_for_recording(dir, Path::new("/archive/recording-x.flac.local")),
+            None
+        );
+        assert_eq!(
+            object_name_for_recording(dir, Path::new("/archive/recording-x.flac.rejected")),
+            None
+        );
+        assert_eq!(
+            object_name_for_recording(dir, Path::new("/archive/notes.flac")),
+            None
+        );
+    }
+
+    #[test]
+    fn test_names_follow_format() {
+        for format in [AudioFormat::Flac, AudioFormat::Wav, AudioFormat::Ogg] {
+            let filename = recording_filename("x", format);
+            let object_name = object_name(Some("loud"), "x", format);
+            let ext = format!(".{}", format.extension());
+            assert!(filename.ends_with(&ext), "{}", filename);
+            assert!(object_name.ends_with(&ext), "{}", object_name);
+            let local = Path::new("/rec/loud").join(format!("{}{}", filename, LOCAL_SUFFIX));
+            assert_eq!(
+                object_name_for_local(Path::new("/rec"), &local),
+                Some(("x".to_string(), object_name))
+            );
+        }
+        assert_eq!(recording_filename("x", AudioFormat::Ogg), "recording-x.ogg");
+        assert_eq!(object_name(None, "x", AudioFormat::Ogg), "x.ogg");
+        assert_eq!(AudioFormat::Ogg.content_type(), "audio/ogg");
+    }
+
+    #[test]
+    fn test_secondary_names() {
+        let encoding = SecondaryEncoding {
+            suffix: "16k".to_string(),
+            rate: Some(16000),
+            format: AudioFormat::Flac,
+        };
+        let filename = secondary_filename("x", &encoding);
+        assert_eq!(filename, "recording-x.16k.flac");
+        assert_eq!(secondary_object_name(None, "x", &encoding), "x.16k.flac");
+        assert_eq!(
+            secondary_object_name(Some("loud"), "x", &encoding),
+            "loud/x.16k.flac"
+        );
+        // After a restart, the secondary encoding is recovered under the same object name.
+        assert_eq!(
+            object_name_for_local(
+                Path::new("/rec"),
+                &Path::new("/rec/loud").join(format!("{}{}", filename, LOCAL_SUFFIX))
+            ),
+            Some(("x".to_string(), "loud/x.16k.flac".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_spare_filename() {
+        assert!(is_spare_filename(&spare_filename(0)));
+        assert!(is_spare_filename(&spare_filename(123)));
+        assert!(!is_spare_filename("spare-x.flac.part"));
+        assert!(!is_spare_filename("recording-20230601T120000.flac.part"));
+        assert_eq!(id_from_local_filename(&spare_filename(1)), None);
+    }
+
+    #[test]
+    fn test_loudness_class_unmatched() {
+        // Order in config doesn't matter, and peaks below every class go unclassified.
+        let classes = [class("loud", 0.5), class("normal", 0.1)];
+        assert_eq!(loudness_class(&classes, 100), None);
+        assert_eq!(loudness_class(&classes, 8000), Some("normal"));
+        assert_eq!(loudness_class(&[], 8000), None);
+    }
+
+    #[test]
+    fn test_object_key_template() {
+        let id = "20240102T030405";
+        let hash = format!("{:016x}", id_hash(id));
+        let template = ObjectKeyTemplate::new("{hash2}/{name}").unwrap();
+        let key = template.render(id, "loud/20240102T030405.flac");
+        assert_eq!(key, format!("{}/loud/20240102T030405.flac", &hash[..2]));
+        // The same ID always gets the same shard, and its secondary encoding gets it too.
+        assert_eq!(template.render(id, "loud/20240102T030405.flac"), key);
+        assert!(template
+            .render(id, "20240102T030405.16k.ogg")
+            .starts_with(&format!("{}/", &hash[..2])));
+
+        let template = ObjectKeyTemplate::new("by-hash/{hash16}-{id}/{name}").unwrap();
+        assert_eq!(
+            template.render(id, "x.flac"),
+            format!("by-hash/{hash}-{id}/x.flac")
+        );
+        assert_eq!(
+            ObjectKeyTemplate::new("{name}")
+                .unwrap()
+                .render(id, "x.flac"),
+            "x.flac"
+        );
+
+        // Known FNV-1a values, so that a change to the hash (and so to every sharded object name)
+        // can't go unnoticed.
+        assert_eq!(id_hash(""), 0xcbf29ce484222325);
+        assert_eq!(id_hash("a"), 0xaf63dc4c8601ec8c);
+
+        for bad in [
+            "{hash2}/{id}.flac",
+            "{hash0}/{name}",
+            "{hash17}/{name}",
+            "{hash}/{name}",
+            "{date}/{name}",
+            "{hash2/{name}",
+            "}{name}",
+            "/{name}",
+        ] {
+            assert!(ObjectKeyTemplate::new(bad).is_err(), "{}", bad);
+        }
+    }
+}
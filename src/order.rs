@@ -0,0 +1,85 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Turns for segments to be finalized in the order that they started, for `ordered_uploads`.
+///
+/// Each segment takes a ticket when it starts, and waits before finalizing until every segment
+/// with an earlier ticket is done, so that segments reach the upload queue in order even when a
+/// later one's encoder exits first.
+pub struct FinishOrder {
+    next_ticket: AtomicU64,
+    /// Ticket of the segment whose turn it is.
+    turn: Mutex<u64>,
+    notify: tokio::sync::Notify,
+}
+
+impl FinishOrder {
+    pub fn new() -> Self {
+        Self {
+            next_ticket: AtomicU64::new(0),
+            turn: Mutex::new(0),
+            notify: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Takes the next ticket. Every ticket taken must eventually be passed to [`Self::done`], or
+    /// all later segments wait forever.
+    pub fn take_ticket(&self) -> u64 {
+        self.next_ticket.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Waits until it's the turn of the segment with `ticket`.
+    pub async fn wait(&self, ticket: u64) {
+        loop {
+            // Created before checking, so that a `done` in between still wakes this.
+            let notified = self.notify.notified();
+            if *self.turn.lock().unwrap() >= ticket {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Passes the turn from the segment with `ticket` to the next one.
+    pub fn done(&self, ticket: u64) {
+        // A segment that's finished twice gets its turn right away the second time, so this
+        // mustn't move the turn back.
+        let mut turn = self.turn.lock().unwrap();
+        *turn = (*turn).max(ticket + 1);
+        drop(turn);
+        self.notify.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_finishes_in_order() {
+        let order = Arc::new(FinishOrder::new());
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let tickets: Vec<u64> = (0..4).map(|_| order.take_ticket()).collect();
+        assert_eq!(tickets, [0, 1, 2, 3]);
+        // Later segments are ready to finish sooner.
+        let tasks: Vec<_> = tickets
+            .into_iter()
+            .map(|ticket| {
+                let order = order.clone();
+                let finished = finished.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(40 - 10 * ticket)).await;
+                    order.wait(ticket).await;
+                    finished.lock().unwrap().push(ticket);
+                    order.done(ticket);
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*finished.lock().unwrap(), [0, 1, 2, 3]);
+    }
+}
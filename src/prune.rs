@@ -0,0 +1,102 @@
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use log::{error, info};
+
+use crate::{naming, upload};
+
+/// How often to look for recordings older than `local_retention_secs`.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Deletes local copies of uploaded recordings once they're older than `retention`, forever.
+///
+/// Which recordings have been uploaded is tracked by their filenames, just as for uploads: a
+/// recording keeps its `.local` suffix until it's in GCS, and only then is renamed to its final
+/// name. So only recordings with final names are ever deleted. Pending uploads (`.local`), those
+/// still being written (`.part`), and those set aside (`.rejected`) are left alone, however old.
+/// Age is by modification time, which is when the encoder finished writing the recording.
+pub async fn run(roots: Vec<PathBuf>, retention: Duration) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let mut found = Vec::new();
+        for root in &roots {
+            if let Err(e) = upload::scan_files(root, &mut found, is_uploaded).await {
+                error!(
+                    "Failed to scan {} for recordings to prune: {:#}",
+                    root.display(),
+                    e
+                );
+            }
+        }
+        let mut pruned = 0;
+        for path in expired(found, SystemTime::now(), retention) {
+            match tokio::fs::remove_file(&path).await {
+                Ok(()) => pruned += 1,
+                // E.g., if someone else already cleaned it up.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => error!("Failed to prune {}: {}", path.display(), e),
+            }
+        }
+        if pruned > 0 {
+            info!(
+                "Pruned {} uploaded recording(s) past local_retention_secs",
+                pruned
+            );
+        }
+    }
+}
+
+/// Whether `name` is the filename of a recording that has been uploaded.
+fn is_uploaded(name: &str) -> bool {
+    naming::id_from_recording_filename(name).is_some()
+}
+
+/// Returns the paths of the files, from `found` with their modification times, that are older
+/// than `retention` as of `now`.
+fn expired(
+    found: Vec<(SystemTime, PathBuf)>,
+    now: SystemTime,
+    retention: Duration,
+) -> Vec<PathBuf> {
+    found
+        .into_iter()
+        .filter(|(modified, _)| {
+            now.duration_since(*modified)
+                .is_ok_and(|age| age > retention)
+        })
+        .map(|(_, path)| path)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_uploaded() {
+        assert!(is_uploaded("recording-20240101T120000.flac"));
+        assert!(is_uploaded("recording-20240101T120000-left.flac"));
+        assert!(is_uploaded("recording-20240101T120000.16k.ogg"));
+        assert!(!is_uploaded("recording-20240101T120000.flac.local"));
+        assert!(!is_uploaded("recording-20240101T120000.flac.part"));
+        assert!(!is_uploaded("recording-20240101T120000.flac.rejected"));
+        assert!(!is_uploaded("spare-0.flac.part"));
+        assert!(!is_uploaded("notes.txt"));
+    }
+
+    #[test]
+    fn test_expired() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100_000);
+        let day = Duration::from_secs(86400);
+        let found = vec![
+            (now - day - Duration::from_secs(1), PathBuf::from("old")),
+            (now - day, PathBuf::from("exactly")),
+            (now - Duration::from_secs(1), PathBuf::from("new")),
+            // E.g., if the clock was set back.
+            (now + Duration::from_secs(60), PathBuf::from("future")),
+        ];
+        assert_eq!(expired(found, now, day), vec![PathBuf::from("old")]);
+    }
+}
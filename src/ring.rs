@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+
+/// The most recent audio, up to a fixed number of bytes, for capturing what happened just before
+/// a trigger.
+///
+/// This lives in memory: at 48 kHz, 16-bit mono, that's about 5.5 MiB per minute of audio. Keeping
+/// it in memory avoids writing continuously to storage (which would wear out an SD card, say)
+/// at the cost of losing the buffer if `reccon` exits before it's saved.
+pub struct RingBuffer {
+    buf: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    /// Creates a buffer that holds up to `capacity` bytes, rounded down to a whole sample.
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity & !1;
+        Self {
+            buf: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends audio, dropping the oldest audio if that exceeds the capacity.
+    pub fn push(&mut self, chunk: &[u8]) {
+        let chunk = &chunk[chunk.len().saturating_sub(self.capacity)..];
+        let overflow = (self.buf.len() + chunk.len()).saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+        self.buf.extend(chunk);
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Removes and returns all buffered audio, oldest first.
+    pub fn take(&mut self) -> Vec<u8> {
+        // Only as big as what's buffered, which may be much less than the capacity.
+        self.buf.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keeps_most_recent() {
+        let mut ring = RingBuffer::new(6);
+        ring.push(&[1, 2, 3, 4]);
+        assert_eq!(ring.len(), 4);
+        ring.push(&[5, 6, 7, 8]);
+        assert_eq!(ring.take(), vec![3, 4, 5, 6, 7, 8]);
+        assert_eq!(ring.len(), 0);
+
+        // A chunk bigger than the whole buffer keeps only its tail.
+        ring.push(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(ring.take(), vec![5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_capacity_is_whole_samples() {
+        let mut ring = RingBuffer::new(5);
+        ring.push(&[1, 2, 3, 4, 5, 6]);
+        assert_eq!(ring.take(), vec![3, 4, 5, 6]);
+    }
+}
@@ -1,21 +1,194 @@
+use std::collections::VecDeque;
 use std::fmt::Debug;
 
 use log::{debug, trace};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 
-#[derive(Debug, Clone)]
+use crate::config::ByteOrder;
+
+#[derive(Debug, Clone, Default)]
 pub struct Config {
     pub chunk_size: usize,
     pub max_total_chunks: u32,
     pub min_hot_chunks: u32,
     pub max_quiet_chunks: u32,
     pub threshold: i16,
+    /// Byte order of the samples in each chunk.
+    pub byte_order: ByteOrder,
+    /// Whether to subtract each chunk's mean (DC offset) from its samples before comparing them to
+    /// `threshold`, for inputs with a large bias.
+    pub remove_dc: bool,
+    /// If set, each chunk's level is multiplied by this (saturating at full scale) before it's
+    /// compared to `threshold`, for a quiet input. This doesn't change the audio, or its stats.
+    pub detect_gain: Option<f64>,
+    /// How to measure a chunk's level to compare against `threshold`.
+    pub detector: Detector,
+    /// If set, the threshold rises above `threshold` to track the levels of recent chunks. See
+    /// [`RollingThreshold`].
+    pub rolling_threshold: Option<RollingThreshold>,
+    /// If set, `max_quiet_chunks` is ignored in favor of a quiet tail that grows with how loud and
+    /// how long the segment has been. See [`effective_max_quiet_chunks`].
+    pub adaptive_tail: Option<TailBounds>,
+    /// If set, segments are finalized and a new one started after this many chunks even if the
+    /// audio never goes quiet, like `max_total_chunks` but meant to be much shorter. Adaptive tails
+    /// are still scaled by `max_total_chunks`.
+    pub flush_chunks: Option<u32>,
+    /// If set, segments also roll over at a brief dip in level that stays above `threshold`, for
+    /// audio with constant background that never goes quiet. See [`RelativeDip`].
+    pub relative_dip: Option<RelativeDip>,
+    /// If set, `min_hot_chunks` and the quiet tail vary randomly from segment to segment. See
+    /// [`Jitter`].
+    pub jitter: Option<Jitter>,
+    /// Number of chunks after a segment ends during which hot audio is ignored, so that a brief
+    /// pause can't immediately start another segment.
+    pub cooldown_chunks: u32,
+    /// Number of consecutive quiet chunks that a pending segment survives, so that a stuttery
+    /// onset can still reach `min_hot_chunks`. Quiet chunks don't count toward that minimum.
+    pub pending_quiet_tolerance: u32,
+    /// Samples per frame of each segment's [`Envelope`], or 0 to not track envelopes.
+    pub envelope_frame_samples: usize,
+    /// Most frames that an [`Envelope`] holds before merging them.
+    pub envelope_max_frames: usize,
+    /// If set, every segment starts with exactly this many bytes from before its first chunk:
+    /// pre-roll going back as far as that, or as far as there's audio, and silence before it for
+    /// the rest. A segment that a roll-over started gets all silence, since it has no pre-roll.
+    pub lead_bytes: Option<usize>,
+}
+
+impl Config {
+    fn new_stats(&self) -> Stats {
+        Stats {
+            envelope: Envelope::new(self.envelope_frame_samples, self.envelope_max_frames),
+            ..Default::default()
+        }
+    }
+}
+
+/// How to reduce a chunk of samples to a single level.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Detector {
+    /// The largest absolute sample.
+    #[default]
+    Peak,
+    /// The given percentile (between 0 and 100) of absolute samples, which ignores a few outliers
+    /// like isolated pops.
+    Percentile(f64),
+}
+
+/// A threshold that follows a room that slowly gets louder, like a party ramping up, so that it
+/// doesn't trigger on everything once the background alone is louder than `threshold`.
+///
+/// The effective threshold is the given percentile of the levels of the chunks in the last
+/// `window_chunks`, but never less than `threshold`. To keep this cheap over a window of minutes,
+/// only every so many chunks' level is kept, so that at most [`ROLLING_HISTORY_LEN`] are, and the
+/// threshold is recomputed as each one is added. Every chunk counts, whether in a segment or not.
+#[derive(Debug, Clone, Copy)]
+pub struct RollingThreshold {
+    pub window_chunks: u32,
+    /// Between 0 and 100.
+    pub percentile: f64,
+}
+
+/// Most chunk levels that a [`RollingThreshold`] keeps.
+const ROLLING_HISTORY_LEN: u32 = 256;
+/// The most that buffers are allocated up front, so that odd settings (like a huge
+/// `min_hot_chunks`) can't ask for an absurd allocation before any audio arrives. They still grow
+/// as needed past this.
+const MAX_PRESIZE_BYTES: usize = 1 << 20;
+
+/// A natural boundary within audio that never goes quiet: a stretch of hot chunks whose levels are
+/// all well below the segment's running level.
+///
+/// The running level is a moving average of the levels of the segment's hot chunks, over about
+/// `running_chunks`, not counting those in a dip. Only once it has that many does a dip count, so
+/// that a segment isn't split right as it starts.
+#[derive(Debug, Clone, Copy)]
+pub struct RelativeDip {
+    /// A chunk is in a dip if its level is less than this fraction of the running level.
+    pub ratio: f64,
+    /// Consecutive chunks in a dip that end the segment.
+    pub min_chunks: u32,
+    pub running_chunks: u32,
+}
+
+/// Running level of an active segment, per [`RelativeDip`].
+#[derive(Debug, Default)]
+struct DipTracker {
+    running_level: f64,
+    /// Hot chunks averaged into `running_level` so far.
+    chunks: u32,
+    /// Consecutive chunks in a dip so far.
+    dip_chunks: u32,
+}
+
+impl DipTracker {
+    /// Takes note of the next chunk of the segment, returning whether it ends a dip long enough
+    /// to split on. Quiet chunks are left to `max_quiet_chunks` instead.
+    fn accept(&mut self, dip: &RelativeDip, level: i32, is_quiet: bool) -> bool {
+        if is_quiet {
+            self.dip_chunks = 0;
+            return false;
+        }
+        let level = f64::from(level);
+        if self.chunks >= dip.running_chunks && level < self.running_level * dip.ratio {
+            self.dip_chunks += 1;
+            return self.dip_chunks >= dip.min_chunks;
+        }
+        self.dip_chunks = 0;
+        self.chunks += 1;
+        // A plain average until there are `running_chunks` levels, then an exponential one.
+        let weight = 1.0 / f64::from(self.chunks.min(dip.running_chunks).max(1));
+        self.running_level += weight * (level - self.running_level);
+        false
+    }
+}
+
+/// Random variation in when segments start and end, so that recorders listening to the same room
+/// don't all cut their segments at the same moment.
+///
+/// Each segment's `min_hot_chunks` and quiet tail are scaled by their own random factor between
+/// `1 - fraction` and `1 + fraction`, drawn from an RNG seeded with `seed`, so that a run can be
+/// reproduced.
+#[derive(Debug, Clone, Copy)]
+pub struct Jitter {
+    /// Between 0 and 1.
+    pub fraction: f64,
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TailBounds {
+    pub min_quiet_chunks: u32,
+    pub max_quiet_chunks: u32,
 }
 
 pub struct Segmentation {
     config: Config,
     state: State,
+    /// The chunk before the current one, which becomes the pre-roll of a segment that starts on
+    /// the current one. It's kept through quiet chunks and cooldowns, so it's only ever empty
+    /// before the first chunk, and a segment starting on the second-ever chunk gets the same
+    /// pre-roll as any later one. With `lead_bytes`, it's instead the last that many bytes before
+    /// the current chunk, however many chunks they span.
     last_chunk: Vec<u8>,
+    /// `lead_bytes` of silence, for the lead of a segment with no pre-roll.
+    lead_silence: Vec<u8>,
     pending_buf: Vec<u8>,
+    /// Level above which a chunk is hot: `threshold`, or more per `rolling_threshold`.
+    threshold: i32,
+    /// Level of the last chunk accepted, per `detector`, and the threshold it was compared against.
+    last_level: (i32, i32),
+    /// Levels of every `rolling_decimation`th recent chunk, oldest first, per `rolling_threshold`.
+    rolling_history: VecDeque<i32>,
+    rolling_decimation: u32,
+    /// Chunks since the last one whose level was kept in `rolling_history`.
+    rolling_skipped: u32,
+    /// `min_hot_chunks` for the current segment, per `jitter`.
+    min_hot_chunks: u32,
+    /// Factor by which the current segment's quiet tail is scaled, per `jitter`.
+    quiet_scale: f64,
+    rng: StdRng,
 }
 
 impl Debug for Segmentation {
@@ -27,6 +200,9 @@ impl Debug for Segmentation {
                 &format_args!("[len = {}]", self.last_chunk.len()),
             )
             .field("state", &self.state)
+            .field("threshold", &self.threshold)
+            .field("min_hot_chunks", &self.min_hot_chunks)
+            .field("quiet_scale", &self.quiet_scale)
             .finish()
     }
 }
@@ -34,15 +210,25 @@ impl Debug for Segmentation {
 #[derive(Debug)]
 enum State {
     Quiet,
+    /// A segment just ended; ignore this many more chunks before going back to `Quiet`.
+    Cooldown {
+        remaining_chunks: u32,
+    },
     Pending {
         id: String,
         total_chunks: u32,
-        consecutive_hot_chunks: u32,
+        /// Hot chunks so far, which are consecutive unless `pending_quiet_tolerance` is set.
+        hot_chunks: u32,
+        consecutive_quiet_chunks: u32,
+        stats: Stats,
     },
     Active {
         started: bool,
         total_chunks: u32,
         consecutive_quiet_chunks: u32,
+        /// Stats of this segment so far.
+        stats: Stats,
+        dip: DipTracker,
     },
 }
 
@@ -50,22 +236,319 @@ enum State {
 pub enum Event<'a> {
     Start { id: String },
     Data(&'a [u8]),
-    End,
+    End(Stats),
+}
+
+/// Summary of a segment, reported when it ends.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Largest absolute sample in the segment, excluding any quiet pre-roll. This may be as high as
+    /// `32768`, from an `i16::MIN` sample.
+    pub peak: i32,
+    /// Number of samples at full scale (`i16::MIN` or `i16::MAX`), which suggest clipping.
+    pub clipped_samples: u64,
+    /// Number of bytes of audio emitted for the segment, including pre-roll. Unlike a count of
+    /// chunks, this accounts for a short final chunk.
+    pub bytes: u64,
+    /// Number of those bytes that are the quiet pre-roll from before the segment's first hot chunk.
+    pub preroll_bytes: u64,
+    /// Number of bytes from the end of the pre-roll through the segment's last hot chunk, which
+    /// leaves out its quiet tail.
+    pub active_bytes: u64,
+    pub end_reason: EndReason,
+    /// Loudness over time, including pre-roll, if enabled by `envelope_frame_samples`.
+    pub envelope: Envelope,
+    /// Whether the input ended before the segment had `min_hot_chunks` hot chunks. A segment only
+    /// starts once it has that many, so this only happens to one that rolled over (as after
+    /// `flush_chunks`) shortly before the end of input.
+    pub incomplete: bool,
+}
+
+/// A coarse timeline of a segment's loudness, as the peak absolute sample of each frame of audio,
+/// for drawing a waveform without decoding the recording.
+///
+/// To keep it small however long the segment is, adjacent frames are merged in pairs whenever
+/// there are `max_frames` of them, doubling the frame length.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// Samples per frame, or 0 if not tracking an envelope.
+    pub frame_samples: usize,
+    /// Peak absolute sample of each frame. The last frame may be short.
+    pub peaks: Vec<u16>,
+    max_frames: usize,
+    /// Peak and length so far of the frame in progress.
+    partial_peak: u16,
+    partial_samples: usize,
+}
+
+/// Why a segment ended.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EndReason {
+    /// The audio went quiet for long enough.
+    #[default]
+    Silence,
+    /// The segment reached `max_total_chunks` and rolled over.
+    MaxLength,
+    /// The segment reached `flush_chunks` and rolled over.
+    Flush,
+    /// The segment's level dipped, per `relative_dip`, and it rolled over.
+    Dip,
+    /// The segment's file reached a maximum size and rolled over, per [`Segmentation::roll_over`].
+    MaxSize,
+    /// The input ended.
+    Eof,
+    /// Recording was cut short outside the segmenter, like by a failed encoder.
+    Interrupted,
+    /// A ring buffer was saved on demand.
+    Triggered,
+    /// Recording stopped because `reccon` reached a configured limit.
+    Stopped,
+}
+
+impl EndReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EndReason::Silence => "silence",
+            EndReason::MaxLength => "max-length",
+            EndReason::Flush => "flush",
+            EndReason::Dip => "dip",
+            EndReason::MaxSize => "max-size",
+            EndReason::Eof => "eof",
+            EndReason::Interrupted => "interrupted",
+            EndReason::Triggered => "triggered",
+            EndReason::Stopped => "stopped",
+        }
+    }
+}
+
+impl Stats {
+    /// Computes the stats of a standalone stretch of audio, outside of segmentation, filling in
+    /// the given empty `envelope`.
+    pub fn measure(
+        raw_audio: &[u8],
+        order: ByteOrder,
+        end_reason: EndReason,
+        mut envelope: Envelope,
+    ) -> Self {
+        envelope.add(raw_audio, order);
+        envelope.finish();
+        Stats {
+            peak: chunk_peak(raw_audio, false, order),
+            clipped_samples: clipped_samples(raw_audio, order),
+            bytes: raw_audio.len() as u64,
+            preroll_bytes: 0,
+            active_bytes: raw_audio.len() as u64,
+            end_reason,
+            envelope,
+            incomplete: false,
+        }
+    }
+
+    fn add_chunk(&mut self, peak: i32, clipped_samples: u64) {
+        self.peak = self.peak.max(peak);
+        self.clipped_samples += clipped_samples;
+    }
+}
+
+impl Envelope {
+    /// Creates an empty envelope with frames of `frame_samples` samples, or a disabled one if
+    /// that's 0. `max_frames` is rounded up to an even number, at least 2.
+    pub fn new(frame_samples: usize, max_frames: usize) -> Self {
+        Self {
+            frame_samples,
+            max_frames: if frame_samples == 0 {
+                0
+            } else {
+                (max_frames.max(2) + 1) & !1
+            },
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, raw_audio: &[u8], order: ByteOrder) {
+        if self.frame_samples == 0 {
+            return;
+        }
+        for z in abs_samples(raw_audio, false, order) {
+            self.partial_peak = self.partial_peak.max(z as u16);
+            self.partial_samples += 1;
+            if self.partial_samples >= self.frame_samples {
+                self.end_frame();
+            }
+        }
+    }
+
+    /// Ends the frame in progress, if any, even though it's short.
+    pub fn finish(&mut self) {
+        if self.partial_samples > 0 {
+            self.end_frame();
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.peaks.push(std::mem::take(&mut self.partial_peak));
+        self.partial_samples = 0;
+        if self.peaks.len() >= self.max_frames {
+            // There's an even number of frames, so the next frame starts on a merged boundary.
+            self.peaks = self
+                .peaks
+                .chunks(2)
+                .map(|pair| pair.iter().copied().max().unwrap())
+                .collect();
+            self.frame_samples *= 2;
+        }
+    }
+
+    /// Encodes the peaks as two hex digits each, scaled so that `ff` is full scale.
+    pub fn to_hex(&self) -> String {
+        self.peaks
+            .iter()
+            .map(|&peak| format!("{:02x}", u32::from(peak) * 255 / 32768))
+            .collect()
+    }
 }
 
 impl Segmentation {
     pub fn new(config: Config) -> Self {
+        let window_chunks = config
+            .rolling_threshold
+            .map_or(0, |r| r.window_chunks.max(1));
+        let rolling_decimation = window_chunks.div_ceil(ROLLING_HISTORY_LEN).max(1);
         Self {
-            pending_buf: Vec::with_capacity(config.chunk_size * config.min_hot_chunks as usize),
-            last_chunk: Vec::with_capacity(config.chunk_size),
+            pending_buf: Vec::with_capacity(presize(config.chunk_size, config.min_hot_chunks)),
+            last_chunk: Vec::with_capacity(presize(config.chunk_size, 1)),
+            lead_silence: vec![0; config.lead_bytes.unwrap_or(0)],
             state: State::Quiet,
+            threshold: i32::from(config.threshold),
+            last_level: (0, i32::from(config.threshold)),
+            rolling_history: VecDeque::with_capacity((window_chunks / rolling_decimation) as usize),
+            rolling_decimation,
+            rolling_skipped: 0,
+            min_hot_chunks: config.min_hot_chunks,
+            quiet_scale: 1.0,
+            rng: StdRng::seed_from_u64(config.jitter.map_or(0, |j| j.seed)),
             config,
         }
     }
 
+    /// Draws the `min_hot_chunks` and quiet tail scale for a new segment, per `jitter`.
+    fn jitter(config: &Config, rng: &mut StdRng) -> (u32, f64) {
+        let Some(jitter) = config.jitter else {
+            return (config.min_hot_chunks, 1.0);
+        };
+        let fraction = jitter.fraction.clamp(0.0, 1.0);
+        let hot_scale = 1.0 + rng.gen_range(-fraction..=fraction);
+        let quiet_scale = 1.0 + rng.gen_range(-fraction..=fraction);
+        let min_hot_chunks = scale_chunks(config.min_hot_chunks, hot_scale);
+        trace!(
+            "Jittered min_hot_chunks = {}, quiet tail scale = {:.3}",
+            min_hot_chunks,
+            quiet_scale
+        );
+        (min_hot_chunks, quiet_scale)
+    }
+
+    /// Returns the level of the last chunk accepted and the threshold that it was compared against,
+    /// so that the chunk was hot if its level was greater (unless it was judged otherwise, per
+    /// [`Segmentation::accept_judged`]).
+    pub fn last_level(&self) -> (i32, i32) {
+        self.last_level
+    }
+
+    /// Takes note of the level of a chunk, for the rolling threshold, if any.
+    fn record_level(&mut self, level: i32) {
+        let Some(rolling) = self.config.rolling_threshold else {
+            return;
+        };
+        self.rolling_skipped += 1;
+        if self.rolling_skipped < self.rolling_decimation {
+            return;
+        }
+        self.rolling_skipped = 0;
+        let len = (rolling.window_chunks.max(1) / self.rolling_decimation).max(1) as usize;
+        if self.rolling_history.len() >= len {
+            self.rolling_history.pop_front();
+        }
+        self.rolling_history.push_back(level);
+        let mut levels: Vec<i32> = self.rolling_history.iter().copied().collect();
+        let threshold =
+            nearest_rank(&mut levels, rolling.percentile).max(self.config.threshold.into());
+        if threshold != self.threshold {
+            debug!("Threshold is now {}", threshold);
+            self.threshold = threshold;
+        }
+    }
+
+    /// Ends the active segment, if any, without accepting any more audio. The segment's stats are
+    /// returned if it had started; a pending segment is discarded.
+    pub fn finish(&mut self, end_reason: EndReason) -> Option<Stats> {
+        match std::mem::replace(&mut self.state, State::Quiet) {
+            State::Active {
+                started: true,
+                mut stats,
+                ..
+            } => {
+                stats.end_reason = end_reason;
+                stats.envelope.finish();
+                Some(stats)
+            }
+            _ => None,
+        }
+    }
+
+    /// Ends the active segment, if any, like when it reaches `max_total_chunks`: the next chunk
+    /// starts a new segment that picks up right where this one left off, as long as the audio
+    /// doesn't go quiet. This is for limits that the segmenter can't see, like the size of the
+    /// encoded file. The segment's stats are returned if it had started.
+    pub fn roll_over(&mut self, end_reason: EndReason) -> Option<Stats> {
+        let State::Active {
+            started: started @ true,
+            total_chunks,
+            stats,
+            dip,
+            ..
+        } = &mut self.state
+        else {
+            return None;
+        };
+        *started = false;
+        // Any quiet tail so far carries over, as when rolling over per `max_total_chunks`.
+        *total_chunks = 0;
+        *dip = DipTracker::default();
+        (self.min_hot_chunks, self.quiet_scale) = Self::jitter(&self.config, &mut self.rng);
+        let mut stats = std::mem::replace(stats, self.config.new_stats());
+        stats.end_reason = end_reason;
+        stats.envelope.finish();
+        Some(stats)
+    }
+
+    /// Processes the next chunk of audio, returning the resulting events.
+    ///
+    /// Whether the chunk is quiet is decided by looking at `detect`, which is usually the same as
+    /// `chunk` but may instead be a filtered copy of it. Only `chunk` is emitted as `Data`.
     pub fn accept<'a, F>(
         &'a mut self,
         chunk: &'a [u8],
+        detect: &[u8],
+        gen_id: F,
+    ) -> impl Iterator<Item = Event<'a>>
+    where
+        F: FnMut() -> String,
+    {
+        self.accept_judged(chunk, detect, None, gen_id)
+    }
+
+    /// Like [`Segmentation::accept`], but with whether the chunk is `hot` decided elsewhere, like
+    /// by an external voice activity detector, instead of by comparing its level to the threshold.
+    /// If `hot` is `None`, the threshold decides after all.
+    ///
+    /// The chunk's level is still measured either way, for the rolling threshold and
+    /// [`Segmentation::last_level`].
+    pub fn accept_judged<'a, F>(
+        &'a mut self,
+        chunk: &'a [u8],
+        detect: &[u8],
+        hot: Option<bool>,
         mut gen_id: F,
     ) -> impl Iterator<Item = Event<'a>>
     where
@@ -74,7 +557,27 @@ impl Segmentation {
         // TODO: Use or write an iterator implementation that doesn't allocate. We only need to
         // return, like, four events at max.
         let mut events: Vec<Event<'_>> = Vec::new();
-        let is_quiet = is_quiet(chunk, self.config.threshold);
+        let (remove_dc, order) = (self.config.remove_dc, self.config.byte_order);
+        let chunk_peak = chunk_peak(detect, remove_dc, order);
+        let level = match self.config.detector {
+            Detector::Peak => chunk_peak,
+            Detector::Percentile(p) => chunk_percentile(detect, remove_dc, p, order),
+        };
+        let level = match self.config.detect_gain {
+            Some(gain) => boosted(level, gain),
+            None => level,
+        };
+        let is_quiet = match hot {
+            Some(hot) => !hot,
+            None => level <= self.threshold,
+        };
+        self.last_level = (level, self.threshold);
+        // Only once this chunk is judged, so that it isn't compared against itself.
+        if !chunk.is_empty() {
+            self.record_level(level);
+        }
+        // Clipping is a property of the recorded audio, so look at `chunk` rather than `detect`.
+        let clipped_samples = clipped_samples(chunk, order);
         assert!(
             chunk.len() <= self.config.chunk_size,
             "{} > {}",
@@ -85,42 +588,86 @@ impl Segmentation {
         // Move forward through the `Quiet -> Pending -> Active` state machine, by zero or more
         // steps.
 
+        if let State::Cooldown { remaining_chunks } = &mut self.state {
+            if *remaining_chunks == 0 {
+                debug!("Cooldown over");
+                self.state = State::Quiet;
+            } else {
+                *remaining_chunks -= 1;
+            }
+        }
+
         if let State::Quiet = &self.state {
             if !is_quiet {
                 debug!("Mic is hot; segment is now pending");
                 let id = gen_id();
+                (self.min_hot_chunks, self.quiet_scale) = Self::jitter(&self.config, &mut self.rng);
                 self.pending_buf.clear();
+                // Silence tops up whatever pre-roll there is to `lead_bytes`.
+                let padding = self
+                    .lead_silence
+                    .len()
+                    .saturating_sub(self.last_chunk.len());
+                self.pending_buf
+                    .extend_from_slice(&self.lead_silence[..padding]);
                 self.pending_buf.extend_from_slice(&self.last_chunk);
+                let mut stats = self.config.new_stats();
+                stats.envelope.add(&self.pending_buf, order);
+                stats.preroll_bytes = self.pending_buf.len() as u64;
                 self.state = State::Pending {
                     id,
                     total_chunks: if self.last_chunk.is_empty() { 0 } else { 1 },
-                    consecutive_hot_chunks: 0,
+                    hot_chunks: 0,
+                    consecutive_quiet_chunks: 0,
+                    stats,
                 };
             }
         }
 
-        // If pending, maybe discard this segment, or maybe promote it to active.
+        // If pending, maybe discard this segment, or maybe promote it to active. A segment that
+        // just became pending above falls through to here on the same chunk, so with
+        // `min_hot_chunks = 1`, its first hot chunk starts it (with the pre-roll) right away.
         if let State::Pending {
             id,
             total_chunks,
-            consecutive_hot_chunks,
+            hot_chunks,
+            consecutive_quiet_chunks,
+            stats,
         } = &mut self.state
         {
-            if is_quiet {
+            // The end of input can't be tolerated, though.
+            if is_quiet
+                && (*consecutive_quiet_chunks >= self.config.pending_quiet_tolerance
+                    || chunk.is_empty())
+            {
                 debug!("Mic is quiet; pending segment discarded");
                 self.state = State::Quiet;
+            } else if is_quiet {
+                debug!("Mic is quiet; segment is still pending");
+                *consecutive_quiet_chunks += 1;
+                stats.add_chunk(chunk_peak, clipped_samples);
+                stats.envelope.add(chunk, order);
+                self.pending_buf.extend_from_slice(chunk);
+                *total_chunks += 1;
             } else {
-                *consecutive_hot_chunks += 1;
-                if *consecutive_hot_chunks >= self.config.min_hot_chunks {
+                *consecutive_quiet_chunks = 0;
+                *hot_chunks += 1;
+                if *hot_chunks >= self.min_hot_chunks {
+                    // This chunk itself is accounted for once the segment is active, below.
                     let id = std::mem::take(id);
                     events.push(Event::Start { id });
                     events.push(Event::Data(&self.pending_buf));
+                    stats.bytes += self.pending_buf.len() as u64;
                     self.state = State::Active {
                         started: true,
                         total_chunks: *total_chunks,
                         consecutive_quiet_chunks: 0,
+                        stats: std::mem::take(stats),
+                        dip: DipTracker::default(),
                     };
                 } else {
+                    stats.add_chunk(chunk_peak, clipped_samples);
+                    stats.envelope.add(chunk, order);
                     self.pending_buf.extend_from_slice(chunk);
                     *total_chunks += 1;
                 }
@@ -132,13 +679,24 @@ impl Segmentation {
             started,
             total_chunks,
             consecutive_quiet_chunks,
+            stats,
+            dip,
         } = &mut self.state
         {
-            *total_chunks += 1;
             if !*started {
                 events.push(Event::Start { id: gen_id() });
                 *started = true;
+                if !self.lead_silence.is_empty() {
+                    events.push(Event::Data(&self.lead_silence));
+                    stats.envelope.add(&self.lead_silence, order);
+                    stats.bytes += self.lead_silence.len() as u64;
+                    stats.preroll_bytes = self.lead_silence.len() as u64;
+                }
             }
+            *total_chunks += 1;
+            stats.add_chunk(chunk_peak, clipped_samples);
+            stats.envelope.add(chunk, order);
+            stats.bytes += chunk.len() as u64;
             events.push(Event::Data(chunk));
 
             if is_quiet {
@@ -151,38 +709,204 @@ impl Segmentation {
                     debug!("Mic is hot; segment is active");
                 }
                 *consecutive_quiet_chunks = 0;
+                stats.active_bytes = stats.bytes - stats.preroll_bytes;
             }
 
-            if *consecutive_quiet_chunks >= self.config.max_quiet_chunks || chunk.is_empty() {
-                events.push(Event::End);
-                self.state = State::Quiet;
-            } else if *total_chunks >= self.config.max_total_chunks {
-                debug!("Segment exceeded max chunks; rolling over to new segment");
-                events.push(Event::End);
+            let dipped = self
+                .config
+                .relative_dip
+                .is_some_and(|config| dip.accept(&config, level, is_quiet));
+
+            // A segment that rolled over during a quiet tail picks up the rest of it, so it may have
+            // fewer chunks of its own than quiet ones so far.
+            let hot_chunks = total_chunks.saturating_sub(*consecutive_quiet_chunks);
+            let max_quiet_chunks = scale_chunks(
+                effective_max_quiet_chunks(&self.config, hot_chunks, stats.peak),
+                self.quiet_scale,
+            );
+            let roll_over_reason = if *total_chunks >= self.config.max_total_chunks {
+                Some(EndReason::MaxLength)
+            } else if self.config.flush_chunks.is_some_and(|n| *total_chunks >= n) {
+                Some(EndReason::Flush)
+            } else if dipped {
+                Some(EndReason::Dip)
+            } else {
+                None
+            };
+            if *consecutive_quiet_chunks >= max_quiet_chunks || chunk.is_empty() {
+                stats.end_reason = if chunk.is_empty() {
+                    EndReason::Eof
+                } else {
+                    EndReason::Silence
+                };
+                stats.incomplete = chunk.is_empty() && hot_chunks < self.min_hot_chunks;
+                stats.envelope.finish();
+                events.push(Event::End(std::mem::take(stats)));
+                self.state = if self.config.cooldown_chunks > 0 {
+                    State::Cooldown {
+                        remaining_chunks: self.config.cooldown_chunks,
+                    }
+                } else {
+                    State::Quiet
+                };
+            } else if let Some(end_reason) = roll_over_reason {
+                debug!(
+                    "Segment ended ({}); rolling over to new segment",
+                    end_reason.as_str()
+                );
+                stats.end_reason = end_reason;
+                stats.envelope.finish();
+                events.push(Event::End(std::mem::take(stats)));
+                (self.min_hot_chunks, self.quiet_scale) = Self::jitter(&self.config, &mut self.rng);
                 self.state = State::Active {
                     started: false,
                     total_chunks: 0,
                     consecutive_quiet_chunks: *consecutive_quiet_chunks,
+                    stats: self.config.new_stats(),
+                    dip: DipTracker::default(),
                 }
             }
         }
 
-        self.last_chunk.clear();
-        self.last_chunk.extend_from_slice(chunk);
+        match self.config.lead_bytes {
+            None => {
+                self.last_chunk.clear();
+                self.last_chunk.extend_from_slice(chunk);
+            }
+            Some(lead) => {
+                self.last_chunk.extend_from_slice(chunk);
+                let excess = self.last_chunk.len().saturating_sub(lead);
+                self.last_chunk.drain(..excess);
+            }
+        }
 
         events.into_iter()
     }
 }
 
-fn is_quiet(raw_audio: &[u8], threshold: i16) -> bool {
-    let max_sample = raw_audio
-        .chunks(2)
-        .map(|c| i16::from_le_bytes([c[0], c[1]]))
-        .map(|z| z.abs())
-        .max()
-        .unwrap_or(0);
-    trace!("Max sample: {} <=> {}", max_sample, threshold);
-    max_sample <= threshold
+/// Computes how many consecutive quiet chunks end a segment that had `total_chunks` chunks before
+/// its current quiet tail and a peak absolute sample of `peak`.
+///
+/// Without `adaptive_tail`, this is just `max_quiet_chunks`. Otherwise, the tail is interpolated
+/// linearly between the configured bounds by the average of two fractions: the segment's peak as a
+/// fraction of full scale, and its length as a fraction of `max_total_chunks`. So a faint, short
+/// segment gets the minimum tail and a full-scale, maximum-length segment gets the maximum:
+///
+/// ```text
+/// tail = min + (max - min) * (peak / i16::MAX + total_chunks / max_total_chunks) / 2
+/// ```
+fn effective_max_quiet_chunks(config: &Config, total_chunks: u32, peak: i32) -> u32 {
+    let Some(bounds) = config.adaptive_tail else {
+        return config.max_quiet_chunks;
+    };
+    let loudness = (f64::from(peak) / f64::from(i16::MAX)).clamp(0.0, 1.0);
+    let length = if config.max_total_chunks == 0 {
+        1.0
+    } else {
+        (f64::from(total_chunks) / f64::from(config.max_total_chunks)).clamp(0.0, 1.0)
+    };
+    let span = bounds
+        .max_quiet_chunks
+        .saturating_sub(bounds.min_quiet_chunks);
+    bounds.min_quiet_chunks + (f64::from(span) * (loudness + length) / 2.0).round() as u32
+}
+
+/// Scales a number of chunks by a jitter factor, rounding, but keeping it at least 1 unless it was
+/// 0 to begin with.
+fn scale_chunks(chunks: u32, scale: f64) -> u32 {
+    if chunks == 0 {
+        return 0;
+    }
+    ((f64::from(chunks) * scale).round() as u32).max(1)
+}
+
+/// Returns the largest absolute sample in the chunk, after removing its DC offset if requested.
+pub fn chunk_peak(raw_audio: &[u8], remove_dc: bool, order: ByteOrder) -> i32 {
+    let max_sample = abs_samples(raw_audio, remove_dc, order).max().unwrap_or(0);
+    trace!("Max sample: {}", max_sample);
+    max_sample
+}
+
+/// Returns the root mean square of the chunk's samples, after removing its DC offset if requested.
+pub fn chunk_rms(raw_audio: &[u8], remove_dc: bool, order: ByteOrder) -> f64 {
+    let samples = abs_samples(raw_audio, remove_dc, order);
+    let (sum, n) = samples.fold((0.0, 0u64), |(sum, n), z| {
+        (sum + f64::from(z) * f64::from(z), n + 1)
+    });
+    if n == 0 {
+        0.0
+    } else {
+        (sum / n as f64).sqrt()
+    }
+}
+
+/// Returns the `percentile`th percentile (by nearest rank) of absolute samples in the chunk, after
+/// removing its DC offset if requested.
+fn chunk_percentile(raw_audio: &[u8], remove_dc: bool, percentile: f64, order: ByteOrder) -> i32 {
+    let mut samples: Vec<i32> = abs_samples(raw_audio, remove_dc, order).collect();
+    let level = nearest_rank(&mut samples, percentile);
+    trace!("Percentile {} sample: {}", percentile, level);
+    level
+}
+
+/// Returns the `percentile`th percentile (by nearest rank) of `values`, reordering them, or 0 if
+/// there are none.
+fn nearest_rank(values: &mut [i32], percentile: f64) -> i32 {
+    if values.is_empty() {
+        return 0;
+    }
+    let rank = (percentile.clamp(0.0, 100.0) / 100.0 * values.len() as f64).ceil() as usize;
+    let index = rank.clamp(1, values.len()) - 1;
+    *values.select_nth_unstable(index).1
+}
+
+/// Iterates over the absolute values of the chunk's samples, after removing its DC offset if
+/// requested.
+fn abs_samples(
+    raw_audio: &[u8],
+    remove_dc: bool,
+    order: ByteOrder,
+) -> impl Iterator<Item = i32> + '_ {
+    // A short final chunk may end with half a sample, which we ignore.
+    let samples = move || {
+        raw_audio
+            .chunks_exact(2)
+            .map(move |c| i32::from(order.read_i16([c[0], c[1]])))
+    };
+    let offset = if remove_dc { dc_offset(samples()) } else { 0 };
+    samples().map(move |z| (z - offset).abs())
+}
+
+/// Returns what a `level` of absolute samples would be if every sample were multiplied by `gain`,
+/// saturating at full scale. Since both detectors pick out one of the absolute samples, scaling
+/// the level is the same as scaling every sample, but cheaper.
+fn boosted(level: i32, gain: f64) -> i32 {
+    (f64::from(level) * gain).round().min(32768.0) as i32
+}
+
+/// Counts the samples in the chunk that are at full scale.
+fn clipped_samples(raw_audio: &[u8], order: ByteOrder) -> u64 {
+    raw_audio
+        .chunks_exact(2)
+        .filter(|c| matches!(order.read_i16([c[0], c[1]]), i16::MIN | i16::MAX))
+        .count() as u64
+}
+
+/// Computes the mean of the given samples, rounded toward zero.
+fn dc_offset(samples: impl Iterator<Item = i32>) -> i32 {
+    let (sum, n) = samples.fold((0i64, 0i64), |(sum, n), z| (sum + i64::from(z), n + 1));
+    if n == 0 {
+        0
+    } else {
+        (sum / n) as i32
+    }
+}
+
+/// How many bytes to allocate up front for `chunks` chunks of `chunk_size` bytes each.
+fn presize(chunk_size: usize, chunks: u32) -> usize {
+    chunk_size
+        .saturating_mul(chunks as usize)
+        .min(MAX_PRESIZE_BYTES)
 }
 
 #[cfg(test)]
@@ -229,12 +953,17 @@ mod tests {
                 }
                 (_, Event::Start { id }) => result.push(TestEvent::Start { id }),
                 (_, Event::Data(chunk)) => result.push(TestEvent::Data(chunk.into())),
-                (_, Event::End) => result.push(TestEvent::End),
+                (_, Event::End(_)) => result.push(TestEvent::End),
             }
         }
         result
     }
 
+    /// An `Event::End` for use with `test_events`, which ignores its stats.
+    fn end() -> Event<'static> {
+        Event::End(Stats::default())
+    }
+
     struct TestBed {
         pub seg: Segmentation,
         pub ids: Ids,
@@ -248,7 +977,7 @@ mod tests {
             }
         }
         pub fn accept(&mut self, chunk: &[u8]) -> Vec<TestEvent> {
-            test_events(self.seg.accept(chunk, || self.ids.next()))
+            test_events(self.seg.accept(chunk, chunk, || self.ids.next()))
         }
     }
 
@@ -260,6 +989,7 @@ mod tests {
             min_hot_chunks: 2,
             max_quiet_chunks: 3,
             threshold: 0x0100,
+            ..Default::default()
         });
         let id0 = tb.ids.peek();
         let chunk0 = [0x00, 0x00, 0x00, 0x01]; // quiet
@@ -289,11 +1019,158 @@ mod tests {
         assert_eq!(tb.accept(&chunk6), test_events([Event::Data(&chunk6)]));
         assert_eq!(
             tb.accept(&chunk7),
-            test_events([Event::Data(&chunk7), Event::End])
+            test_events([Event::Data(&chunk7), end()])
         );
         assert_eq!(tb.accept(&chunk8), vec![]);
     }
 
+    #[test]
+    fn test_min_hot_chunks_one() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 2,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let chunk_quiet = [0x00, 0x00, 0x01, 0x00];
+        let chunk_hot = [0x00, 0x02, 0x00, 0x03];
+
+        // The first hot chunk starts the segment on the same call, with the pre-roll before it.
+        let id0 = tb.ids.peek();
+        assert_eq!(tb.accept(&chunk_quiet), vec![]);
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            test_events([
+                Event::Start { id: id0 },
+                Event::Data(&chunk_quiet),
+                Event::Data(&chunk_hot),
+            ]),
+        );
+        assert_eq!(
+            tb.accept(&chunk_quiet),
+            test_events([Event::Data(&chunk_quiet)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_quiet),
+            test_events([Event::Data(&chunk_quiet), end()])
+        );
+
+        // Likewise with no pre-roll, when the very first chunk is hot.
+        let mut tb = TestBed::new(tb.seg.config.clone());
+        let id0 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            test_events([Event::Start { id: id0 }, Event::Data(&chunk_hot)]),
+        );
+    }
+
+    #[test]
+    fn test_detect_gain() {
+        let config = Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 2,
+            threshold: 0x0100,
+            ..Default::default()
+        };
+        // Quiet as is, but hot with a gain of 4.
+        let chunk = 0x0080i16
+            .to_le_bytes()
+            .into_iter()
+            .chain((-0x0050i16).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let mut tb = TestBed::new(config.clone());
+        assert_eq!(tb.accept(&chunk), vec![]);
+        assert_eq!(tb.seg.last_level(), (0x0080, 0x0100));
+
+        let mut tb = TestBed::new(Config {
+            detect_gain: Some(4.0),
+            ..config
+        });
+        let id0 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk),
+            test_events([Event::Start { id: id0 }, Event::Data(&chunk)]),
+        );
+        assert_eq!(tb.seg.last_level(), (0x0200, 0x0100));
+        // The gain is only for detection, so the stats are of the audio as recorded.
+        let stats = tb.seg.finish(EndReason::Stopped).unwrap();
+        assert_eq!(stats.peak, 0x0080);
+
+        assert_eq!(boosted(0x0100, 2.5), 0x0280);
+        assert_eq!(boosted(0x4000, 4.0), 32768);
+        assert_eq!(boosted(0, 100.0), 0);
+    }
+
+    #[test]
+    fn test_first_segment_preroll() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            cooldown_chunks: 1,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_quiet = [0x00, 0x00, 0x01, 0x00];
+        let chunk_hot = [0x00, 0x02, 0x00, 0x03];
+        let mut accept = |chunk: &[u8]| {
+            let mut preroll = None;
+            let events = test_events(seg.accept(chunk, chunk, || ids.next()).inspect(|ev| {
+                if let Event::End(stats) = ev {
+                    preroll = Some(stats.preroll_bytes);
+                }
+            }));
+            (events, preroll)
+        };
+
+        // A segment starting on the second-ever chunk gets the first as its pre-roll, just like
+        // any later segment.
+        assert_eq!(accept(&chunk_quiet), (vec![], None));
+        assert_eq!(
+            accept(&chunk_hot),
+            (
+                vec![
+                    TestEvent::Start { id: Ids::id_at(0) },
+                    TestEvent::Data([chunk_quiet, chunk_hot].concat()),
+                ],
+                None
+            )
+        );
+        assert_eq!(
+            accept(&chunk_quiet),
+            (
+                vec![TestEvent::Data(chunk_quiet.to_vec()), TestEvent::End],
+                Some(4)
+            )
+        );
+        // Chunks during the cooldown still prime the pre-roll.
+        assert_eq!(accept(&chunk_quiet), (vec![], None));
+        assert_eq!(accept(&chunk_quiet), (vec![], None));
+        assert_eq!(
+            accept(&chunk_hot),
+            (
+                vec![
+                    TestEvent::Start { id: Ids::id_at(1) },
+                    TestEvent::Data([chunk_quiet, chunk_hot].concat()),
+                ],
+                None
+            )
+        );
+        assert_eq!(
+            accept(&chunk_quiet),
+            (
+                vec![TestEvent::Data(chunk_quiet.to_vec()), TestEvent::End],
+                Some(4)
+            )
+        );
+    }
+
     #[test]
     fn test_max_chunks() {
         let mut tb = TestBed::new(Config {
@@ -302,6 +1179,7 @@ mod tests {
             min_hot_chunks: 2,
             max_quiet_chunks: 3,
             threshold: 0x0100,
+            ..Default::default()
         });
         let chunk_off = [0x01, 0x00, 0x01, 0x00];
         let chunk_on = [0xcc, 0xcc, 0xcc, 0xcc];
@@ -327,7 +1205,7 @@ mod tests {
         }
         assert_eq!(
             tb.accept(&chunk_on), // 10
-            test_events([Event::Data(&chunk_on), Event::End])
+            test_events([Event::Data(&chunk_on), end()])
         );
 
         let id1 = tb.ids.peek();
@@ -340,51 +1218,1117 @@ mod tests {
         }
         assert_eq!(
             tb.accept(&chunk_on),
-            test_events([Event::Data(&chunk_on), Event::End])
+            test_events([Event::Data(&chunk_on), end()])
         );
     }
 
     #[test]
-    fn test_max_chunks_from_start() {
+    fn test_max_chunks_mid_tail() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 4,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let chunk_off = [0x01, 0x00, 0x01, 0x00];
+        let chunk_on = [0xcc, 0xcc, 0xcc, 0xcc];
+
+        tb.accept(&chunk_on);
+        tb.accept(&chunk_on);
+        assert_eq!(
+            tb.accept(&chunk_off),
+            test_events([Event::Data(&chunk_off)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_off),
+            test_events([Event::Data(&chunk_off), end()])
+        );
+        // The segment rolled over two chunks into its quiet tail, so the next one finishes it.
+        let id1 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk_off),
+            test_events([Event::Start { id: id1 }, Event::Data(&chunk_off), end()])
+        );
+        assert_eq!(tb.accept(&chunk_off), vec![]);
+    }
+
+    #[test]
+    fn test_lead_bytes() {
+        let config = |lead_bytes| Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            lead_bytes: Some(lead_bytes),
+            ..Default::default()
+        };
+        let chunk_quiet = [0x01, 0x00, 0x02, 0x00];
+        let chunk_hot = [0x00, 0x02, 0x00, 0x03];
+        let preroll = |events: Vec<Event>| match events.last() {
+            Some(Event::End(stats)) => stats.preroll_bytes,
+            _ => panic!("expected End, got {:?}", events),
+        };
+
+        // Less pre-roll than the lead: silence tops it up.
+        let mut tb = TestBed::new(config(6));
+        assert_eq!(tb.accept(&chunk_quiet), vec![]);
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                TestEvent::Data([&[0, 0][..], &chunk_quiet, &chunk_hot].concat()),
+            ]
+        );
+        let events = tb.seg.accept(&chunk_quiet, &chunk_quiet, || tb.ids.next());
+        assert_eq!(preroll(events.collect()), 6);
+
+        // More: it's trimmed to the lead, keeping the audio just before the segment.
+        let mut tb = TestBed::new(config(2));
+        assert_eq!(tb.accept(&chunk_quiet), vec![]);
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                TestEvent::Data([&chunk_quiet[2..], &chunk_hot].concat()),
+            ]
+        );
+        let events = tb.seg.accept(&chunk_quiet, &chunk_quiet, || tb.ids.next());
+        assert_eq!(preroll(events.collect()), 2);
+
+        // Pre-roll reaches back past the previous chunk, up to the lead.
+        let mut tb = TestBed::new(config(6));
+        let other_quiet = [0x03, 0x00, 0x04, 0x00];
+        assert_eq!(tb.accept(&other_quiet), vec![]);
+        assert_eq!(tb.accept(&chunk_quiet), vec![]);
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                TestEvent::Data([&other_quiet[2..], &chunk_quiet, &chunk_hot].concat()),
+            ]
+        );
+
+        // A segment that a roll-over started has no pre-roll, so its lead is all silence.
+        assert!(tb.seg.roll_over(EndReason::MaxSize).is_some());
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            vec![
+                TestEvent::Start { id: Ids::id_at(1) },
+                TestEvent::Data([&[0; 6][..], &chunk_hot].concat()),
+            ]
+        );
+        let events = tb.seg.accept(&chunk_quiet, &chunk_quiet, || tb.ids.next());
+        assert_eq!(preroll(events.collect()), 6);
+    }
+
+    #[test]
+    fn test_roll_over() {
         let mut tb = TestBed::new(Config {
             chunk_size: 4,
             max_total_chunks: 10,
             min_hot_chunks: 2,
             max_quiet_chunks: 3,
             threshold: 0x0100,
+            ..Default::default()
         });
+        let chunk_off = [0x01, 0x00, 0x01, 0x00];
         let chunk_on = [0xcc, 0xcc, 0xcc, 0xcc];
 
-        // no quiet chunks to start!
+        // Nothing to roll over yet, even while pending.
+        assert_eq!(tb.seg.roll_over(EndReason::MaxSize), None);
         let id0 = tb.ids.peek();
-        assert_eq!(tb.accept(&chunk_on), vec![]); // 1
+        assert_eq!(tb.accept(&chunk_on), vec![]);
+        assert_eq!(tb.seg.roll_over(EndReason::MaxSize), None);
+
         assert_eq!(
-            tb.accept(&chunk_on), // 2
+            tb.accept(&chunk_on),
             test_events([
                 Event::Start { id: id0 },
                 Event::Data(&chunk_on),
                 Event::Data(&chunk_on),
             ])
         );
-        for _ in 3..=9 {
-            assert_eq!(tb.accept(&chunk_on), test_events([Event::Data(&chunk_on)]));
-        }
-        assert_eq!(
-            tb.accept(&chunk_on), // 10
-            test_events([Event::Data(&chunk_on), Event::End])
-        );
+        let stats = tb.seg.roll_over(EndReason::MaxSize).unwrap();
+        assert_eq!(stats.end_reason, EndReason::MaxSize);
+        assert_eq!(stats.bytes, 8);
+        // Not twice, though.
+        assert_eq!(tb.seg.roll_over(EndReason::MaxSize), None);
 
+        // The next chunk starts the next segment, even if it's quiet, with a fresh count toward
+        // `max_total_chunks`.
         let id1 = tb.ids.peek();
         assert_eq!(
-            tb.accept(&chunk_on),
-            test_events([Event::Start { id: id1 }, Event::Data(&chunk_on)])
+            tb.accept(&chunk_off),
+            test_events([Event::Start { id: id1 }, Event::Data(&chunk_off)])
         );
         for _ in 2..=9 {
             assert_eq!(tb.accept(&chunk_on), test_events([Event::Data(&chunk_on)]));
         }
         assert_eq!(
             tb.accept(&chunk_on),
-            test_events([Event::Data(&chunk_on), Event::End])
+            test_events([Event::Data(&chunk_on), end()])
         );
     }
+
+    #[test]
+    fn test_roll_over_mid_tail() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let chunk_off = [0x01, 0x00, 0x01, 0x00];
+        let chunk_on = [0xcc, 0xcc, 0xcc, 0xcc];
+
+        tb.accept(&chunk_on);
+        tb.accept(&chunk_off);
+        tb.accept(&chunk_off);
+        assert!(tb.seg.roll_over(EndReason::MaxSize).is_some());
+        // The next segment picks up the rest of the quiet tail, so this finishes it.
+        let id1 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk_off),
+            test_events([Event::Start { id: id1 }, Event::Data(&chunk_off), end()])
+        );
+        assert_eq!(tb.accept(&chunk_off), vec![]);
+    }
+
+    #[test]
+    fn test_relative_dip() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 100,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            relative_dip: Some(RelativeDip {
+                ratio: 0.25, // about -12 dB
+                min_chunks: 2,
+                running_chunks: 4,
+            }),
+            ..Default::default()
+        });
+        let chunk_loud = [0x00, 0x40, 0x00, 0x40];
+        let chunk_dip = [0x00, 0x08, 0x00, 0x08]; // -18 dB, but still hot
+        let chunk_quiet = [0x80, 0x00, 0x80, 0x00];
+
+        let id0 = tb.ids.peek();
+        assert_eq!(tb.accept(&chunk_loud), vec![]);
+        assert_eq!(
+            tb.accept(&chunk_loud),
+            test_events([
+                Event::Start { id: id0 },
+                Event::Data(&chunk_loud),
+                Event::Data(&chunk_loud),
+            ]),
+        );
+        // Too early in the segment for a dip to count.
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        for _ in 0..8 {
+            assert_eq!(
+                tb.accept(&chunk_loud),
+                test_events([Event::Data(&chunk_loud)])
+            );
+        }
+        // A dip that's too short, or broken up by quiet chunks, doesn't split the segment.
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_loud),
+            test_events([Event::Data(&chunk_loud)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_quiet),
+            test_events([Event::Data(&chunk_quiet)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        assert_eq!(
+            tb.accept(&chunk_loud),
+            test_events([Event::Data(&chunk_loud)])
+        );
+
+        // A long enough dip ends the segment...
+        assert_eq!(
+            tb.accept(&chunk_dip),
+            test_events([Event::Data(&chunk_dip)])
+        );
+        let mut events = tb.seg.accept(&chunk_dip, &chunk_dip, || tb.ids.next());
+        assert_eq!(events.next(), Some(Event::Data(&chunk_dip)));
+        match events.next() {
+            Some(Event::End(stats)) => assert_eq!(stats.end_reason, EndReason::Dip),
+            other => panic!("expected Event::End, got {:?}", other),
+        }
+        assert_eq!(events.next(), None);
+        drop(events);
+
+        // ...and the next one starts right away, since the audio is still hot.
+        let id1 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk_loud),
+            test_events([Event::Start { id: id1 }, Event::Data(&chunk_loud)]),
+        );
+    }
+
+    #[test]
+    fn test_max_chunks_from_start() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let chunk_on = [0xcc, 0xcc, 0xcc, 0xcc];
+
+        // no quiet chunks to start!
+        let id0 = tb.ids.peek();
+        assert_eq!(tb.accept(&chunk_on), vec![]); // 1
+        assert_eq!(
+            tb.accept(&chunk_on), // 2
+            test_events([
+                Event::Start { id: id0 },
+                Event::Data(&chunk_on),
+                Event::Data(&chunk_on),
+            ])
+        );
+        for _ in 3..=9 {
+            assert_eq!(tb.accept(&chunk_on), test_events([Event::Data(&chunk_on)]));
+        }
+        assert_eq!(
+            tb.accept(&chunk_on), // 10
+            test_events([Event::Data(&chunk_on), end()])
+        );
+
+        let id1 = tb.ids.peek();
+        assert_eq!(
+            tb.accept(&chunk_on),
+            test_events([Event::Start { id: id1 }, Event::Data(&chunk_on)])
+        );
+        for _ in 2..=9 {
+            assert_eq!(tb.accept(&chunk_on), test_events([Event::Data(&chunk_on)]));
+        }
+        assert_eq!(
+            tb.accept(&chunk_on),
+            test_events([Event::Data(&chunk_on), end()])
+        );
+    }
+
+    #[test]
+    fn test_flush_interval() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 100,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            flush_chunks: Some(5),
+            ..Default::default()
+        });
+        // Distinct hot chunks, so that we can tell that none are dropped or duplicated.
+        let chunks: Vec<[u8; 4]> = (0..12u8).map(|i| [i, 0x10, i, 0x10]).collect();
+        let mut events: Vec<TestEvent> = vec![];
+        for chunk in &chunks {
+            for ev in tb.accept(chunk) {
+                match (events.last_mut(), ev) {
+                    (Some(TestEvent::Data(buf)), TestEvent::Data(more)) => buf.extend(more),
+                    (_, ev) => events.push(ev),
+                }
+            }
+        }
+        let data = |range: std::ops::Range<usize>| TestEvent::Data(chunks[range].concat());
+        assert_eq!(
+            events,
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                data(0..5),
+                TestEvent::End,
+                TestEvent::Start { id: Ids::id_at(1) },
+                data(5..10),
+                TestEvent::End,
+                TestEvent::Start { id: Ids::id_at(2) },
+                data(10..12),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cooldown() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            cooldown_chunks: 3,
+            ..Default::default()
+        });
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        assert_eq!(
+            tb.accept(&chunk_hot).first(),
+            Some(&TestEvent::Start { id: Ids::id_at(0) })
+        );
+        assert_eq!(tb.accept(&chunk_quiet).last(), Some(&TestEvent::End));
+        // Hot chunks during the cooldown are discarded, and don't start a segment.
+        for _ in 0..3 {
+            assert_eq!(tb.accept(&chunk_hot), vec![]);
+        }
+        // Afterward, hot audio starts a segment as usual.
+        assert_eq!(
+            tb.accept(&chunk_hot),
+            vec![
+                TestEvent::Start { id: Ids::id_at(1) },
+                TestEvent::Data([chunk_hot, chunk_hot].concat()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_remove_dc() {
+        // A "silent" signal riding on a large DC bias: samples hover around 0x2000.
+        let biased: Vec<u8> = [0x2000i16, 0x2010, 0x1ff0, 0x2008]
+            .iter()
+            .flat_map(|z| z.to_le_bytes())
+            .collect();
+        assert!(chunk_peak(&biased, false, ByteOrder::Le) > 0x0100);
+        assert!(chunk_peak(&biased, true, ByteOrder::Le) <= 0x0100);
+
+        // Actual signal is still detected once the bias is removed.
+        let loud: Vec<u8> = [0x2000i16, 0x3000, 0x1000, 0x2000]
+            .iter()
+            .flat_map(|z| z.to_le_bytes())
+            .collect();
+        assert!(chunk_peak(&loud, true, ByteOrder::Le) > 0x0100);
+
+        // Extreme samples mustn't overflow.
+        let extreme: Vec<u8> = [i16::MIN, i16::MAX]
+            .iter()
+            .flat_map(|z| z.to_le_bytes())
+            .collect();
+        assert!(chunk_peak(&extreme, false, ByteOrder::Le) > 0x0100);
+        assert!(chunk_peak(&extreme, true, ByteOrder::Le) > 0x0100);
+    }
+
+    #[test]
+    fn test_byte_order() {
+        let be =
+            |samples: &[i16]| -> Vec<u8> { samples.iter().flat_map(|z| z.to_be_bytes()).collect() };
+        // Read as little-endian, the first of these quiet samples would be a loud 0x1000.
+        let quiet = be(&[0x0010, -0x0001]);
+        assert!(chunk_peak(&quiet, false, ByteOrder::Be) <= 0x0100);
+        assert!(chunk_peak(&quiet, false, ByteOrder::Le) > 0x0100);
+        let loud = be(&[0x0010, 0x2000]);
+        assert!(chunk_peak(&loud, false, ByteOrder::Be) > 0x0100);
+        assert_eq!(chunk_peak(&loud, false, ByteOrder::Be), 0x2000);
+        assert_eq!(
+            clipped_samples(&be(&[i16::MAX, 1, i16::MIN]), ByteOrder::Be),
+            2
+        );
+
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            byte_order: ByteOrder::Be,
+            ..Default::default()
+        });
+        assert_eq!(tb.accept(&quiet), vec![]);
+        assert_eq!(
+            tb.accept(&loud),
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                TestEvent::Data([quiet.clone(), loud.clone()].concat()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_percentile_ignores_spikes() {
+        // Mostly silence, with a few huge pops.
+        let mut samples = [0x0010i16; 100];
+        samples[10] = i16::MAX;
+        samples[50] = i16::MIN;
+        samples[90] = 0x6000;
+        let chunk: Vec<u8> = samples.iter().flat_map(|z| z.to_le_bytes()).collect();
+
+        assert!(chunk_peak(&chunk, false, ByteOrder::Le) > 0x0100);
+        assert_eq!(chunk_percentile(&chunk, false, 95.0, ByteOrder::Le), 0x0010);
+        assert_eq!(chunk_percentile(&chunk, false, 100.0, ByteOrder::Le), 32768);
+        assert_eq!(chunk_percentile(&[], false, 95.0, ByteOrder::Le), 0);
+        assert_eq!(chunk_rms(&[], false, ByteOrder::Le), 0.0);
+        let square: Vec<u8> = [300i16, -300, 300, -300]
+            .iter()
+            .flat_map(|z| z.to_le_bytes())
+            .collect();
+        assert_eq!(chunk_rms(&square, false, ByteOrder::Le), 300.0);
+
+        let config = Config {
+            chunk_size: chunk.len(),
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            ..Default::default()
+        };
+        let mut tb = TestBed::new(Config {
+            detector: Detector::Percentile(95.0),
+            ..config.clone()
+        });
+        assert_eq!(tb.accept(&chunk), vec![]);
+        // The same chunk is hot by peak.
+        let mut tb = TestBed::new(config);
+        assert!(!tb.accept(&chunk).is_empty());
+    }
+
+    #[test]
+    fn test_rolling_threshold_ramp() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 1000,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            rolling_threshold: Some(RollingThreshold {
+                window_chunks: 8,
+                percentile: 50.0,
+            }),
+            ..Default::default()
+        });
+        let chunk_at = |level: i16| [level.to_le_bytes(), level.to_le_bytes()].concat();
+
+        // In a quiet room, the threshold stays where it's configured.
+        for _ in 0..8 {
+            assert_eq!(tb.accept(&chunk_at(0x0010)), vec![]);
+        }
+        assert_eq!(tb.seg.threshold, 0x0100);
+
+        // As the room gets louder, the threshold follows, to the median of the last 8 chunks.
+        let mut last = tb.seg.threshold;
+        for step in 2..=32 {
+            tb.accept(&chunk_at(step * 0x0100));
+            assert!(tb.seg.threshold >= last);
+            last = tb.seg.threshold;
+        }
+        assert_eq!(tb.seg.threshold, 28 * 0x0100);
+
+        // So audio well above the configured threshold no longer keeps a segment going.
+        assert_eq!(tb.accept(&chunk_at(0x1000)).last(), Some(&TestEvent::End));
+        assert_eq!(tb.accept(&chunk_at(0x1000)), vec![]);
+
+        // Once the room quiets down again, the threshold comes back down, but no lower than
+        // configured.
+        for _ in 0..8 {
+            tb.accept(&chunk_at(0x0010));
+        }
+        assert_eq!(tb.seg.threshold, 0x0100);
+        assert!(!tb.accept(&chunk_at(0x0200)).is_empty());
+    }
+
+    #[test]
+    fn test_rolling_threshold_decimation() {
+        let seg = Segmentation::new(Config {
+            rolling_threshold: Some(RollingThreshold {
+                window_chunks: 1000,
+                percentile: 90.0,
+            }),
+            ..Default::default()
+        });
+        assert_eq!(seg.rolling_decimation, 4);
+        let mut tb = TestBed {
+            seg,
+            ids: Ids::new(),
+        };
+        tb.seg.config.chunk_size = 4;
+        tb.seg.config.max_total_chunks = 10;
+        // Only every 4th chunk's level is kept.
+        for level in [0x0200i16, 0x0300, 0x0400, 0x0500] {
+            tb.accept(&[level.to_le_bytes(), level.to_le_bytes()].concat());
+        }
+        assert_eq!(tb.seg.rolling_history, [0x0500]);
+        assert_eq!(tb.seg.threshold, 0x0500);
+    }
+
+    #[test]
+    fn test_adaptive_tail_bounds() {
+        let config = Config {
+            max_total_chunks: 100,
+            max_quiet_chunks: 3,
+            adaptive_tail: Some(TailBounds {
+                min_quiet_chunks: 2,
+                max_quiet_chunks: 12,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(effective_max_quiet_chunks(&config, 0, 0), 2);
+        assert_eq!(
+            effective_max_quiet_chunks(&config, 100, i32::from(i16::MAX)),
+            12
+        );
+        // Beyond-full-scale inputs (e.g., `i16::MIN`) stay within bounds.
+        assert_eq!(effective_max_quiet_chunks(&config, 1000, 32768), 12);
+        // Half loudness, no length: a quarter of the way up.
+        assert_eq!(effective_max_quiet_chunks(&config, 0, 16384), 5);
+
+        let fixed = Config {
+            adaptive_tail: None,
+            ..config
+        };
+        assert_eq!(
+            effective_max_quiet_chunks(&fixed, 100, i32::from(i16::MAX)),
+            3
+        );
+    }
+
+    #[test]
+    fn test_adaptive_tail_extremes() {
+        let config = Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            adaptive_tail: Some(TailBounds {
+                min_quiet_chunks: 1,
+                max_quiet_chunks: 5,
+            }),
+            ..Default::default()
+        };
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        // A faint, short segment ends after the minimum tail.
+        let mut tb = TestBed::new(config.clone());
+        let chunk_faint = [0x01, 0x01, 0x00, 0x00];
+        let events = tb.accept(&chunk_faint);
+        assert_eq!(
+            events.first(),
+            Some(&TestEvent::Start { id: Ids::id_at(0) })
+        );
+        assert_eq!(tb.accept(&chunk_quiet).last(), Some(&TestEvent::End));
+
+        // A full-scale segment gets a longer tail: with 1 of 10 chunks, that's
+        // `1 + 4 * (1.0 + 0.1) / 2 = 3.2`, rounded to 3.
+        let mut tb = TestBed::new(config);
+        let chunk_loud = [0xff, 0x7f, 0x00, 0x80];
+        assert!(!tb.accept(&chunk_loud).is_empty());
+        assert_eq!(
+            tb.accept(&chunk_quiet),
+            vec![TestEvent::Data(chunk_quiet.to_vec())]
+        );
+        assert_eq!(
+            tb.accept(&chunk_quiet),
+            vec![TestEvent::Data(chunk_quiet.to_vec())]
+        );
+        assert_eq!(tb.accept(&chunk_quiet).last(), Some(&TestEvent::End));
+    }
+
+    #[test]
+    fn test_end_stats() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_hot = [0x00, 0x02, 0x00, 0xf0]; // 0x0200, -0x1000
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        seg.accept(&chunk_hot, &chunk_hot, || ids.next())
+            .for_each(drop);
+        let events: Vec<Event> = seg
+            .accept(&chunk_quiet, &chunk_quiet, || ids.next())
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Data(&chunk_quiet),
+                Event::End(Stats {
+                    peak: 0x1000,
+                    clipped_samples: 0,
+                    bytes: 8,
+                    preroll_bytes: 0,
+                    active_bytes: 4,
+                    end_reason: EndReason::Silence,
+                    envelope: Envelope::default(),
+                    incomplete: false,
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clipped_samples() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_clipped = [0xff, 0x7f, 0x00, 0x80]; // i16::MAX, i16::MIN
+        let chunk_hot = [0x00, 0x10, 0xfe, 0x7f]; // 0x1000, i16::MAX - 1
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        // Clipping while pending still counts, once the segment starts, and the chunk that starts
+        // it counts only once.
+        for chunk in [&chunk_hot, &chunk_clipped, &chunk_clipped] {
+            seg.accept(chunk, chunk, || ids.next()).for_each(drop);
+        }
+        let events: Vec<Event> = seg
+            .accept(&chunk_quiet, &chunk_quiet, || ids.next())
+            .collect();
+        assert_eq!(
+            events.last(),
+            Some(&Event::End(Stats {
+                peak: 32768,
+                clipped_samples: 4,
+                bytes: 16,
+                preroll_bytes: 0,
+                active_bytes: 12,
+                end_reason: EndReason::Silence,
+                envelope: Envelope::default(),
+                incomplete: false,
+            }))
+        );
+        assert_eq!(clipped_samples(&chunk_hot, ByteOrder::Le), 0);
+    }
+
+    #[test]
+    fn test_final_partial_chunk() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        // Half a chunk, ending in half a sample.
+        let chunk_partial = [0x00, 0x10, 0x00];
+
+        seg.accept(&chunk_hot, &chunk_hot, || ids.next())
+            .for_each(drop);
+        let events: Vec<Event> = seg
+            .accept(&chunk_partial, &chunk_partial, || ids.next())
+            .collect();
+        assert_eq!(events, vec![Event::Data(&chunk_partial)]);
+        let events: Vec<Event> = seg.accept(&[], &[], || ids.next()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Data(&[]),
+                Event::End(Stats {
+                    peak: 0x1000,
+                    clipped_samples: 0,
+                    bytes: 7,
+                    preroll_bytes: 0,
+                    active_bytes: 7,
+                    end_reason: EndReason::Eof,
+                    envelope: Envelope::default(),
+                    incomplete: false,
+                })
+            ]
+        );
+    }
+
+    #[test]
+    fn test_incomplete_on_eof() {
+        let config = Config {
+            chunk_size: 4,
+            max_total_chunks: 100,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        };
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let ids = Ids::new();
+        let end_stats = |seg: &mut Segmentation, chunk: &[u8]| {
+            seg.accept(chunk, chunk, || ids.next())
+                .find_map(|ev| match ev {
+                    Event::End(stats) => Some(stats),
+                    _ => None,
+                })
+        };
+
+        // A short hot burst never starts a segment, so the end of input just discards it.
+        let mut seg = Segmentation::new(config.clone());
+        assert_eq!(end_stats(&mut seg, &chunk_hot), None);
+        assert_eq!(end_stats(&mut seg, &[]), None);
+
+        // A segment that started is complete, however soon the input ends.
+        let mut seg = Segmentation::new(config.clone());
+        for _ in 0..2 {
+            assert_eq!(end_stats(&mut seg, &chunk_hot), None);
+        }
+        let stats = end_stats(&mut seg, &[]).unwrap();
+        assert_eq!(stats.end_reason, EndReason::Eof);
+        assert!(!stats.incomplete);
+
+        // But a short burst after rolling over is incomplete.
+        let mut seg = Segmentation::new(Config {
+            flush_chunks: Some(3),
+            ..config
+        });
+        for _ in 0..2 {
+            assert_eq!(end_stats(&mut seg, &chunk_hot), None);
+        }
+        let stats = end_stats(&mut seg, &chunk_hot).unwrap();
+        assert_eq!(stats.end_reason, EndReason::Flush);
+        assert!(!stats.incomplete);
+        assert_eq!(end_stats(&mut seg, &chunk_hot), None);
+        let stats = end_stats(&mut seg, &[]).unwrap();
+        assert_eq!(stats.end_reason, EndReason::Eof);
+        assert!(stats.incomplete);
+    }
+
+    #[test]
+    fn test_accept_judged() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+        let mut accept =
+            |chunk: &[u8], hot| test_events(seg.accept_judged(chunk, chunk, hot, || ids.next()));
+
+        // Loud, but judged not hot.
+        assert_eq!(accept(&chunk_hot, Some(false)), vec![]);
+        // Silent, but judged hot.
+        assert_eq!(
+            accept(&chunk_quiet, Some(true)),
+            vec![
+                TestEvent::Start { id: Ids::id_at(0) },
+                TestEvent::Data([chunk_hot, chunk_quiet].concat()),
+            ]
+        );
+        // Without a verdict, the threshold decides.
+        assert_eq!(
+            accept(&chunk_hot, None),
+            vec![TestEvent::Data(chunk_hot.to_vec())]
+        );
+        assert_eq!(
+            accept(&chunk_hot, Some(false)),
+            vec![TestEvent::Data(chunk_hot.to_vec()), TestEvent::End]
+        );
+    }
+
+    #[test]
+    fn test_pending_quiet_tolerance() {
+        let config = Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 3,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        };
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+        let onset = [chunk_hot, chunk_quiet, chunk_hot, chunk_hot];
+
+        // By default, the quiet chunk discards the pending segment, and there aren't enough hot
+        // chunks after it to start another.
+        let mut tb = TestBed::new(config.clone());
+        for chunk in &onset {
+            assert_eq!(tb.accept(chunk), vec![]);
+        }
+
+        let mut tb = TestBed::new(Config {
+            pending_quiet_tolerance: 1,
+            ..config
+        });
+        let id = tb.ids.peek();
+        for chunk in &onset[..3] {
+            assert_eq!(tb.accept(chunk), vec![]);
+        }
+        assert_eq!(
+            tb.accept(&onset[3]),
+            vec![TestEvent::Start { id }, TestEvent::Data(onset.concat()),]
+        );
+
+        // Too many quiet chunks in a row still discard it.
+        let mut tb = TestBed::new(Config {
+            pending_quiet_tolerance: 1,
+            min_hot_chunks: 3,
+            chunk_size: 4,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        for chunk in [chunk_hot, chunk_quiet, chunk_quiet, chunk_hot, chunk_hot] {
+            assert_eq!(tb.accept(&chunk), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut envelope = Envelope::new(2, 4);
+        let samples: [i16; 9] = [1, 2, 3, -4, 5, 6, 7, 8, -9];
+        let raw: Vec<u8> = samples.iter().flat_map(|z| z.to_le_bytes()).collect();
+        envelope.add(&raw[..6], ByteOrder::Le);
+        assert_eq!(envelope.peaks, vec![2]);
+        envelope.add(&raw[6..], ByteOrder::Le);
+        // Four full frames are merged into two, and the next frame is in progress.
+        assert_eq!(envelope.peaks, vec![4, 8]);
+        assert_eq!(envelope.frame_samples, 4);
+        envelope.finish();
+        assert_eq!(envelope.peaks, vec![4, 8, 9]);
+        assert_eq!(
+            Envelope {
+                peaks: vec![0, 16384, 32768],
+                ..Default::default()
+            }
+            .to_hex(),
+            "007fff"
+        );
+    }
+
+    #[test]
+    fn test_envelope_in_stats() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            envelope_frame_samples: 2,
+            envelope_max_frames: 16,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_quiet = [0x10, 0x00, 0x00, 0x00];
+        let chunk_hot = [0x00, 0x10, 0x00, 0x20];
+        seg.accept(&chunk_quiet, &chunk_quiet, || ids.next())
+            .for_each(drop);
+        seg.accept(&chunk_hot, &chunk_hot, || ids.next())
+            .for_each(drop);
+        let events: Vec<Event> = seg
+            .accept(&chunk_quiet, &chunk_quiet, || ids.next())
+            .collect();
+        let Some(Event::End(stats)) = events.last() else {
+            panic!("expected End, got {:?}", events);
+        };
+        // One frame per chunk, including pre-roll.
+        assert_eq!(stats.envelope.peaks, vec![0x10, 0x2000, 0x10]);
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut tb = TestBed::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 2,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+
+        // Nothing to finish while quiet or pending.
+        assert_eq!(tb.seg.finish(EndReason::Stopped), None);
+        assert_eq!(tb.accept(&chunk_hot), vec![]);
+        assert_eq!(tb.seg.finish(EndReason::Stopped), None);
+
+        // The pending segment was discarded, so this one starts afresh, with the previous chunk as
+        // pre-roll.
+        assert_eq!(tb.accept(&chunk_hot), vec![]);
+        assert!(!tb.accept(&chunk_hot).is_empty());
+        assert_eq!(
+            tb.seg.finish(EndReason::Stopped),
+            Some(Stats {
+                peak: 0x1000,
+                clipped_samples: 0,
+                bytes: 12,
+                preroll_bytes: 4,
+                active_bytes: 8,
+                end_reason: EndReason::Stopped,
+                envelope: Envelope::default(),
+                incomplete: false,
+            })
+        );
+        assert_eq!(tb.seg.finish(EndReason::Stopped), None);
+    }
+
+    #[test]
+    fn test_separate_detection_chunk() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 10,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 1,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        // Loud raw audio doesn't trigger if the detection copy is quiet...
+        let events = test_events(seg.accept(&chunk_hot, &chunk_quiet, || ids.next()));
+        assert_eq!(events, vec![]);
+
+        // ...and quiet raw audio does, if the detection copy is hot. Either way, only the raw
+        // audio is emitted.
+        let id0 = ids.peek();
+        let events = test_events(seg.accept(&chunk_quiet, &chunk_hot, || ids.next()));
+        assert_eq!(
+            events,
+            test_events([
+                Event::Start { id: id0 },
+                Event::Data(&chunk_hot),
+                Event::Data(&chunk_quiet),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_jitter() {
+        let config = Config {
+            chunk_size: 4,
+            max_total_chunks: 100,
+            min_hot_chunks: 10,
+            max_quiet_chunks: 10,
+            threshold: 0x0100,
+            jitter: Some(Jitter {
+                fraction: 0.2,
+                seed: 1,
+            }),
+            ..Default::default()
+        };
+
+        // Draws stay within the fraction, and the same seed draws the same thresholds.
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut same_rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let (min_hot_chunks, quiet_scale) = Segmentation::jitter(&config, &mut rng);
+            assert!((8..=12).contains(&min_hot_chunks), "{}", min_hot_chunks);
+            assert!((0.8..=1.2).contains(&quiet_scale), "{}", quiet_scale);
+            assert_eq!(
+                Segmentation::jitter(&config, &mut same_rng),
+                (min_hot_chunks, quiet_scale)
+            );
+        }
+        let unjittered = Config {
+            jitter: None,
+            ..config.clone()
+        };
+        assert_eq!(Segmentation::jitter(&unjittered, &mut rng), (10, 1.0));
+
+        // A segment starts once it has its own `min_hot_chunks` hot chunks.
+        let mut tb = TestBed::new(config);
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let mut hot_chunks = 0;
+        while tb.accept(&chunk_hot).is_empty() {
+            hot_chunks += 1;
+        }
+        assert_eq!(hot_chunks + 1, tb.seg.min_hot_chunks);
+    }
+
+    #[test]
+    fn test_active_bytes() {
+        let mut seg = Segmentation::new(Config {
+            chunk_size: 4,
+            max_total_chunks: 100,
+            min_hot_chunks: 1,
+            max_quiet_chunks: 3,
+            threshold: 0x0100,
+            ..Default::default()
+        });
+        let ids = Ids::new();
+        let chunk_hot = [0x00, 0x10, 0x00, 0x10];
+        let chunk_quiet = [0x00, 0x00, 0x00, 0x00];
+
+        // A single hot chunk: most of the recording is its pre-roll and quiet tail.
+        let mut stats = None;
+        for chunk in [
+            &chunk_quiet,
+            &chunk_hot,
+            &chunk_quiet,
+            &chunk_quiet,
+            &chunk_quiet,
+        ] {
+            for event in seg.accept(chunk, chunk, || ids.next()) {
+                if let Event::End(s) = event {
+                    stats = Some(s);
+                }
+            }
+        }
+        let stats = stats.expect("segment should have ended");
+        assert_eq!(stats.bytes, 20);
+        assert_eq!(stats.preroll_bytes, 4);
+        assert_eq!(stats.active_bytes, 4);
+
+        // Quiet chunks between hot ones count, up to the last hot one.
+        let mut stats = None;
+        let chunks = [
+            &chunk_hot,
+            &chunk_quiet,
+            &chunk_hot,
+            &chunk_quiet,
+            &chunk_quiet,
+        ];
+        for chunk in chunks.into_iter().chain([&chunk_quiet]) {
+            for event in seg.accept(chunk, chunk, || ids.next()) {
+                if let Event::End(s) = event {
+                    stats = Some(s);
+                }
+            }
+        }
+        let stats = stats.expect("segment should have ended");
+        assert_eq!(stats.bytes, 28);
+        assert_eq!(stats.preroll_bytes, 4);
+        assert_eq!(stats.active_bytes, 12);
+    }
+
+    #[test]
+    fn test_presize() {
+        assert_eq!(presize(4, 3), 12);
+        assert_eq!(presize(16384, u32::MAX), MAX_PRESIZE_BYTES);
+        assert_eq!(presize(usize::MAX, 2), MAX_PRESIZE_BYTES);
+        // Doesn't try to allocate it all up front.
+        let seg = Segmentation::new(Config {
+            chunk_size: 16384,
+            min_hot_chunks: u32::MAX,
+            ..Default::default()
+        });
+        assert!(seg.pending_buf.capacity() <= MAX_PRESIZE_BYTES);
+    }
 }
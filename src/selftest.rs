@@ -0,0 +1,240 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{error, info};
+
+use crate::storage::FilePermissions;
+use crate::{encoder, input, seg, soxi, InputSource};
+use crate::{BYTES_PER_SEC, CHUNK_SIZE, MAX_QUIET_CHUNKS, MAX_TOTAL_CHUNKS, MIN_HOT_CHUNKS};
+use crate::{RAW_AUDIO_ARGS, SAMPLE_RATE};
+
+/// Length of the test tone.
+const TONE_SECS: f64 = 3.0;
+/// How long to wait before playing the tone, so that recording is underway.
+const LEAD_IN: Duration = Duration::from_secs(1);
+/// How long to record: long enough for the lead-in, the tone, and the quiet tail after it.
+const RECORD_SECS: u64 = 10;
+
+/// A segment recorded during the self-test.
+#[derive(Debug)]
+struct Recorded {
+    peak: i32,
+    duration_secs: f64,
+}
+
+/// Records for a few seconds while playing a test tone, and checks that the tone came out as a
+/// recording, exercising `rec(1)`, segmentation, and encoding together. This expects the audio
+/// output to be looped back to the input, as with an instrument's line-out.
+///
+/// It records from `source`, as recording would. With `split_channels`, only the first of the
+/// channels is tested.
+///
+/// The temporary directory of recordings is removed if the test passes, and left for inspection if
+/// not.
+pub fn run(threshold: i16, mut source: InputSource) -> anyhow::Result<()> {
+    if let Some(map) = &mut source.channel_map {
+        map.truncate(1);
+    }
+    let dir = std::env::temp_dir().join(format!("reccon-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+    info!(
+        "Self-test: recording for {} seconds while playing a {}-second test tone",
+        RECORD_SECS, TONE_SECS
+    );
+    let recorded = record(&dir, threshold, &source)?;
+    match verify(&recorded, threshold) {
+        Ok(()) => {
+            let _ = std::fs::remove_dir_all(&dir);
+            info!("Self-test passed");
+            Ok(())
+        }
+        Err(e) => {
+            error!("Recordings from the self-test are in {}", dir.display());
+            Err(e)
+        }
+    }
+}
+
+/// Plays the test tone while recording into `dir`, returning what was recorded.
+fn record(dir: &Path, threshold: i16, source: &InputSource) -> anyhow::Result<Vec<Recorded>> {
+    let mut rec = source
+        .command(RAW_AUDIO_ARGS)
+        .args(["trim", "0", &RECORD_SECS.to_string()])
+        .stdout(Stdio::piped())
+        .spawn()
+        .context("Failed to spawn rec(1); is SoX installed?")?;
+    let mut input = input::Input::direct(rec.stdout.take().unwrap());
+    let player = std::thread::spawn(|| {
+        std::thread::sleep(LEAD_IN);
+        Command::new("play")
+            .args(["-q", "-n", "synth", &TONE_SECS.to_string()])
+            .args(["sine", "440", "vol", "0.5"])
+            .status()
+    });
+
+    let mut seg = seg::Segmentation::new(seg::Config {
+        chunk_size: CHUNK_SIZE,
+        max_total_chunks: MAX_TOTAL_CHUNKS,
+        min_hot_chunks: MIN_HOT_CHUNKS,
+        max_quiet_chunks: MAX_QUIET_CHUNKS,
+        threshold,
+        ..Default::default()
+    });
+    let mut chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+    let mut detect_chunk: Vec<u8> = Vec::with_capacity(CHUNK_SIZE);
+    let mut active: Option<(Child, PathBuf)> = None;
+    let mut finished: Vec<(seg::Stats, PathBuf)> = Vec::new();
+    let mut next_id = 0;
+    loop {
+        input.read(CHUNK_SIZE, &mut chunk, &mut detect_chunk)?;
+        let gen_id = || {
+            next_id += 1;
+            next_id.to_string()
+        };
+        for ev in seg.accept(&chunk, &detect_chunk, gen_id) {
+            match ev {
+                seg::Event::Start { id } => {
+                    let path = dir.join(format!("selftest-{}.flac", id));
+                    let permissions = FilePermissions::default();
+                    let encoder = encoder::spawn(&path, RAW_AUDIO_ARGS, None, true, &permissions)?;
+                    active = Some((encoder, path));
+                }
+                seg::Event::Data(data) => {
+                    let (encoder, _) = active.as_mut().expect("Data with no active segment");
+                    encoder
+                        .stdin
+                        .as_mut()
+                        .unwrap()
+                        .write_all(data)
+                        .context("Failed to write to encoder")?;
+                }
+                seg::Event::End(stats) => {
+                    let (mut encoder, path) = active.take().expect("End with no active segment");
+                    drop(encoder.stdin.take());
+                    let status = encoder.wait().context("Failed to wait for encoder")?;
+                    if !status.success() {
+                        anyhow::bail!("Encoder exited unhealthy: {}", status);
+                    }
+                    finished.push((stats, path));
+                }
+            }
+        }
+        if chunk.is_empty() {
+            break;
+        }
+    }
+    let status = rec.wait().context("Failed to wait for rec(1)")?;
+    if !status.success() {
+        anyhow::bail!("rec(1) exited unhealthy: {}", status);
+    }
+    match player.join().unwrap() {
+        Ok(status) if status.success() => {}
+        Ok(status) => anyhow::bail!("play(1) exited unhealthy: {}", status),
+        Err(e) => return Err(e).context("Failed to spawn play(1)"),
+    }
+
+    let soxi = soxi::Soxi::new();
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start async runtime")?;
+    let mut recorded = Vec::new();
+    for (stats, path) in finished {
+        let samples: u64 = rt
+            .block_on(soxi.query("-s", &path))?
+            .parse()
+            .with_context(|| format!("Invalid sample count for {}", path.display()))?;
+        recorded.push(Recorded {
+            peak: stats.peak,
+            duration_secs: samples as f64 / SAMPLE_RATE as f64,
+        });
+    }
+    Ok(recorded)
+}
+
+/// Checks that the test tone produced exactly one recording, which was loud enough and about as
+/// long as the tone plus its quiet tail.
+fn verify(recorded: &[Recorded], threshold: i16) -> anyhow::Result<()> {
+    let [segment] = recorded else {
+        anyhow::bail!(
+            "Expected 1 recording of the test tone, but got {}; is the output looped back to the \
+             input, and is the threshold ({}) below the tone's level?",
+            recorded.len(),
+            threshold
+        );
+    };
+    info!(
+        "Recorded the test tone with peak {:.3} and duration {:.2} seconds",
+        f64::from(segment.peak) / f64::from(i16::MAX),
+        segment.duration_secs
+    );
+    if segment.peak <= i32::from(threshold) {
+        anyhow::bail!(
+            "Test tone's peak ({}) should be above the threshold ({})",
+            segment.peak,
+            threshold
+        );
+    }
+    // The recording runs from about when the tone starts (give or take a chunk of pre-roll) until
+    // the quiet tail ends it.
+    let tail_secs = (MAX_QUIET_CHUNKS as usize * CHUNK_SIZE) as f64 / BYTES_PER_SEC as f64;
+    let chunk_secs = CHUNK_SIZE as f64 / BYTES_PER_SEC as f64;
+    let min = TONE_SECS;
+    let max = TONE_SECS + tail_secs + 2.0 * chunk_secs;
+    if !(min..=max).contains(&segment.duration_secs) {
+        anyhow::bail!(
+            "Test tone's recording should last between {:.2} and {:.2} seconds, but lasted {:.2}",
+            min,
+            max,
+            segment.duration_secs
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify() {
+        let good = Recorded {
+            peak: 16000,
+            duration_secs: 8.2,
+        };
+        assert!(verify(&[good], 1000).is_ok());
+
+        assert!(verify(&[], 1000).is_err());
+        let two = [
+            Recorded {
+                peak: 16000,
+                duration_secs: 1.5,
+            },
+            Recorded {
+                peak: 16000,
+                duration_secs: 6.5,
+            },
+        ];
+        assert!(verify(&two, 1000).is_err());
+
+        let quiet = Recorded {
+            peak: 500,
+            duration_secs: 8.2,
+        };
+        assert!(verify(&[quiet], 1000).is_err());
+        let short = Recorded {
+            peak: 16000,
+            duration_secs: 2.0,
+        };
+        assert!(verify(&[short], 1000).is_err());
+        let long = Recorded {
+            peak: 16000,
+            duration_secs: 30.0,
+        };
+        assert!(verify(&[long], 1000).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{debug, warn};
+use serde_json::Value;
+
+use crate::{config, gcs};
+
+/// Readings from a [`Sensor`], as metadata to add to a recording's.
+pub type Readings = serde_json::Map<String, Value>;
+
+/// An external command, per `sensor_command`, that reports readings (e.g., temperature) from
+/// sensors as JSON, for each segment as it starts.
+pub struct Sensor {
+    command: Vec<String>,
+    timeout: Duration,
+    runtime: tokio::runtime::Handle,
+}
+
+impl Sensor {
+    pub fn new(
+        command: Vec<String>,
+        timeout: Duration,
+        runtime: tokio::runtime::Handle,
+    ) -> anyhow::Result<Self> {
+        config::check_command("sensor_command", &command)?;
+        Ok(Self {
+            command,
+            timeout,
+            runtime,
+        })
+    }
+
+    /// Starts taking readings for segment `id` in the background, so that starting the segment
+    /// never waits on the command. If that fails, it's logged, and there are no readings.
+    pub fn start(&self, id: &str) -> tokio::task::JoinHandle<Readings> {
+        let (command, timeout, id) = (self.command.clone(), self.timeout, id.to_string());
+        self.runtime.spawn(async move {
+            match read(&command, timeout, &id).await {
+                Ok(readings) => {
+                    debug!("Read {} sensor value(s) for segment {}", readings.len(), id);
+                    readings
+                }
+                Err(e) => {
+                    warn!("sensor_command failed for segment {}: {:#}", id, e);
+                    Readings::new()
+                }
+            }
+        })
+    }
+}
+
+/// Runs `command` for segment `id`, and parses what it prints, killing it if it takes longer
+/// than `timeout`.
+async fn read(command: &[String], timeout: Duration, id: &str) -> anyhow::Result<Readings> {
+    let mut child = tokio::process::Command::new(&command[0]);
+    child
+        .args(&command[1..])
+        .env("RECCON_ID", id)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true);
+    let output = tokio::time::timeout(timeout, child.output())
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out after {:?}", timeout))?
+        .with_context(|| format!("Failed to spawn {}", command[0]))?;
+    if !output.status.success() {
+        anyhow::bail!("{} exited with {}", command[0], output.status);
+    }
+    let value = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("{} didn't print valid JSON", command[0]))?;
+    flatten(value)
+}
+
+/// Turns a JSON object of readings into metadata, whose values are all strings: strings are kept
+/// as they are, `null`s are left out, and anything else is kept as JSON, like `21.5` or `[1,2]`.
+fn flatten(value: Value) -> anyhow::Result<Readings> {
+    let Value::Object(object) = value else {
+        anyhow::bail!("Need a JSON object of readings, but got {}", value);
+    };
+    Ok(object
+        .into_iter()
+        .filter_map(|(key, value)| match value {
+            Value::Null => None,
+            Value::String(s) => Some((key, s.into())),
+            value => Some((key, value.to_string().into())),
+        })
+        .collect())
+}
+
+/// Adds `readings` for segment `id` to its `metadata`, leaving out (with a warning) any that would
+/// replace `reccon`'s own keys or others already there, including under the names that `renames`
+/// (per `metadata_keys`) gives them in GCS. Keys are compared case-insensitively, as they are in
+/// HTTP headers.
+pub fn merge(
+    metadata: &mut serde_json::Map<String, Value>,
+    readings: &Readings,
+    renames: Option<&BTreeMap<String, String>>,
+    id: &str,
+) {
+    for (key, value) in readings {
+        let taken = key.is_empty()
+            || gcs::RESERVED_METADATA_KEYS
+                .iter()
+                .copied()
+                .chain(metadata.keys().map(String::as_str))
+                .chain(
+                    renames
+                        .into_iter()
+                        .flat_map(|r| r.values())
+                        .map(String::as_str),
+                )
+                .any(|other| other.eq_ignore_ascii_case(key));
+        if taken {
+            warn!(
+                "Leaving out sensor reading {:?} for segment {}, which would replace metadata",
+                key, id
+            );
+            continue;
+        }
+        metadata.insert(key.clone(), value.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flatten() {
+        let mut object = serde_json::Map::new();
+        object.insert("temperature-c".to_string(), 21.5.into());
+        object.insert("station".to_string(), "north".into());
+        object.insert("raining".to_string(), false.into());
+        object.insert("wind".to_string(), Value::Null);
+        let readings = flatten(object.into()).unwrap();
+        assert_eq!(readings["temperature-c"], "21.5");
+        assert_eq!(readings["station"], "north");
+        assert_eq!(readings["raining"], "false");
+        assert!(!readings.contains_key("wind"));
+        assert!(flatten(Value::from(vec![1, 2])).is_err());
+        assert!(flatten(Value::Null).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("started-at".to_string(), "2024-01-01T12:00:00Z".into());
+        metadata.insert("site".to_string(), "marsh".into());
+        let mut readings = Readings::new();
+        readings.insert("humidity-pct".to_string(), "64".into());
+        readings.insert("Site".to_string(), "elsewhere".into());
+        readings.insert("duration-secs".to_string(), "0".into());
+        readings.insert(String::new(), "x".into());
+        merge(&mut metadata, &readings, None, "20240101T120000");
+        assert_eq!(metadata.len(), 3);
+        assert_eq!(metadata["humidity-pct"], "64");
+        assert_eq!(metadata["site"], "marsh");
+        assert!(!metadata.contains_key("duration-secs"));
+
+        // A reading mustn't take the name that a key of `reccon`'s gets in GCS either.
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("samples".to_string(), "48000".into());
+        let renames = BTreeMap::from([("samples".to_string(), "audio_samples".to_string())]);
+        let mut readings = Readings::new();
+        readings.insert("Audio_Samples".to_string(), "0".into());
+        readings.insert("humidity-pct".to_string(), "64".into());
+        merge(&mut metadata, &readings, Some(&renames), "20240101T120000");
+        let keys: Vec<&str> = metadata.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["humidity-pct", "samples"]);
+    }
+}
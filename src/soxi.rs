@@ -0,0 +1,176 @@
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+
+use log::warn;
+
+/// How many times to run `soxi(1)` for a query before giving up.
+const ATTEMPTS: u32 = 3;
+/// Delay between attempts, which doubles each time.
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Runs `soxi(1)` queries against finished recordings, retrying transient failures (like when
+/// the system is under load, or when `soxi(1)` gets to a file before its metadata has landed and
+/// prints nothing).
+pub struct Soxi {
+    program: OsString,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl Soxi {
+    pub fn new() -> Self {
+        Self::with_program("soxi")
+    }
+
+    /// Uses the given program instead of `soxi` from the `PATH`.
+    pub fn with_program(program: impl Into<OsString>) -> Self {
+        Self {
+            program: program.into(),
+            attempts: ATTEMPTS,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Runs `soxi $query $file` and returns the output (with trailing whitespace trimmed).
+    pub async fn query(&self, query: &str, file: &Path) -> anyhow::Result<String> {
+        let mut delay = self.retry_delay;
+        let mut attempt = 1;
+        loop {
+            match self.query_once(query, file).await {
+                Err(e) if attempt < self.attempts => {
+                    warn!(
+                        "soxi {:?} failed (attempt {}/{}); retrying: {:#}",
+                        query, attempt, self.attempts, e
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn query_once(&self, query: &str, file: &Path) -> anyhow::Result<String> {
+        let output = tokio::process::Command::new(&self.program)
+            .arg(query)
+            .arg(file)
+            // So that it doesn't outlive an upload given up on, per `upload_deadline_secs`.
+            .kill_on_drop(true)
+            .output()
+            .await?;
+        if !output.status.success() {
+            anyhow::bail!(
+                "Failed to query soxi {:?} for {} ({}): {}",
+                query,
+                file.display(),
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        let mut s: String = match String::from_utf8(output.stdout) {
+            Ok(s) => s,
+            Err(e) => String::from_utf8_lossy(&e.into_bytes()).into_owned(),
+        };
+        s.truncate(s.trim_end().len());
+        if s.is_empty() {
+            anyhow::bail!("soxi {:?} printed nothing for {}", query, file.display());
+        }
+        Ok(s)
+    }
+}
+
+/// Flushes a just-finished recording to storage, so that `soxi(1)` sees all of it, down to the
+/// metadata that the encoder writes last. This is best effort: failing just means that queries
+/// might have to be retried.
+pub async fn sync(file: &Path) {
+    let res = match tokio::fs::File::open(file).await {
+        Ok(f) => f.sync_all().await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!(
+            "Failed to sync {} before measuring it: {}",
+            file.display(),
+            e
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+
+    use tempfile::TempDir;
+
+    /// Writes a fake `soxi` that fails `failures` times before printing `48000`, in a directory
+    /// that's removed once it's dropped.
+    fn flaky_soxi(failures: u32) -> (TempDir, PathBuf) {
+        flaky_soxi_with(failures, "echo busy >&2; exit 1")
+    }
+
+    /// Like `flaky_soxi`, but each failure runs the shell command `fail`.
+    fn flaky_soxi_with(failures: u32, fail: &str) -> (TempDir, PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let script = dir.path().join("soxi");
+        let count = dir.path().join("count");
+        std::fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                 n=$(cat '{count}' 2>/dev/null || echo 0)\n\
+                 echo $((n + 1)) >'{count}'\n\
+                 if [ \"$n\" -lt {failures} ]; then {fail}; fi\n\
+                 echo 48000\n",
+                count = count.display(),
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        (dir, script)
+    }
+
+    fn soxi(program: PathBuf) -> Soxi {
+        Soxi {
+            retry_delay: Duration::ZERO,
+            ..Soxi::with_program(program)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_transient_failures() {
+        let (_dir, program) = flaky_soxi(2);
+        let soxi = soxi(program);
+        let rate = soxi.query("-r", Path::new("x.flac")).await.unwrap();
+        assert_eq!(rate, "48000");
+    }
+
+    #[tokio::test]
+    async fn test_retries_empty_output() {
+        // As when the file's metadata hasn't landed yet.
+        let (_dir, program) = flaky_soxi_with(1, "exit 0");
+        let flaky = soxi(program);
+        let samples = flaky.query("-s", Path::new("x.flac")).await.unwrap();
+        assert_eq!(samples, "48000");
+
+        let (_dir, program) = flaky_soxi_with(3, "exit 0");
+        let broken = soxi(program);
+        let err = broken.query("-s", Path::new("x.flac")).await.unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("printed nothing"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_attempts() {
+        let (_dir, program) = flaky_soxi(3);
+        let soxi = soxi(program);
+        let err = soxi.query("-r", Path::new("x.flac")).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("busy"), "{:#}", err);
+    }
+}
@@ -0,0 +1,353 @@
+use std::future::Future;
+use std::io;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::Context;
+use log::{info, warn};
+
+/// How many times to attempt a filesystem operation that fails because storage went away.
+const RETRY_ATTEMPTS: u32 = 4;
+/// Delay before the first retry; doubles on each subsequent retry.
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+/// How long a single filesystem operation may take before it's considered hung.
+const OP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often to check whether the primary storage directory has come back.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_FILENAME: &str = ".reccon-probe";
+
+/// Directories that recordings are written to: the primary `storage_dir` (or several, with
+/// `storage_dirs`), and optionally a local `fallback_dir` for when the primary (e.g., a network
+/// mount) becomes unavailable.
+///
+/// With several primaries, new segments take turns among them, round-robin, to spread writes
+/// across disks. They fail together: any is marked as failed when a finalization step finds it
+/// gone (`EIO`, `ESTALE`, `ENOTCONN`, or an operation that hangs past a timeout), or when an
+/// encoder writing to it dies mid-segment, since the error doesn't say which it was. While they're
+/// failed, new segments are written to the fallback directory, and [`Storage::watch`]
+/// periodically probes each primary by writing and removing a small file. Once the probes all
+/// succeed, new segments go to the primaries again. Recordings already written to the fallback
+/// directory stay there, and are uploaded from there like any others.
+pub struct Storage {
+    primaries: Vec<PathBuf>,
+    fallback: Option<PathBuf>,
+    primary_ok: AtomicBool,
+    /// How many segments have started in the primaries, to pick the next one's.
+    started: AtomicUsize,
+}
+
+impl Storage {
+    /// Needs at least one primary.
+    pub fn new(primaries: Vec<PathBuf>, fallback: Option<PathBuf>) -> Self {
+        assert!(!primaries.is_empty(), "Need at least one storage directory");
+        Self {
+            primaries,
+            fallback,
+            primary_ok: AtomicBool::new(true),
+            started: AtomicUsize::new(0),
+        }
+    }
+
+    /// Directory in which to start a new segment. With several primaries, each call picks the
+    /// next in turn, so this should be called once per segment.
+    pub fn next_dir(&self) -> &Path {
+        match &self.fallback {
+            Some(fallback) if !self.primary_ok.load(Ordering::SeqCst) => fallback,
+            _ => {
+                let n = self.started.fetch_add(1, Ordering::SeqCst);
+                &self.primaries[n % self.primaries.len()]
+            }
+        }
+    }
+
+    pub fn fallback_dir(&self) -> Option<&Path> {
+        self.fallback.as_deref()
+    }
+
+    /// Switches new segments to the fallback directory, if there is one.
+    pub fn mark_failed(&self) {
+        if let Some(fallback) = &self.fallback {
+            if self.primary_ok.swap(false, Ordering::SeqCst) {
+                warn!(
+                    "Storage directory {} is unavailable; recording to {} instead",
+                    self.describe_primaries(),
+                    fallback.display()
+                );
+            }
+        }
+    }
+
+    /// Marks the primary as failed if the given error indicates that it's gone.
+    pub fn check_error(&self, e: &io::Error) {
+        if is_storage_gone(e) {
+            self.mark_failed();
+        }
+    }
+
+    /// Probes the primary storage directory while it's marked as failed, forever.
+    pub async fn watch(&self) {
+        if self.fallback.is_none() {
+            return;
+        }
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            if self.primary_ok.load(Ordering::SeqCst) {
+                continue;
+            }
+            let mut all_ok = true;
+            for primary in &self.primaries {
+                match tokio::time::timeout(OP_TIMEOUT, probe(primary)).await {
+                    Ok(Ok(())) => continue,
+                    Ok(Err(e)) => warn!(
+                        "Storage directory {} still unavailable: {}",
+                        primary.display(),
+                        e
+                    ),
+                    Err(_) => warn!(
+                        "Storage directory {} still unavailable: probe timed out",
+                        primary.display()
+                    ),
+                }
+                all_ok = false;
+            }
+            if all_ok {
+                info!(
+                    "Storage directory {} is available again",
+                    self.describe_primaries()
+                );
+                self.primary_ok.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// The primaries, for logs.
+    fn describe_primaries(&self) -> String {
+        let names: Vec<_> = self
+            .primaries
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        names.join(", ")
+    }
+}
+
+async fn probe(dir: &Path) -> io::Result<()> {
+    let path = dir.join(PROBE_FILENAME);
+    tokio::fs::write(&path, b"").await?;
+    tokio::fs::remove_file(&path).await
+}
+
+/// Permissions and ownership for new recordings, instead of those implied by the process umask.
+#[derive(Debug, Clone, Default)]
+pub struct FilePermissions {
+    /// Permission bits, like `0o640`.
+    pub mode: Option<u32>,
+    /// Group ID to give each file.
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    /// Creates an empty file at `path` with these permissions, so that an encoder writing to it
+    /// later keeps them.
+    pub fn create(&self, path: &Path) -> io::Result<()> {
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if let Some(mode) = self.mode {
+            options.mode(mode);
+        }
+        options.open(path)?;
+        self.apply(path)
+    }
+
+    /// Applies these permissions to an existing file.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        // The mode passed at creation is masked by the umask, so set it again explicitly.
+        if let Some(mode) = self.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+        }
+        if let Some(gid) = self.gid {
+            std::os::unix::fs::chown(path, None, Some(gid))?;
+        }
+        Ok(())
+    }
+}
+
+/// Resolves a group given by name or numeric ID, per `/etc/group`.
+pub fn resolve_group(group: &str) -> anyhow::Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let groups = std::fs::read_to_string("/etc/group").context("Failed to read /etc/group")?;
+    find_group(&groups, group).with_context(|| format!("No such group: {:?}", group))
+}
+
+/// Finds the ID of the named group in the contents of an `/etc/group` file.
+fn find_group(groups: &str, name: &str) -> Option<u32> {
+    groups.lines().find_map(|line| {
+        let mut fields = line.split(':');
+        if fields.next()? != name {
+            return None;
+        }
+        fields.nth(1)?.parse().ok()
+    })
+}
+
+/// Whether an I/O error suggests that the underlying storage has gone away (e.g., a network mount
+/// was disconnected), as opposed to an ordinary problem with a single file.
+pub fn is_storage_gone(e: &io::Error) -> bool {
+    const EIO: i32 = 5;
+    #[cfg(target_os = "linux")]
+    const ESTALE: i32 = 116;
+    #[cfg(target_os = "linux")]
+    const ENOTCONN: i32 = 107;
+    #[cfg(not(target_os = "linux"))]
+    const ESTALE: i32 = 70;
+    #[cfg(not(target_os = "linux"))]
+    const ENOTCONN: i32 = 57;
+    e.kind() == io::ErrorKind::TimedOut || matches!(e.raw_os_error(), Some(EIO | ESTALE | ENOTCONN))
+}
+
+/// Whether a rename to `to` that failed with `e` had already happened: its source is gone, but
+/// `to` exists. Renames are how a segment moves from one stage to the next, so this means that
+/// something else already moved it along.
+pub fn already_renamed(e: &io::Error, to: &Path) -> bool {
+    e.kind() == io::ErrorKind::NotFound && to.exists()
+}
+
+/// Resolves `dir` like [`std::fs::canonicalize`], even if it doesn't exist yet: as much of it as
+/// exists is canonicalized, and the rest appended, so that neither symlinks nor relative paths
+/// hide that two directories overlap.
+pub fn canonical_dir(dir: &Path) -> PathBuf {
+    for ancestor in dir.ancestors() {
+        let existing = match ancestor.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => ancestor,
+        };
+        if let Ok(canonical) = std::fs::canonicalize(existing) {
+            let rest = dir.strip_prefix(ancestor).expect("ancestor is a prefix");
+            return canonical.join(rest);
+        }
+    }
+    dir.to_path_buf()
+}
+
+/// Runs a filesystem operation, retrying with exponential backoff while it fails because storage
+/// seems to have gone away. Each attempt is bounded by a timeout, so that a hung mount counts as a
+/// failure rather than blocking forever.
+pub async fn retry<T, F, Fut>(what: &str, mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let mut attempt = 1;
+    let mut delay = RETRY_INITIAL_DELAY;
+    loop {
+        let res = match tokio::time::timeout(OP_TIMEOUT, op()).await {
+            Ok(res) => res,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("timed out after {:?}", OP_TIMEOUT),
+            )),
+        };
+        match res {
+            Err(e) if is_storage_gone(&e) && attempt < RETRY_ATTEMPTS => {
+                warn!(
+                    "Failed to {} (attempt {}/{}); retrying in {:?}: {}",
+                    what, attempt, RETRY_ATTEMPTS, delay, e
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                delay *= 2;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_storage_gone() {
+        assert!(is_storage_gone(&io::Error::from_raw_os_error(5)));
+        assert!(is_storage_gone(&io::Error::from(io::ErrorKind::TimedOut)));
+        assert!(!is_storage_gone(&io::Error::from(io::ErrorKind::NotFound)));
+        assert!(!is_storage_gone(&io::Error::from(
+            io::ErrorKind::PermissionDenied
+        )));
+    }
+
+    #[test]
+    fn test_already_renamed() {
+        let dir = std::env::temp_dir().join(format!("reccon-storage-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (from, to) = (dir.join("a.flac.part"), dir.join("a.flac.local"));
+        std::fs::write(&from, b"").unwrap();
+        std::fs::rename(&from, &to).unwrap();
+        let e = std::fs::rename(&from, &to).unwrap_err();
+        assert!(already_renamed(&e, &to));
+        assert!(!already_renamed(&e, &dir.join("b.flac.local")));
+        let e = io::Error::from(io::ErrorKind::PermissionDenied);
+        assert!(!already_renamed(&e, &to));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_canonical_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = std::fs::canonicalize(tmp.path()).unwrap();
+        std::fs::create_dir(root.join("real")).unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("link")).unwrap();
+        assert_eq!(canonical_dir(&root.join("real")), root.join("real"));
+        assert_eq!(canonical_dir(&root.join("link")), root.join("real"));
+        // Only the part that exists resolves, and the rest is kept as is.
+        assert_eq!(
+            canonical_dir(&root.join("link/new/deeper")),
+            root.join("real/new/deeper")
+        );
+        assert_eq!(canonical_dir(&root.join("new")), root.join("new"));
+        let cwd = std::env::current_dir().unwrap().canonicalize().unwrap();
+        assert_eq!(
+            canonical_dir(Path::new("no-such-dir")),
+            cwd.join("no-such-dir")
+        );
+    }
+
+    #[test]
+    fn test_find_group() {
+        let groups = "root:x:0:\naudio:x:29:pulse,alice\n# comment\nrecordings:x:1001:\n";
+        assert_eq!(find_group(groups, "audio"), Some(29));
+        assert_eq!(find_group(groups, "recordings"), Some(1001));
+        assert_eq!(find_group(groups, "video"), None);
+        assert_eq!(find_group(groups, "audi"), None);
+    }
+
+    #[test]
+    fn test_next_dir_switches_to_fallback() {
+        let storage = Storage::new(vec!["/mnt/nfs".into()], Some("/var/tmp".into()));
+        assert_eq!(storage.next_dir(), Path::new("/mnt/nfs"));
+        storage.mark_failed();
+        assert_eq!(storage.next_dir(), Path::new("/var/tmp"));
+
+        let storage = Storage::new(vec!["/mnt/nfs".into()], None);
+        storage.mark_failed();
+        assert_eq!(storage.next_dir(), Path::new("/mnt/nfs"));
+    }
+
+    #[test]
+    fn test_next_dir_round_robin() {
+        let storage = Storage::new(
+            vec!["/mnt/a".into(), "/mnt/b".into()],
+            Some("/var/tmp".into()),
+        );
+        assert_eq!(storage.next_dir(), Path::new("/mnt/a"));
+        assert_eq!(storage.next_dir(), Path::new("/mnt/b"));
+        assert_eq!(storage.next_dir(), Path::new("/mnt/a"));
+        storage.mark_failed();
+        assert_eq!(storage.next_dir(), Path::new("/var/tmp"));
+        assert_eq!(storage.next_dir(), Path::new("/var/tmp"));
+    }
+}
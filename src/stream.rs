@@ -0,0 +1,300 @@
+use std::io::Read;
+use std::process::ChildStdout;
+use std::sync::Arc;
+
+use anyhow::Context;
+use log::{debug, warn};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::config::ContentHash;
+use crate::gcs::{self, ChunkStatus};
+use crate::{encoder, hash, Shared};
+
+/// How much of a recording to have ready before sending it to GCS. Each chunk costs a request, so
+/// this trades requests against how much is left to send once the recording ends.
+const CHUNK_BYTES: usize = 4 * gcs::RESUMABLE_CHUNK_ALIGN;
+/// How much of the encoder's output to read at a time.
+const READ_BYTES: usize = 64 * 1024;
+
+type Metadata = serde_json::Map<String, serde_json::Value>;
+
+/// Where to stream a recording to.
+pub struct Destination {
+    /// ID of the segment, for logging and its `Content-Disposition`.
+    pub id: String,
+    /// Object name, relative to the prefix, after any `object_key_template`.
+    pub object_name: String,
+    /// Metadata known when the recording starts, which the object is created with.
+    pub metadata: Metadata,
+}
+
+/// An upload of a recording straight from its encoder's output, with `stream_to_gcs`, in progress.
+pub struct Stream {
+    finish: oneshot::Sender<Option<Metadata>>,
+    outcome: tokio::task::JoinHandle<Result<u64, Fallback>>,
+}
+
+/// Why a streamed upload failed, along with everything that the encoder wrote, so that the
+/// recording can be saved to a file and uploaded from there instead.
+pub struct Fallback {
+    pub error: anyhow::Error,
+    pub contents: Vec<u8>,
+}
+
+impl Stream {
+    /// Starts uploading what an encoder writes to `output`. Nothing shows up in GCS until
+    /// [`Stream::finish`].
+    pub fn start(
+        runtime: &tokio::runtime::Handle,
+        shared: Arc<Shared>,
+        dest: Destination,
+        output: ChildStdout,
+    ) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // Keep reading while chunks are being sent, so that a slow upload never makes the encoder
+        // stop reading the audio that we write it.
+        let id = dest.id.clone();
+        std::thread::spawn(move || read_output(output, tx, &id));
+        let (finish, decision) = oneshot::channel();
+        let outcome = runtime.spawn(upload(shared, dest, rx, decision));
+        Stream { finish, outcome }
+    }
+
+    /// Finishes the upload once its encoder has exited, adding `metadata` to the object's. Returns
+    /// the size of the recording.
+    pub async fn finish(self, metadata: Metadata) -> Result<u64, Fallback> {
+        // The upload only stops listening by finishing, in which case there's nothing to tell it.
+        let _ = self.finish.send(Some(metadata));
+        match self.outcome.await {
+            Ok(outcome) => outcome,
+            Err(e) => Err(Fallback {
+                error: anyhow::Error::new(e).context("Streaming upload failed"),
+                contents: Vec::new(),
+            }),
+        }
+    }
+
+    /// Abandons the upload once its encoder has exited, leaving nothing in GCS.
+    pub async fn discard(self) {
+        let _ = self.finish.send(None);
+        let _ = self.outcome.await;
+    }
+}
+
+/// Sends everything that the encoder writes to `output` to `tx`, until it exits.
+fn read_output(mut output: ChildStdout, tx: mpsc::UnboundedSender<Vec<u8>>, id: &str) {
+    let mut buf = vec![0; READ_BYTES];
+    loop {
+        match output.read(&mut buf) {
+            Ok(0) => return,
+            Ok(n) => {
+                if tx.send(buf[..n].to_vec()).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                warn!("Failed to read encoder output for segment {}: {}", id, e);
+                return;
+            }
+        }
+    }
+}
+
+/// Uploads the encoder's output as it arrives, and then finishes or abandons the upload per
+/// `decision`. All of the output is kept until the upload finishes, for the [`Fallback`].
+async fn upload(
+    shared: Arc<Shared>,
+    dest: Destination,
+    mut output: mpsc::UnboundedReceiver<Vec<u8>>,
+    decision: oneshot::Receiver<Option<Metadata>>,
+) -> Result<u64, Fallback> {
+    let mut session = Session {
+        shared: &shared,
+        dest: &dest,
+        uri: None,
+        sent: 0,
+    };
+    let mut contents = Vec::new();
+    // Hashed as it arrives, so that there's nothing left to hash once the recording ends.
+    let content_hash = shared.uploader.as_ref().and_then(|u| u.content_hash);
+    let mut hasher = content_hash.map(hash::Hasher::new);
+    let mut failed = None;
+    while let Some(data) = output.recv().await {
+        contents.extend_from_slice(&data);
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&data);
+        }
+        if failed.is_some() {
+            continue;
+        }
+        if let Some(end) = ready_chunk(session.sent, contents.len()) {
+            if let Err(e) = session.send(&contents[..end], false).await {
+                warn!(
+                    "Failed to stream segment {} to GCS; will save it to a file instead: {:#}",
+                    dest.id, e
+                );
+                failed = Some(e);
+            }
+        }
+    }
+    let Ok(Some(metadata)) = decision.await else {
+        session.cancel().await;
+        return Ok(0);
+    };
+    let res = match failed {
+        Some(e) => Err(e),
+        None => {
+            let digest = content_hash.zip(hasher.map(hash::Hasher::hex_digest));
+            session.finish(&contents, metadata, digest).await
+        }
+    };
+    match res {
+        Ok(()) => Ok(contents.len() as u64),
+        Err(error) => {
+            session.cancel().await;
+            Err(Fallback { error, contents })
+        }
+    }
+}
+
+/// Returns where the next chunk to send ends, if enough of the recording is ready to send, given
+/// that the first `sent` bytes of the `len` so far have been sent.
+fn ready_chunk(sent: u64, len: usize) -> Option<usize> {
+    let sent = sent as usize;
+    let ready = len.checked_sub(sent)?;
+    (ready >= CHUNK_BYTES)
+        .then(|| sent + ready / gcs::RESUMABLE_CHUNK_ALIGN * gcs::RESUMABLE_CHUNK_ALIGN)
+}
+
+/// A resumable upload session, started once there's something to send.
+struct Session<'a> {
+    shared: &'a Shared,
+    dest: &'a Destination,
+    uri: Option<String>,
+    /// How many bytes GCS has.
+    sent: u64,
+}
+
+impl<'a> Session<'a> {
+    fn client(&self) -> anyhow::Result<&'a gcs::Client> {
+        let uploader = self.shared.uploader.as_ref();
+        uploader
+            .and_then(|u| u.client())
+            .context("Can't upload without a GCS client")
+    }
+
+    fn object_metadata(&self, metadata: Metadata) -> Metadata {
+        match &self.shared.uploader {
+            Some(uploader) => uploader.object_metadata(metadata),
+            None => metadata,
+        }
+    }
+
+    /// Sends `contents` (all of the recording so far) from where GCS left off, until GCS has all of
+    /// it. With `last`, these are all of the contents, and this finishes the upload.
+    async fn send(&mut self, contents: &[u8], last: bool) -> anyhow::Result<()> {
+        let client = self.client()?;
+        let uri = match &self.uri {
+            Some(uri) => uri.clone(),
+            None => {
+                let content_disposition = client.options.content_disposition(&self.dest.id);
+                let uri = client
+                    .start_resumable(
+                        &self.dest.object_name,
+                        encoder::FORMAT.content_type(),
+                        content_disposition.as_deref(),
+                        &self.object_metadata(self.dest.metadata.clone()).into(),
+                    )
+                    .await?;
+                debug!("Started streaming segment {} to GCS", self.dest.id);
+                self.uri.insert(uri).clone()
+            }
+        };
+        let len = contents.len() as u64;
+        let total = last.then_some(len);
+        loop {
+            let chunk = &contents[self.sent as usize..];
+            match client.put_chunk(&uri, self.sent, chunk, total).await? {
+                ChunkStatus::Done if last => {
+                    self.sent = len;
+                    self.uri = None;
+                    return Ok(());
+                }
+                ChunkStatus::Done => anyhow::bail!("GCS finished the upload before the recording"),
+                ChunkStatus::Incomplete { persisted } if persisted == len && !last => {
+                    self.sent = persisted;
+                    return Ok(());
+                }
+                ChunkStatus::Incomplete { persisted }
+                    if persisted > self.sent && persisted < len =>
+                {
+                    // GCS kept only part of the chunk; send it the rest.
+                    self.sent = persisted;
+                }
+                ChunkStatus::Incomplete { persisted } => anyhow::bail!(
+                    "GCS has {} bytes of the upload after sending it {}",
+                    persisted,
+                    len
+                ),
+            }
+        }
+    }
+
+    /// Sends the rest of the recording and then adds `metadata` to the object, along with the
+    /// `hash` of its contents, if any.
+    async fn finish(
+        &mut self,
+        contents: &[u8],
+        mut metadata: Metadata,
+        hash: Option<(ContentHash, String)>,
+    ) -> anyhow::Result<()> {
+        self.send(contents, true).await?;
+        if let Some((hash, digest)) = hash {
+            metadata.insert(hash.metadata_key().to_string(), digest.into());
+        }
+        self.client()?
+            .patch_metadata(
+                &self.dest.object_name,
+                &self.object_metadata(metadata).into(),
+            )
+            .await
+            .context("Failed to set metadata of streamed recording")
+    }
+
+    /// Abandons the session, if it was started and not finished.
+    async fn cancel(&mut self) {
+        let (Some(uri), Ok(client)) = (self.uri.take(), self.client()) else {
+            return;
+        };
+        if let Err(e) = client.cancel_resumable(&uri).await {
+            debug!(
+                "Failed to cancel streaming upload of segment {}: {:#}",
+                self.dest.id, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_chunk() {
+        const ALIGN: usize = gcs::RESUMABLE_CHUNK_ALIGN;
+        assert_eq!(ready_chunk(0, 0), None);
+        assert_eq!(ready_chunk(0, CHUNK_BYTES - 1), None);
+        assert_eq!(ready_chunk(0, CHUNK_BYTES), Some(CHUNK_BYTES));
+        // Only whole multiples of the alignment, however much is ready.
+        assert_eq!(
+            ready_chunk(0, CHUNK_BYTES + ALIGN + 10),
+            Some(CHUNK_BYTES + ALIGN)
+        );
+        assert_eq!(
+            ready_chunk(ALIGN as u64, ALIGN + CHUNK_BYTES + 10),
+            Some(ALIGN + CHUNK_BYTES)
+        );
+        assert_eq!(ready_chunk(CHUNK_BYTES as u64, CHUNK_BYTES + 10), None);
+    }
+}
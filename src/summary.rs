@@ -0,0 +1,63 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Running totals over one run of `reccon`, for the summary that `--summary` prints on exit.
+#[derive(Default)]
+pub struct Counters {
+    /// Segments finished and kept on disk.
+    segments: AtomicU64,
+    /// Total size of those segments' primary recordings.
+    bytes: AtomicU64,
+    /// Recordings uploaded to GCS, including secondary encodings and leftovers from before.
+    uploaded: AtomicU64,
+    /// Segments that couldn't be finished, and recordings that couldn't be uploaded.
+    failures: AtomicU64,
+}
+
+impl Counters {
+    pub fn add_segment(&self, bytes: u64) {
+        self.segments.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_upload(&self) {
+        self.uploaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn add_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Formats the totals so far, and how long `reccon` ran, as one line of JSON.
+    pub fn summary(&self, runtime: Duration) -> String {
+        let mut summary = serde_json::Map::new();
+        let mut insert = |key: &str, counter: &AtomicU64| {
+            summary.insert(key.to_string(), counter.load(Ordering::Relaxed).into());
+        };
+        insert("segments", &self.segments);
+        insert("bytes", &self.bytes);
+        insert("uploaded", &self.uploaded);
+        insert("failures", &self.failures);
+        let runtime_secs = runtime.as_millis() as f64 / 1000.0;
+        summary.insert("runtime_secs".to_string(), runtime_secs.into());
+        serde_json::Value::Object(summary).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let counters = Counters::default();
+        counters.add_segment(1000);
+        counters.add_segment(234);
+        counters.add_upload();
+        counters.add_failure();
+        assert_eq!(
+            counters.summary(Duration::from_millis(61_500)),
+            r#"{"bytes":1234,"failures":1,"runtime_secs":61.5,"segments":2,"uploaded":1}"#
+        );
+    }
+}
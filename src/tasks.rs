@@ -0,0 +1,78 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Work spawned in the background for a finished recording, like transcribing it, that nothing
+/// waits on as it runs, but that `reccon` lets finish before it exits, as it does uploads.
+#[derive(Default)]
+pub struct Tasks {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    running: AtomicUsize,
+    notify: tokio::sync::Notify,
+}
+
+/// Counts a task as running until it's dropped, even if the task panics.
+struct Running(Arc<Inner>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if self.0.running.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.notify.notify_waiters();
+        }
+    }
+}
+
+impl Tasks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns `task` on the current runtime, to be waited for by [`Self::idle`].
+    pub fn spawn<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.inner.running.fetch_add(1, Ordering::AcqRel);
+        let running = Running(self.inner.clone());
+        tokio::spawn(async move {
+            let _running = running;
+            task.await;
+        });
+    }
+
+    /// Waits until no spawned tasks are running.
+    pub async fn idle(&self) {
+        loop {
+            // Created before checking, so that the last task finishing in between still wakes this.
+            let notified = self.inner.notify.notified();
+            if self.inner.running.load(Ordering::Acquire) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_idle() {
+        let tasks = Tasks::new();
+        tasks.idle().await;
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn(async move {
+            let _ = rx.await;
+        });
+        let waiting = tokio::time::timeout(Duration::from_millis(10), tasks.idle());
+        assert!(waiting.await.is_err());
+        tx.send(()).unwrap();
+        tasks.idle().await;
+    }
+}
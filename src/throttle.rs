@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// A limit on how many segments start within any sliding `window`, for `max_segments_per_minute`.
+///
+/// Each segment that starts is remembered until it's a `window` old. While `max` are remembered,
+/// no more may start; as the oldest age out, others may start again.
+pub struct Throttle {
+    max: usize,
+    window: Duration,
+    starts: VecDeque<Instant>,
+    /// Segments not started since the last one that was.
+    pub skipped: u64,
+}
+
+impl Throttle {
+    pub fn new(max: u32, window: Duration) -> Self {
+        let max = max as usize;
+        Self {
+            max,
+            window,
+            starts: VecDeque::with_capacity(max),
+            skipped: 0,
+        }
+    }
+
+    /// Returns whether a segment may start at `now`, counting it if so.
+    pub fn try_start(&mut self, now: Instant) -> bool {
+        while let Some(&oldest) = self.starts.front() {
+            if now.saturating_duration_since(oldest) < self.window {
+                break;
+            }
+            self.starts.pop_front();
+        }
+        if self.starts.len() >= self.max {
+            self.skipped += 1;
+            return false;
+        }
+        self.starts.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle() {
+        let t0 = Instant::now();
+        let secs = |s| t0 + Duration::from_secs(s);
+        let mut throttle = Throttle::new(2, Duration::from_secs(60));
+        assert!(throttle.try_start(secs(0)));
+        assert!(throttle.try_start(secs(10)));
+        assert!(!throttle.try_start(secs(20)));
+        assert!(!throttle.try_start(secs(59)));
+        assert_eq!(throttle.skipped, 2);
+        // The first start ages out, making room for one more.
+        assert!(throttle.try_start(secs(60)));
+        assert!(!throttle.try_start(secs(65)));
+        // Then the second.
+        assert!(throttle.try_start(secs(70)));
+        // Long after, the window is empty again.
+        assert!(throttle.try_start(secs(1000)));
+        assert!(throttle.try_start(secs(1000)));
+        assert!(!throttle.try_start(secs(1000)));
+    }
+}
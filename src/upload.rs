@@ -0,0 +1,788 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+use log::{debug, error, info, warn};
+
+use crate::config::{AudioFormat, ContentHash};
+use crate::journal::Journal;
+use crate::storage::{self, Storage};
+use crate::verify::Verdict;
+use crate::{gcs, naming, Shared};
+
+/// A finished recording waiting to be uploaded.
+pub struct Job {
+    /// Segment ID, for logging.
+    pub id: String,
+    /// Object name, relative to the GCS prefix.
+    pub object_name: String,
+    pub local_filename: PathBuf,
+    pub final_filename: PathBuf,
+    /// Metadata gathered when the segment finished. This is empty for segments recovered from
+    /// disk, whose in-memory state is gone.
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Whether `metadata` includes measurements of the recording. If not (as when recovered from
+    /// disk, or when measuring failed), they're added just before uploading.
+    pub measured: bool,
+    pub format: AudioFormat,
+    /// Whether this is a segment's primary recording, rather than a secondary encoding.
+    pub primary: bool,
+    /// When the segment's audio stopped, for logging upload latency. This is unknown for segments
+    /// recovered from disk.
+    pub ended: Option<Instant>,
+}
+
+/// Uploads finished recordings to GCS, oldest first, with a bounded in-memory backlog.
+///
+/// The source of truth for what needs uploading is the set of `.local` files under the storage
+/// directory (and the fallback directory, if any); the in-memory queue is a bounded window onto
+/// it. When a segment finishes while the queue is full, its `.local` file stays on disk (so the
+/// recording is never dropped) but it isn't enqueued. Instead, the queue is marked as having
+/// spilled. Once the draining task empties the queue, it rescans the storage directory for
+/// `.local` files it doesn't already know about and enqueues them, oldest first by modification
+/// time, until the queue is full again. The queue starts out spilled, so the first rescan picks up
+/// anything left over from a previous run. With a [`Journal`], it's the source of truth instead,
+/// and rescans just take what it has as pending, oldest first by segment ID. Either way, rescans
+/// skip segments that are still being finished (see [`Uploader::claim`]), which are pushed once
+/// they're measured.
+///
+/// One or more draining tasks ([`drain`]) each upload one job at a time. An upload that fails
+/// leaves its `.local` file in place, to be retried on the next startup.
+///
+/// With a [`LatestAlias`], each primary recording is also copied to a fixed object name after it
+/// uploads, if it's the newest one so far.
+///
+/// Each upload reads its whole file into memory, so with a memory budget, the draining tasks
+/// together only hold that many bytes of recordings at once. Each one reserves as many bytes as
+/// its file's size from a semaphore before reading it, and waits if they aren't free. A file
+/// larger than the whole budget reserves all of it, so it's uploaded alone rather than never.
+///
+/// If `ordered`, the queue is kept sorted by segment ID instead, so that recordings left over from
+/// before (or spilled) are still uploaded before newer ones. With one draining task, recordings
+/// then upload in the order they started, if they're queued in that order.
+pub struct Uploader {
+    /// Where uploads go, even before there's a client to upload with.
+    pub path: gcs::Path,
+    /// How object names are rewritten before uploading, if at all.
+    pub object_key: Option<naming::ObjectKeyTemplate>,
+    /// Hash of each recording's contents to add to its metadata, if any.
+    pub content_hash: Option<ContentHash>,
+    /// Names to give metadata keys in GCS instead of `reccon`'s own, per `metadata_keys`.
+    pub metadata_keys: BTreeMap<String, String>,
+    /// Longest that uploading a recording may take, including measuring and verifying it, per
+    /// `upload_deadline_secs`.
+    pub deadline: Option<Duration>,
+    /// Where each recording's progress is recorded, per `upload_journal`.
+    pub journal: Option<Journal>,
+    /// Set once authenticated, which may be after startup. Nothing is uploaded until then.
+    client: OnceLock<gcs::Client>,
+    /// Directories to scan for `.local` files: the storage directory, then any fallback.
+    roots: Vec<PathBuf>,
+    capacity: usize,
+    /// How long to wait before the first rescan, if it hasn't happened yet.
+    recovery_delay: Mutex<Option<Duration>>,
+    queue: Mutex<Queue>,
+    notify: tokio::sync::Notify,
+    latest_alias: Option<LatestAlias>,
+    /// Bytes of recordings that uploads may hold in memory at once, as the total permits of
+    /// `memory`, if limited.
+    memory_budget: Option<u32>,
+    memory: tokio::sync::Semaphore,
+    ordered: bool,
+}
+
+/// A segment whose `.local` files rescans leave alone, per [`Uploader::claim`].
+pub struct Claim<'a> {
+    uploader: &'a Uploader,
+    id: String,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.uploader.queue.lock().unwrap().claimed.remove(&self.id);
+    }
+}
+
+/// An object kept as a copy of the newest recording uploaded so far.
+///
+/// Uploads can finish out of order, since several workers upload at once and recordings left over
+/// from before are uploaded alongside new ones. So recordings are compared by segment ID, which
+/// sorts by start time, and only one that's newer than the last one copied replaces the alias. The
+/// lock is held while copying, so that two copies can't race and leave the older one in place.
+struct LatestAlias {
+    /// Object name of the copy, relative to the GCS prefix.
+    object_name: String,
+    /// ID of the segment last copied to the alias, if any.
+    newest: tokio::sync::Mutex<Option<String>>,
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// `.local` files that are enqueued or being uploaded, so that rescans don't duplicate them.
+    known: HashSet<PathBuf>,
+    /// Whether there may be `.local` files on disk that aren't in `jobs`.
+    spilled: bool,
+    /// IDs of segments whose `.local` files are still being finished, per [`Uploader::claim`].
+    claimed: HashSet<String>,
+}
+
+impl Queue {
+    /// Adds a job at the back, or in order of segment ID if `ordered`.
+    fn add(&mut self, job: Job, ordered: bool) {
+        self.known.insert(job.local_filename.clone());
+        if ordered {
+            let at = self.jobs.partition_point(|queued| queued.id <= job.id);
+            self.jobs.insert(at, job);
+        } else {
+            self.jobs.push_back(job);
+        }
+    }
+
+    /// Adds those of `jobs` that aren't already known or claimed, as long as fewer than `capacity`
+    /// are queued, marking the queue as spilled if they don't all fit. Returns how many it added.
+    fn refill(&mut self, jobs: Vec<Job>, capacity: usize, ordered: bool) -> usize {
+        let mut added = 0;
+        for job in jobs {
+            if self.known.contains(&job.local_filename) || self.claimed.contains(&job.id) {
+                continue;
+            }
+            if self.jobs.len() >= capacity {
+                self.spilled = true;
+                break;
+            }
+            self.add(job, ordered);
+            added += 1;
+        }
+        added
+    }
+}
+
+impl Uploader {
+    /// Creates an uploader that waits `recovery_delay` before picking up `.local` files left over
+    /// from a previous run, and keeps a copy of the newest recording at `latest_alias`, if set.
+    /// Uploads hold at most `memory_budget` bytes in memory at once, if set, up to 4 GiB. See the
+    /// type docs for `ordered`.
+    pub fn new(
+        path: gcs::Path,
+        roots: Vec<PathBuf>,
+        capacity: usize,
+        recovery_delay: Duration,
+        latest_alias: Option<String>,
+        memory_budget: Option<u64>,
+        ordered: bool,
+    ) -> Self {
+        let memory_budget =
+            memory_budget.map(|bytes| u32::try_from(bytes.max(1)).unwrap_or(u32::MAX));
+        Self {
+            path,
+            object_key: None,
+            content_hash: None,
+            metadata_keys: BTreeMap::new(),
+            deadline: None,
+            journal: None,
+            client: OnceLock::new(),
+            roots,
+            capacity: capacity.max(1),
+            recovery_delay: Mutex::new(Some(recovery_delay).filter(|d| !d.is_zero())),
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                known: HashSet::new(),
+                spilled: true,
+                claimed: HashSet::new(),
+            }),
+            notify: tokio::sync::Notify::new(),
+            latest_alias: latest_alias.map(|object_name| LatestAlias {
+                object_name,
+                newest: tokio::sync::Mutex::new(None),
+            }),
+            memory_budget,
+            memory: tokio::sync::Semaphore::new(memory_budget.map_or(0, |b| b as usize)),
+            ordered,
+        }
+    }
+
+    /// Returns the name that segment `id` is uploaded as, for a job with the given object name,
+    /// per `object_key`.
+    pub fn object_key(&self, id: &str, object_name: &str) -> String {
+        match &self.object_key {
+            Some(template) => template.render(id, object_name),
+            None => object_name.to_string(),
+        }
+    }
+
+    /// Waits until there's room in the memory budget to read a file of `len` bytes, if there's a
+    /// budget at all, and reserves it until the returned permit is dropped.
+    async fn reserve_memory(&self, len: u64) -> Option<tokio::sync::SemaphorePermit<'_>> {
+        let budget = self.memory_budget?;
+        let permits = u32::try_from(len).unwrap_or(u32::MAX).min(budget);
+        Some(self.memory.acquire_many(permits).await.unwrap())
+    }
+
+    /// Keeps rescans from enqueuing segment `id`'s `.local` files until the returned claim is
+    /// dropped, so that they're only enqueued by [`Uploader::push`], with the metadata gathered as
+    /// the segment finished, rather than as recovered from disk in the meantime.
+    pub fn claim(&self, id: &str) -> Claim<'_> {
+        let id = id.to_string();
+        self.queue.lock().unwrap().claimed.insert(id.clone());
+        Claim { uploader: self, id }
+    }
+
+    /// Enqueues a job, or leaves it on disk for a later rescan if the queue is full.
+    pub fn push(&self, job: Job) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.known.contains(&job.local_filename) {
+            return;
+        }
+        if queue.jobs.len() >= self.capacity {
+            warn!(
+                "Upload queue full; segment {} will be uploaded once the backlog drains",
+                job.id
+            );
+            queue.spilled = true;
+            return;
+        }
+        queue.add(job, self.ordered);
+        drop(queue);
+        self.notify.notify_one();
+    }
+
+    pub fn client(&self) -> Option<&gcs::Client> {
+        self.client.get()
+    }
+
+    /// Provides the client to upload with. This must happen before [`drain`] starts.
+    pub fn set_client(&self, client: gcs::Client) {
+        if self.client.set(client).is_err() {
+            panic!("GCS client already set");
+        }
+    }
+
+    /// Waits until no jobs are queued or being uploaded, or returns right away if there's no
+    /// client to upload them with.
+    pub async fn idle(&self) {
+        if self.client().is_none() {
+            return;
+        }
+        loop {
+            {
+                let queue = self.queue.lock().unwrap();
+                if queue.known.is_empty() && !queue.spilled {
+                    return;
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Waits for the next job, rescanning the storage directory if the queue has spilled.
+    async fn next(&self) -> Job {
+        loop {
+            let spilled = {
+                let mut queue = self.queue.lock().unwrap();
+                if let Some(job) = queue.jobs.pop_front() {
+                    return job;
+                }
+                std::mem::take(&mut queue.spilled)
+            };
+            if spilled {
+                self.refill().await;
+                continue;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Copies a just-uploaded job to the latest alias, if it's the newest recording so far.
+    async fn update_latest_alias(&self, client: &gcs::Client, job: &Job) -> anyhow::Result<()> {
+        let Some(alias) = &self.latest_alias else {
+            return Ok(());
+        };
+        if !job.primary {
+            return Ok(());
+        }
+        let mut newest = alias.newest.lock().await;
+        if newest.as_ref().is_some_and(|newest| *newest >= job.id) {
+            return Ok(());
+        }
+        client.copy(&job.object_name, &alias.object_name).await?;
+        debug!("Copied segment {} to {}", job.id, alias.object_name);
+        *newest = Some(job.id.clone());
+        Ok(())
+    }
+
+    /// Renames the keys of `metadata` per `metadata_keys`, for an object in GCS.
+    pub fn object_metadata(
+        &self,
+        metadata: serde_json::Map<String, serde_json::Value>,
+    ) -> serde_json::Map<String, serde_json::Value> {
+        rename_keys(metadata, &self.metadata_keys)
+    }
+
+    /// Marks a job as no longer in flight.
+    fn done(&self, job: &Job) {
+        self.queue.lock().unwrap().known.remove(&job.local_filename);
+    }
+
+    /// Enqueues pending uploads, oldest first: those in the journal, if any, and otherwise the
+    /// `.local` files found under the storage directories.
+    async fn refill(&self) {
+        let delay = self.recovery_delay.lock().unwrap().take();
+        if let Some(delay) = delay {
+            info!(
+                "Waiting {:.1} seconds before uploading recordings left over from before",
+                delay.as_secs_f64()
+            );
+            tokio::time::sleep(delay).await;
+        }
+        let jobs = match &self.journal {
+            Some(journal) => journal.pending(),
+            None => self.scan().await,
+        };
+        let added = self
+            .queue
+            .lock()
+            .unwrap()
+            .refill(jobs, self.capacity, self.ordered);
+        if added > 0 {
+            info!("Enqueued {} pending upload(s) from disk", added);
+        }
+        // Wake other draining tasks, if there are any.
+        for _ in 0..added {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Finds the `.local` files under the storage directories, oldest first by modification time,
+    /// as uploads.
+    async fn scan(&self) -> Vec<Job> {
+        let mut found = Vec::new();
+        for root in &self.roots {
+            let is_local = |name: &str| naming::id_from_local_filename(name).is_some();
+            if let Err(e) = scan_files(root, &mut found, is_local).await {
+                error!(
+                    "Failed to scan {} for pending uploads: {:#}",
+                    root.display(),
+                    e
+                );
+            }
+        }
+        found.sort();
+        found
+            .into_iter()
+            .filter_map(|(_, path)| recovered_job(&self.roots, path))
+            .collect()
+    }
+}
+
+/// Recursively collects the files under `dir` whose names match, with their modification times.
+pub async fn scan_files(
+    dir: &Path,
+    found: &mut Vec<(SystemTime, PathBuf)>,
+    matches: impl Fn(&str) -> bool,
+) -> anyhow::Result<()> {
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = tokio::fs::read_dir(&dir)
+            .await
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+        while let Some(entry) = entries.next_entry().await? {
+            let meta = entry.metadata().await?;
+            let path = entry.path();
+            if meta.is_dir() {
+                dirs.push(path);
+            } else if matches(&entry.file_name().to_string_lossy()) {
+                found.push((meta.modified()?, path));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reconstructs an upload job from a `.local` file found under one of `roots`.
+pub fn recovered_job(roots: &[PathBuf], local_filename: PathBuf) -> Option<Job> {
+    // Prefer the innermost root, in case one is nested inside another.
+    let root = roots
+        .iter()
+        .filter(|r| local_filename.starts_with(r))
+        .max_by_key(|r| r.components().count())?;
+    let (id, object_name) = naming::object_name_for_local(root, &local_filename)?;
+    let final_filename = local_filename.with_extension("");
+    let format = AudioFormat::from_extension(final_filename.extension()?.to_str()?)?;
+    let primary = object_name.ends_with(&naming::object_name(None, &id, format));
+    Some(Job {
+        id,
+        object_name,
+        local_filename,
+        final_filename,
+        metadata: serde_json::Map::new(),
+        measured: false,
+        format,
+        primary,
+        ended: None,
+    })
+}
+
+/// Uploads queued jobs, forever, once the uploader has a client.
+pub async fn drain(shared: Arc<Shared>) {
+    let Some(uploader) = &shared.uploader else {
+        return;
+    };
+    let Some(client) = uploader.client() else {
+        error!("Can't upload without a GCS client");
+        return;
+    };
+    loop {
+        let mut job = uploader.next().await;
+        // Jobs only ever carry their untemplated names, which are what a restart recovers too.
+        job.object_name = uploader.object_key(&job.id, &job.object_name);
+        let upload = upload_job(&shared, uploader, client, &job);
+        if with_deadline(uploader.deadline, upload).await.is_none() {
+            error!(
+                "Gave up on uploading segment {} after upload_deadline_secs ({:?}); leaving it \
+                 to retry",
+                job.id,
+                uploader.deadline.unwrap_or_default()
+            );
+            shared.counters.add_failure();
+        }
+        uploader.done(&job);
+    }
+}
+
+/// Measures, checks, and uploads the recording of `job`, and then does whatever else follows
+/// uploading it.
+async fn upload_job(shared: &Arc<Shared>, uploader: &Uploader, client: &gcs::Client, job: &Job) {
+    let mut metadata = job.metadata.clone();
+    if !job.measured {
+        match crate::segment_metadata(shared, &job.local_filename).await {
+            Ok(measured) => metadata.extend(measured),
+            Err(e) => {
+                error!("Not uploading segment {} without metadata: {:#}", job.id, e);
+                shared.counters.add_failure();
+                return;
+            }
+        }
+    }
+    if let Some(samples) = too_few_samples(&metadata, shared.min_samples) {
+        warn!(
+            "Not uploading segment {} with only {} samples; setting it aside",
+            job.id, samples
+        );
+        reject_segment(&shared.storage, uploader, job).await;
+        return;
+    }
+    if shared.verify_before_upload {
+        match crate::verify::verify(&job.local_filename, job.format).await {
+            Ok(Verdict::Intact) => {}
+            Ok(Verdict::Corrupt(problem)) => {
+                error!(
+                    "Not uploading segment {}, which failed to decode; setting it aside: {}",
+                    job.id, problem
+                );
+                shared.counters.add_failure();
+                reject_segment(&shared.storage, uploader, job).await;
+                return;
+            }
+            Err(e) => {
+                error!(
+                    "Not uploading segment {} without verifying it: {:#}",
+                    job.id, e
+                );
+                shared.counters.add_failure();
+                return;
+            }
+        }
+    }
+    let hook = shared.hook.as_ref().filter(|_| job.primary);
+    if hook.is_some_and(|hook| hook.before_upload) {
+        let (local, dest) = (&job.local_filename, &job.final_filename);
+        crate::hook::post_process(shared, &job.id, local, dest, &metadata).await;
+    }
+    match upload_segment(client, uploader, &shared.storage, job, &metadata).await {
+        Ok(()) => {
+            shared.counters.add_upload();
+            if let Some(journal) = &uploader.journal {
+                journal.uploaded(&job.id, job.primary);
+            }
+            if hook.is_some_and(|hook| !hook.before_upload) {
+                crate::hook::spawn_post_process(shared, &job.id, &job.final_filename, &metadata);
+            }
+            // Only now is the recording at its final path, where it won't move again.
+            if job.primary && shared.asr.is_some() {
+                shared.background.spawn(crate::asr::transcribe_segment(
+                    shared.clone(),
+                    job.id.clone(),
+                    job.final_filename.clone(),
+                    Some(job.object_name.clone()),
+                ));
+            }
+            if let Some(index) = &shared.index {
+                if let Err(e) = index.mark_uploaded(&job.id, &job.final_filename) {
+                    error!("{:#}", e);
+                }
+            }
+            if let Err(e) = uploader.update_latest_alias(client, job).await {
+                error!(
+                    "Failed to update latest alias to segment {}: {:#}",
+                    job.id, e
+                );
+            }
+        }
+        Err(e) => {
+            error!("Failed to upload segment {} to GCS: {:#}", job.id, e);
+            shared.counters.add_failure();
+        }
+    }
+}
+
+/// Runs `fut` to completion, or until `deadline` passes, if set, in which case it's dropped.
+async fn with_deadline<F: Future>(deadline: Option<Duration>, fut: F) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout(deadline, fut).await.ok(),
+        None => Some(fut.await),
+    }
+}
+
+/// Returns the sample count in `metadata`, if it was measured and is less than `min_samples`, which
+/// means that the recording is empty or corrupt (e.g., after a device hiccup) and not worth
+/// uploading.
+fn too_few_samples(
+    metadata: &serde_json::Map<String, serde_json::Value>,
+    min_samples: u64,
+) -> Option<u64> {
+    let samples = metadata.get("samples")?.as_str()?.parse::<u64>().ok()?;
+    (samples < min_samples).then_some(samples)
+}
+
+/// Renames the keys of `metadata` that are in `renames`, keeping their values.
+pub fn rename_keys(
+    metadata: serde_json::Map<String, serde_json::Value>,
+    renames: &BTreeMap<String, String>,
+) -> serde_json::Map<String, serde_json::Value> {
+    if renames.is_empty() {
+        return metadata;
+    }
+    metadata
+        .into_iter()
+        .map(|(key, value)| match renames.get(&key) {
+            Some(renamed) => (renamed.clone(), value),
+            None => (key, value),
+        })
+        .collect()
+}
+
+/// Renames a recording that won't be uploaded from `.local` to `.rejected`, so that it's kept for
+/// inspection but never picked up for upload again.
+async fn reject_segment(storage: &Storage, uploader: &Uploader, job: &Job) {
+    let mut rejected = job.final_filename.clone().into_os_string();
+    rejected.push(naming::REJECTED_SUFFIX);
+    let res = storage::retry("set aside segment", || {
+        tokio::fs::rename(&job.local_filename, &rejected)
+    })
+    .await;
+    match res {
+        Ok(()) => {
+            if let Some(journal) = &uploader.journal {
+                journal.dropped(&job.id, job.primary);
+            }
+        }
+        Err(e) => {
+            storage.check_error(&e);
+            error!("Failed to set aside segment {}: {}", job.id, e);
+        }
+    }
+}
+
+async fn upload_segment(
+    gcs: &gcs::Client,
+    uploader: &Uploader,
+    storage: &Storage,
+    job: &Job,
+    metadata: &serde_json::Map<String, serde_json::Value>,
+) -> anyhow::Result<()> {
+    let local_name = &job.local_filename;
+    let len = match storage::retry("stat segment", || tokio::fs::metadata(local_name)).await {
+        Ok(meta) => meta.len(),
+        // E.g., if the segment was finished twice, and the other upload won.
+        Err(e) if storage::already_renamed(&e, &job.final_filename) => {
+            info!("Segment {} was already uploaded; skipping it", job.id);
+            return Ok(());
+        }
+        Err(e) => {
+            storage.check_error(&e);
+            return Err(e)
+                .with_context(|| format!("Failed to stat segment {}", local_name.display()));
+        }
+    };
+    let memory = uploader.reserve_memory(len).await;
+    let mut contents = storage::retry("read segment", || tokio::fs::read(local_name))
+        .await
+        .inspect_err(|e| storage.check_error(e))
+        .with_context(|| format!("Failed to read segment from {}", local_name.display()))?;
+    let mut metadata = metadata.clone();
+    if let Some(hash) = uploader.content_hash {
+        let digest;
+        (contents, digest) = hash.hex_digest_blocking(contents).await?;
+        metadata.insert(hash.metadata_key().to_string(), digest.into());
+    }
+    let metadata = uploader.object_metadata(metadata).into();
+    let content_disposition = gcs.options.content_disposition(&job.id);
+
+    gcs.put_meta(
+        &job.object_name,
+        &contents,
+        job.format.content_type(),
+        content_disposition.as_deref(),
+        &metadata,
+    )
+    .await?;
+    // The recording is out of memory, so others can be read in while this one is finalized.
+    drop(contents);
+    drop(memory);
+    match job.ended {
+        Some(ended) => info!(
+            "Uploaded to GCS: gs://{}/{}{}, {:.3} s after segment {} ended",
+            gcs.path.bucket,
+            gcs.path.prefix,
+            job.object_name,
+            ended.elapsed().as_secs_f64(),
+            job.id
+        ),
+        None => debug!(
+            "Uploaded to GCS: gs://{}/{}{}",
+            gcs.path.bucket, gcs.path.prefix, job.object_name
+        ),
+    }
+
+    storage::retry("finalize segment", || {
+        tokio::fs::rename(local_name, &job.final_filename)
+    })
+    .await
+    .inspect_err(|e| storage.check_error(e))
+    .context("Failed to finalize filename")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_too_few_samples() {
+        let metadata = |samples: &str| {
+            let mut m = serde_json::Map::new();
+            m.insert("samples".to_string(), samples.to_string().into());
+            m
+        };
+        assert_eq!(too_few_samples(&metadata("0"), 1), Some(0));
+        assert_eq!(too_few_samples(&metadata("0"), 0), None);
+        assert_eq!(too_few_samples(&metadata("4799"), 4800), Some(4799));
+        assert_eq!(too_few_samples(&metadata("4800"), 4800), None);
+        // Without a sample count, there's nothing to go on.
+        assert_eq!(too_few_samples(&serde_json::Map::new(), 1), None);
+        assert_eq!(too_few_samples(&metadata("unknown"), 1), None);
+    }
+
+    #[test]
+    fn test_rename_keys() {
+        let mut metadata = serde_json::Map::new();
+        metadata.insert("samples".to_string(), "48000".into());
+        metadata.insert("sample-rate".to_string(), "48000".into());
+        metadata.insert("project".to_string(), "practice".into());
+        let renames = BTreeMap::from([
+            ("samples".to_string(), "audio_samples".to_string()),
+            ("edge".to_string(), "audio_edge".to_string()),
+        ]);
+        let renamed = rename_keys(metadata.clone(), &renames);
+        let keys: Vec<&str> = renamed.keys().map(String::as_str).collect();
+        assert_eq!(keys, ["audio_samples", "project", "sample-rate"]);
+        assert_eq!(renamed["audio_samples"], metadata["samples"]);
+        assert_eq!(rename_keys(metadata.clone(), &BTreeMap::new()), metadata);
+    }
+
+    #[tokio::test]
+    async fn test_with_deadline() {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_secs(60)).await;
+            "done"
+        };
+        let deadline = Some(Duration::from_millis(10));
+        assert_eq!(with_deadline(deadline, slow()).await, None);
+        assert_eq!(
+            with_deadline(deadline, async { "done" }).await,
+            Some("done")
+        );
+        // Without a deadline, it waits however long it takes.
+        let pending = tokio::time::timeout(Duration::from_millis(10), with_deadline(None, slow()));
+        assert!(pending.await.is_err());
+    }
+
+    fn job(id: &str) -> Job {
+        Job {
+            id: id.to_string(),
+            object_name: format!("recording-{id}.flac"),
+            local_filename: PathBuf::from(format!("recording-{id}.flac.local")),
+            final_filename: PathBuf::from(format!("recording-{id}.flac")),
+            metadata: serde_json::Map::new(),
+            measured: false,
+            format: AudioFormat::Flac,
+            primary: true,
+            ended: None,
+        }
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let ids = ["20240101T000002", "20240101T000001", "20240101T000003"];
+        for (ordered, expected) in [
+            (false, ids),
+            (
+                true,
+                ["20240101T000001", "20240101T000002", "20240101T000003"],
+            ),
+        ] {
+            let mut queue = Queue {
+                jobs: VecDeque::new(),
+                known: HashSet::new(),
+                spilled: false,
+                claimed: HashSet::new(),
+            };
+            for id in ids {
+                queue.add(job(id), ordered);
+            }
+            let queued: Vec<&str> = queue.jobs.iter().map(|j| j.id.as_str()).collect();
+            assert_eq!(queued, expected, "ordered: {ordered}");
+            assert_eq!(queue.known.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_claim() {
+        let path = gcs::Path {
+            bucket: "bucket".to_string(),
+            prefix: String::new(),
+        };
+        let uploader = Uploader::new(path, vec![], 2, Duration::ZERO, None, None, false);
+        let found = || ["20240101T000001", "20240101T000002", "20240101T000003"].map(job);
+
+        // A segment that's still finishing is left to be pushed, and others fill the queue.
+        let claim = uploader.claim("20240101T000002");
+        let mut queue = uploader.queue.lock().unwrap();
+        // As the first rescan does.
+        queue.spilled = false;
+        assert_eq!(queue.refill(found().into(), 2, false), 2);
+        let queued: Vec<&str> = queue.jobs.iter().map(|j| j.id.as_str()).collect();
+        assert_eq!(queued, ["20240101T000001", "20240101T000003"]);
+        assert!(!queue.spilled);
+        drop(queue);
+
+        drop(claim);
+        let mut queue = uploader.queue.lock().unwrap();
+        assert!(queue.claimed.is_empty());
+        assert_eq!(queue.refill(found().into(), 2, false), 0);
+        assert!(queue.spilled);
+    }
+}
@@ -0,0 +1,149 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use anyhow::Context;
+
+/// An external voice activity detector, per `vad_command`, that judges each chunk of audio in place
+/// of the threshold.
+///
+/// The protocol is a lockstep exchange over pipes. For each chunk, `reccon` writes the chunk's raw
+/// samples (mono, signed 16-bit, at 48 kHz, in the configured `byte_order`) to the detector's
+/// standard input, and then waits for the detector to write back one line: `1` if the chunk has
+/// speech, or `0` if it doesn't. Chunks are all the same size, except maybe the last one before
+/// the input ends. Only one chunk is ever outstanding, so the detector never has to buffer, but it
+/// must keep up with real time.
+///
+/// The exchange happens on a separate thread, so that a detector that stops reading or answering
+/// can't hang recording: a detector that doesn't answer within the timeout is killed, and
+/// considered failed.
+pub struct Vad {
+    child: Child,
+    chunks: mpsc::Sender<Vec<u8>>,
+    verdicts: mpsc::Receiver<io::Result<bool>>,
+    timeout: Duration,
+}
+
+impl Vad {
+    pub fn spawn(command: &[String], timeout: Duration) -> anyhow::Result<Self> {
+        let mut child = Command::new(&command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to spawn vad_command {}", command[0]))?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (chunks, chunks_rx) = mpsc::channel();
+        let (verdicts_tx, verdicts) = mpsc::channel();
+        std::thread::spawn(move || exchange(stdin, stdout, chunks_rx, verdicts_tx));
+        Ok(Vad {
+            child,
+            chunks,
+            verdicts,
+            timeout,
+        })
+    }
+
+    /// Asks the detector whether `chunk` has speech. After this fails once, the detector is dead,
+    /// and it should be dropped.
+    pub fn judge(&mut self, chunk: &[u8]) -> anyhow::Result<bool> {
+        if self.chunks.send(chunk.to_vec()).is_err() {
+            anyhow::bail!("vad_command has already failed");
+        }
+        match self.verdicts.recv_timeout(self.timeout) {
+            Ok(verdict) => verdict.context("vad_command failed"),
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                // This also unblocks the exchange thread, whose pipes break.
+                let _ = self.child.kill();
+                anyhow::bail!("vad_command didn't answer within {:?}", self.timeout)
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("vad_command failed"),
+        }
+    }
+}
+
+impl Drop for Vad {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Sends each chunk from `chunks` to the detector and its answer to `verdicts`, until either side
+/// goes away or the detector fails.
+fn exchange(
+    mut stdin: ChildStdin,
+    mut stdout: BufReader<ChildStdout>,
+    chunks: mpsc::Receiver<Vec<u8>>,
+    verdicts: mpsc::Sender<io::Result<bool>>,
+) {
+    let mut line = String::new();
+    for chunk in chunks {
+        let verdict = stdin
+            .write_all(&chunk)
+            .and_then(|()| stdin.flush())
+            .and_then(|()| {
+                line.clear();
+                match stdout.read_line(&mut line)? {
+                    0 => Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "exited without answering",
+                    )),
+                    _ => parse_verdict(&line),
+                }
+            });
+        let failed = verdict.is_err();
+        if verdicts.send(verdict).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Parses one line of the detector's output.
+fn parse_verdict(line: &str) -> io::Result<bool> {
+    match line.trim() {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected 0 or 1, but got {:?}", other),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_verdict() {
+        assert!(parse_verdict("1\n").unwrap());
+        assert!(!parse_verdict("0\n").unwrap());
+        assert!(!parse_verdict(" 0 \r\n").unwrap());
+        assert!(parse_verdict("yes\n").is_err());
+        assert!(parse_verdict("\n").is_err());
+    }
+
+    #[test]
+    fn test_vad() {
+        // Answers each 4-byte chunk by whether its first byte is nonzero.
+        let script = "while chunk=$(head -c 4 | od -An -tu1); [ -n \"$chunk\" ]; do \
+                      set -- $chunk; [ \"$1\" -ne 0 ] && echo 1 || echo 0; done";
+        let command = ["sh", "-c", script].map(String::from);
+        let mut vad = Vad::spawn(&command, Duration::from_secs(10)).unwrap();
+        assert!(vad.judge(&[1, 0, 0, 0]).unwrap());
+        assert!(!vad.judge(&[0, 0, 0, 0]).unwrap());
+        assert!(vad.judge(&[7, 0, 0, 0]).unwrap());
+
+        let command = ["sh", "-c", "head -c 4 >/dev/null; echo maybe"].map(String::from);
+        let mut vad = Vad::spawn(&command, Duration::from_secs(10)).unwrap();
+        assert!(vad.judge(&[0; 4]).is_err());
+        assert!(vad.judge(&[0; 4]).is_err());
+
+        let command = ["sleep", "10"].map(String::from);
+        let mut vad = Vad::spawn(&command, Duration::from_millis(100)).unwrap();
+        assert!(vad.judge(&[0; 4]).is_err());
+    }
+}
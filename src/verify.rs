@@ -0,0 +1,113 @@
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::Context;
+
+use crate::config::AudioFormat;
+
+/// Outcome of decoding a recording to check it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Intact,
+    /// The decoder rejected the file, with its complaint.
+    Corrupt(String),
+}
+
+/// Checks that the decoders for recordings in each of `formats` are installed, since
+/// `verify_before_upload` can't give its guarantee without them.
+pub fn check_installed(formats: impl IntoIterator<Item = AudioFormat>) -> anyhow::Result<()> {
+    let mut programs: Vec<&str> = formats.into_iter().map(decoder).collect();
+    programs.sort_unstable();
+    programs.dedup();
+    for program in programs {
+        let status = Command::new(program)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .with_context(|| {
+                format!(
+                    "Failed to run {program}, which verify_before_upload needs; is it installed?"
+                )
+            })?;
+        if !status.success() {
+            anyhow::bail!("{program} --version failed ({})", status);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the program that [`verify`] decodes recordings in `format` with.
+fn decoder(format: AudioFormat) -> &'static str {
+    match format {
+        AudioFormat::Flac => "flac",
+        AudioFormat::Wav | AudioFormat::Ogg => "sox",
+    }
+}
+
+/// Decodes the whole recording at `file`, without writing the audio anywhere.
+///
+/// FLAC is checked with `flac -t`, which also compares the decoded audio against the checksum that
+/// the encoder stored, so it catches corruption that reading the header alone wouldn't. Other
+/// formats have no such checksum, so they're just decoded with `sox(1)`.
+pub async fn verify(file: &Path, format: AudioFormat) -> anyhow::Result<Verdict> {
+    let args = command(file, format);
+    let output = tokio::process::Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {:?} to verify {}", args[0], file.display()))?;
+    if output.status.success() {
+        return Ok(Verdict::Intact);
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Ok(Verdict::Corrupt(format!(
+        "{:?} failed ({}): {}",
+        args[0],
+        output.status,
+        stderr.trim()
+    )))
+}
+
+/// Returns the program and arguments that decode `file` for [`verify`].
+fn command(file: &Path, format: AudioFormat) -> Vec<OsString> {
+    match format {
+        AudioFormat::Flac => vec![
+            decoder(format).into(),
+            "-t".into(),
+            "-s".into(),
+            "--".into(),
+            file.into(),
+        ],
+        // The type has to be given, since `file` may have a suffix like `.local` after it.
+        AudioFormat::Wav | AudioFormat::Ogg => vec![
+            decoder(format).into(),
+            "-q".into(),
+            "-t".into(),
+            format.extension().into(),
+            file.into(),
+            "-n".into(),
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(
+            command(Path::new("a/b.flac.local"), AudioFormat::Flac),
+            ["flac", "-t", "-s", "--", "a/b.flac.local"]
+        );
+        assert_eq!(
+            command(Path::new("a/b.ogg.local"), AudioFormat::Ogg),
+            ["sox", "-q", "-t", "ogg", "a/b.ogg.local", "-n"]
+        );
+    }
+}